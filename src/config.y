@@ -11,8 +11,28 @@ TopLevels -> Result<Vec<TopLevel>, ()>:
 
 TopLevel -> Result<TopLevel, ()>:
     "ACCOUNT" "STRING" "{" AccountFields "}" { Ok(TopLevel::Account(overall_span($1, $5), map_err($2)?, $4?)) }
+  | "AUDIT_LOG" "=" "STRING" ";" { Ok(TopLevel::AuditLog(map_err($3)?)) }
+  | "CLIENT_TIMEOUT" "=" "TIME" ";" { Ok(TopLevel::ClientTimeout(map_err($3)?)) }
+  | "ERROR_NOTIFY_INTERVAL" "=" "TIME" ";" { Ok(TopLevel::ErrorNotifyInterval(map_err($3)?)) }
+  | "FRONTEND" "=" "STRING" ";" { Ok(TopLevel::Frontend(map_err($3)?)) }
+  | "HTTP_ERROR_FILE" "=" "STRING" ";" { Ok(TopLevel::HttpErrorFile(map_err($3)?)) }
+  | "HTTP_IPV6" "=" "BOOL" ";" { Ok(TopLevel::HttpIpv6(map_err($3)?)) }
+  | "HTTP_SUCCESS_FILE" "=" "STRING" ";" { Ok(TopLevel::HttpSuccessFile(map_err($3)?)) }
+  | "HTTP_TIMEOUT" "=" "TIME" ";" { Ok(TopLevel::HttpTimeout(map_err($3)?)) }
+  | "HTTPS_PROXY" "=" "STRING" ";" { Ok(TopLevel::HttpsProxy(map_err($3)?)) }
+  | "MAX_ACCOUNTS" "=" "INT" ";" { Ok(TopLevel::MaxAccounts(map_err($3)?)) }
+  | "NOTIFY_AUTHORISATIONS_CMD" "=" "STRING" ";" { Ok(TopLevel::NotifyAuthorisationsCmd(map_err($3)?)) }
+  | "NOTIFY_CMD" "=" "STRING" ";" { Ok(TopLevel::NotifyCmd(map_err($3)?)) }
+  | "NOTIFY_ERROR_CMD" "=" "STRING" ";" { Ok(TopLevel::NotifyErrorCmd(map_err($3)?)) }
   | "NOTIFY_INTERVAL" "=" "TIME" ";" { Ok(TopLevel::NotifyInterval(map_err($3)?)) }
+  | "NOTIFY_SUCCESS_CMD" "=" "STRING" ";" { Ok(TopLevel::NotifySuccessCmd(map_err($3)?)) }
+  | "OPEN_BROWSER" "=" "BOOL" ";" { Ok(TopLevel::OpenBrowser(map_err($3)?)) }
+  | "REFRESH_BEFORE_EXPIRY" "=" "TIME" ";" { Ok(TopLevel::RefreshBeforeExpiry(map_err($3)?)) }
+  | "REFRESH_CHECK_INTERVAL" "=" "TIME" ";" { Ok(TopLevel::RefreshCheckInterval(map_err($3)?)) }
   | "REFRESH_RETRY_INTERVAL" "=" "TIME" ";" { Ok(TopLevel::RefreshRetryInterval(map_err($3)?)) }
+  | "SHUTDOWN_GRACE_PERIOD" "=" "TIME" ";" { Ok(TopLevel::ShutdownGracePeriod(map_err($3)?)) }
+  | "TLS_CA_CERT_FILE" "=" "STRING" ";" { Ok(TopLevel::TlsCaCertFile(map_err($3)?)) }
+  | "USER_AGENT" "=" "STRING" ";" { Ok(TopLevel::UserAgent(map_err($3)?)) }
   ;
 
 AccountFields -> Result<Vec<AccountField>, ()>:
@@ -21,19 +41,52 @@ AccountFields -> Result<Vec<AccountField>, ()>:
   ;
 
 AccountField -> Result<AccountField, ()>:
-    "AUTH_URI" "=" "STRING" ";" { Ok(AccountField::AuthUri(map_err($3)?)) }
+    "AUTH_FLOW" "=" "STRING" ";" { Ok(AccountField::AuthFlow(map_err($3)?)) }
+  | "AUTH_PARAMS" "=" "{" AuthParams "}" ";" { Ok(AccountField::AuthParams($1.unwrap_or_else(|x| x).span(), $4?)) }
+  | "AUTH_URI" "=" "STRING" ";" { Ok(AccountField::AuthUri(map_err($3)?)) }
+  | "AUTH_URI_OVERRIDE_CMD" "=" "STRING" ";" { Ok(AccountField::AuthUriOverrideCmd(map_err($3)?)) }
   | "CLIENT_ID" "=" "STRING" ";" { Ok(AccountField::ClientId(map_err($3)?)) }
   | "CLIENT_SECRET" "=" "STRING" ";" { Ok(AccountField::ClientSecret(map_err($3)?)) }
+  | "DEFAULT_TOKEN_LIFETIME" "=" "TIME" ";" { Ok(AccountField::DefaultTokenLifetime(map_err($3)?)) }
   | "LOGIN_HINT" "=" "STRING" ";" { Ok(AccountField::LoginHint(map_err($3)?)) }
+  | "MAX_REFRESH_FAILURES" "=" "INT" ";" { Ok(AccountField::MaxRefreshFailures(map_err($3)?)) }
+  | "NOTIFY_MAX_COUNT" "=" "INT" ";" { Ok(AccountField::NotifyMaxCount(map_err($3)?)) }
+  | "NOTIFY_PENDING_INTERVAL" "=" "TIME" ";" { Ok(AccountField::NotifyPendingInterval(map_err($3)?)) }
+  | "NOT_TRANSIENT_ERROR_IF" "=" "[" Strings "]" ";" { Ok(AccountField::NotTransientErrorIf($1.unwrap_or_else(|x| x).span(), $4?)) }
   | "REDIRECT_URI" "=" "STRING" ";" { Ok(AccountField::RedirectUri(map_err($3)?)) }
   | "REFRESH_BEFORE_EXPIRY" "=" "TIME" ";" { Ok(AccountField::RefreshBeforeExpiry(map_err($3)?)) }
   | "REFRESH_AT_LEAST" "=" "TIME" ";" { Ok(AccountField::RefreshAtLeast(map_err($3)?)) }
-  | "SCOPES" "=" "[" Scopes "]" ";" { Ok(AccountField::Scopes($1.unwrap_or_else(|x| x).span(), $4?)) }
+  | "REFRESH_IF_UNUSED_FOR" "=" "TIME" ";" { Ok(AccountField::RefreshIfUnusedFor(map_err($3)?)) }
+  | "RESPONSE_TYPE" "=" "STRING" ";" { Ok(AccountField::ResponseType(map_err($3)?)) }
+  | "REVOKE_URI" "=" "STRING" ";" { Ok(AccountField::RevokeUri(map_err($3)?)) }
+  | "SASL_USER" "=" "STRING" ";" { Ok(AccountField::SaslUser(map_err($3)?)) }
+  | "SCOPES" "=" "[" Strings "]" ";" { Ok(AccountField::Scopes($1.unwrap_or_else(|x| x).span(), $4?)) }
+  | "SCOPES_CMD" "=" "STRING" ";" { Ok(AccountField::ScopesCmd(map_err($3)?)) }
+  | "SCOPES_ENCODE" "=" "STRING" ";" { Ok(AccountField::ScopesEncode(map_err($3)?)) }
+  | "SCOPES_SEPARATOR" "=" "STRING" ";" { Ok(AccountField::ScopesSeparator(map_err($3)?)) }
+  | "TAGS" "=" "[" Strings "]" ";" { Ok(AccountField::Tags($1.unwrap_or_else(|x| x).span(), $4?)) }
+  | "TLS_CA_CERT_FILE" "=" "STRING" ";" { Ok(AccountField::TlsCaCertFile(map_err($3)?)) }
   | "TOKEN_URI" "=" "STRING" ";" { Ok(AccountField::TokenUri(map_err($3)?)) }
+  | "TOKEN_URI_METHOD" "=" "STRING" ";" { Ok(AccountField::TokenUriMethod(map_err($3)?)) }
+  | "TRANSIENT_ERROR_IF" "=" "[" Strings "]" ";" { Ok(AccountField::TransientErrorIf($1.unwrap_or_else(|x| x).span(), $4?)) }
+  | "USE_NONCE" "=" "BOOL" ";" { Ok(AccountField::UseNonce(map_err($3)?)) }
+  | "USER_AGENT" "=" "STRING" ";" { Ok(AccountField::UserAgent(map_err($3)?)) }
+  | "USERINFO_ENDPOINT" "=" "STRING" ";" { Ok(AccountField::UserinfoEndpoint(map_err($3)?)) }
+  | "VERIFY_TLS" "=" "BOOL" ";" { Ok(AccountField::VerifyTls(map_err($3)?)) }
   ;
 
-Scopes -> Result<Vec<Span>, ()>:
-    Scopes "," "STRING" {
+AuthParams -> Result<Vec<(Span, Span)>, ()>:
+    AuthParams "," "STRING" "=" "STRING" {
+      let mut spans = $1?;
+      spans.push((map_err($3)?, map_err($5)?));
+      Ok(spans)
+    }
+  | "STRING" "=" "STRING" { Ok(vec![(map_err($1)?, map_err($3)?)]) }
+  | { Ok(vec![]) }
+  ;
+
+Strings -> Result<Vec<Span>, ()>:
+    Strings "," "STRING" {
       let mut spans = $1?;
       spans.push(map_err($3)?);
       Ok(spans)
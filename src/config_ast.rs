@@ -2,18 +2,61 @@ use lrpar::Span;
 
 pub enum TopLevel {
     Account(Span, Span, Vec<AccountField>),
+    AuditLog(Span),
+    ClientTimeout(Span),
+    ErrorNotifyInterval(Span),
+    Frontend(Span),
+    HttpErrorFile(Span),
+    HttpIpv6(Span),
+    HttpSuccessFile(Span),
+    HttpTimeout(Span),
+    HttpsProxy(Span),
+    MaxAccounts(Span),
+    NotifyAuthorisationsCmd(Span),
+    NotifyCmd(Span),
+    NotifyErrorCmd(Span),
     NotifyInterval(Span),
+    NotifySuccessCmd(Span),
+    OpenBrowser(Span),
+    RefreshBeforeExpiry(Span),
+    RefreshCheckInterval(Span),
     RefreshRetryInterval(Span),
+    ShutdownGracePeriod(Span),
+    TlsCaCertFile(Span),
+    UserAgent(Span),
 }
 
 pub enum AccountField {
+    AuthFlow(Span),
+    AuthParams(Span, Vec<(Span, Span)>),
     AuthUri(Span),
+    AuthUriOverrideCmd(Span),
     ClientId(Span),
     ClientSecret(Span),
+    DefaultTokenLifetime(Span),
     LoginHint(Span),
+    MaxRefreshFailures(Span),
+    NotifyMaxCount(Span),
+    NotifyPendingInterval(Span),
+    NotTransientErrorIf(Span, Vec<Span>),
     RedirectUri(Span),
     RefreshBeforeExpiry(Span),
     RefreshAtLeast(Span),
+    RefreshIfUnusedFor(Span),
+    ResponseType(Span),
+    RevokeUri(Span),
+    SaslUser(Span),
     Scopes(Span, Vec<Span>),
+    ScopesCmd(Span),
+    ScopesEncode(Span),
+    ScopesSeparator(Span),
+    Tags(Span, Vec<Span>),
+    TlsCaCertFile(Span),
     TokenUri(Span),
+    TokenUriMethod(Span),
+    TransientErrorIf(Span, Vec<Span>),
+    UseNonce(Span),
+    UserAgent(Span),
+    UserinfoEndpoint(Span),
+    VerifyTls(Span),
 }
@@ -0,0 +1,142 @@
+use std::{error::Error, fmt, io};
+
+/// The exit code for errors other than those below. Exit codes are relied upon by users' scripts,
+/// so they must not be changed.
+pub const EXIT_ERROR: i32 = 1;
+/// The exit code when the server isn't running.
+pub const EXIT_SERVER_UNREACHABLE: i32 = 2;
+/// The exit code when a token is not yet available because authentication is pending.
+pub const EXIT_TOKEN_PENDING: i32 = 3;
+/// The exit code when an account doesn't exist.
+pub const EXIT_ACCOUNT_NOT_FOUND: i32 = 4;
+/// The exit code when the server is running, but didn't respond in time.
+pub const EXIT_SERVER_UNRESPONSIVE: i32 = 5;
+
+/// Errors that can occur when a client communicates with the pizauth server.
+#[derive(Debug)]
+pub enum PizauthError {
+    /// The server isn't running or isn't accepting connections.
+    DaemonNotRunning,
+    /// The server has no account with the given name.
+    AccountNotFound(String),
+    /// The named account has no token, but authentication is in progress.
+    TokenPending(String),
+    /// The server sent a response that could not be understood.
+    ProtocolError(String),
+    /// The server understood the request but could not fulfil it.
+    ServerError(String),
+    /// An I/O error occurred communicating with the server.
+    IoError(io::Error),
+    /// The server did not respond in time (e.g. because it is deadlocked).
+    Timeout,
+    /// More than one of the above errors occurred (e.g. when operating on multiple accounts).
+    Multiple(Vec<PizauthError>),
+}
+
+impl PizauthError {
+    /// Return the code the command-line interface should exit with for this error. For
+    /// [PizauthError::Multiple], this is the code of the most severe error, where errors without a
+    /// specific exit code are the most severe, followed by the server being unresponsive or
+    /// unreachable, unknown accounts, and pending tokens.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            PizauthError::DaemonNotRunning => EXIT_SERVER_UNREACHABLE,
+            PizauthError::Timeout => EXIT_SERVER_UNRESPONSIVE,
+            PizauthError::AccountNotFound(_) => EXIT_ACCOUNT_NOT_FOUND,
+            PizauthError::TokenPending(_) => EXIT_TOKEN_PENDING,
+            PizauthError::ProtocolError(_)
+            | PizauthError::ServerError(_)
+            | PizauthError::IoError(_) => EXIT_ERROR,
+            PizauthError::Multiple(errs) => errs
+                .iter()
+                .map(|e| e.exit_code())
+                .max_by_key(|x| match *x {
+                    EXIT_TOKEN_PENDING => 0,
+                    EXIT_ACCOUNT_NOT_FOUND => 1,
+                    EXIT_SERVER_UNREACHABLE | EXIT_SERVER_UNRESPONSIVE => 2,
+                    _ => 3,
+                })
+                .unwrap_or(EXIT_ERROR),
+        }
+    }
+}
+
+impl fmt::Display for PizauthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PizauthError::DaemonNotRunning => {
+                write!(f, "pizauth authenticator not running or not responding")
+            }
+            PizauthError::AccountNotFound(act_name) => write!(f, "No account '{act_name:}'"),
+            PizauthError::TokenPending(act_name) => write!(
+                f,
+                "{act_name:}: Token unavailable until authentication complete"
+            ),
+            PizauthError::ProtocolError(msg) | PizauthError::ServerError(msg) => {
+                write!(f, "{msg:}")
+            }
+            PizauthError::IoError(e) => write!(f, "{e:}"),
+            PizauthError::Timeout => write!(
+                f,
+                "Timed out waiting for pizauth authenticator: it may be unresponsive (check its \
+                log, and if necessary kill and restart it)"
+            ),
+            PizauthError::Multiple(errs) => write!(
+                f,
+                "{}",
+                errs.iter()
+                    .map(|e| e.to_string())
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            ),
+        }
+    }
+}
+
+impl Error for PizauthError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PizauthError::IoError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for PizauthError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            // Timeouts on Unix sockets are reported as `WouldBlock`.
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => PizauthError::Timeout,
+            _ => PizauthError::IoError(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_exit_code() {
+        assert_eq!(
+            PizauthError::DaemonNotRunning.exit_code(),
+            EXIT_SERVER_UNREACHABLE
+        );
+        assert_eq!(PizauthError::Timeout.exit_code(), EXIT_SERVER_UNRESPONSIVE);
+        assert_eq!(
+            PizauthError::TokenPending("x".to_owned()).exit_code(),
+            EXIT_TOKEN_PENDING
+        );
+        let pending = || PizauthError::TokenPending("x".to_owned());
+        let unknown = || PizauthError::AccountNotFound("y".to_owned());
+        let error = || PizauthError::ServerError("z".to_owned());
+        assert_eq!(
+            PizauthError::Multiple(vec![pending(), unknown(), pending()]).exit_code(),
+            EXIT_ACCOUNT_NOT_FOUND
+        );
+        assert_eq!(
+            PizauthError::Multiple(vec![pending(), error(), unknown()]).exit_code(),
+            EXIT_ERROR
+        );
+    }
+}
@@ -0,0 +1,158 @@
+//! A front-end which runs user-specified shell commands, allowing notifications to be integrated
+//! with any desktop or notification system without pizauth needing to know about it. Each command
+//! is passed a JSON object on stdin. If a command is not specified, the notification is logged
+//! instead.
+
+use std::{
+    error::Error,
+    io::{self, Write},
+    process::{Command, Stdio},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use json::{object, JsonValue};
+use log::{error, info};
+use url::Url;
+
+use super::{error_body, ErrorKind, Frontend};
+use crate::config::Config;
+
+/// How long a command may run before it is killed.
+const CMD_TIMEOUT: Duration = Duration::from_secs(30);
+/// How often do we check whether a command has exited?
+const CMD_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+pub struct Cmd {
+    authorisations_cmd: Option<String>,
+    error_cmd: Option<String>,
+    success_cmd: Option<String>,
+}
+
+impl Cmd {
+    /// Create a front-end which runs the `notify_*_cmd`s in `conf`.
+    pub fn from_config(conf: &Config) -> Self {
+        Cmd {
+            authorisations_cmd: conf.notify_authorisations_cmd.clone(),
+            error_cmd: conf.notify_error_cmd.clone(),
+            success_cmd: conf.notify_success_cmd.clone(),
+        }
+    }
+}
+
+impl Frontend for Cmd {
+    fn main_loop(self: Arc<Self>) -> Result<(), Box<dyn Error>> {
+        // All the work is done in the `notify_*` functions, but returning would terminate pizauth.
+        loop {
+            thread::park();
+        }
+    }
+
+    fn notify_error(
+        &self,
+        act_name: Option<String>,
+        kind: ErrorKind,
+        msg: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        match &self.error_cmd {
+            Some(cmd) => run_cmd(
+                cmd,
+                object! { account: act_name, kind: kind.as_str(), message: msg },
+                CMD_TIMEOUT,
+            ),
+            None => {
+                error!("{}", error_body(act_name.as_deref(), msg));
+                Ok(())
+            }
+        }
+    }
+
+    fn notify_success(&self, act_name: String) -> Result<(), Box<dyn Error>> {
+        match &self.success_cmd {
+            Some(cmd) => run_cmd(cmd, object! { account: act_name }, CMD_TIMEOUT),
+            None => {
+                info!("{act_name:}: authenticated");
+                Ok(())
+            }
+        }
+    }
+
+    fn notify_authorisations(&self, to_notify: Vec<(String, Url)>) -> Result<(), Box<dyn Error>> {
+        match &self.authorisations_cmd {
+            Some(cmd) => {
+                let mut accounts = JsonValue::new_array();
+                for (act_name, url) in to_notify {
+                    accounts.push(object! { account: act_name, url: url.as_str() })?;
+                }
+                run_cmd(cmd, object! { accounts: accounts }, CMD_TIMEOUT)
+            }
+            None => {
+                for (act_name, url) in to_notify {
+                    info!("{act_name:}: authorisation required at {url:}");
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Run `cmd` with `sh -c`, passing it `input` on stdin, and wait for it to exit. If it has not
+/// exited after `timeout`, it is killed.
+fn run_cmd(cmd: &str, input: JsonValue, timeout: Duration) -> Result<(), Box<dyn Error>> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .map_err(|e| format!("Can't run '{cmd:}': {e:}"))?;
+    // The input is small enough to fit in a pipe's buffer, so this won't block even if the
+    // command doesn't read its stdin. If the command has already exited, that's its business.
+    if let Some(mut stdin) = child.stdin.take() {
+        match stdin.write_all(input.dump().as_bytes()) {
+            Err(e) if e.kind() != io::ErrorKind::BrokenPipe => return Err(e.into()),
+            _ => (),
+        }
+    }
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            if status.success() {
+                return Ok(());
+            }
+            return Err(format!("'{cmd:}' failed: {status:}").into());
+        }
+        if Instant::now() >= deadline {
+            child.kill().ok();
+            child.wait()?;
+            return Err(format!("'{cmd:}' timed out after {}s", timeout.as_secs()).into());
+        }
+        thread::sleep(CMD_POLL_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{env, fs, process};
+
+    #[test]
+    fn test_run_cmd() {
+        let p = env::temp_dir().join(format!("pizauth_test_run_cmd_{}", process::id()));
+        let cmd = format!("cat > '{}'", p.display());
+        run_cmd(&cmd, object! { account: "x" }, CMD_TIMEOUT).unwrap();
+        let input = json::parse(&fs::read_to_string(&p).unwrap()).unwrap();
+        fs::remove_file(&p).unwrap();
+        assert_eq!(input["account"], "x");
+
+        match run_cmd("exit 3", object! {}, CMD_TIMEOUT) {
+            Err(e) if e.to_string().contains("failed") => (),
+            _ => panic!(),
+        }
+        match run_cmd("sleep 10", object! {}, Duration::from_millis(100)) {
+            Err(e) if e.to_string().contains("timed out") => (),
+            _ => panic!(),
+        }
+    }
+}
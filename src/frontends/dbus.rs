@@ -0,0 +1,194 @@
+//! A front-end which talks directly to the desktop's notification server over D-Bus (using the
+//! [zbus crate](https://crates.io/crates/zbus)).
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    process::Command,
+    sync::{Arc, Mutex},
+    thread,
+    time::Instant,
+};
+
+use log::error;
+use url::Url;
+use zbus::{
+    blocking::{Connection, Proxy},
+    zvariant::Value,
+};
+
+use super::{error_body, ErrorKind, Frontend, TransientLimiter};
+
+/// The key of the action which opens an authorisation URL in the user's browser.
+const OPEN_ACTION: &str = "open";
+/// The key of the action which notification servers invoke when the notification itself is
+/// clicked.
+const DEFAULT_ACTION: &str = "default";
+/// The values of the notification specification's `urgency` hint.
+const URGENCY_LOW: u8 = 0;
+const URGENCY_NORMAL: u8 = 1;
+const URGENCY_CRITICAL: u8 = 2;
+
+/// A frontend using the `org.freedesktop.Notifications` D-Bus interface. Each pending
+/// authorisation is shown as a separate notification with an "Open browser" action.
+pub struct DBus {
+    proxy: Proxy<'static>,
+    /// The notification ID and authorisation URL of each account which is pending authorisation.
+    pending: Mutex<HashMap<String, (u32, Url)>>,
+    transient_limiter: TransientLimiter,
+}
+
+impl DBus {
+    /// Connect to the desktop's notification server.
+    pub fn new() -> Result<Self, Box<dyn Error>> {
+        let conn = Connection::session()?;
+        let proxy = Proxy::new(
+            &conn,
+            "org.freedesktop.Notifications",
+            "/org/freedesktop/Notifications",
+            "org.freedesktop.Notifications",
+        )?;
+        let caps: Vec<String> = proxy.call("GetCapabilities", &())?;
+        if !caps.iter().any(|x| x == "actions") {
+            return Err("Notification server does not have required capability: actions".into());
+        }
+        Ok(DBus {
+            proxy,
+            pending: Mutex::new(HashMap::new()),
+            transient_limiter: TransientLimiter::new(),
+        })
+    }
+
+    /// Show a notification with the urgency `urgency`, replacing the notification `replaces_id`
+    /// (if it is non-zero), and returning the new notification's ID.
+    fn notify(
+        &self,
+        replaces_id: u32,
+        urgency: u8,
+        summary: &str,
+        body: &str,
+        actions: &[&str],
+    ) -> Result<u32, Box<dyn Error>> {
+        let hints: HashMap<&str, Value> = HashMap::from([("urgency", Value::U8(urgency))]);
+        Ok(self.proxy.call(
+            "Notify",
+            &(
+                "pizauth",
+                replaces_id,
+                "",
+                summary,
+                body,
+                actions,
+                hints,
+                -1i32,
+            ),
+        )?)
+    }
+
+    /// If `act_name` has a pending authorisation notification, close it.
+    fn close_pending(&self, act_name: &str) -> Result<(), Box<dyn Error>> {
+        let id = self
+            .pending
+            .lock()
+            .unwrap()
+            .remove(act_name)
+            .map(|(id, _)| id);
+        if let Some(id) = id {
+            self.proxy.call::<_, _, ()>("CloseNotification", &(id,))?;
+        }
+        Ok(())
+    }
+}
+
+impl Frontend for DBus {
+    fn main_loop(self: Arc<Self>) -> Result<(), Box<dyn Error>> {
+        for msg in self.proxy.receive_signal("ActionInvoked")? {
+            let (id, action) = match msg.body().deserialize::<(u32, String)>() {
+                Ok(x) => x,
+                Err(e) => {
+                    error!("{e:}");
+                    continue;
+                }
+            };
+            if action != OPEN_ACTION && action != DEFAULT_ACTION {
+                continue;
+            }
+            let url = self
+                .pending
+                .lock()
+                .unwrap()
+                .values()
+                .find(|(x, _)| *x == id)
+                .map(|(_, url)| url.clone());
+            if let Some(url) = url {
+                // `xdg-open` normally exits quickly, but we don't want to stop listening for
+                // other actions if it doesn't.
+                thread::spawn(move || {
+                    if let Err(e) = Command::new("xdg-open").arg(url.as_str()).status() {
+                        error!("Can't open {url:}: {e:}");
+                    }
+                });
+            }
+        }
+        Err("Connection to notification server closed".into())
+    }
+
+    fn notify_error(
+        &self,
+        act_name: Option<String>,
+        kind: ErrorKind,
+        msg: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        if !self
+            .transient_limiter
+            .allow(act_name.as_deref(), kind, Instant::now())
+        {
+            return Ok(());
+        }
+        let urgency = match kind {
+            ErrorKind::TransientNetwork => URGENCY_LOW,
+            ErrorKind::RefreshTokenInvalid => URGENCY_CRITICAL,
+            ErrorKind::TokenEndpointRejected | ErrorKind::ConfigReload | ErrorKind::Internal => {
+                URGENCY_NORMAL
+            }
+        };
+        if let Some(act_name) = &act_name {
+            self.close_pending(act_name)?;
+        }
+        self.notify(
+            0,
+            urgency,
+            &format!("pizauth: {}", kind.summary()),
+            &escape(&error_body(act_name.as_deref(), msg)),
+            &[],
+        )?;
+        Ok(())
+    }
+
+    fn notify_success(&self, act_name: String) -> Result<(), Box<dyn Error>> {
+        self.close_pending(&act_name)
+    }
+
+    fn notify_authorisations(&self, to_notify: Vec<(String, Url)>) -> Result<(), Box<dyn Error>> {
+        let mut pending = self.pending.lock().unwrap();
+        for (act_name, url) in to_notify {
+            let replaces_id = pending.get(&act_name).map(|(id, _)| *id).unwrap_or(0);
+            let id = self.notify(
+                replaces_id,
+                URGENCY_NORMAL,
+                "pizauth: Authorization needed",
+                &escape(&act_name),
+                &[OPEN_ACTION, "Open browser", DEFAULT_ACTION, "Open browser"],
+            )?;
+            pending.insert(act_name, (id, url));
+        }
+        Ok(())
+    }
+}
+
+/// Escape `s` so that notification servers which support body markup display it literally.
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
@@ -0,0 +1,52 @@
+//! A front-end for machines without any notification mechanism (e.g. servers accessed over SSH),
+//! which writes authorisation URLs and errors to pizauth's log (syslog, or stderr if the server
+//! is run with `-d`).
+
+use std::{error::Error, sync::Arc, thread};
+
+use log::{error, info, warn};
+use url::Url;
+
+use super::{ErrorKind, Frontend};
+
+pub struct Headless;
+
+impl Frontend for Headless {
+    fn main_loop(self: Arc<Self>) -> Result<(), Box<dyn Error>> {
+        // All the work is done in the `notify_*` functions, but returning would terminate pizauth.
+        loop {
+            thread::park();
+        }
+    }
+
+    fn notify_error(
+        &self,
+        act_name: Option<String>,
+        kind: ErrorKind,
+        msg: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        match (act_name, kind) {
+            // pizauth retries by itself, so these are only logged if the user asks for warnings.
+            (Some(act_name), ErrorKind::TransientNetwork) => {
+                warn!("account '{act_name:}' failed to refresh: {msg:}")
+            }
+            (Some(act_name), _) => error!("account '{act_name:}' failed to authenticate: {msg:}"),
+            (None, _) => error!("{msg:}"),
+        }
+        Ok(())
+    }
+
+    fn notify_success(&self, act_name: String) -> Result<(), Box<dyn Error>> {
+        info!("account '{act_name:}' authenticated");
+        Ok(())
+    }
+
+    fn notify_authorisations(&self, to_notify: Vec<(String, Url)>) -> Result<(), Box<dyn Error>> {
+        // By default only errors are logged, but the user must see these whatever the verbosity.
+        // The notifier already ensures that each URL is only repeated every `notify_interval`.
+        for (act_name, url) in to_notify {
+            error!("account '{act_name:}' requires authorisation: {url:}");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,239 @@
+//! A front-end for macOS, which shows notifications with `osascript` and can open authorisation
+//! URLs in the user's browser with `open`.
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    process::Command,
+    sync::{Arc, Mutex},
+    thread,
+    time::Instant,
+};
+
+use log::error;
+use url::Url;
+
+use super::{error_body, ErrorKind, Frontend, TransientLimiter};
+use crate::config::Config;
+
+/// Runs the external programs the macOS frontend relies on. This is a trait so that the frontend's
+/// logic can be tested on other platforms.
+pub trait Runner: Send + Sync {
+    /// Run `prog` with `args`, waiting for it to exit.
+    fn run(&self, prog: &str, args: &[&str]) -> Result<(), Box<dyn Error>>;
+}
+
+/// The [Runner] which really runs programs.
+#[derive(Default)]
+pub struct SystemRunner;
+
+impl Runner for SystemRunner {
+    fn run(&self, prog: &str, args: &[&str]) -> Result<(), Box<dyn Error>> {
+        let status = Command::new(prog).args(args).status()?;
+        if !status.success() {
+            return Err(format!("{prog:} failed: {status:}").into());
+        }
+        Ok(())
+    }
+}
+
+pub struct MacOs<R: Runner = SystemRunner> {
+    runner: R,
+    /// Should pending authorisation URLs be opened in the user's browser?
+    open_browser: bool,
+    /// The URL most recently opened for each account, so that reminders don't open the same URL
+    /// again.
+    opened: Mutex<HashMap<String, Url>>,
+    transient_limiter: TransientLimiter,
+}
+
+impl MacOs {
+    /// Create a front-end configured by `conf`.
+    pub fn from_config(conf: &Config) -> Self {
+        MacOs::with_runner(SystemRunner, conf.open_browser)
+    }
+}
+
+impl<R: Runner> MacOs<R> {
+    fn with_runner(runner: R, open_browser: bool) -> Self {
+        MacOs {
+            runner,
+            open_browser,
+            opened: Mutex::new(HashMap::new()),
+            transient_limiter: TransientLimiter::new(),
+        }
+    }
+
+    /// Show a notification with the title `title` and the body `body`.
+    fn notify(&self, title: &str, body: &str) -> Result<(), Box<dyn Error>> {
+        let script = format!(
+            "display notification {} with title {}",
+            applescript_str(body),
+            applescript_str(title)
+        );
+        self.runner.run("osascript", &["-e", &script])
+    }
+}
+
+impl<R: Runner> Frontend for MacOs<R> {
+    fn main_loop(self: Arc<Self>) -> Result<(), Box<dyn Error>> {
+        // All the work is done in the `notify_*` functions, but returning would terminate pizauth.
+        loop {
+            thread::park();
+        }
+    }
+
+    fn notify_error(
+        &self,
+        act_name: Option<String>,
+        kind: ErrorKind,
+        msg: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        if !self
+            .transient_limiter
+            .allow(act_name.as_deref(), kind, Instant::now())
+        {
+            return Ok(());
+        }
+        if let Some(act_name) = &act_name {
+            self.opened.lock().unwrap().remove(act_name);
+        }
+        self.notify(
+            &format!("pizauth: {}", kind.summary()),
+            &error_body(act_name.as_deref(), msg),
+        )
+    }
+
+    fn notify_success(&self, act_name: String) -> Result<(), Box<dyn Error>> {
+        self.opened.lock().unwrap().remove(&act_name);
+        self.notify("pizauth: Authenticated", &act_name)
+    }
+
+    fn notify_authorisations(&self, to_notify: Vec<(String, Url)>) -> Result<(), Box<dyn Error>> {
+        for (act_name, url) in to_notify {
+            if self.open_browser {
+                let mut opened = self.opened.lock().unwrap();
+                if opened.get(&act_name) != Some(&url) {
+                    // If `open` fails, the notification below still tells the user what to do.
+                    match self.runner.run("/usr/bin/open", &[url.as_str()]) {
+                        Ok(()) => {
+                            opened.insert(act_name.clone(), url.clone());
+                        }
+                        Err(e) => error!("Can't open {url:}: {e:}"),
+                    }
+                }
+                drop(opened);
+                self.notify(
+                    "pizauth: Authorization needed",
+                    &format!("{act_name:}: complete authorization in your browser"),
+                )?;
+            } else {
+                self.notify(
+                    "pizauth: Authorization needed",
+                    &format!("{act_name:}: {url:}"),
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Quote `s` as an AppleScript string literal.
+fn applescript_str(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A [Runner] which records the programs it is asked to run.
+    #[derive(Default)]
+    struct MockRunner {
+        runs: Mutex<Vec<Vec<String>>>,
+    }
+
+    impl Runner for MockRunner {
+        fn run(&self, prog: &str, args: &[&str]) -> Result<(), Box<dyn Error>> {
+            let mut run = vec![prog.to_owned()];
+            run.extend(args.iter().map(|x| (*x).to_owned()));
+            self.runs.lock().unwrap().push(run);
+            Ok(())
+        }
+    }
+
+    impl MacOs<MockRunner> {
+        fn take_runs(&self) -> Vec<Vec<String>> {
+            self.runner.runs.lock().unwrap().drain(..).collect()
+        }
+    }
+
+    #[test]
+    fn test_applescript_str() {
+        assert_eq!(applescript_str("a"), "\"a\"");
+        assert_eq!(applescript_str(r#"a"b\c"#), r#""a\"b\\c""#);
+    }
+
+    #[test]
+    fn test_notify() {
+        let url = Url::parse("http://a.com/?x=\"").unwrap();
+        let fe = MacOs::with_runner(MockRunner::default(), false);
+        fe.notify_authorisations(vec![("x".to_owned(), url.clone())])
+            .unwrap();
+        let runs = fe.take_runs();
+        assert_eq!(runs.len(), 1);
+        assert_eq!(&runs[0][..2], &["osascript", "-e"]);
+        assert!(runs[0][2].starts_with("display notification \"x: http://a.com/?x=%22\""));
+
+        fe.notify_error(
+            Some("x".to_owned()),
+            ErrorKind::RefreshTokenInvalid,
+            "it's \"bad\"",
+        )
+        .unwrap();
+        let runs = fe.take_runs();
+        assert!(runs[0][2].contains(r#""x: it's \"bad\"""#));
+        assert!(runs[0][2].ends_with(r#""pizauth: Reauthentication required""#));
+
+        // Transient errors are rate-limited per account.
+        for _ in 0..2 {
+            fe.notify_error(Some("x".to_owned()), ErrorKind::TransientNetwork, "e")
+                .unwrap();
+            fe.notify_error(Some("y".to_owned()), ErrorKind::TransientNetwork, "e")
+                .unwrap();
+        }
+        assert_eq!(fe.take_runs().len(), 2);
+    }
+
+    #[test]
+    fn test_open_browser() {
+        let url1 = Url::parse("http://a.com/1").unwrap();
+        let url2 = Url::parse("http://a.com/2").unwrap();
+        let fe = MacOs::with_runner(MockRunner::default(), true);
+        let opens = |fe: &MacOs<MockRunner>| {
+            fe.take_runs()
+                .into_iter()
+                .filter(|x| x[0] == "/usr/bin/open")
+                .map(|x| x[1].clone())
+                .collect::<Vec<_>>()
+        };
+
+        fe.notify_authorisations(vec![("x".to_owned(), url1.clone())])
+            .unwrap();
+        assert_eq!(opens(&fe), vec![url1.to_string()]);
+        // A reminder doesn't reopen the same URL...
+        fe.notify_authorisations(vec![("x".to_owned(), url1.clone())])
+            .unwrap();
+        assert!(opens(&fe).is_empty());
+        // ...but a new URL is opened.
+        fe.notify_authorisations(vec![("x".to_owned(), url2.clone())])
+            .unwrap();
+        assert_eq!(opens(&fe), vec![url2.to_string()]);
+        // Once authentication has finished, the same URL can be opened again.
+        fe.notify_success("x".to_owned()).unwrap();
+        fe.take_runs();
+        fe.notify_authorisations(vec![("x".to_owned(), url2.clone())])
+            .unwrap();
+        assert_eq!(opens(&fe), vec![url2.to_string()]);
+    }
+}
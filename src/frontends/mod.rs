@@ -1,26 +1,206 @@
+pub mod cmd;
+#[cfg(feature = "frontend_dbus")]
+pub mod dbus;
+pub mod headless;
+pub mod macos;
 #[cfg(feature = "frontend_notify-rust")]
 pub mod notify_rust;
+pub mod stderr;
 
-use std::{error::Error, sync::Arc};
+#[cfg(not(target_os = "macos"))]
+use std::env;
+use std::{
+    collections::HashMap,
+    error::Error,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
 use url::Url;
 
-pub trait Frontend: Send + Sync {
-    /// Create a front-end instance.
-    fn new() -> Result<Self, Box<dyn Error>>
-    where
-        Self: Sized;
+use crate::config::Config;
+
+/// The frontends a user can select with the `frontend` config option. Only frontends which pizauth
+/// was built with are available.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FrontendKind {
+    Cmd,
+    #[cfg(feature = "frontend_dbus")]
+    DBus,
+    Headless,
+    MacOs,
+    #[cfg(feature = "frontend_notify-rust")]
+    NotifyRust,
+    Stderr,
+}
+
+impl FrontendKind {
+    /// Return the frontend called `name`, or `Err(String)` (containing a human readable message)
+    /// if there is no such frontend or pizauth was not built with it.
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        match name {
+            "cmd" => Ok(FrontendKind::Cmd),
+            #[cfg(feature = "frontend_dbus")]
+            "dbus" => Ok(FrontendKind::DBus),
+            #[cfg(not(feature = "frontend_dbus"))]
+            "dbus" => Err("pizauth was built without the 'frontend_dbus' feature".to_owned()),
+            "headless" => Ok(FrontendKind::Headless),
+            "macos" => Ok(FrontendKind::MacOs),
+            #[cfg(feature = "frontend_notify-rust")]
+            "notify-rust" => Ok(FrontendKind::NotifyRust),
+            #[cfg(not(feature = "frontend_notify-rust"))]
+            "notify-rust" => {
+                Err("pizauth was built without the 'frontend_notify-rust' feature".to_owned())
+            }
+            "stderr" => Ok(FrontendKind::Stderr),
+            _ => Err(format!("Unknown frontend '{name:}'")),
+        }
+    }
+
+    /// The name by which the `frontend` config option selects this frontend.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FrontendKind::Cmd => "cmd",
+            #[cfg(feature = "frontend_dbus")]
+            FrontendKind::DBus => "dbus",
+            FrontendKind::Headless => "headless",
+            FrontendKind::MacOs => "macos",
+            #[cfg(feature = "frontend_notify-rust")]
+            FrontendKind::NotifyRust => "notify-rust",
+            FrontendKind::Stderr => "stderr",
+        }
+    }
+
+    /// Return the frontend used if the config doesn't specify one: the native frontend on macOS;
+    /// the headless frontend if there is no graphical display; otherwise the first frontend
+    /// pizauth was built with.
+    pub fn default_kind() -> Self {
+        #[cfg(target_os = "macos")]
+        return FrontendKind::MacOs;
+        #[cfg(not(target_os = "macos"))]
+        {
+            if !["DISPLAY", "WAYLAND_DISPLAY"]
+                .iter()
+                .any(|x| env::var_os(x).is_some_and(|v| !v.is_empty()))
+            {
+                return FrontendKind::Headless;
+            }
+            #[cfg(feature = "frontend_notify-rust")]
+            return FrontendKind::NotifyRust;
+            #[cfg(all(feature = "frontend_dbus", not(feature = "frontend_notify-rust")))]
+            return FrontendKind::DBus;
+            #[cfg(not(any(feature = "frontend_dbus", feature = "frontend_notify-rust")))]
+            return FrontendKind::Stderr;
+        }
+    }
+}
+
+/// How often frontends which rate-limit [ErrorKind::TransientNetwork] errors show one for a given
+/// account.
+const TRANSIENT_NOTIFY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// The kind of error a frontend is being notified of, so that it can decide how urgently (or
+/// whether) to show it to the user.
+#[derive(Clone, Copy, Debug, Hash, PartialEq)]
+pub enum ErrorKind {
+    /// Refreshing failed in a way that is likely to succeed if retried (e.g. a network error or an
+    /// overloaded server). pizauth will retry without the user needing to do anything.
+    TransientNetwork,
+    /// Obtaining a token failed because the OAuth2 server rejected our request, couldn't be
+    /// contacted, or sent a response we can't use: the user must authenticate again.
+    TokenEndpointRejected,
+    /// The refresh token is no longer valid (or pizauth has given up on it): the user must
+    /// reauthenticate.
+    RefreshTokenInvalid,
+    /// The config could not be reloaded: the old config remains in force.
+    ConfigReload,
+    /// Something went wrong within pizauth itself.
+    Internal,
+}
+
+impl ErrorKind {
+    /// A short machine readable name for this kind of error.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorKind::TransientNetwork => "transient_network",
+            ErrorKind::TokenEndpointRejected => "token_endpoint_rejected",
+            ErrorKind::RefreshTokenInvalid => "refresh_token_invalid",
+            ErrorKind::ConfigReload => "config_reload",
+            ErrorKind::Internal => "internal",
+        }
+    }
+
+    /// A short human readable summary of this kind of error, suitable for a notification's title.
+    pub fn summary(&self) -> &'static str {
+        match self {
+            ErrorKind::TransientNetwork => "Refreshing failed (will retry)",
+            ErrorKind::TokenEndpointRejected => "Authentication failed",
+            ErrorKind::RefreshTokenInvalid => "Reauthentication required",
+            ErrorKind::ConfigReload => "Config reload failed",
+            ErrorKind::Internal => "Internal error",
+        }
+    }
+}
+
+/// Format the error `msg` for display, prefixed by `act_name` if the error relates to an account.
+fn error_body(act_name: Option<&str>, msg: &str) -> String {
+    match act_name {
+        Some(act_name) => format!("{act_name:}: {msg:}"),
+        None => msg.to_owned(),
+    }
+}
+
+/// Records when each account last had an [ErrorKind::TransientNetwork] error shown, so that
+/// frontends which interrupt the user can show such errors at most every
+/// [TRANSIENT_NOTIFY_INTERVAL]: the refresher retries regularly, and an hour-long outage shouldn't
+/// produce dozens of notifications.
+struct TransientLimiter {
+    last_shown: Mutex<HashMap<Option<String>, Instant>>,
+}
+
+impl TransientLimiter {
+    fn new() -> Self {
+        TransientLimiter {
+            last_shown: Mutex::new(HashMap::new()),
+        }
+    }
 
+    /// Should an error of kind `kind` for `act_name` be shown at time `now`? Errors other than
+    /// [ErrorKind::TransientNetwork] are always shown.
+    fn allow(&self, act_name: Option<&str>, kind: ErrorKind, now: Instant) -> bool {
+        if kind != ErrorKind::TransientNetwork {
+            return true;
+        }
+        let mut last_shown = self.last_shown.lock().unwrap();
+        match last_shown.get(&act_name.map(|x| x.to_owned())) {
+            Some(t) if now.saturating_duration_since(*t) < TRANSIENT_NOTIFY_INTERVAL => false,
+            _ => {
+                last_shown.insert(act_name.map(|x| x.to_owned()), now);
+                true
+            }
+        }
+    }
+}
+
+/// A front-end, which tells the user about pending authorisations and errors. Front-ends are used
+/// as trait objects, created by [build_frontend].
+pub trait Frontend: Send + Sync {
     /// Execute the main loop of the front-end. When this function returns, pizauth will terminate.
     fn main_loop(self: Arc<Self>) -> Result<(), Box<dyn Error>>;
 
-    /// Notify the user that an account has failed to authenticate. Note that:
+    /// Notify the user of an error of kind `kind`, described by `msg`. If the error relates to a
+    /// specific account, `act_name` is `Some`. Note that:
     ///   1. This function may be called from an arbitrary thread. If the frontend needs to execute
     ///      some code on a specific thread, it will need to communicate the notification to that
     ///      thread itself.
     ///   2. This function can block for as long as it wants, but for as long as it blocks, the
     ///      frontend may not be informed of further notifications.
-    fn notify_error(&self, act_name: String, msg: &str) -> Result<(), Box<dyn Error>>;
+    fn notify_error(
+        &self,
+        act_name: Option<String>,
+        kind: ErrorKind,
+        msg: &str,
+    ) -> Result<(), Box<dyn Error>>;
 
     /// Notify the user that an account has authenticated. Note that:
     ///   1. This function may be called from an arbitrary thread. If the frontend needs to execute
@@ -39,7 +219,75 @@ pub trait Frontend: Send + Sync {
     fn notify_authorisations(&self, to_notify: Vec<(String, Url)>) -> Result<(), Box<dyn Error>>;
 }
 
-pub fn preferred_frontend() -> Result<Arc<dyn Frontend>, Box<dyn Error>> {
-    #[cfg(feature = "frontend_notify-rust")]
-    Ok(Arc::new(notify_rust::NotifyRust::new()?))
+/// Create the frontend called `name` (one of the values accepted by the `frontend` config
+/// option), taking any frontend specific settings from `conf`.
+pub fn build_frontend(name: &str, conf: &Config) -> Result<Arc<dyn Frontend>, Box<dyn Error>> {
+    match FrontendKind::from_name(name)? {
+        FrontendKind::Cmd => Ok(Arc::new(cmd::Cmd::from_config(conf))),
+        #[cfg(feature = "frontend_dbus")]
+        FrontendKind::DBus => Ok(Arc::new(dbus::DBus::new()?)),
+        FrontendKind::Headless => Ok(Arc::new(headless::Headless)),
+        FrontendKind::MacOs => Ok(Arc::new(macos::MacOs::from_config(conf))),
+        #[cfg(feature = "frontend_notify-rust")]
+        FrontendKind::NotifyRust => Ok(Arc::new(notify_rust::NotifyRust::new()?)),
+        FrontendKind::Stderr => Ok(Arc::new(stderr::Stderr)),
+    }
+}
+
+/// Create the frontend selected by `conf` or, if it doesn't select one, the default frontend.
+pub fn preferred_frontend(conf: &Config) -> Result<Arc<dyn Frontend>, Box<dyn Error>> {
+    build_frontend(
+        conf.frontend
+            .unwrap_or_else(FrontendKind::default_kind)
+            .as_str(),
+        conf,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_build_frontend() {
+        for kind in [
+            FrontendKind::Cmd,
+            FrontendKind::Headless,
+            FrontendKind::MacOs,
+            FrontendKind::Stderr,
+        ] {
+            assert_eq!(FrontendKind::from_name(kind.as_str()), Ok(kind));
+        }
+
+        let conf = Config::from_str(
+            r#"account "x" {
+                auth_uri = "http://a.com";
+                client_id = "b";
+                client_secret = "c";
+                scopes = ["d"];
+                token_uri = "http://g.com";
+            }"#,
+        )
+        .unwrap();
+        assert!(build_frontend("stderr", &conf).is_ok());
+        match build_frontend("nope", &conf) {
+            Err(e) if e.to_string().contains("Unknown frontend 'nope'") => (),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_transient_limiter() {
+        let limiter = TransientLimiter::new();
+        let now = Instant::now();
+        let later = now + TRANSIENT_NOTIFY_INTERVAL;
+        assert!(limiter.allow(Some("x"), ErrorKind::TransientNetwork, now));
+        assert!(!limiter.allow(Some("x"), ErrorKind::TransientNetwork, now));
+        // Accounts are limited independently, and other kinds of error aren't limited at all.
+        assert!(limiter.allow(Some("y"), ErrorKind::TransientNetwork, now));
+        assert!(limiter.allow(Some("x"), ErrorKind::RefreshTokenInvalid, now));
+        assert!(limiter.allow(Some("x"), ErrorKind::RefreshTokenInvalid, now));
+        assert!(limiter.allow(Some("x"), ErrorKind::TransientNetwork, later));
+        assert!(!limiter.allow(Some("x"), ErrorKind::TransientNetwork, later));
+    }
 }
@@ -9,12 +9,14 @@ use std::{
 };
 
 use log::error;
+#[cfg(all(unix, not(target_os = "macos")))]
+use notify_rust::Urgency;
 use notify_rust::{
     get_capabilities, get_server_information, Notification, NotificationHandle, Timeout,
 };
 use url::Url;
 
-use super::Frontend;
+use super::{error_body, ErrorKind, Frontend, TransientLimiter};
 
 const NOTIFICATION_TIMEOUT: u64 = 30; // Seconds
 
@@ -27,10 +29,12 @@ pub struct NotifyRust {
     /// Queued authentication URLs. A `None` URL means "this account has now authenticated and it
     /// no longer needs to be displayed to the user."
     auth_urls: Mutex<HashMap<String, Option<Url>>>,
+    transient_limiter: TransientLimiter,
 }
 
-impl Frontend for NotifyRust {
-    fn new() -> Result<Self, Box<dyn Error>> {
+impl NotifyRust {
+    /// Create a front-end, checking that the notification server has the capabilities it needs.
+    pub fn new() -> Result<Self, Box<dyn Error>> {
         let caps = get_capabilities()?;
         let mut missing = Vec::new();
         for c in ["body", "body-hyperlinks", "body-markup"] {
@@ -44,6 +48,7 @@ impl Frontend for NotifyRust {
                 auth_pred: Mutex::new(false),
                 auth_condvar: Condvar::new(),
                 auth_urls: Mutex::new(HashMap::new()),
+                transient_limiter: TransientLimiter::new(),
             })
         } else {
             Err(format!(
@@ -53,7 +58,9 @@ impl Frontend for NotifyRust {
             .into())
         }
     }
+}
 
+impl Frontend for NotifyRust {
     fn main_loop(self: Arc<Self>) -> Result<(), Box<dyn Error>> {
         thread::spawn(move || {
             let mut auth_timeout: Option<Instant> = None;
@@ -176,19 +183,40 @@ impl Frontend for NotifyRust {
         }
     }
 
-    fn notify_error(&self, act_name: String, msg: &str) -> Result<(), Box<dyn Error>> {
-        let mut lk = self.auth_urls.lock().unwrap();
-        lk.insert(act_name.clone(), None);
-        drop(lk);
-        *self.auth_pred.lock().unwrap() = true;
-        self.auth_condvar.notify_one();
-
-        match Notification::new()
-            .summary(&format!("pizauth: Authentication failed"))
-            .body(&format!("{act_name:}: {msg:}"))
-            .appname("pizauth")
-            .show()
+    fn notify_error(
+        &self,
+        act_name: Option<String>,
+        kind: ErrorKind,
+        msg: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        if !self
+            .transient_limiter
+            .allow(act_name.as_deref(), kind, Instant::now())
         {
+            return Ok(());
+        }
+        if let Some(act_name) = &act_name {
+            let mut lk = self.auth_urls.lock().unwrap();
+            lk.insert(act_name.clone(), None);
+            drop(lk);
+            *self.auth_pred.lock().unwrap() = true;
+            self.auth_condvar.notify_one();
+        }
+
+        let mut notification = Notification::new();
+        notification
+            .summary(&format!("pizauth: {}", kind.summary()))
+            .body(&error_body(act_name.as_deref(), msg))
+            .appname("pizauth");
+        #[cfg(all(unix, not(target_os = "macos")))]
+        notification.urgency(match kind {
+            ErrorKind::TransientNetwork => Urgency::Low,
+            ErrorKind::RefreshTokenInvalid => Urgency::Critical,
+            ErrorKind::TokenEndpointRejected | ErrorKind::ConfigReload | ErrorKind::Internal => {
+                Urgency::Normal
+            }
+        });
+        match notification.show() {
             Ok(_) => Ok(()),
             Err(e) => Err(e.into()),
         }
@@ -0,0 +1,55 @@
+//! A front-end which prints authentication URLs to stderr, for headless environments (e.g. servers
+//! and CI) where there is no desktop to show notifications on.
+
+use std::{
+    error::Error,
+    io::{self, Write},
+    sync::Arc,
+    thread,
+};
+
+use url::Url;
+
+use super::{ErrorKind, Frontend};
+
+pub struct Stderr;
+
+impl Frontend for Stderr {
+    fn main_loop(self: Arc<Self>) -> Result<(), Box<dyn Error>> {
+        // All the work is done in the `notify_*` functions, but returning would terminate pizauth.
+        loop {
+            thread::park();
+        }
+    }
+
+    fn notify_error(
+        &self,
+        act_name: Option<String>,
+        kind: ErrorKind,
+        msg: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        match (act_name, kind) {
+            (Some(act_name), ErrorKind::TransientNetwork) => {
+                writeln!(io::stderr(), "REFRESH FAILED for {act_name:}: {msg:}")?
+            }
+            (Some(act_name), _) => writeln!(io::stderr(), "AUTH FAILED for {act_name:}: {msg:}")?,
+            (None, _) => writeln!(io::stderr(), "ERROR: {msg:}")?,
+        }
+        Ok(())
+    }
+
+    fn notify_success(&self, act_name: String) -> Result<(), Box<dyn Error>> {
+        writeln!(io::stderr(), "AUTH SUCCEEDED for {act_name:}")?;
+        Ok(())
+    }
+
+    fn notify_authorisations(&self, to_notify: Vec<(String, Url)>) -> Result<(), Box<dyn Error>> {
+        // We write all the lines in one go so that they aren't interleaved with other output.
+        let mut s = String::new();
+        for (act_name, url) in to_notify {
+            s.push_str(&format!("AUTH REQUIRED for {act_name:}: {url:}\n"));
+        }
+        io::stderr().write_all(s.as_bytes())?;
+        Ok(())
+    }
+}
@@ -0,0 +1,215 @@
+//! The protocol spoken between pizauth's command-line interface and its server over a Unix socket.
+//! Each message, in either direction, is sent as a frame consisting of a 1 byte protocol version,
+//! a 4 byte big-endian length, and then that many bytes of UTF-8. A client can send several
+//! requests on one connection: the server replies to each, in order. Most requests have a single
+//! reply frame, but `test` has any number of `info:` frames before its final reply.
+//!
+//! Requests are a command name followed by space separated, URL encoded, arguments (see
+//! [encode_request]), so that arguments such as account names can contain any character. Replies
+//! are of the form `<kind>:<payload>`: since the kind never contains a colon, and frames have an
+//! explicit length, the payload can contain any character. The frame header's layout must never
+//! change, so that when the client and server speak different protocol versions, each side can tell
+//! the other which version it speaks. When the server receives a frame with a different version, it
+//! replies with an `error:` frame of its own version and closes the connection.
+
+use std::{
+    borrow::Cow,
+    error::Error,
+    fmt,
+    io::{self, Read, Write},
+};
+
+/// The version of the protocol. This must be changed whenever the protocol changes in an
+/// incompatible way.
+pub const PROTOCOL_VERSION: u8 = 5;
+/// The maximum length in bytes of a frame's body.
+const MAX_FRAME_LEN: u32 = 1024 * 1024;
+
+/// Write `body` as a single frame to `w`. The frame is written in pieces so that secrets in `body`
+/// are not copied into an intermediate buffer.
+pub fn write_frame<W: Write>(w: &mut W, body: &[u8]) -> io::Result<()> {
+    let len = u32::try_from(body.len())
+        .ok()
+        .filter(|x| *x <= MAX_FRAME_LEN)
+        .ok_or_else(|| invalid_data("Message too long".to_owned()))?;
+    let mut hdr = [PROTOCOL_VERSION, 0, 0, 0, 0];
+    hdr[1..].copy_from_slice(&len.to_be_bytes());
+    w.write_all(&hdr)?;
+    w.write_all(body)?;
+    Ok(())
+}
+
+/// Read a single frame from `r`, returning its body, or `None` if `r` was closed before a new
+/// frame started.
+pub fn read_frame<R: Read>(r: &mut R) -> io::Result<Option<String>> {
+    let mut version = [0u8];
+    if let Err(e) = r.read_exact(&mut version) {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            return Ok(None);
+        }
+        return Err(e);
+    }
+    if version[0] != PROTOCOL_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            VersionMismatch { theirs: version[0] },
+        ));
+    }
+    let mut len = [0u8; 4];
+    r.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len);
+    if len > MAX_FRAME_LEN {
+        return Err(invalid_data(format!("Message of {len:} bytes is too long")));
+    }
+    let mut body = vec![0; len as usize];
+    r.read_exact(&mut body)?;
+    String::from_utf8(body)
+        .map(Some)
+        .map_err(|e| invalid_data(e.to_string()))
+}
+
+/// Encode a request for the command `name` with arguments `args`.
+pub fn encode_request<S: AsRef<str>>(name: &str, args: &[S]) -> String {
+    let mut req = name.to_owned();
+    for x in args {
+        req.push(' ');
+        req.push_str(&urlencoding::encode(x.as_ref()));
+    }
+    req
+}
+
+/// Decode `req` into its command name followed by its arguments.
+pub fn decode_request(req: &str) -> Result<Vec<Cow<'_, str>>, String> {
+    let mut fields = req.split(' ');
+    let name = fields.next().unwrap_or("");
+    std::iter::once(Ok(Cow::Borrowed(name)))
+        .chain(fields.map(|x| {
+            urlencoding::decode(x).map_err(|_| format!("Malformed argument to '{name:}'"))
+        }))
+        .collect()
+}
+
+/// Split `reply` into its kind and payload.
+pub fn split_reply(reply: &str) -> Option<(&str, &str)> {
+    reply.split_once(':')
+}
+
+/// The error [read_frame] returns when the other side speaks a different protocol version.
+#[derive(Debug)]
+pub struct VersionMismatch {
+    /// The first byte of the other side's frame.
+    pub theirs: u8,
+}
+
+impl VersionMismatch {
+    /// If `e` was caused by a protocol version mismatch, return the details.
+    pub fn from_io_error(e: &io::Error) -> Option<&VersionMismatch> {
+        e.get_ref()
+            .and_then(|x| x.downcast_ref::<VersionMismatch>())
+    }
+
+    /// Does the other side predate framed messages? Such clients send, and expect, plain text, so
+    /// the first byte they send is the first letter of a command or reply kind.
+    pub fn is_unframed(&self) -> bool {
+        self.theirs.is_ascii_lowercase()
+    }
+
+    /// Does the other side speak a newer protocol version than us?
+    pub fn is_newer(&self) -> bool {
+        !self.is_unframed() && self.theirs > PROTOCOL_VERSION
+    }
+}
+
+impl fmt::Display for VersionMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_unframed() {
+            write!(
+                f,
+                "Protocol version mismatch (expected {PROTOCOL_VERSION:}, got an unframed message)"
+            )
+        } else {
+            write!(
+                f,
+                "Protocol version mismatch (expected {PROTOCOL_VERSION:}, got {})",
+                self.theirs
+            )
+        }
+    }
+}
+
+impl Error for VersionMismatch {}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn requests() {
+        for act_name in [
+            "x",
+            "work: primary",
+            "a  b",
+            "a\nb",
+            "caf\u{e9} \u{1f355}",
+            "100%",
+            "",
+        ] {
+            let req = encode_request("refresh", &[act_name]);
+            assert_eq!(req.matches(' ').count(), 1);
+            assert!(!req.contains('\n'));
+            assert_eq!(decode_request(&req).unwrap(), vec!["refresh", act_name]);
+        }
+        assert_eq!(
+            decode_request(&encode_request("showtoken", &["a b", "c", "d:e"])).unwrap(),
+            vec!["showtoken", "a b", "c", "d:e"]
+        );
+        assert_eq!(
+            decode_request(&encode_request::<&str>("status", &[])).unwrap(),
+            vec!["status"]
+        );
+        assert_eq!(decode_request("").unwrap(), vec![""]);
+        // Invalid UTF-8.
+        assert!(decode_request("refresh %ff").is_err());
+    }
+
+    #[test]
+    fn frames() {
+        let mut buf = Vec::new();
+        write_frame(&mut buf, b"status").unwrap();
+        write_frame(&mut buf, b"a\nb").unwrap();
+        write_frame(&mut buf, b"").unwrap();
+        assert_eq!(&buf[..5], &[PROTOCOL_VERSION, 0, 0, 0, 6]);
+        let mut r = buf.as_slice();
+        assert_eq!(read_frame(&mut r).unwrap().as_deref(), Some("status"));
+        assert_eq!(read_frame(&mut r).unwrap().as_deref(), Some("a\nb"));
+        assert_eq!(read_frame(&mut r).unwrap().as_deref(), Some(""));
+        assert_eq!(read_frame(&mut r).unwrap(), None);
+
+        // A client speaking the old, unframed, protocol.
+        let e = read_frame(&mut b"status".as_slice()).unwrap_err();
+        assert!(e.to_string().starts_with("Protocol version mismatch"));
+        let vm = VersionMismatch::from_io_error(&e).unwrap();
+        assert!(vm.is_unframed() && !vm.is_newer());
+        // Clients speaking older and newer framed protocols.
+        let e = read_frame(&mut [PROTOCOL_VERSION - 1, 0, 0, 0, 0].as_slice()).unwrap_err();
+        assert!(!VersionMismatch::from_io_error(&e).unwrap().is_newer());
+        let e = read_frame(&mut [PROTOCOL_VERSION + 1, 0, 0, 0, 0].as_slice()).unwrap_err();
+        assert!(VersionMismatch::from_io_error(&e).unwrap().is_newer());
+        assert!(VersionMismatch::from_io_error(&invalid_data("x".to_owned())).is_none());
+
+        assert_eq!(split_reply("ok:"), Some(("ok", "")));
+        assert_eq!(split_reply("error:a b: c"), Some(("error", "a b: c")));
+        assert_eq!(split_reply("error:a:b"), Some(("error", "a:b")));
+        assert_eq!(split_reply("ok"), None);
+        // Truncated frames.
+        assert!(read_frame(&mut [PROTOCOL_VERSION, 0].as_slice()).is_err());
+        assert!(read_frame(&mut [PROTOCOL_VERSION, 0, 0, 0, 2, b'a'].as_slice()).is_err());
+        // Over-long frames.
+        assert!(read_frame(&mut [PROTOCOL_VERSION, 0xff, 0, 0, 0].as_slice()).is_err());
+        assert!(write_frame(&mut buf, &vec![0; MAX_FRAME_LEN as usize + 1]).is_err());
+    }
+}
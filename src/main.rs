@@ -2,30 +2,56 @@
 
 mod config;
 mod config_ast;
+mod error;
 mod frontends;
+mod ipc;
+mod secret;
 mod server;
 mod user_sender;
 
 use std::{
+    cell::OnceCell,
     env::{self, current_exe},
-    fs,
-    path::PathBuf,
+    error::Error,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Read, Write},
+    os::unix::io::{AsRawFd, FromRawFd},
+    path::{self, Path, PathBuf},
     process,
+    time::Duration,
 };
 
 use getopts::Options;
-use log::error;
-use nix::unistd::daemon;
+use log::{error, warn};
+use nix::unistd::{close, dup2, fork, pipe, setsid, ForkResult};
 
-use config::Config;
-use user_sender::show_token;
+use config::{Config, CLIENT_TIMEOUT_DEFAULT};
+use error::{
+    EXIT_ACCOUNT_NOT_FOUND, EXIT_ERROR, EXIT_SERVER_UNREACHABLE, EXIT_SERVER_UNRESPONSIVE,
+    EXIT_TOKEN_PENDING,
+};
+use user_sender::{show_token, TokenFormat};
 
 /// Name of cache directory within $XDG_DATA_HOME.
 const PIZAUTH_CACHE_LEAF: &str = "pizauth";
 /// Name of socket file within $XDG_DATA_HOME/PIZAUTH_CACHE_LEAF.
 const PIZAUTH_CACHE_SOCK_LEAF: &str = "pizauth.sock";
+/// Name of the file containing the server's PID within $XDG_DATA_HOME/PIZAUTH_CACHE_LEAF.
+const PIZAUTH_CACHE_PID_LEAF: &str = "pizauth.pid";
+/// Name of the file through which `pizauth restart` hands refresh tokens to the new server within
+/// $XDG_DATA_HOME/PIZAUTH_CACHE_LEAF.
+const PIZAUTH_CACHE_RESTART_LEAF: &str = "pizauth.restart";
 /// Name of `pizauth.conf` file relative to $XDG_CONFIG_HOME.
 const PIZAUTH_CONF_LEAF: &str = "pizauth.conf";
+/// The config path which means "read the config from stdin".
+const CONF_STDIN: &str = "-";
+/// What a daemonised server writes to the pipe returned by [daemonise] once it is ready to accept
+/// requests.
+const DAEMON_READY: &str = "ready\n";
+/// How many seconds does `pizauth monitor` wait between checks by default?
+const MONITOR_INTERVAL_DEFAULT: u64 = 30;
+/// By default, `pizauth monitor` refreshes tokens which expire within this many seconds.
+const MONITOR_WARN_BEFORE_DEFAULT: u64 = 300;
 
 fn progname() -> String {
     match current_exe() {
@@ -41,16 +67,16 @@ fn progname() -> String {
 /// Exit with a fatal error: only to be called before the log crate is setup.
 fn fatal(msg: &str) -> ! {
     eprintln!("{msg:}");
-    process::exit(1);
+    process::exit(EXIT_ERROR);
 }
 
 /// Print out program usage then exit. This function must not be called after daemonisation.
 fn usage() -> ! {
     let pn = progname();
     eprintln!(
-        "Usage:\n  {pn:} refresh [-c <config-path>] [<account> ... <account>]\n  {pn:} reload [-c <config-path>]\n  {pn:} server [-c <config-path>] [-dv]\n  {pn:} show [-c <config-path>] [-v] <account>\n  {pn:} shutdown"
+        "Usage:\n  {pn:} check-config [-c <config-path>] [-v]\n  {pn:} completion [-c <config-path>] <account> <url>\n  {pn:} diagnose [-c <config-path>]\n  {pn:} dump [-c <config-path>] [--json]\n  {pn:} forget [-c <config-path>] [--tag <tag>] [<account> ... <account>]\n  {pn:} info [-c <config-path>] [--json]\n  {pn:} metrics [-c <config-path>]\n  {pn:} monitor [-c <config-path>] [--interval-secs <secs>] [--warn-before-secs <secs>]\n  {pn:} refresh [-c <config-path>] [--force] [--tag <tag>] [<account> ... <account>]\n  {pn:} reload [-c <config-path>]\n  {pn:} restart [-c <config-path>]\n  {pn:} restore [-c <config-path>]\n  {pn:} rotate [-c <config-path>] [--tag <tag>] [<account> ... <account>]\n  {pn:} server [-c <config-path>] [-dv] [--daemonize] [--check-interval-secs <secs>] [--migrate-v1 <path>] [--port <port>] [--one-shot [--output <path>] [--timeout <secs>]] [--validate-accounts [--strict-validate]] [--allow-dump] [--socket-activation]\n  {pn:} show [-c <config-path>] [-v] [--id-token] [--scopes <scopes>] [--format raw|bearer|basic|json|xoauth2|oauthbearer] [--user <user>] [--host <host>] [--port <port>] [--min-validity <secs>] [--json] <account> ... <account>\n  {pn:} shutdown [-c <config-path>]\n  {pn:} status [-c <config-path>] [--json] [--tag <tag>]\n  {pn:} test [-c <config-path>] <account>\n  {pn:} test-auth [-c <config-path>] <account>\n\nExit codes:\n  0 success\n  {EXIT_ERROR:} error\n  {EXIT_SERVER_UNREACHABLE:} server not running\n  {EXIT_TOKEN_PENDING:} token pending authentication\n  {EXIT_ACCOUNT_NOT_FOUND:} no such account\n  {EXIT_SERVER_UNRESPONSIVE:} server not responding"
     );
-    process::exit(1)
+    process::exit(EXIT_ERROR)
 }
 
 fn cache_path() -> PathBuf {
@@ -70,6 +96,8 @@ fn cache_path() -> PathBuf {
     p
 }
 
+/// The config path given by `-c`, or the default config path if `-c` wasn't specified. The config
+/// need not exist: only some commands need it (see [load_conf] and [client_timeout]).
 fn conf_path(matches: &getopts::Matches) -> PathBuf {
     match matches.opt_str("c") {
         Some(p) => PathBuf::from(&p),
@@ -86,17 +114,138 @@ fn conf_path(matches: &getopts::Matches) -> PathBuf {
                 },
             }
             p.push(PIZAUTH_CONF_LEAF);
-            if !p.is_file() {
-                fatal(&format!(
-                    "No config file found at {}",
-                    p.to_str().unwrap_or("pizauth.conf")
-                ));
-            }
             p
         }
     }
 }
 
+/// Detach from the terminal with the traditional double fork, redirecting stdin, stdout, and stderr
+/// to `/dev/null`. The original process only exits once the daemon has written to the returned
+/// pipe: if the daemon writes [DAEMON_READY], it exits successfully; otherwise it prints whatever
+/// the daemon wrote (which should explain why the daemon couldn't start) and exits with an error.
+/// The working directory is left unchanged so that a relative config path can still be reloaded.
+/// This must be called before any threads are created.
+fn daemonise() -> Result<File, Box<dyn Error>> {
+    let (rd, wr) = pipe()?;
+    // Safe because we have only one thread.
+    if let ForkResult::Parent { .. } = unsafe { fork() }? {
+        close(wr)?;
+        let mut rdr = BufReader::new(unsafe { File::from_raw_fd(rd) });
+        let mut msg = String::new();
+        rdr.read_line(&mut msg)?;
+        if msg == DAEMON_READY {
+            process::exit(0);
+        }
+        rdr.read_to_string(&mut msg)?;
+        match msg.trim() {
+            "" => fatal("Server exited before it was ready"),
+            x => fatal(x),
+        }
+    }
+    close(rd)?;
+    // Becoming a session leader detaches us from the terminal; forking again means that the daemon
+    // isn't a session leader, and so can never acquire a controlling terminal.
+    setsid()?;
+    if let ForkResult::Parent { .. } = unsafe { fork() }? {
+        process::exit(0);
+    }
+    let null = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+    for fd in 0..=2 {
+        dup2(null.as_raw_fd(), fd)?;
+    }
+    Ok(unsafe { File::from_raw_fd(wr) })
+}
+
+/// The arguments (after `server`) with which `pizauth restart` starts the replacement of a server
+/// started with `matches` and the config at `conf_path`, or why such a server can't be restarted.
+/// The replacement always detaches from the terminal, and options which only make sense when a
+/// server is first started (`--migrate-v1`) are dropped.
+fn restart_args(
+    matches: &getopts::Matches,
+    conf_path: Option<&Path>,
+) -> Result<Vec<String>, String> {
+    let conf_path = match conf_path {
+        Some(x) => x,
+        None => {
+            return Err(
+                "This server's config was read from stdin, so it can't be restarted".to_owned(),
+            )
+        }
+    };
+    if matches.opt_present("one-shot") {
+        return Err("One-shot servers can't be restarted".to_owned());
+    }
+    // The activated socket can't be passed on, so the replacement would have to bind a socket of
+    // its own.
+    #[cfg(feature = "socket_activation")]
+    if matches.opt_present("socket-activation") {
+        return Err(
+            "This server was started by socket activation: restart it with your service manager"
+                .to_owned(),
+        );
+    }
+    let mut args = vec![
+        "--daemonize".to_owned(),
+        "-c".to_owned(),
+        conf_path.to_string_lossy().into_owned(),
+    ];
+    for _ in 0..matches.opt_count("v") {
+        args.push("-v".to_owned());
+    }
+    for opt in ["check-interval-secs", "port"] {
+        if let Some(x) = matches.opt_str(opt) {
+            args.push(format!("--{opt:}"));
+            args.push(x);
+        }
+    }
+    for opt in ["validate-accounts", "strict-validate", "allow-dump"] {
+        if matches.opt_present(opt) {
+            args.push(format!("--{opt:}"));
+        }
+    }
+    Ok(args)
+}
+
+/// Load the config at `conf_path`, or from stdin if `conf_path` is [CONF_STDIN], exiting if it
+/// can't be loaded.
+fn load_conf(conf_path: &Path) -> Config {
+    if conf_path == Path::new(CONF_STDIN) {
+        Config::from_stdin()
+    } else if !conf_path.is_file() {
+        fatal(&format!(
+            "Config error: no config file found at {}",
+            conf_path.display()
+        ))
+    } else {
+        Config::from_path(conf_path)
+    }
+    .unwrap_or_else(|m| fatal(&format!("Config error: {m:}")))
+}
+
+/// The timeout for a client command which only needs the config for its `client_timeout`. Such
+/// commands work without a usable config (e.g. on a machine which only shares a server's cache
+/// directory), so if the config at `conf_path` is missing or invalid, we log why at the `-v`
+/// level and use the default timeout.
+fn client_timeout(conf_path: &Path) -> Duration {
+    let conf = if conf_path == Path::new(CONF_STDIN) {
+        Config::from_stdin()
+    } else if !conf_path.is_file() {
+        Err(format!("no config file found at {}", conf_path.display()))
+    } else {
+        Config::from_path(conf_path)
+    };
+    match conf {
+        Ok(x) => x.client_timeout,
+        Err(m) => {
+            warn!("Ignoring config (using the default client timeout): {m:}");
+            CLIENT_TIMEOUT_DEFAULT
+        }
+    }
+}
+
 fn main() {
     let args: Vec<String> = env::args().collect();
     if args.len() < 2 {
@@ -107,10 +256,116 @@ fn main() {
         .optflag("h", "help", "")
         .optflagmulti("v", "verbose", "");
 
-    let cache_path = cache_path();
     match args[1].as_str() {
-        "refresh" => {
+        "check-config" => {
+            let matches = opts.parse(&args[2..]).unwrap_or_else(|_| usage());
+            if matches.opt_present("h") || !matches.free.is_empty() {
+                usage();
+            }
+            let conf_path = conf_path(&matches);
+            // We deliberately use the same function as `server` and `reload` so that a config
+            // which passes here can't then be rejected by a running server.
+            let conf = load_conf(&conf_path);
+            println!("config OK: {} accounts", conf.accounts.len());
+            if matches.opt_present("v") {
+                let mut act_names = conf.accounts.keys().collect::<Vec<_>>();
+                act_names.sort();
+                for act_name in act_names {
+                    println!("{}", conf.accounts[act_name].redacted());
+                }
+            }
+        }
+        "diagnose" => {
+            let matches = opts.parse(&args[2..]).unwrap_or_else(|_| usage());
+            if matches.opt_present("h") || !matches.free.is_empty() {
+                usage();
+            }
+            stderrlog::new()
+                .module(module_path!())
+                .verbosity(matches.opt_count("v"))
+                .init()
+                .unwrap();
+            let timeout = client_timeout(&conf_path(&matches));
+            if let Err(e) = user_sender::diagnose(timeout, &cache_path()) {
+                error!("{e:}");
+                process::exit(e.exit_code());
+            }
+        }
+        "dump" => {
+            opts.optflag(
+                "",
+                "json",
+                "Dump a snapshot of each account's state as JSON.",
+            );
+            let matches = opts.parse(&args[2..]).unwrap_or_else(|_| usage());
+            if matches.opt_present("h") || !matches.free.is_empty() {
+                usage();
+            }
+            stderrlog::new()
+                .module(module_path!())
+                .verbosity(matches.opt_count("v"))
+                .init()
+                .unwrap();
+            let timeout = client_timeout(&conf_path(&matches));
+            if let Err(e) = user_sender::dump(timeout, &cache_path(), matches.opt_present("json")) {
+                error!("{e:}");
+                process::exit(e.exit_code());
+            }
+        }
+        "completion" => {
+            let matches = opts.parse(&args[2..]).unwrap_or_else(|_| usage());
+            if matches.opt_present("h") || matches.free.len() != 2 {
+                usage();
+            }
+            stderrlog::new()
+                .module(module_path!())
+                .verbosity(matches.opt_count("v"))
+                .init()
+                .unwrap();
+            let timeout = client_timeout(&conf_path(&matches));
+            if let Err(e) =
+                user_sender::completion(timeout, &cache_path(), &matches.free[0], &matches.free[1])
+            {
+                error!("{e:}");
+                process::exit(e.exit_code());
+            }
+        }
+        "forget" => {
+            opts.optopt("", "tag", "Also use the accounts with this tag.", "<tag>");
             let matches = opts.parse(&args[2..]).unwrap_or_else(|_| usage());
+            if matches.opt_present("h") || (matches.free.is_empty() && !matches.opt_present("tag"))
+            {
+                usage();
+            }
+            stderrlog::new()
+                .module(module_path!())
+                .verbosity(matches.opt_count("v"))
+                .init()
+                .unwrap();
+            let timeout = client_timeout(&conf_path(&matches));
+            let tag = matches.opt_str("tag");
+            if let Err(e) =
+                user_sender::forget(timeout, &cache_path(), matches.free, tag.as_deref())
+            {
+                error!("{e:}");
+                process::exit(e.exit_code());
+            }
+        }
+        "refresh" => {
+            let matches = opts
+                .optflag(
+                    "",
+                    "force",
+                    "Contact the token endpoint now, even if pizauth would otherwise wait.",
+                )
+                .optopt(
+                    "",
+                    "tag",
+                    "Also refresh the accounts with this tag.",
+                    "<tag>",
+                )
+                .parse(&args[2..])
+                .unwrap_or_else(|_| usage());
             if matches.opt_present("h") {
                 usage();
             }
@@ -120,15 +375,22 @@ fn main() {
                 .init()
                 .unwrap();
             let conf_path = conf_path(&matches);
-            let conf = Config::from_path(&conf_path).unwrap_or_else(|m| fatal(&m));
-            let accounts = if matches.free.is_empty() {
-                conf.accounts.keys().cloned().collect::<Vec<_>>()
+            let force = matches.opt_present("force");
+            let tag = matches.opt_str("tag");
+            // Refreshing every account is the only case where we need to know what's in the
+            // config.
+            let (timeout, accounts) = if matches.free.is_empty() && tag.is_none() {
+                let conf = load_conf(&conf_path);
+                let accounts = conf.accounts.keys().cloned().collect::<Vec<_>>();
+                (conf.client_timeout, accounts)
             } else {
-                matches.free
+                (client_timeout(&conf_path), matches.free)
             };
-            if let Err(e) = user_sender::refresh(conf, &cache_path, accounts) {
+            if let Err(e) =
+                user_sender::refresh(timeout, &cache_path(), accounts, tag.as_deref(), force)
+            {
                 error!("{e:}");
-                process::exit(1);
+                process::exit(e.exit_code());
             }
         }
         "reload" => {
@@ -142,22 +404,195 @@ fn main() {
                 .init()
                 .unwrap();
             let conf_path = conf_path(&matches);
-            let conf = Config::from_path(&conf_path).unwrap_or_else(|m| fatal(&m));
-            if let Err(e) = user_sender::reload(conf, conf_path, &cache_path) {
+            if conf_path == Path::new(CONF_STDIN) {
+                fatal("Can't reload a config from stdin: restart the server instead");
+            }
+            // The server loads, and reports any problems with, the config itself.
+            let timeout = client_timeout(&conf_path);
+            if let Err(e) = user_sender::reload(timeout, conf_path, &cache_path()) {
                 error!("{e:}");
-                process::exit(1);
+                process::exit(e.exit_code());
+            }
+        }
+        "restore" => {
+            let matches = opts.parse(&args[2..]).unwrap_or_else(|_| usage());
+            if matches.opt_present("h") || !matches.free.is_empty() {
+                usage();
+            }
+            stderrlog::new()
+                .module(module_path!())
+                .verbosity(matches.opt_count("v"))
+                .init()
+                .unwrap();
+            let conf_path = conf_path(&matches);
+            if conf_path == Path::new(CONF_STDIN) {
+                fatal("Can't read both the config and the dump from stdin");
+            }
+            let timeout = client_timeout(&conf_path);
+            if let Err(e) = user_sender::restore(timeout, &cache_path()) {
+                error!("{e:}");
+                process::exit(e.exit_code());
             }
         }
         "server" => {
-            let matches = opts
-                .optflag("d", "", "Don't detach from the terminal.")
-                .parse(&args[2..])
-                .unwrap_or_else(|_| usage());
+            opts.optflag("d", "", "Don't detach from the terminal (the default).")
+                .optflag(
+                    "",
+                    "daemonize",
+                    "Detach from the terminal once the server is ready.",
+                )
+                .optopt(
+                    "",
+                    "check-interval-secs",
+                    "Maximum seconds between the refresher's clock checks.",
+                    "<secs>",
+                )
+                .optopt(
+                    "",
+                    "migrate-v1",
+                    "Import refresh tokens from a legacy file.",
+                    "<path>",
+                )
+                .optopt(
+                    "",
+                    "port",
+                    "Port for the HTTP server to listen on (default: any free port).",
+                    "<port>",
+                )
+                .optflag(
+                    "",
+                    "one-shot",
+                    "Exit once all accounts are authenticated, writing their tokens as JSON.",
+                )
+                .optopt(
+                    "",
+                    "output",
+                    "Where --one-shot writes tokens (default: stdout).",
+                    "<path>",
+                )
+                .optopt(
+                    "",
+                    "timeout",
+                    "Seconds --one-shot waits for all accounts to be authenticated.",
+                    "<secs>",
+                )
+                .optflag(
+                    "",
+                    "validate-accounts",
+                    "Check that each account's token_uri is reachable before starting.",
+                )
+                .optflag(
+                    "",
+                    "strict-validate",
+                    "Don't start if --validate-accounts finds a problem.",
+                )
+                .optflag(
+                    "",
+                    "allow-dump",
+                    "Allow clients to dump refresh tokens with 'dump', or each account's state with 'dump --json'.",
+                );
+            #[cfg(feature = "socket_activation")]
+            opts.optflag(
+                "",
+                "socket-activation",
+                "Use the socket passed by systemd-style socket activation.",
+            );
+            let matches = opts.parse(&args[2..]).unwrap_or_else(|_| usage());
             if matches.opt_present("h") || !matches.free.is_empty() {
                 usage();
             }
-            let daemonise = !matches.opt_present("d");
-            if daemonise {
+            if matches.opt_present("daemonize") {
+                if matches.opt_present("d") {
+                    fatal("-d and --daemonize are mutually exclusive");
+                }
+                if matches.opt_present("one-shot") {
+                    fatal("--daemonize can't be used with --one-shot");
+                }
+            }
+            if matches.opt_present("strict-validate") && !matches.opt_present("validate-accounts") {
+                fatal("--strict-validate can only be used with --validate-accounts");
+            }
+            let check_interval =
+                matches
+                    .opt_str("check-interval-secs")
+                    .map(|x| match x.parse::<u64>() {
+                        Ok(n) if n > 0 => Duration::from_secs(n),
+                        _ => fatal("--check-interval-secs must be a positive integer"),
+                    });
+            let http_port = matches.opt_str("port").map(|x| match x.parse::<u16>() {
+                Ok(n) if n > 0 => n,
+                _ => fatal("--port must be an integer from 1 to 65535"),
+            });
+            let one_shot = if matches.opt_present("one-shot") {
+                Some(server::OneShot {
+                    output: matches.opt_str("output").map(PathBuf::from),
+                    timeout: matches.opt_str("timeout").map(|x| match x.parse::<u64>() {
+                        Ok(n) if n > 0 => Duration::from_secs(n),
+                        _ => fatal("--timeout must be a positive integer"),
+                    }),
+                })
+            } else if matches.opt_present("output") || matches.opt_present("timeout") {
+                fatal("--output and --timeout can only be used with --one-shot");
+            } else {
+                None
+            };
+            let cache_path = cache_path();
+            // The activated socket must be picked up before we daemonise. We also bind our own
+            // socket before daemonising so that, if another server is running, the user is told.
+            let bind_socket =
+                || server::bind_socket(&cache_path).unwrap_or_else(|e| fatal(&format!("{e:}")));
+            #[cfg(feature = "socket_activation")]
+            let listener = if matches.opt_present("socket-activation") {
+                server::activated_listener()
+                    .unwrap_or_else(|e| fatal(&format!("Cannot use activated socket: {e:}")))
+            } else {
+                bind_socket()
+            };
+            #[cfg(not(feature = "socket_activation"))]
+            let listener = bind_socket();
+            // Once we've daemonised, stdin is no longer available, so the config must be read
+            // first.
+            let conf_path = conf_path(&matches);
+            let conf = load_conf(&conf_path);
+            let mut insecure = conf
+                .accounts
+                .values()
+                .filter(|act| !act.verify_tls)
+                .map(|act| act.name.as_str())
+                .collect::<Vec<_>>();
+            if !insecure.is_empty() {
+                insecure.sort();
+                eprintln!(
+                    "WARNING: TLS certificates are not verified for account(s) {}: only use \
+                    'verify_tls = false' with development servers",
+                    insecure.join(", ")
+                );
+            }
+            // Once we've daemonised, stderr is no longer available, so problems must be reported
+            // first.
+            if matches.opt_present("validate-accounts") {
+                let failures = server::validate_accounts(&conf);
+                for x in &failures {
+                    eprintln!("WARNING: account {x:}");
+                }
+                if !failures.is_empty() && matches.opt_present("strict-validate") {
+                    fatal("Not starting: account validation failed");
+                }
+            }
+            // The config is reloaded, and servers restarted, from this path, but `pizauth restart`
+            // may be run from a different working directory than the server.
+            let conf_path = if conf_path == Path::new(CONF_STDIN) {
+                None
+            } else {
+                Some(path::absolute(&conf_path).unwrap_or_else(|e| {
+                    fatal(&format!("Can't find {}: {e:}", conf_path.display()))
+                }))
+            };
+            let restart_args = restart_args(&matches, conf_path.as_deref());
+            // Once we've daemonised, stdout is no longer available, which is why `--daemonize` can't
+            // be used with `--one-shot`.
+            let mut ready = None;
+            if matches.opt_present("daemonize") {
                 let formatter = syslog::Formatter3164 {
                     process: progname(),
                     ..Default::default()
@@ -174,7 +609,8 @@ fn main() {
                 log::set_boxed_logger(Box::new(syslog::BasicLogger::new(logger)))
                     .map(|()| log::set_max_level(levelfilter))
                     .unwrap_or_else(|e| fatal(&format!("Cannot set logger: {e:}")));
-                daemon(true, false).unwrap_or_else(|e| fatal(&format!("Cannot daemonise: {e:}")));
+                ready =
+                    Some(daemonise().unwrap_or_else(|e| fatal(&format!("Cannot daemonise: {e:}"))));
             } else {
                 stderrlog::new()
                     .module(module_path!())
@@ -182,19 +618,68 @@ fn main() {
                     .init()
                     .unwrap();
             }
-            let conf_path = conf_path(&matches);
-            let conf = Config::from_path(&conf_path).unwrap_or_else(|m| fatal(&m));
-            if let Err(e) = server::server(conf, cache_path.as_path()) {
+            // If the server fails to start, the process which started us needs to know why.
+            let failed = ready.as_ref().and_then(|x| x.try_clone().ok());
+            if let Err(e) = server::server(
+                conf,
+                conf_path,
+                cache_path.as_path(),
+                listener,
+                check_interval,
+                http_port,
+                matches.opt_str("migrate-v1").map(PathBuf::from),
+                one_shot,
+                matches.opt_present("allow-dump"),
+                restart_args,
+                ready,
+            ) {
                 error!("{e:}");
+                if let Some(mut x) = failed {
+                    x.write_all(format!("{e:}\n").as_bytes()).ok();
+                }
                 process::exit(1);
             }
         }
         "show" => {
-            let matches = opts.parse(&args[2..]).unwrap_or_else(|_| usage());
+            let matches = opts
+                .optflag("", "id-token", "Show the OpenID Connect ID token.")
+                .optmulti(
+                    "",
+                    "scopes",
+                    "Fail unless the token has these scopes.",
+                    "<scopes>",
+                )
+                .optopt(
+                    "",
+                    "format",
+                    "Show the token as-is, as an HTTP Authorization header, as JSON, or as a SASL initial client response.",
+                    "raw|bearer|basic|json|xoauth2|oauthbearer",
+                )
+                .optopt("", "user", "The user name for SASL formats.", "<user>")
+                .optopt(
+                    "",
+                    "host",
+                    "The server's host name for OAUTHBEARER.",
+                    "<host>",
+                )
+                .optopt("", "port", "The server's port for OAUTHBEARER.", "<port>")
+                .optopt(
+                    "",
+                    "min-validity",
+                    "Refresh the token first if it expires within this many seconds.",
+                    "<secs>",
+                )
+                .optflag(
+                    "",
+                    "json",
+                    "Print a JSON object mapping each account to its token.",
+                )
+                .parse(&args[2..])
+                .unwrap_or_else(|_| usage());
             if matches.opt_present("h") {
                 usage();
             }
-            if matches.free.len() != 1 {
+            if matches.free.is_empty() {
                 usage();
             }
             stderrlog::new()
@@ -202,12 +687,266 @@ fn main() {
                 .verbosity(matches.opt_count("v"))
                 .init()
                 .unwrap();
-            let account = matches.free[0].as_str();
             let conf_path = conf_path(&matches);
-            let conf = Config::from_path(&conf_path).unwrap_or_else(|m| fatal(&m));
-            if let Err(e) = show_token(conf, cache_path.as_path(), account) {
+            let scopes = matches
+                .opt_strs("scopes")
+                .iter()
+                .flat_map(|x| x.split_whitespace().map(|y| y.to_owned()))
+                .collect::<Vec<_>>();
+            let format = matches.opt_str("format");
+            let sasl = matches!(format.as_deref(), Some("xoauth2" | "oauthbearer"));
+            if (!matches!(format.as_deref(), None | Some("raw")) && matches.opt_present("id-token"))
+                || (format.as_deref() == Some("json") && matches.opt_present("json"))
+                || (!sasl && matches.opt_present("user"))
+                || (format.as_deref() != Some("oauthbearer")
+                    && (matches.opt_present("host") || matches.opt_present("port")))
+            {
+                usage();
+            }
+            // The config is only loaded if we need an account's `sasl_user` or `client_id`: the
+            // server only sends tokens, so neither can be obtained from it.
+            let conf = OnceCell::new();
+            let act_conf = |account: &str| {
+                conf.get_or_init(|| load_conf(&conf_path))
+                    .accounts
+                    .get(account)
+            };
+            let user = |account: &str| {
+                matches
+                    .opt_str("user")
+                    .or_else(|| act_conf(account)?.sasl_user.clone())
+                    .unwrap_or_else(|| {
+                        fatal(&format!(
+                            "No user for {account:}: specify --user or set sasl_user"
+                        ))
+                    })
+            };
+            let min_validity = matches
+                .opt_str("min-validity")
+                .map(|x| match x.parse::<u64>() {
+                    Ok(x) if x > 0 => Duration::from_secs(x),
+                    _ => fatal("--min-validity must be a positive integer"),
+                });
+            // Some formats need details of the account, so each account has its own format.
+            let accounts = matches
+                .free
+                .iter()
+                .map(|account| {
+                    let f = match format.as_deref() {
+                        None | Some("raw") => TokenFormat::Raw,
+                        Some("bearer") => TokenFormat::Bearer,
+                        Some("basic") => TokenFormat::Basic {
+                            client_id: act_conf(account)
+                                .map(|x| x.client_id.clone())
+                                .unwrap_or_else(|| {
+                                    fatal(&format!("Config error: no account '{account:}'"))
+                                }),
+                        },
+                        Some("json") => TokenFormat::Json,
+                        Some("xoauth2") => TokenFormat::XOAuth2 {
+                            user: user(account),
+                        },
+                        Some("oauthbearer") => TokenFormat::OAuthBearer {
+                            user: user(account),
+                            host: matches.opt_str("host"),
+                            port: matches.opt_str("port").map(|x| {
+                                x.parse::<u16>()
+                                    .unwrap_or_else(|_| fatal("--port must be a valid port number"))
+                            }),
+                        },
+                        Some(_) => usage(),
+                    };
+                    (account.to_owned(), f)
+                })
+                .collect::<Vec<_>>();
+            let timeout = conf
+                .get()
+                .map(|x| x.client_timeout)
+                .unwrap_or_else(|| client_timeout(&conf_path));
+            if let Err(e) = show_token(
+                timeout,
+                &cache_path(),
+                &accounts,
+                &scopes,
+                matches.opt_present("id-token"),
+                min_validity,
+                matches.opt_present("json"),
+            ) {
                 error!("{e:}");
-                process::exit(1);
+                process::exit(e.exit_code());
+            }
+        }
+        "info" => {
+            opts.optflag("", "json", "Print details as JSON.");
+            let matches = opts.parse(&args[2..]).unwrap_or_else(|_| usage());
+            if matches.opt_present("h") || !matches.free.is_empty() {
+                usage();
+            }
+            stderrlog::new()
+                .module(module_path!())
+                .verbosity(matches.opt_count("v"))
+                .init()
+                .unwrap();
+            let timeout = client_timeout(&conf_path(&matches));
+            if let Err(e) = user_sender::info(timeout, &cache_path(), matches.opt_present("json")) {
+                error!("{e:}");
+                process::exit(e.exit_code());
+            }
+        }
+        "metrics" => {
+            let matches = opts.parse(&args[2..]).unwrap_or_else(|_| usage());
+            if matches.opt_present("h") || !matches.free.is_empty() {
+                usage();
+            }
+            stderrlog::new()
+                .module(module_path!())
+                .verbosity(matches.opt_count("v"))
+                .init()
+                .unwrap();
+            let timeout = client_timeout(&conf_path(&matches));
+            if let Err(e) = user_sender::metrics(timeout, &cache_path()) {
+                error!("{e:}");
+                process::exit(e.exit_code());
+            }
+        }
+        "monitor" => {
+            opts.optopt(
+                "",
+                "interval-secs",
+                "Seconds between checks of the accounts' states (default: 30).",
+                "<secs>",
+            )
+            .optopt(
+                "",
+                "warn-before-secs",
+                "Refresh tokens which expire within this many seconds (default: 300).",
+                "<secs>",
+            );
+            let matches = opts.parse(&args[2..]).unwrap_or_else(|_| usage());
+            if matches.opt_present("h") || !matches.free.is_empty() {
+                usage();
+            }
+            let secs = |name: &str, default: u64| match matches.opt_str(name) {
+                Some(x) => match x.parse::<u64>() {
+                    Ok(n) if n > 0 => Duration::from_secs(n),
+                    _ => fatal(&format!("--{name:} must be a positive integer")),
+                },
+                None => Duration::from_secs(default),
+            };
+            let interval = secs("interval-secs", MONITOR_INTERVAL_DEFAULT);
+            let warn_before = secs("warn-before-secs", MONITOR_WARN_BEFORE_DEFAULT);
+            stderrlog::new()
+                .module(module_path!())
+                .verbosity(matches.opt_count("v"))
+                .init()
+                .unwrap();
+            let timeout = client_timeout(&conf_path(&matches));
+            if let Err(e) = user_sender::monitor(timeout, &cache_path(), interval, warn_before) {
+                error!("{e:}");
+                process::exit(e.exit_code());
+            }
+        }
+        "status" => {
+            opts.optflag("", "json", "Print account states as JSON.");
+            opts.optopt("", "tag", "Only print the accounts with this tag.", "<tag>");
+            let matches = opts.parse(&args[2..]).unwrap_or_else(|_| usage());
+            if matches.opt_present("h") || !matches.free.is_empty() {
+                usage();
+            }
+            stderrlog::new()
+                .module(module_path!())
+                .verbosity(matches.opt_count("v"))
+                .init()
+                .unwrap();
+            let timeout = client_timeout(&conf_path(&matches));
+            let tag = matches.opt_str("tag");
+            if let Err(e) = user_sender::status(
+                timeout,
+                &cache_path(),
+                matches.opt_present("json"),
+                tag.as_deref(),
+            ) {
+                error!("{e:}");
+                process::exit(e.exit_code());
+            }
+        }
+        "test" => {
+            let matches = opts.parse(&args[2..]).unwrap_or_else(|_| usage());
+            if matches.opt_present("h") || matches.free.len() != 1 {
+                usage();
+            }
+            stderrlog::new()
+                .module(module_path!())
+                .verbosity(matches.opt_count("v"))
+                .init()
+                .unwrap();
+            let timeout = client_timeout(&conf_path(&matches));
+            if let Err(e) = user_sender::test(timeout, &cache_path(), &matches.free[0]) {
+                error!("{e:}");
+                process::exit(e.exit_code());
+            }
+        }
+        "test-auth" => {
+            let matches = opts.parse(&args[2..]).unwrap_or_else(|_| usage());
+            if matches.opt_present("h") || matches.free.len() != 1 {
+                usage();
+            }
+            stderrlog::new()
+                .module(module_path!())
+                .verbosity(matches.opt_count("v"))
+                .init()
+                .unwrap();
+            // This deliberately doesn't involve the server, so that an account can be checked
+            // before the server is started with it.
+            let conf = load_conf(&conf_path(&matches));
+            let act_name = &matches.free[0];
+            let act = match conf.accounts.get(act_name) {
+                Some(x) => x,
+                None => {
+                    error!("No account '{act_name:}'");
+                    process::exit(EXIT_ACCOUNT_NOT_FOUND);
+                }
+            };
+            if let Err(e) = server::test_auth(act) {
+                error!("{e:}");
+                process::exit(EXIT_ERROR);
+            }
+        }
+        "restart" => {
+            let matches = opts.parse(&args[2..]).unwrap_or_else(|_| usage());
+            if matches.opt_present("h") || !matches.free.is_empty() {
+                usage();
+            }
+            stderrlog::new()
+                .module(module_path!())
+                .verbosity(matches.opt_count("v"))
+                .init()
+                .unwrap();
+            let timeout = client_timeout(&conf_path(&matches));
+            if let Err(e) = user_sender::restart(timeout, &cache_path()) {
+                error!("{e:}");
+                process::exit(e.exit_code());
+            }
+        }
+        "rotate" => {
+            opts.optopt("", "tag", "Also use the accounts with this tag.", "<tag>");
+            let matches = opts.parse(&args[2..]).unwrap_or_else(|_| usage());
+            if matches.opt_present("h") || (matches.free.is_empty() && !matches.opt_present("tag"))
+            {
+                usage();
+            }
+            stderrlog::new()
+                .module(module_path!())
+                .verbosity(matches.opt_count("v"))
+                .init()
+                .unwrap();
+            let timeout = client_timeout(&conf_path(&matches));
+            let tag = matches.opt_str("tag");
+            if let Err(e) =
+                user_sender::rotate(timeout, &cache_path(), matches.free, tag.as_deref())
+            {
+                error!("{e:}");
+                process::exit(e.exit_code());
             }
         }
         "shutdown" => {
@@ -220,11 +959,10 @@ fn main() {
                 .verbosity(matches.opt_count("v"))
                 .init()
                 .unwrap();
-            let conf_path = conf_path(&matches);
-            let conf = Config::from_path(&conf_path).unwrap_or_else(|m| fatal(&m));
-            if let Err(e) = user_sender::shutdown(conf, conf_path, &cache_path) {
+            let timeout = client_timeout(&conf_path(&matches));
+            if let Err(e) = user_sender::shutdown(timeout, &cache_path()) {
                 error!("{e:}");
-                process::exit(1);
+                process::exit(e.exit_code());
             }
         }
         _ => usage(),
@@ -0,0 +1,63 @@
+//! Secrets (e.g. access tokens and client secrets) which should not outlive their use.
+
+use std::{
+    fmt, ptr,
+    sync::atomic::{compiler_fence, Ordering},
+};
+
+/// A string whose contents are overwritten with zeros when it is dropped, and which is never
+/// displayed by `Debug`. Note that this cannot guarantee that no other copies of the secret exist
+/// (e.g. from before it was wrapped in a `SecretString`): it merely reduces the number of copies
+/// lying around in freed memory.
+#[derive(Clone, PartialEq)]
+pub struct SecretString(String);
+
+impl SecretString {
+    /// Return the secret. Callers should avoid copying the result where possible.
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for SecretString {
+    fn from(s: String) -> Self {
+        SecretString(s)
+    }
+}
+
+impl From<&str> for SecretString {
+    fn from(s: &str) -> Self {
+        SecretString(s.to_owned())
+    }
+}
+
+impl fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<redacted>")
+    }
+}
+
+impl Drop for SecretString {
+    fn drop(&mut self) {
+        // SAFETY: zero bytes are valid UTF-8. We use volatile writes so that the compiler can't
+        // optimise away writes to memory that is about to be freed.
+        for b in unsafe { self.0.as_bytes_mut() } {
+            unsafe { ptr::write_volatile(b, 0) };
+        }
+        compiler_fence(Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_secret_string() {
+        let s = SecretString::from("hunter2");
+        assert_eq!(s.expose(), "hunter2");
+        assert_eq!(s.clone(), s);
+        assert_eq!(format!("{s:?}"), "<redacted>");
+        assert!(!format!("{:?}", Some(s)).contains("hunter2"));
+    }
+}
@@ -0,0 +1,218 @@
+//! The audit log (`audit_log` in the config): a file to which a JSON line is appended every time
+//! an account's [TokenState] changes. The log records only which states accounts moved between
+//! (and when active tokens expire), never token material.
+//!
+//! Entries are created while the global lock is held, but are written by a dedicated thread, so
+//! that a slow disk never holds up other users of the lock.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write,
+    os::unix::fs::{MetadataExt, OpenOptionsExt},
+    sync::mpsc::{self, Receiver, Sender},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use json::object;
+use log::warn;
+
+use super::TokenState;
+
+enum Msg {
+    /// Append the line (which must end in a newline) to the audit log at the path.
+    Entry(String, String),
+    /// Reply once all previous entries have been written.
+    Flush(Sender<()>),
+}
+
+/// A handle to the thread which writes audit log entries.
+pub struct AuditLog {
+    sender: Sender<Msg>,
+}
+
+impl AuditLog {
+    pub fn new() -> Self {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || writer(receiver));
+        AuditLog { sender }
+    }
+
+    /// Queue a record of `act_name` moving from `old` to `new` at `now` to be appended to the
+    /// audit log at `path`. This never blocks.
+    pub fn record(
+        &self,
+        path: &str,
+        now: SystemTime,
+        act_name: &str,
+        old: &TokenState,
+        new: &TokenState,
+    ) {
+        let line = entry(now, act_name, old, new);
+        self.sender.send(Msg::Entry(path.to_owned(), line)).ok();
+    }
+
+    /// Wait until all previously queued entries have been written, or until `timeout` has
+    /// elapsed.
+    pub fn flush(&self, timeout: Duration) {
+        let (ack_sender, ack_receiver) = mpsc::channel();
+        if self.sender.send(Msg::Flush(ack_sender)).is_ok() {
+            ack_receiver.recv_timeout(timeout).ok();
+        }
+    }
+}
+
+/// Return the line recording `act_name` moving from `old` to `new` at `now`.
+fn entry(now: SystemTime, act_name: &str, old: &TokenState, new: &TokenState) -> String {
+    let mut entry = object! {
+        timestamp: iso8601(now),
+        account: act_name,
+        old: old.variant_name(),
+        new: new.variant_name(),
+    };
+    if let TokenState::Active { expiry, .. } = new {
+        entry["expiry"] = iso8601(*expiry).into();
+    }
+    format!("{}\n", entry.dump())
+}
+
+/// Write the entries received on `receiver` until all [AuditLog]s are dropped. Failing to write to
+/// the log must not stop pizauth from working, so errors are only logged.
+fn writer(receiver: Receiver<Msg>) {
+    // The audit log currently open, and its path.
+    let mut open: Option<(String, File)> = None;
+    for msg in receiver {
+        let (path, line) = match msg {
+            Msg::Entry(path, line) => (path, line),
+            Msg::Flush(ack_sender) => {
+                ack_sender.send(()).ok();
+                continue;
+            }
+        };
+        // The file is reopened if the config now names a different file, or if the file has been
+        // moved or removed (e.g. by log rotation).
+        let reuse = match &open {
+            Some((open_path, f)) if *open_path == path => {
+                match (fs::metadata(&path), f.metadata()) {
+                    (Ok(m1), Ok(m2)) => m1.dev() == m2.dev() && m1.ino() == m2.ino(),
+                    _ => false,
+                }
+            }
+            _ => false,
+        };
+        if !reuse {
+            // Each entry is written with a single `write` to a file opened with `O_APPEND`, so
+            // entries from concurrent writers can't be interleaved, and a crash can't damage
+            // earlier entries.
+            open = match OpenOptions::new()
+                .append(true)
+                .create(true)
+                .mode(0o600)
+                .open(&path)
+            {
+                Ok(f) => Some((path.clone(), f)),
+                Err(e) => {
+                    warn!("Can't write to audit log {path:}: {e:}");
+                    continue;
+                }
+            };
+        }
+        if let Some((_, f)) = &mut open {
+            if let Err(e) = f.write_all(line.as_bytes()) {
+                warn!("Can't write to audit log {path:}: {e:}");
+                open = None;
+            }
+        }
+    }
+}
+
+/// Format `t` as an ISO 8601 UTC timestamp with a precision of one second.
+pub fn iso8601(t: SystemTime) -> String {
+    let secs = t
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let (days, rem) = (secs / 86400, secs % 86400);
+    // Convert days since the epoch to a date in the proleptic Gregorian calendar, using the
+    // algorithm from http://howardhinnant.github.io/date_algorithms.html#civil_from_days.
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + u64::from(m <= 2);
+    format!(
+        "{y:04}-{m:02}-{d:02}T{:02}:{:02}:{:02}Z",
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::secret::SecretString;
+    use std::{env, fs, process, time::Duration, time::Instant};
+
+    #[test]
+    fn test_iso8601() {
+        let t = |secs| iso8601(UNIX_EPOCH + Duration::from_secs(secs));
+        assert_eq!(t(0), "1970-01-01T00:00:00Z");
+        assert_eq!(t(951782400), "2000-02-29T00:00:00Z");
+        assert_eq!(t(1700000000), "2023-11-14T22:13:20Z");
+        assert_eq!(t(4107542399), "2100-02-28T23:59:59Z");
+    }
+
+    #[test]
+    fn test_record() {
+        let p = env::temp_dir().join(format!("pizauth_test_audit_{}", process::id()));
+        let path = p.to_str().unwrap();
+        let now = UNIX_EPOCH + Duration::from_secs(1700000000);
+        let active = TokenState::Active {
+            access_token: SecretString::from("secret_access"),
+            refreshed_at: Instant::now(),
+            last_refresh_attempt: None,
+            consecutive_refresh_failures: 0,
+            expiry: now + Duration::from_secs(60),
+            id_token: None,
+            refresh_token: Some(SecretString::from("secret_refresh")),
+            display_name: None,
+        };
+        let audit = AuditLog::new();
+        audit.record(path, now, "x", &TokenState::Empty, &active);
+        audit.record(path, now, "x", &active, &TokenState::Empty);
+        audit.flush(Duration::from_secs(10));
+        let log = fs::read_to_string(&p).unwrap();
+        fs::remove_file(&p).unwrap();
+
+        assert!(!log.contains("secret"));
+        let lines = log
+            .lines()
+            .map(|l| json::parse(l).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["timestamp"], "2023-11-14T22:13:20Z");
+        assert_eq!(lines[0]["account"], "x");
+        assert_eq!(lines[0]["old"], "Empty");
+        assert_eq!(lines[0]["new"], "Active");
+        assert_eq!(lines[0]["expiry"], "2023-11-14T22:14:20Z");
+        assert!(lines[1]["expiry"].is_null());
+
+        // A removed (e.g. rotated) log is recreated.
+        audit.record(path, now, "x", &TokenState::Empty, &active);
+        audit.flush(Duration::from_secs(10));
+        assert_eq!(fs::read_to_string(&p).unwrap().lines().count(), 1);
+        fs::remove_file(&p).unwrap();
+
+        // An unwritable log doesn't stop later entries from being written.
+        audit.record("/", now, "x", &TokenState::Empty, &active);
+        audit.record(path, now, "x", &TokenState::Empty, &active);
+        audit.flush(Duration::from_secs(10));
+        assert_eq!(fs::read_to_string(&p).unwrap().lines().count(), 1);
+        fs::remove_file(&p).unwrap();
+    }
+}
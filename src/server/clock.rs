@@ -0,0 +1,25 @@
+//! pizauth's notion of time. All of the server's timing decisions go through a [Clock] so that
+//! they can be tested without waiting for real time to pass.
+
+use std::time::{Instant, SystemTime};
+
+pub trait Clock: Send + Sync {
+    /// The current monotonic time. Note that on some platforms this does not advance while the
+    /// system is suspended.
+    fn now(&self) -> Instant;
+    /// The current wall-clock time.
+    fn wall_now(&self) -> SystemTime;
+}
+
+/// The clock used outside of tests: this simply defers to the operating system.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn wall_now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
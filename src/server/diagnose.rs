@@ -0,0 +1,751 @@
+//! Checks for common misconfigurations, as reported by `pizauth diagnose` and `pizauth test`. None
+//! of these checks change pizauth's state.
+
+use std::{
+    error::Error,
+    fmt,
+    net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use rustls::{ClientConnection, ServerName};
+use url::Url;
+
+use super::{make_token_request, token_response::TokenResponse, AuthenticatorState, TokenState};
+use crate::{
+    config::{is_loopback, Account, Config},
+    secret::SecretString,
+};
+
+/// How many seconds should each network check wait before giving up?
+const NET_TIMEOUT: u64 = 5;
+/// The NTP server we compare the system clock against.
+const NTP_SERVER: &str = "pool.ntp.org:123";
+/// Seconds between the NTP epoch (1900-01-01) and the Unix epoch (1970-01-01).
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+/// How many seconds can the system clock differ from the NTP server's before we complain?
+const MAX_CLOCK_SKEW: u64 = 60;
+/// The grant type `pizauth test` asks for. No token endpoint should support it, so the request
+/// can't obtain (or invalidate) a token, but a well-behaved endpoint will still tell us if it
+/// doesn't recognise the client.
+const TEST_GRANT_TYPE: &str = "urn:pizauth:params:oauth:grant-type:test";
+
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Outcome::Pass => write!(f, "pass"),
+            Outcome::Warn => write!(f, "warn"),
+            Outcome::Fail => write!(f, "fail"),
+        }
+    }
+}
+
+struct Check {
+    name: &'static str,
+    outcome: Outcome,
+    detail: String,
+}
+
+impl Check {
+    fn new(name: &'static str, outcome: Outcome, detail: String) -> Self {
+        Check {
+            name,
+            outcome,
+            detail,
+        }
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}: {}", self.name, self.outcome, self.detail)
+    }
+}
+
+/// Run all checks, returning a table of results suitable for showing to the user.
+pub fn diagnose(pstate: &AuthenticatorState) -> String {
+    // We copy what we need and drop the lock before doing any network I/O.
+    let ct_lk = pstate.ct_lock();
+    let mut acts = ct_lk
+        .act_ids()
+        .map(|act_id| {
+            let act_name = &ct_lk.account(&act_id).name;
+            (
+                Arc::clone(&ct_lk.config().accounts[act_name]),
+                check_tokenstate(ct_lk.account(&act_id), ct_lk.tokenstate(&act_id)),
+            )
+        })
+        .collect::<Vec<_>>();
+    drop(ct_lk);
+    acts.sort_by(|(a, _), (b, _)| a.name.cmp(&b.name));
+
+    // Network checks can be slow to fail, so we do them all in parallel.
+    let (clock, mut rows) = thread::scope(|s| {
+        let clock = s.spawn(|| check_clock(pstate.clock.wall_now()));
+        let acts = acts
+            .into_iter()
+            .map(|(act, state)| {
+                let token_uri = act.token_uri.clone();
+                (s.spawn(move || check_token_uri(&token_uri)), act, state)
+            })
+            .collect::<Vec<_>>();
+        let rows = acts
+            .into_iter()
+            .flat_map(|(token_uri, act, state)| {
+                [
+                    token_uri.join().unwrap(),
+                    check_redirect_uri(&act, pstate.http_port),
+                    state,
+                ]
+                .map(|c| (act.name.clone(), c))
+            })
+            .collect::<Vec<_>>();
+        (clock.join().unwrap(), rows)
+    });
+    rows.push((String::new(), clock));
+    table(rows)
+}
+
+/// Format `rows` of `(account name, check)` as a table.
+fn table(rows: Vec<(String, Check)>) -> String {
+    let act_width = rows
+        .iter()
+        .map(|(x, _)| x.len())
+        .chain(["account".len()])
+        .max()
+        .unwrap();
+    let name_width = rows
+        .iter()
+        .map(|(_, c)| c.name.len())
+        .chain(["check".len()])
+        .max()
+        .unwrap();
+    let mut lines = vec![format!(
+        "{:act_width$}  {:name_width$}  result  detail",
+        "account", "check"
+    )];
+    for (act_name, c) in rows {
+        lines.push(format!(
+            "{act_name:act_width$}  {:name_width$}  {:6}  {}",
+            c.name,
+            c.outcome.to_string(),
+            c.detail
+        ));
+    }
+    lines.join("\n")
+}
+
+/// Can we make a TCP connection to `token_uri`?
+fn check_token_uri(token_uri: &str) -> Check {
+    let addrs = match Url::parse(token_uri).map(|x| x.socket_addrs(|| None)) {
+        Ok(Ok(x)) => x,
+        Ok(Err(e)) => return Check::new("token_uri", Outcome::Fail, format!("{e:}")),
+        Err(e) => return Check::new("token_uri", Outcome::Fail, format!("Invalid URI: {e:}")),
+    };
+    let mut errs = Vec::new();
+    for addr in addrs {
+        match TcpStream::connect_timeout(&addr, Duration::from_secs(NET_TIMEOUT)) {
+            Ok(_) => {
+                return Check::new("token_uri", Outcome::Pass, format!("Connected to {addr:}"))
+            }
+            Err(e) => errs.push(format!("{addr:}: {e:}")),
+        }
+    }
+    if errs.is_empty() {
+        errs.push("No addresses found".to_owned());
+    }
+    Check::new("token_uri", Outcome::Fail, errs.join("; "))
+}
+
+/// Does `act`'s token endpoint respond to a malformed request (a `HEAD` with no parameters) with
+/// a 4xx error, as a real token endpoint should? The request is made with the same HTTP client
+/// configuration (e.g. proxy and TLS settings) as real token requests.
+fn check_token_endpoint(act: &Account) -> Check {
+    let expected = |code| format!("Responded with {code:}, but expected a 4xx error");
+    match act.agent(&act.token_uri).head(&act.token_uri).call() {
+        Err(ureq::Error::Status(code, _)) if (400..500).contains(&code) => Check::new(
+            "token_endpoint",
+            Outcome::Pass,
+            format!("Responded with {code:}"),
+        ),
+        Err(ureq::Error::Status(code, _)) => {
+            Check::new("token_endpoint", Outcome::Fail, expected(code))
+        }
+        Ok(response) => Check::new("token_endpoint", Outcome::Fail, expected(response.status())),
+        Err(e) => Check::new(
+            "token_endpoint",
+            Outcome::Fail,
+            format!("{e:}{}", act.transport_desc(&act.token_uri)),
+        ),
+    }
+}
+
+/// Check that the token endpoint of each account in `conf` responds as expected (see
+/// [check_token_endpoint]), returning a description of each failure, ordered by account name.
+pub fn validate_accounts(conf: &Config) -> Vec<String> {
+    let mut acts = conf.accounts.values().collect::<Vec<_>>();
+    acts.sort_by(|a, b| a.name.cmp(&b.name));
+    // Network checks can be slow to fail, so we do them all in parallel.
+    thread::scope(|s| {
+        acts.into_iter()
+            .map(|act| (act, s.spawn(|| check_token_endpoint(act))))
+            .collect::<Vec<_>>()
+            .into_iter()
+            .filter_map(|(act, c)| {
+                let c = c.join().unwrap();
+                (c.outcome != Outcome::Pass).then(|| format!("{}: {}", act.name, c.detail))
+            })
+            .collect()
+    })
+}
+
+/// Check, step by step, that `act`'s token endpoint can be reached: resolving its host, connecting
+/// to it, performing a TLS handshake, and finally making a token request which no endpoint will
+/// grant. Each check's result is passed to `report` as it completes; checks stop at the first
+/// failure, since later checks depend on earlier ones. Returns `Ok(true)` if no check failed.
+/// Neither the account's tokens nor any other part of pizauth's state are used or changed.
+pub fn test_account<E>(
+    act: &Account,
+    mut report: impl FnMut(String) -> Result<(), E>,
+) -> Result<bool, E> {
+    let mut checks = Vec::new();
+    match act.proxy(&act.token_uri) {
+        // Only the proxy knows how it connects to the token endpoint, so we can only test that
+        // end-to-end.
+        Some(_) => checks.push(Check::new(
+            "transport",
+            Outcome::Pass,
+            format!(
+                "Skipping DNS, TCP, and TLS checks{}",
+                act.transport_desc(&act.token_uri)
+            ),
+        )),
+        None => {
+            let (dns, addrs) = check_dns(&act.token_uri);
+            let failed = dns.outcome == Outcome::Fail;
+            report(dns.to_string())?;
+            if failed {
+                return Ok(false);
+            }
+            let (tcp, stream) = check_tcp(&addrs, act.http_timeout);
+            report(tcp.to_string())?;
+            let mut stream = match stream {
+                Some(x) => x,
+                None => return Ok(false),
+            };
+            if act.token_uri.starts_with("https:") {
+                checks.push(check_tls(act, &mut stream));
+            }
+        }
+    }
+    for c in checks {
+        let failed = c.outcome == Outcome::Fail;
+        report(c.to_string())?;
+        if failed {
+            return Ok(false);
+        }
+    }
+    let c = check_token_request(act);
+    let failed = c.outcome == Outcome::Fail;
+    report(c.to_string())?;
+    Ok(!failed)
+}
+
+/// Resolve the host of `token_uri`, returning the addresses it resolves to.
+fn check_dns(token_uri: &str) -> (Check, Vec<SocketAddr>) {
+    let url = match Url::parse(token_uri) {
+        Ok(x) => x,
+        Err(e) => {
+            return (
+                Check::new("dns", Outcome::Fail, format!("Invalid URI: {e:}")),
+                vec![],
+            )
+        }
+    };
+    let host = url.host_str().unwrap_or("");
+    match url.socket_addrs(|| None) {
+        Ok(x) if !x.is_empty() => {
+            let addrs = x.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+            let c = Check::new(
+                "dns",
+                Outcome::Pass,
+                format!("{host:} resolved to {}", addrs.join(", ")),
+            );
+            (c, x)
+        }
+        Ok(_) => (
+            Check::new("dns", Outcome::Fail, format!("{host:}: No addresses found")),
+            vec![],
+        ),
+        Err(e) => (
+            Check::new("dns", Outcome::Fail, format!("{host:}: {e:}")),
+            vec![],
+        ),
+    }
+}
+
+/// Connect to the first of `addrs` which accepts a TCP connection within `timeout`, returning the
+/// connection if there was one.
+fn check_tcp(addrs: &[SocketAddr], timeout: Duration) -> (Check, Option<TcpStream>) {
+    let mut errs = Vec::new();
+    for addr in addrs {
+        let before = Instant::now();
+        match TcpStream::connect_timeout(addr, timeout) {
+            Ok(x) => {
+                let c = Check::new(
+                    "tcp",
+                    Outcome::Pass,
+                    format!("Connected to {addr:} in {}ms", before.elapsed().as_millis()),
+                );
+                return (c, Some(x));
+            }
+            Err(e) => errs.push(format!("{addr:}: {e:}")),
+        }
+    }
+    (Check::new("tcp", Outcome::Fail, errs.join("; ")), None)
+}
+
+/// Perform a TLS handshake with `act`'s token endpoint over `stream`, with the same TLS
+/// configuration as token requests.
+fn check_tls(act: &Account, stream: &mut TcpStream) -> Check {
+    let fail = |e: &dyn fmt::Display| Check::new("tls", Outcome::Fail, format!("{e:}"));
+    let host = Url::parse(&act.token_uri)
+        .ok()
+        .and_then(|x| x.host_str().map(|x| x.trim_matches(['[', ']']).to_owned()))
+        .unwrap_or_default();
+    let server_name = match ServerName::try_from(host.as_str()) {
+        Ok(x) => x,
+        Err(e) => return fail(&e),
+    };
+    let mut conn =
+        match ClientConnection::new(Arc::new(act.tls_config(&act.token_uri)), server_name) {
+            Ok(x) => x,
+            Err(e) => return fail(&e),
+        };
+    if let Err(e) = stream
+        .set_read_timeout(Some(act.http_timeout))
+        .and_then(|_| stream.set_write_timeout(Some(act.http_timeout)))
+    {
+        return fail(&e);
+    }
+    while conn.is_handshaking() {
+        if let Err(e) = conn.complete_io(stream) {
+            return fail(&e);
+        }
+    }
+    let version = conn
+        .protocol_version()
+        .map(|x| format!("{x:?}"))
+        .unwrap_or_default();
+    let suite = conn
+        .negotiated_cipher_suite()
+        .map(|x| format!("{:?}", x.suite()))
+        .unwrap_or_default();
+    if act.verifies_tls(&act.token_uri) {
+        Check::new(
+            "tls",
+            Outcome::Pass,
+            format!("{version:} {suite:}, certificate verified"),
+        )
+    } else {
+        Check::new(
+            "tls",
+            Outcome::Warn,
+            format!("{version:} {suite:}, certificate not verified (verify_tls is false)"),
+        )
+    }
+}
+
+/// Make a token request for [TEST_GRANT_TYPE] with `act`'s client credentials. A 4xx OAuth2 error
+/// shows that the token endpoint is working, unless the error says that the client credentials
+/// were rejected.
+fn check_token_request(act: &Account) -> Check {
+    let pairs = [
+        ("client_id", act.client_id.as_str()),
+        ("client_secret", act.client_secret.expose()),
+        ("grant_type", TEST_GRANT_TYPE),
+    ];
+    match make_token_request(act, &pairs) {
+        Err(ureq::Error::Status(code, response)) if (400..500).contains(&code) => {
+            let content_type = response.content_type().to_owned();
+            let error = response
+                .into_string()
+                .ok()
+                .and_then(|x| TokenResponse::parse(&content_type, &SecretString::from(x)).ok())
+                .and_then(|x| x.error);
+            match error.as_deref() {
+                Some("invalid_client") => Check::new(
+                    "token_endpoint",
+                    Outcome::Fail,
+                    format!(
+                        "Responded with {code:} (invalid_client): check client_id and \
+                        client_secret"
+                    ),
+                ),
+                Some(e) => Check::new(
+                    "token_endpoint",
+                    Outcome::Pass,
+                    format!("Responded with {code:} ({e:})"),
+                ),
+                None => Check::new(
+                    "token_endpoint",
+                    Outcome::Warn,
+                    format!(
+                        "Responded with {code:}, but not with an OAuth2 error: is token_uri \
+                        correct?"
+                    ),
+                ),
+            }
+        }
+        Err(ureq::Error::Status(code, _)) => Check::new(
+            "token_endpoint",
+            Outcome::Fail,
+            format!("Responded with {code:}"),
+        ),
+        Ok(response) => Check::new(
+            "token_endpoint",
+            Outcome::Warn,
+            format!(
+                "Responded with {}, but expected an error: is token_uri correct?",
+                response.status()
+            ),
+        ),
+        Err(e) => Check::new(
+            "token_endpoint",
+            Outcome::Fail,
+            format!("{e:}{}", act.transport_desc(&act.token_uri)),
+        ),
+    }
+}
+
+/// Will the user's browser be redirected to pizauth's HTTP server, listening on `http_port`?
+fn check_redirect_uri(act: &Account, http_port: u16) -> Check {
+    let url = match act.redirect_uri(http_port) {
+        Ok(x) => x,
+        Err(e) => return Check::new("redirect_uri", Outcome::Fail, format!("{e:}")),
+    };
+    if !is_loopback(&url) {
+        return Check::new(
+            "redirect_uri",
+            Outcome::Fail,
+            format!(
+                "Host '{}' is not a loopback address, so pizauth can't receive redirects",
+                url.host_str().unwrap_or("")
+            ),
+        );
+    }
+    match act.redirect_uri_port() {
+        Some(p) if p != http_port => Check::new(
+            "redirect_uri",
+            Outcome::Warn,
+            format!("Port {p:} is replaced by pizauth's HTTP port {http_port:}"),
+        ),
+        _ => Check::new("redirect_uri", Outcome::Pass, url.to_string()),
+    }
+}
+
+/// Is `act`'s token in a plausible state?
+fn check_tokenstate(act: &Account, ts: &TokenState) -> Check {
+    match ts {
+        TokenState::Empty => Check::new("token", Outcome::Pass, "No token".to_owned()),
+        TokenState::Pending {
+            notification_count, ..
+        } => match act.notify_max_count {
+            Some(m) if *notification_count >= m => Check::new(
+                "token",
+                Outcome::Warn,
+                format!(
+                    "Pending authentication, but the user is no longer being notified \
+                    (notify_max_count is {m:})"
+                ),
+            ),
+            _ => Check::new("token", Outcome::Pass, "Pending authentication".to_owned()),
+        },
+        TokenState::Exchanging => Check::new(
+            "token",
+            Outcome::Pass,
+            "Completing authentication".to_owned(),
+        ),
+        TokenState::Active { .. } => Check::new("token", Outcome::Pass, "Active".to_owned()),
+        TokenState::Failed { reason, .. } => Check::new(
+            "token",
+            Outcome::Fail,
+            format!("Refreshing failed ({reason:}): run 'pizauth refresh' to reauthenticate"),
+        ),
+    }
+}
+
+/// Is the system clock (whose current value is `wall_now`) close to an NTP server's?
+fn check_clock(wall_now: SystemTime) -> Check {
+    match ntp_skew(wall_now) {
+        Ok(d) if d <= MAX_CLOCK_SKEW => Check::new(
+            "clock",
+            Outcome::Pass,
+            format!("Within {d:}s of {NTP_SERVER:}"),
+        ),
+        Ok(d) => Check::new(
+            "clock",
+            Outcome::Fail,
+            format!("{d:}s different to {NTP_SERVER:}: token expiry times will be wrong"),
+        ),
+        Err(e) => Check::new(
+            "clock",
+            Outcome::Warn,
+            format!("Can't query {NTP_SERVER:}: {e:}"),
+        ),
+    }
+}
+
+/// Return the number of seconds between `wall_now` and the time reported by [NTP_SERVER], using
+/// a single SNTP (RFC 4330) request.
+fn ntp_skew(wall_now: SystemTime) -> Result<u64, Box<dyn Error>> {
+    let addr = NTP_SERVER
+        .to_socket_addrs()?
+        .next()
+        .ok_or("No addresses found")?;
+    let sock = UdpSocket::bind(if addr.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    })?;
+    sock.set_read_timeout(Some(Duration::from_secs(NET_TIMEOUT)))?;
+    let mut buf = [0u8; 48];
+    // Leap indicator 0, version 4, mode 3 (client).
+    buf[0] = 0b00_100_011;
+    sock.send_to(&buf, addr)?;
+    let (len, _) = sock.recv_from(&mut buf)?;
+    if len < buf.len() {
+        return Err("Truncated NTP response".into());
+    }
+    // The integer part of the server's transmit timestamp.
+    let ntp_secs = u64::from(u32::from_be_bytes([buf[40], buf[41], buf[42], buf[43]]));
+    let server_secs = ntp_secs
+        .checked_sub(NTP_UNIX_OFFSET)
+        .ok_or("Invalid NTP timestamp")?;
+    let local_secs = wall_now.duration_since(UNIX_EPOCH)?.as_secs();
+    Ok(server_secs.abs_diff(local_secs))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+    };
+
+    use crate::server::test_utils::act_conf;
+
+    fn act(redirect_uri: &str) -> Arc<Account> {
+        let mut c = Config::from_str(&act_conf(
+            "x",
+            &[
+                ("redirect_uri", &format!("\"{redirect_uri:}\"")),
+                ("notify_max_count", "2"),
+            ],
+        ))
+        .unwrap();
+        c.accounts.remove("x").unwrap()
+    }
+
+    #[test]
+    fn test_token_uri() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let c = check_token_uri(&format!("http://127.0.0.1:{port:}/"));
+        assert_eq!(c.outcome, Outcome::Pass);
+        drop(listener);
+        let c = check_token_uri(&format!("http://127.0.0.1:{port:}/"));
+        assert_eq!(c.outcome, Outcome::Fail);
+    }
+
+    #[test]
+    fn test_token_endpoint() {
+        // Respond to the next request on `listener` with `status`.
+        fn respond(listener: &TcpListener, status: &str) {
+            let (stream, _) = listener.accept().unwrap();
+            let mut rdr = BufReader::new(stream);
+            let mut line = String::new();
+            while rdr.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            rdr.into_inner()
+                .write_all(format!("HTTP/1.1 {status:}\r\nContent-Length: 0\r\n\r\n").as_bytes())
+                .unwrap();
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let conf = Config::from_str(&act_conf(
+            "x",
+            &[
+                ("redirect_uri", ""),
+                ("token_uri", &format!("\"http://127.0.0.1:{port:}/token\"")),
+            ],
+        ))
+        .unwrap();
+        let srv = thread::spawn(move || {
+            respond(&listener, "400 Bad Request");
+            respond(&listener, "200 OK");
+            respond(&listener, "500 Internal Server Error");
+        });
+        assert!(validate_accounts(&conf).is_empty());
+        assert_eq!(
+            validate_accounts(&conf),
+            ["x: Responded with 200, but expected a 4xx error"]
+        );
+        assert_eq!(
+            validate_accounts(&conf),
+            ["x: Responded with 500, but expected a 4xx error"]
+        );
+        srv.join().unwrap();
+        // Nothing is listening any more.
+        assert_eq!(validate_accounts(&conf).len(), 1);
+    }
+
+    #[test]
+    fn test_test_account() {
+        // Respond to the next HTTP request on `listener` with `status` and the JSON `body`,
+        // ignoring connections (e.g. the TCP check's) which close without making a request.
+        fn respond(listener: &TcpListener, status: &str, body: &str) {
+            loop {
+                let mut rdr = BufReader::new(listener.accept().unwrap().0);
+                let mut len = None;
+                loop {
+                    let mut line = String::new();
+                    if rdr.read_line(&mut line).unwrap() == 0 || line.trim().is_empty() {
+                        break;
+                    }
+                    let len = len.get_or_insert(0);
+                    if let Some((k, v)) = line.split_once(':') {
+                        if k.eq_ignore_ascii_case("content-length") {
+                            *len = v.trim().parse::<usize>().unwrap();
+                        }
+                    }
+                }
+                if let Some(len) = len {
+                    rdr.read_exact(&mut vec![0; len]).unwrap();
+                    let resp = format!(
+                        "HTTP/1.1 {status:}\r\nContent-Type: application/json\r\n\
+                         Content-Length: {}\r\n\r\n{body:}",
+                        body.len()
+                    );
+                    rdr.into_inner().write_all(resp.as_bytes()).unwrap();
+                    return;
+                }
+            }
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut conf = Config::from_str(&act_conf(
+            "x",
+            &[
+                ("redirect_uri", ""),
+                ("token_uri", &format!("\"http://127.0.0.1:{port:}/token\"")),
+            ],
+        ))
+        .unwrap();
+        let act = conf.accounts.remove("x").unwrap();
+        let test = || {
+            let mut lines = Vec::new();
+            let passed = test_account::<()>(&act, |x| {
+                lines.push(x);
+                Ok(())
+            })
+            .unwrap();
+            (passed, lines)
+        };
+        let srv = thread::spawn(move || {
+            respond(
+                &listener,
+                "400 Bad Request",
+                r#"{"error": "unsupported_grant_type"}"#,
+            );
+            respond(
+                &listener,
+                "401 Unauthorized",
+                r#"{"error": "invalid_client"}"#,
+            );
+        });
+
+        let (passed, lines) = test();
+        assert!(passed);
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("dns: pass: 127.0.0.1 resolved to 127.0.0.1:"));
+        assert!(lines[1].starts_with("tcp: pass: Connected to 127.0.0.1:"));
+        assert_eq!(
+            lines[2],
+            "token_endpoint: pass: Responded with 400 (unsupported_grant_type)"
+        );
+
+        let (passed, lines) = test();
+        assert!(!passed);
+        assert!(lines[2].starts_with("token_endpoint: fail: Responded with 401 (invalid_client)"));
+
+        // Nothing is listening any more, so checks stop after the TCP check fails.
+        srv.join().unwrap();
+        let (passed, lines) = test();
+        assert!(!passed);
+        assert_eq!(lines.len(), 2);
+        assert!(lines[1].starts_with("tcp: fail: "));
+    }
+
+    #[test]
+    fn test_redirect_uri() {
+        let c = check_redirect_uri(&act("http://localhost/"), 1234);
+        assert_eq!(c.outcome, Outcome::Pass);
+        assert_eq!(c.detail, "http://localhost:1234/");
+        let c = check_redirect_uri(&act("http://[::1]/"), 1234);
+        assert_eq!(c.outcome, Outcome::Pass);
+        let c = check_redirect_uri(&act("http://127.0.0.1:8080/"), 1234);
+        assert_eq!(c.outcome, Outcome::Warn);
+        let c = check_redirect_uri(&act("http://example.com/"), 1234);
+        assert_eq!(c.outcome, Outcome::Fail);
+    }
+
+    #[test]
+    fn test_tokenstate() {
+        let act = act("http://localhost/");
+        let pending = |notification_count| TokenState::Pending {
+            last_notification: None,
+            notification_count,
+            nonce: None,
+            state: [0; crate::server::STATE_LEN],
+            url: Url::parse("http://a.com/").unwrap(),
+        };
+        assert_eq!(
+            check_tokenstate(&act, &TokenState::Empty).outcome,
+            Outcome::Pass
+        );
+        assert_eq!(check_tokenstate(&act, &pending(1)).outcome, Outcome::Pass);
+        assert_eq!(check_tokenstate(&act, &pending(2)).outcome, Outcome::Warn);
+    }
+
+    #[test]
+    fn test_table() {
+        let t = table(vec![
+            (
+                "abc".to_owned(),
+                Check::new("token", Outcome::Pass, "Active".to_owned()),
+            ),
+            (
+                String::new(),
+                Check::new("clock", Outcome::Warn, "x".to_owned()),
+            ),
+        ]);
+        assert_eq!(
+            t,
+            "account  check  result  detail\nabc      token  pass    Active\n         clock  warn    x"
+        );
+    }
+}
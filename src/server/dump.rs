@@ -0,0 +1,296 @@
+//! Dumping and restoring refresh tokens, so that authenticated accounts can be moved to another
+//! pizauth server (e.g. on a new machine) without the user having to reauthenticate each of them.
+//!
+//! A dump is a warning comment, a version line, and then one line per account of the form
+//! `<account name> <account fingerprint> <refresh token>`, each field being URL encoded. Only
+//! accounts whose config has the same [Account::fingerprint] as the dumped account's are restored.
+//!
+//! Separately, [dump_state] dumps a snapshot of every account's state, without any secrets, for
+//! debugging.
+
+use std::{collections::HashSet, error::Error, time::UNIX_EPOCH};
+
+use json::JsonValue;
+
+use super::{state::LockedStateSummary, AuthenticatorState, CTGuard, TokenState};
+use crate::{config::Account, secret::SecretString};
+
+/// The comment at the start of every dump.
+pub const DUMP_WARNING: &str =
+    "# This contains refresh tokens which give access to your accounts: keep it secret!";
+/// The version line of dumps in the current format.
+pub const DUMP_VERSION: &str = "pizauth_dump 1";
+
+/// Return a dump of the refresh tokens of all accounts with an active token.
+pub fn dump(pstate: &AuthenticatorState) -> SecretString {
+    dump_locked(&pstate.ct_lock())
+}
+
+/// Return a dump of the refresh tokens of all accounts with an active token in `ct_lk`. This
+/// allows the caller to ensure that no token is rotated between the dump being made and it being
+/// used.
+pub fn dump_locked(ct_lk: &CTGuard) -> SecretString {
+    let mut lines = ct_lk
+        .act_ids()
+        .filter_map(|act_id| match ct_lk.tokenstate(&act_id) {
+            TokenState::Active {
+                refresh_token: Some(refresh_token),
+                ..
+            } => Some(dump_line(ct_lk.account(&act_id), refresh_token)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    lines.sort_by(|a, b| a.expose().cmp(b.expose()));
+
+    // We create the dump in one go so that no partial copies of it are left in freed memory.
+    let len = DUMP_WARNING.len()
+        + DUMP_VERSION.len()
+        + 2
+        + lines.iter().map(|x| x.expose().len() + 1).sum::<usize>();
+    let mut s = String::with_capacity(len);
+    s.push_str(DUMP_WARNING);
+    s.push('\n');
+    s.push_str(DUMP_VERSION);
+    s.push('\n');
+    for l in lines {
+        s.push_str(l.expose());
+        s.push('\n');
+    }
+    SecretString::from(s)
+}
+
+/// Return a JSON object mapping each account's name to a snapshot of its state (see
+/// [LockedStateSummary]).
+pub fn dump_state(pstate: &AuthenticatorState) -> JsonValue {
+    state_json(&pstate.ct_lock().summary())
+}
+
+fn state_json(summary: &LockedStateSummary) -> JsonValue {
+    let mut acts = JsonValue::new_object();
+    for s in &summary.accounts {
+        let mut act = JsonValue::new_object();
+        // JSON numbers can't represent every u128.
+        act["version"] = s.version.to_string().into();
+        act["state"] = s.tokenstate.into();
+        act["notification_count"] = s.notification_count.into();
+        act["last_notification_secs_ago"] = s.last_notification_secs_ago.into();
+        act["refreshed_secs_ago"] = s.refreshed_secs_ago.into();
+        act["last_refresh_attempt_secs_ago"] = s.last_refresh_attempt_secs_ago.into();
+        act["consecutive_refresh_failures"] = s.consecutive_refresh_failures.into();
+        act["expiry"] = s
+            .expiry
+            .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+            .map(|x| x.as_secs())
+            .into();
+        act["has_id_token"] = s.has_id_token.into();
+        act["has_refresh_token"] = s.has_refresh_token.into();
+        act["failed_secs_ago"] = s.failed_secs_ago.into();
+        act["failed_reason"] = s.failed_reason.clone().into();
+        act["last_error"] = match &s.last_error {
+            Some((secs_ago, msg)) => json::object! { secs_ago: *secs_ago, message: msg.as_str() },
+            None => JsonValue::Null,
+        };
+        act["last_used_secs_ago"] = s.last_used_secs_ago.into();
+        acts[s.name.as_str()] = act;
+    }
+    acts
+}
+
+/// Return the dump line for `act` with the refresh token `refresh_token`.
+fn dump_line(act: &Account, refresh_token: &SecretString) -> SecretString {
+    let act_name = urlencoding::encode(&act.name);
+    let fingerprint = act.fingerprint();
+    let refresh_token =
+        SecretString::from(urlencoding::encode(refresh_token.expose()).into_owned());
+    let mut s = String::with_capacity(
+        act_name.len() + fingerprint.len() + refresh_token.expose().len() + 2,
+    );
+    s.push_str(&act_name);
+    s.push(' ');
+    s.push_str(&fingerprint);
+    s.push(' ');
+    s.push_str(refresh_token.expose());
+    SecretString::from(s)
+}
+
+/// Restore the refresh tokens in `entries`, a flat list of `account name, account fingerprint,
+/// refresh token` triples in the format of a dump line (see [restore_tokens]). Returns a human
+/// readable report of what was restored and skipped.
+pub fn restore(pstate: &AuthenticatorState, entries: &[&str]) -> Result<String, Box<dyn Error>> {
+    let mut report = restore_tokens(pstate, parse_entries(entries)?)?
+        .into_iter()
+        .map(|(act_name, skipped)| match skipped {
+            Some(reason) => format!("{act_name:}: skipped ({reason:})"),
+            None => format!("{act_name:}: restored"),
+        })
+        .collect::<Vec<_>>();
+    report.sort();
+    Ok(report.join("\n"))
+}
+
+/// Parse `entries`, a flat list of `account name, account fingerprint, refresh token` triples in
+/// the format of a dump line.
+pub fn parse_entries(entries: &[&str]) -> Result<Vec<RestoreEntry>, Box<dyn Error>> {
+    let entries = entries.chunks_exact(3);
+    if !entries.remainder().is_empty() {
+        return Err("Malformed dump entries".into());
+    }
+    entries
+        .map(|entry| {
+            Ok(RestoreEntry {
+                act_name: urlencoding::decode(entry[0])?.into_owned(),
+                fingerprint: Some(entry[1].to_owned()),
+                refresh_token: SecretString::from(urlencoding::decode(entry[2])?.into_owned()),
+            })
+        })
+        .collect()
+}
+
+/// A refresh token to be restored.
+pub struct RestoreEntry {
+    pub act_name: String,
+    /// The [Account::fingerprint] of the account the token was obtained for, or `None` if it is
+    /// unknown, in which case the token is assumed to be valid for the current account.
+    pub fingerprint: Option<String>,
+    pub refresh_token: SecretString,
+}
+
+/// An account name and, if its refresh token wasn't restored, why not.
+pub type RestoreOutcome = (String, Option<&'static str>);
+
+/// Restore the refresh tokens in `entries`. Accounts which don't exist, whose fingerprint doesn't
+/// match, or which already have a token are skipped. Restored accounts are given an expired
+/// [TokenState::Active], so that the refresher obtains a new access token as soon as possible.
+/// Returns the outcome for each entry.
+pub fn restore_tokens(
+    pstate: &AuthenticatorState,
+    entries: Vec<RestoreEntry>,
+) -> Result<Vec<RestoreOutcome>, Box<dyn Error>> {
+    let mut ct_lk = pstate.ct_lock();
+    let mut seen = HashSet::new();
+    let mut updates = Vec::new();
+    let mut outcomes = Vec::new();
+    for entry in entries {
+        let act_name = entry.act_name;
+        if !seen.insert(act_name.clone()) {
+            outcomes.push((act_name, Some("appears more than once")));
+            continue;
+        }
+        let act_id = match ct_lk.validate_act_name(&act_name) {
+            Some(x) => x,
+            None => {
+                outcomes.push((act_name, Some("no such account")));
+                continue;
+            }
+        };
+        if entry
+            .fingerprint
+            .is_some_and(|x| ct_lk.account(&act_id).fingerprint() != x)
+        {
+            outcomes.push((
+                act_name,
+                Some("account's config differs from the dumped account's"),
+            ));
+            continue;
+        }
+        if let TokenState::Active { .. } | TokenState::Exchanging = ct_lk.tokenstate(&act_id) {
+            outcomes.push((act_name, Some("already has a token")));
+            continue;
+        }
+        updates.push((
+            act_id,
+            TokenState::Active {
+                access_token: SecretString::from(""),
+                refreshed_at: pstate.clock.now(),
+                last_refresh_attempt: None,
+                consecutive_refresh_failures: 0,
+                expiry: pstate.clock.wall_now(),
+                id_token: None,
+                refresh_token: Some(entry.refresh_token),
+                display_name: None,
+            },
+        ));
+        outcomes.push((act_name, None));
+    }
+    // All the account IDs are distinct and were validated while we held the lock, so this can
+    // only fail if there is a bug elsewhere.
+    ct_lk
+        .bulk_tokenstate_replace(updates)
+        .ok_or("Token state changed while restoring")?;
+    drop(ct_lk);
+    pstate.refresher.notify_changes();
+    Ok(outcomes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::test_utils::{mock_pstate, CONF_STR};
+
+    #[test]
+    fn test_dump_restore() {
+        let (pstate, _) = mock_pstate(CONF_STR);
+        let d = dump(&pstate);
+        assert_eq!(d.expose(), format!("{DUMP_WARNING:}\n{DUMP_VERSION:}\n"));
+
+        {
+            let mut ct_lk = pstate.ct_lock();
+            let act_id = ct_lk.validate_act_name("x").unwrap();
+            ct_lk.tokenstate_replace(
+                act_id,
+                TokenState::Active {
+                    access_token: SecretString::from("a"),
+                    refreshed_at: pstate.clock.now(),
+                    last_refresh_attempt: None,
+                    consecutive_refresh_failures: 0,
+                    expiry: pstate.clock.wall_now(),
+                    id_token: None,
+                    refresh_token: Some(SecretString::from("r t")),
+                    display_name: None,
+                },
+            );
+        }
+        let d = dump(&pstate);
+        let lines = d.expose().lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        let entries = lines[2].split(' ').collect::<Vec<_>>();
+        assert_eq!(entries[0], "x");
+        assert_eq!(entries[2], "r%20t");
+
+        // An account which already has a token isn't overwritten.
+        assert_eq!(
+            restore(&pstate, &entries).unwrap(),
+            "x: skipped (already has a token)"
+        );
+
+        let (pstate2, _) = mock_pstate(CONF_STR);
+        let mut bad_fingerprint = entries.clone();
+        bad_fingerprint[1] = "abc";
+        assert_eq!(
+            restore(&pstate2, &bad_fingerprint).unwrap(),
+            "x: skipped (account's config differs from the dumped account's)"
+        );
+        let mut bad_name = entries.clone();
+        bad_name[0] = "y";
+        assert_eq!(
+            restore(&pstate2, &bad_name).unwrap(),
+            "y: skipped (no such account)"
+        );
+        assert!(restore(&pstate2, &entries[..2]).is_err());
+
+        assert_eq!(restore(&pstate2, &entries).unwrap(), "x: restored");
+        let ct_lk = pstate2.ct_lock();
+        let act_id = ct_lk.validate_act_name("x").unwrap();
+        match ct_lk.tokenstate(&act_id) {
+            TokenState::Active {
+                expiry,
+                refresh_token: Some(x),
+                ..
+            } => {
+                assert_eq!(x.expose(), "r t");
+                assert!(*expiry <= pstate2.clock.wall_now());
+            }
+            _ => panic!(),
+        }
+    }
+}
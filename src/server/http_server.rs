@@ -1,22 +1,83 @@
 use std::{
+    collections::HashMap,
     error::Error,
-    io::{BufRead, BufReader, Write},
-    net::{TcpListener, TcpStream},
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{Ipv4Addr, Ipv6Addr, TcpListener, TcpStream},
     sync::Arc,
     thread,
     time::{Duration, Instant},
 };
 
 use log::warn;
-use url::Url;
+use url::{form_urlencoded, Url};
 
-use super::{AuthenticatorState, CTGuardAccountId, TokenState};
+use super::{
+    is_transient, make_token_request, token_response::TokenResponse, userinfo, AuthenticatorState,
+    CTGuard, CTGuardAccountId, TokenState,
+};
+use crate::{
+    config::{Account, Config, ResponseType},
+    frontends::ErrorKind,
+    secret::SecretString,
+};
 
-/// How often should we try making a request to an OAuth server for possibly-temporary transport
-/// issues?
-const RETRY_POST: u8 = 10;
-/// How long to delay between each retry?
-const RETRY_DELAY: u64 = 6;
+/// How many times should we try exchanging an authorisation code for a token if we encounter
+/// transient errors?
+const RETRY_POST: u8 = 3;
+/// How many seconds to delay before each retry? The delay is multiplied by the number of attempts
+/// made so far.
+const RETRY_DELAY: u64 = 1;
+/// Don't start a retry once this many seconds have passed since the first attempt: authorisation
+/// codes typically expire after 60 seconds or so.
+const RETRY_BUDGET: u64 = 20;
+/// How many seconds can reading a request from, or writing a response to, a client take before we
+/// give up on it?
+const HTTP_TIMEOUT: u64 = 5;
+/// The maximum length in bytes of an incoming HTTP request (including headers).
+const MAX_REQUEST_LEN: u64 = 16 * 1024;
+/// How many times should we try to find a port which is free on both IPv4 and IPv6 loopback?
+const BIND_ATTEMPTS: u8 = 10;
+/// The page shown once the user's browser has passed us a plausible looking authorisation code,
+/// unless the user specified `http_success_file`.
+const SUCCESS_PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><title>pizauth</title></head>
+<body>
+<h2>pizauth processing authentication for {account}: you can safely close this page.</h2>
+</body>
+</html>
+"#;
+/// The page shown when something has gone wrong, unless the user specified `http_error_file`.
+const ERROR_PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><title>pizauth</title></head>
+<body>
+<h2>pizauth authentication failed</h2>
+<p>{error}</p>
+</body>
+</html>
+"#;
+
+/// The page sent to the redirect URI of implicit flow accounts. The implicit flow puts the token in
+/// the URL's fragment, which browsers never send to servers, so this resends the fragment as the
+/// query. `replace` stops the URL with the token from being left in the browser's history.
+const FRAGMENT_PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><title>pizauth</title></head>
+<body>
+<noscript><h2>pizauth needs JavaScript to complete this authentication.</h2></noscript>
+<script>
+var fragment = window.location.hash.substring(1);
+if (fragment) {
+  var query = window.location.search;
+  window.location.replace(window.location.pathname + (query ? query + "&" : "?") + fragment);
+} else {
+  document.body.innerHTML = "<h2>pizauth authentication failed</h2><p>No state in request</p>";
+}
+</script>
+</body>
+</html>
+"#;
 
 /// Handle an incoming (hopefully OAuth2) HTTP request.
 fn request(pstate: Arc<AuthenticatorState>, mut stream: TcpStream) -> Result<(), Box<dyn Error>> {
@@ -26,8 +87,18 @@ fn request(pstate: Arc<AuthenticatorState>, mut stream: TcpStream) -> Result<(),
     // server: if there's a problem, we have to reset the tokenstate and force the user to make an
     // entirely fresh request.
 
-    let uri = match parse_get(&mut stream) {
-        Ok(x) => x,
+    // A client which connects and then stalls mustn't tie up a thread forever.
+    stream.set_read_timeout(Some(Duration::from_secs(HTTP_TIMEOUT)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(HTTP_TIMEOUT)))?;
+
+    let uri = match parse_request(&mut stream) {
+        Ok((Method::Get, x)) => x,
+        Ok((Method::Head, _)) => {
+            // HEAD requests (e.g. from proxies probing the port) must not be able to change the
+            // tokenstate, so we respond without looking at the URI.
+            http_head(stream);
+            return Ok(());
+        }
         Err(_) => {
             // If someone couldn't even be bothered giving us a valid URI, it's unlikely this was a
             // genuine request that's worth reporting as an error.
@@ -36,13 +107,46 @@ fn request(pstate: Arc<AuthenticatorState>, mut stream: TcpStream) -> Result<(),
         }
     };
 
+    let params = match query_params(&uri) {
+        Ok(x) => x,
+        Err(e) => {
+            let page = error_page(pstate.ct_lock().config(), "", &e);
+            http_html(stream, "400 Bad Request", &page);
+            return Ok(());
+        }
+    };
+
     // All valid requests (even those reporting an error!) should report back a valid "state" to
     // us, so fish that out of the URI and check that it matches a request we made.
-    let state = match uri.query_pairs().find(|(k, _)| k == "state") {
-        Some((_, state)) => urlencoding::decode_binary(state.as_bytes()).into_owned(),
+    let state = match params.get("state") {
+        Some(state) => urlencoding::decode_binary(state.as_bytes()).into_owned(),
         None => {
-            // As well as malformed OAuth queries this will also 404 for favicon.ico.
-            http_404(stream);
+            let ct_lk = pstate.ct_lock();
+            // A request to one of our redirect URIs without a state is broken (unless it's for
+            // an implicit flow account, whose state is in the fragment), but anything else (e.g.
+            // favicon.ico) is simply not something we serve.
+            let redirect_acts = ct_lk
+                .act_ids()
+                .map(|act_id| ct_lk.account(&act_id))
+                .filter(|act| {
+                    act.redirect_uri(pstate.http_port)
+                        .is_ok_and(|x| x.path() == uri.path())
+                })
+                .collect::<Vec<_>>();
+            if redirect_acts
+                .iter()
+                .any(|act| act.response_type == ResponseType::Token)
+            {
+                drop(ct_lk);
+                http_html(stream, "200 OK", FRAGMENT_PAGE);
+            } else if !redirect_acts.is_empty() {
+                let page = error_page(ct_lk.config(), "", "No state in request");
+                drop(ct_lk);
+                http_html(stream, "400 Bad Request", &page);
+            } else {
+                drop(ct_lk);
+                http_404(stream);
+            }
             return Ok(());
         }
     };
@@ -50,22 +154,29 @@ fn request(pstate: Arc<AuthenticatorState>, mut stream: TcpStream) -> Result<(),
     let act_id = match ct_lk.act_id_matching_token_state(&state) {
         Some(x) => x,
         None => {
-            drop(ct_lk);
-            http_200(
-                stream,
+            let page = error_page(
+                ct_lk.config(),
+                "",
                 "No pending token matches request state: request a fresh token",
             );
+            drop(ct_lk);
+            http_html(stream, "400 Bad Request", &page);
             return Ok(());
         }
     };
 
     // Now that we know which account has been matched we can check if the full URI requested
-    // matched the redirect URI we expected for that account.
+    // matched the redirect URI we expected for that account, including any query parameters the
+    // user specified in the redirect URI.
     let act = ct_lk.account(&act_id);
     let expected_uri = act.redirect_uri(pstate.http_port)?;
     if expected_uri.scheme() != uri.scheme()
         || expected_uri.host_str() != uri.host_str()
         || expected_uri.port() != uri.port()
+        || expected_uri.path() != uri.path()
+        || expected_uri
+            .query_pairs()
+            .any(|(k, v)| params.get(k.as_ref()) != Some(&v.into_owned()))
     {
         // If the redirect URI doesn't match then all we can do is 404.
         drop(ct_lk);
@@ -73,182 +184,498 @@ fn request(pstate: Arc<AuthenticatorState>, mut stream: TcpStream) -> Result<(),
         return Ok(());
     }
 
-    // Did authentication fail?
-    if let Some((_, reason)) = uri.query_pairs().find(|(k, _)| k == "error") {
+    if let Some(reason) = error_reason(&params) {
         let act_id = ct_lk.tokenstate_replace(act_id, TokenState::Empty);
+        ct_lk.set_last_error(&act_id, format!("Authentication failed: {reason:}"));
         let act_name = ct_lk.account(&act_id).name.clone();
-        let msg = format!(
-            "Authentication for {} failed: {}",
-            ct_lk.account(&act_id).name,
-            reason
-        );
+        let msg = format!("Authentication for {act_name:} failed: {reason:}");
+        let page = error_page(ct_lk.config(), &act_name, &reason);
         drop(ct_lk);
-        http_400(stream);
-        pstate.frontend.notify_error(act_name, &msg)?;
+        http_html(stream, "400 Bad Request", &page);
+        pstate.notifier.notify_error(
+            &pstate,
+            Some(act_name),
+            ErrorKind::TokenEndpointRejected,
+            &msg,
+        )?;
         return Ok(());
     }
 
-    // Fish out the code query.
-    let code = match uri.query_pairs().find(|(k, _)| k == "code") {
-        Some((_, code)) => code.to_string(),
-        None => {
-            // A request without a 'code' is broken. This seems very unlikely to happen and if it
-            // does, would retrying our request from scratch improve anything?
+    let grant = match grant(act, &params) {
+        Ok(x) => x,
+        Err(e) => {
+            // A request without a code (or token) is broken. This seems very unlikely to happen
+            // and if it does, would retrying our request from scratch improve anything?
+            let page = error_page(ct_lk.config(), &act.name, &format!("{e:} in request"));
             drop(ct_lk);
-            http_400(stream);
+            http_html(stream, "400 Bad Request", &page);
             return Ok(());
         }
     };
 
-    let token_uri = act.token_uri.clone();
+    let page = success_page(ct_lk.config(), &act.name);
+    let exchange = start_exchange(&pstate, &mut ct_lk, act_id, grant)?;
+
+    // At this point we know we've got a sensible looking query, so we complete the HTTP request,
+    // because we don't know how long we'll spend going through the rest of the OAuth process, and
+    // we can notify the user another way than through their web browser.
+    drop(ct_lk);
+    http_html(stream, "200 OK", &page);
+
+    exchange_code(pstate, exchange)
+}
+
+/// Complete the pending authentication of `act_name` using `url`, the URL that the user's browser
+/// was redirected to, as if pizauth's HTTP server had received the redirect. This is for users
+/// whose browser can't reach pizauth's HTTP server (e.g. because pizauth is running on another
+/// machine). Unlike the HTTP server, we don't check that `url` matches the account's redirect URI:
+/// the state in `url` is what shows that it belongs to the pending authentication. For implicit
+/// flow accounts, `url`'s fragment is treated as part of its query. Returns a human readable error
+/// if the authentication wasn't completed.
+pub fn complete(pstate: Arc<AuthenticatorState>, act_name: &str, url: &str) -> Result<(), String> {
+    let params = redirect_params(url)?;
+    let state = match params.get("state") {
+        Some(x) => urlencoding::decode_binary(x.as_bytes()).into_owned(),
+        None => return Err("No 'state' in URL: check that the whole URL was copied".to_owned()),
+    };
+
+    let mut ct_lk = pstate.ct_lock();
+    let act_id = ct_lk
+        .validate_act_name(act_name)
+        .ok_or_else(|| format!("No account '{act_name:}'"))?;
+    let act_id = match ct_lk.act_id_matching_token_state(&state) {
+        Some(x) if ct_lk.account(&x).name == act_name => x,
+        Some(x) => {
+            return Err(format!(
+                "URL is for account '{}', not '{act_name:}'",
+                ct_lk.account(&x).name
+            ))
+        }
+        None => {
+            return Err(match ct_lk.tokenstate(&act_id) {
+                TokenState::Pending { .. } => format!(
+                    "URL's state doesn't match {act_name:}'s pending authentication: check that \
+                    the URL is from the most recent authentication attempt"
+                ),
+                _ => format!(
+                    "{act_name:} has no pending authentication: run 'pizauth refresh {act_name:}' \
+                    to start one"
+                ),
+            })
+        }
+    };
+
+    if let Some(reason) = error_reason(&params) {
+        let act_id = ct_lk.tokenstate_replace(act_id, TokenState::Empty);
+        ct_lk.set_last_error(&act_id, format!("Authentication failed: {reason:}"));
+        return Err(format!("Authentication failed: {reason:}"));
+    }
+    let grant = grant(ct_lk.account(&act_id), &params)
+        .map_err(|e| format!("{e:} in URL: check that the whole URL was copied"))?;
+    let exchange = start_exchange(&pstate, &mut ct_lk, act_id, grant).map_err(|e| e.to_string())?;
+    drop(ct_lk);
+
+    let rtn = exchange_code(Arc::clone(&pstate), exchange);
+    // Failures are recorded as the account's last error, and even if the exchange succeeded,
+    // notifying the user can fail, so we look at the outcome rather than `rtn`.
+    let ct_lk = pstate.ct_lock();
+    match ct_lk.validate_act_name(act_name) {
+        Some(act_id) => match ct_lk.tokenstate(&act_id) {
+            TokenState::Active { .. } => Ok(()),
+            _ => Err(match (ct_lk.last_error(&act_id), rtn) {
+                (Some((_, msg)), _) => msg.to_owned(),
+                (None, Err(e)) => e.to_string(),
+                (None, Ok(())) => "Authentication failed".to_owned(),
+            }),
+        },
+        None => Err(format!("Account '{act_name:}' was removed")),
+    }
+}
+
+/// Decode the parameters of `url`, a URL which the user's browser was redirected to. `url`'s
+/// fragment, where the implicit flow puts the token, is treated as part of its query.
+pub fn redirect_params(url: &str) -> Result<HashMap<String, String>, String> {
+    let mut uri = Url::parse(url).map_err(|e| format!("Invalid URL: {e:}"))?;
+    if let Some(fragment) = uri.fragment().map(|x| x.to_owned()) {
+        uri.query_pairs_mut()
+            .extend_pairs(form_urlencoded::parse(fragment.as_bytes()));
+    }
+    query_params(&uri)
+}
+
+/// What the authorisation server sent to the redirect URI.
+pub enum Grant {
+    /// An authorisation code, to be exchanged for a token.
+    Code(String),
+    /// The token itself, sent by the implicit flow.
+    Token(TokenResponse),
+}
+
+/// Extract `act`'s grant from the redirect's query `params`, or return a human readable
+/// description of what's missing.
+pub fn grant(act: &Account, params: &HashMap<String, String>) -> Result<Grant, String> {
+    match act.response_type {
+        ResponseType::Code => match params.get("code") {
+            Some(x) => Ok(Grant::Code(x.to_owned())),
+            None => Err("No 'code'".to_owned()),
+        },
+        ResponseType::Token => {
+            let r = TokenResponse::from_pairs(params)?;
+            match r.access_token {
+                Some(_) => Ok(Grant::Token(r)),
+                None => Err("No 'access_token'".to_owned()),
+            }
+        }
+    }
+}
+
+/// What's needed to turn a grant into a token.
+struct Exchange {
+    act_id: CTGuardAccountId,
+    act: Arc<Account>,
+    transport_desc: String,
+    client_id: String,
+    client_secret: SecretString,
+    redirect_uri: String,
+    grant: Grant,
+    nonce: Option<String>,
+}
+
+/// Prepare to turn `grant` into a token for `act_id`, whose tokenstate must be
+/// [TokenState::Pending]. The tokenstate is moved to [TokenState::Exchanging], so that a replayed
+/// request with the same state can no longer match this account.
+fn start_exchange(
+    pstate: &AuthenticatorState,
+    ct_lk: &mut CTGuard,
+    act_id: CTGuardAccountId,
+    grant: Grant,
+) -> Result<Exchange, Box<dyn Error>> {
+    let nonce = match ct_lk.tokenstate(&act_id) {
+        TokenState::Pending { nonce, .. } => nonce.clone(),
+        _ => unreachable!(),
+    };
+    let act = Arc::clone(&ct_lk.config().accounts[&ct_lk.account(&act_id).name]);
+    let transport_desc = act.transport_desc(&act.token_uri);
     let client_id = act.client_id.clone();
     let client_secret = act.client_secret.clone();
     let redirect_uri = act.redirect_uri(pstate.http_port)?.to_string();
+    let act_id = ct_lk.tokenstate_replace(act_id, TokenState::Exchanging);
+    Ok(Exchange {
+        act_id,
+        act,
+        transport_desc,
+        client_id,
+        client_secret,
+        redirect_uri,
+        grant,
+        nonce,
+    })
+}
+
+/// Exchange an authorisation code for a token or, for the implicit flow, store the token we were
+/// sent. The lock must not be held when calling this function.
+fn exchange_code(pstate: Arc<AuthenticatorState>, ex: Exchange) -> Result<(), Box<dyn Error>> {
+    // If the server shuts down while the exchange is in flight, the code could not be used again.
+    let _inflight = pstate.inflight();
+    let Exchange {
+        act_id,
+        act,
+        transport_desc,
+        client_id,
+        client_secret,
+        redirect_uri,
+        grant,
+        nonce,
+    } = ex;
+    let code = match grant {
+        Grant::Code(x) => x,
+        Grant::Token(parsed) => return store_token(pstate, act_id, &act, parsed, nonce),
+    };
     let pairs = [
         ("code", code.as_str()),
         ("client_id", client_id.as_str()),
-        ("client_secret", client_secret.as_str()),
+        ("client_secret", client_secret.expose()),
         ("redirect_uri", redirect_uri.as_str()),
         ("grant_type", "authorization_code"),
     ];
 
-    // At this point we know we've got a sensible looking query, so we complete the HTTP request,
-    // because we don't know how long we'll spend going through the rest of the OAuth process, and
-    // we can notify the user another way than through their web browser.
-    drop(ct_lk);
-    http_200(
-        stream,
-        "pizauth processing authentication: you can safely close this page.",
-    );
-
-    // Try moderately hard to deal with temporary network errors and the like, but assume that any
-    // request that partially makes a connection but does not then fully succeed is an error (since
-    // we can't reuse authentication codes), and we'll have to start again entirely.
-    let mut body = None;
-    for _ in 0..RETRY_POST {
-        match ureq::post(token_uri.as_str()).send_form(&pairs) {
-            Ok(response) => match response.into_string() {
-                Ok(s) => {
-                    body = Some(s);
-                    break;
-                }
-                Err(e) => {
-                    fail(pstate, act_id, &e.to_string())?;
+    // Authorisation codes are short-lived and can only be used once, so we retry briefly on
+    // transient errors (e.g. a network blip), but give up immediately on anything else.
+    let start = Instant::now();
+    let mut attempt = 1;
+    let (content_type, body) = loop {
+        match make_token_request(&act, &pairs) {
+            Ok(response) => match (response.content_type().to_owned(), response.into_string()) {
+                // The body contains secrets.
+                (content_type, Ok(s)) => break (content_type, SecretString::from(s)),
+                (_, Err(e)) => {
+                    fail(
+                        pstate,
+                        act_id,
+                        ErrorKind::TokenEndpointRejected,
+                        &format!("{e:}{transport_desc:}"),
+                    )?;
                     return Ok(());
                 }
             },
+            Err(e)
+                if is_transient(&e)
+                    && attempt < RETRY_POST
+                    && start.elapsed() < Duration::from_secs(RETRY_BUDGET) =>
+            {
+                thread::sleep(Duration::from_secs(RETRY_DELAY * u64::from(attempt)));
+                attempt += 1;
+            }
             Err(ureq::Error::Status(code, response)) => {
                 let reason = match response.into_string() {
                     Ok(r) => format!("{code:}: {r:}"),
                     Err(_) => format!("{code:}"),
                 };
-                fail(pstate, act_id, &reason)?;
+                fail(pstate, act_id, ErrorKind::TokenEndpointRejected, &reason)?;
+                return Ok(());
+            }
+            Err(e) => {
+                let msg = format!(
+                    "couldn't connect to {}: {e:}{transport_desc:}",
+                    act.token_uri
+                );
+                fail(pstate, act_id, ErrorKind::TokenEndpointRejected, &msg)?;
                 return Ok(());
             }
-            Err(_) => (), // Temporary network error or the like
         }
-        thread::sleep(Duration::from_secs(RETRY_DELAY));
-    }
-    let parsed = match body {
-        Some(x) => json::parse(&x)?,
-        None => {
-            fail(pstate, act_id, &format!("couldn't connect to {token_uri:}"))?;
+    };
+    let parsed = match TokenResponse::parse(&content_type, &body) {
+        Ok(x) => x,
+        Err(e) => {
+            fail(pstate, act_id, ErrorKind::TokenEndpointRejected, &e)?;
             return Ok(());
         }
     };
+    store_token(pstate, act_id, &act, parsed, nonce)
+}
 
+/// Make the token in `parsed` `act_id`'s active token, or fail if `parsed` doesn't contain a
+/// valid token. The lock must not be held when calling this function.
+fn store_token(
+    pstate: Arc<AuthenticatorState>,
+    act_id: CTGuardAccountId,
+    act: &Arc<Account>,
+    parsed: TokenResponse,
+    nonce: Option<String>,
+) -> Result<(), Box<dyn Error>> {
     let mut ct_lk = pstate.ct_lock();
     let act_id = match ct_lk.validate_act_id(act_id) {
         Some(x) => x,
         None => return Ok(()),
     };
 
-    if let Some(err_msg) = parsed["error"].as_str() {
+    if let Some(err_msg) = parsed.error.as_deref() {
         drop(ct_lk);
-        fail(pstate, act_id, err_msg)?;
+        fail(pstate, act_id, ErrorKind::TokenEndpointRejected, err_msg)?;
         return Ok(());
     }
 
-    match (
-        parsed["token_type"].as_str(),
-        parsed["expires_in"].as_u64(),
-        parsed["access_token"].as_str(),
-        parsed["refresh_token"].as_str(),
-    ) {
-        (Some(token_type), Some(expires_in), Some(access_token), refresh_token)
-            if token_type == "Bearer" =>
-        {
-            let refreshed_at = Instant::now();
-            let expiry = match refreshed_at.checked_add(Duration::from_secs(expires_in)) {
+    let is_bearer = parsed.is_bearer();
+    let lifetime = parsed.lifetime(act.default_token_lifetime);
+    match parsed.access_token {
+        Some(access_token) if is_bearer => {
+            let id_token = parsed.id_token;
+            if let Some(nonce) = nonce {
+                // We don't verify the ID token's signature, but we do check that it was created in
+                // response to the request we made.
+                match id_token.as_ref().map(|x| id_token_nonce(x.expose())) {
+                    Some(Ok(Some(x))) if x == nonce => (),
+                    _ => {
+                        drop(ct_lk);
+                        fail(
+                            pstate,
+                            act_id,
+                            ErrorKind::TokenEndpointRejected,
+                            "ID token missing or has incorrect nonce",
+                        )?;
+                        return Ok(());
+                    }
+                }
+            }
+            let refreshed_at = pstate.clock.now();
+            let expiry = match pstate.clock.wall_now().checked_add(lifetime) {
                 Some(x) => x,
-                None => return Err("Can't represent expiry".into()),
+                None => {
+                    drop(ct_lk);
+                    fail(
+                        pstate,
+                        act_id,
+                        ErrorKind::Internal,
+                        "Can't represent expiry",
+                    )?;
+                    return Ok(());
+                }
             };
+            let userinfo_token = act
+                .userinfo_endpoint
+                .is_some()
+                .then(|| access_token.clone());
             let act_id = ct_lk.tokenstate_replace(
                 act_id,
                 TokenState::Active {
-                    access_token: access_token.to_owned(),
+                    access_token,
                     expiry,
                     refreshed_at,
                     last_refresh_attempt: None,
-                    refresh_token: refresh_token.map(|x| x.to_owned()),
+                    consecutive_refresh_failures: 0,
+                    id_token,
+                    refresh_token: parsed.refresh_token,
+                    display_name: None,
                 },
             );
+            ct_lk.clear_last_error(&act_id);
+            ct_lk.metrics_mut(&act_id).authentications += 1;
             let act_name = ct_lk.account(&act_id).name.clone();
             drop(ct_lk);
+            pstate.notifier.reset_errors(&act_name);
+            if let Some(x) = userinfo_token {
+                userinfo::fetch_display_name(Arc::clone(&pstate), Arc::clone(act), x);
+            }
             pstate.frontend.notify_success(act_name)?;
             pstate.refresher.notify_changes();
         }
         _ => {
             drop(ct_lk);
-            fail(pstate, act_id, "invalid response received")?;
+            fail(
+                pstate,
+                act_id,
+                ErrorKind::TokenEndpointRejected,
+                "invalid response received",
+            )?;
         }
     }
     Ok(())
 }
 
-/// If a request to an OAuth server has failed then notify the user of that failure and mark the
-/// tokenstate as [TokenState::Empty] unless the config has changed or the user has initiated a new
-/// request while we've been trying (unsuccessfully) with the OAuth server.
+/// If a request to an OAuth server has failed then notify the user of that failure (of kind `kind`)
+/// and mark the tokenstate as [TokenState::Empty] unless the config has changed or the user has
+/// initiated a new request while we've been trying (unsuccessfully) with the OAuth server.
 fn fail(
     pstate: Arc<AuthenticatorState>,
     act_id: CTGuardAccountId,
+    kind: ErrorKind,
     msg: &str,
 ) -> Result<(), Box<dyn Error>> {
     let mut ct_lk = pstate.ct_lock();
     if let Some(act_id) = ct_lk.validate_act_id(act_id) {
         let act_id = ct_lk.tokenstate_replace(act_id, TokenState::Empty);
+        ct_lk.set_last_error(&act_id, format!("Authentication failed: {msg:}"));
         let act_name = ct_lk.account(&act_id).name.clone();
         let msg = format!(
             "Authentication for {} failed: {msg:}",
             ct_lk.account(&act_id).name
         );
         drop(ct_lk);
-        pstate.frontend.notify_error(act_name, &msg)?;
+        pstate
+            .notifier
+            .notify_error(&pstate, Some(act_name), kind, &msg)?;
     }
     Ok(())
 }
 
-/// A very literal, and rather unforgiving, implementation of RFC2616 (HTTP/1.1), returning the URL
-/// of GET requests: returns `Err` for anything else.
-fn parse_get(stream: &mut TcpStream) -> Result<Url, Box<dyn Error>> {
-    let mut rdr = BufReader::new(stream);
+/// Decode the query of `uri` (including `+` and percent-encoding) into a map. If a key is repeated
+/// with the same value, the repeats are ignored; if it is repeated with different values, an error
+/// is returned, since we can't tell which value was intended.
+fn query_params(uri: &Url) -> Result<HashMap<String, String>, String> {
+    let mut params = HashMap::new();
+    for (k, v) in uri.query_pairs() {
+        match params.get(k.as_ref()) {
+            Some(x) if x == &v => (),
+            Some(_) => return Err(format!("Contradictory values for '{k:}' in request")),
+            None => {
+                params.insert(k.into_owned(), v.into_owned());
+            }
+        }
+    }
+    Ok(params)
+}
+
+/// If the redirect's query `params` say that authentication failed, return why. Providers report
+/// this with an `error` code (RFC 6749 section 4.1.2.1) and, optionally, a human readable
+/// `error_description`.
+pub fn error_reason(params: &HashMap<String, String>) -> Option<String> {
+    let error = params.get("error")?;
+    Some(match params.get("error_description") {
+        Some(desc) => format!("{error:}: {desc:}"),
+        None => error.to_owned(),
+    })
+}
+
+/// Return the `nonce` claim, if there is one, from the JWT `id_token`. Note that this does not
+/// verify the JWT's signature.
+pub fn id_token_nonce(id_token: &str) -> Result<Option<String>, Box<dyn Error>> {
+    let payload = id_token.split('.').nth(1).ok_or("Malformed ID token")?;
+    let parsed = json::parse(&String::from_utf8(base64url_decode(payload)?)?)?;
+    Ok(parsed["nonce"].as_str().map(|x| x.to_owned()))
+}
+
+/// Decode the base64url (RFC4648 section 5) encoded string `s`. Padding is optional.
+fn base64url_decode(s: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut out = Vec::with_capacity(s.len() * 3 / 4);
+    let mut buf = 0u32;
+    let mut bits = 0;
+    for c in s.bytes() {
+        let v = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'-' => 62,
+            b'_' => 63,
+            b'=' => break,
+            _ => return Err("Invalid base64url character".into()),
+        };
+        buf = (buf << 6) | u32::from(v);
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buf >> bits) as u8);
+            buf &= (1 << bits) - 1;
+        }
+    }
+    Ok(out)
+}
+
+/// The HTTP methods we support.
+enum Method {
+    Get,
+    Head,
+}
+
+/// A very literal, and rather unforgiving, implementation of RFC2616 (HTTP/1.1), returning the
+/// method and URL of GET and HEAD requests: returns `Err` for anything else, including requests
+/// longer than [MAX_REQUEST_LEN].
+fn parse_request(stream: &mut TcpStream) -> Result<(Method, Url), Box<dyn Error>> {
+    let mut rdr = BufReader::new(stream.take(MAX_REQUEST_LEN));
     let mut req_line = String::new();
-    rdr.read_line(&mut req_line)?;
+    if rdr.read_line(&mut req_line)? == 0 {
+        return Err("Empty HTTP request".into());
+    }
 
     // First the request line:
     //   Request-Line   = Method SP Request-URI SP HTTP-Version CRLF
-    // where Method = "GET" and `SP` is a single space character.
+    // where Method = "GET" | "HEAD" and `SP` is a single space character.
     let req_line_sp = req_line.split(' ').collect::<Vec<_>>();
-    if !matches!(req_line_sp.as_slice(), &["GET", _, _]) {
-        return Err("Malformed HTTP request".into());
-    }
+    let method = match *req_line_sp.as_slice() {
+        ["GET", _, _] => Method::Get,
+        ["HEAD", _, _] => Method::Head,
+        _ => return Err("Malformed HTTP request".into()),
+    };
     let path = req_line_sp[1];
 
     // Consume rest of HTTP request
     let mut req: Vec<String> = Vec::new();
     loop {
         let mut line = String::new();
-        rdr.read_line(&mut line)?;
+        // If we hit the end of the stream before the blank line that ends the header, the client
+        // either gave up or sent more than `MAX_REQUEST_LEN` bytes.
+        if rdr.read_line(&mut line)? == 0 {
+            return Err("Truncated or over-long HTTP request".into());
+        }
         if line.as_str().trim().is_empty() {
             break;
         }
@@ -291,45 +718,571 @@ fn parse_get(stream: &mut TcpStream) -> Result<Url, Box<dyn Error>> {
 
     match host {
         Some(h) => Url::parse(&format!("http://{h:}{path:}"))
+            .map(|x| (method, x))
             .map_err(|e| format!("Invalid request URI: {e:}").into()),
         None => Err("No host field specified in HTTP request".into()),
     }
 }
 
-fn http_200(mut stream: TcpStream, body: &str) {
+/// Return the success page for `act_name`.
+fn success_page(conf: &Config, act_name: &str) -> String {
+    let template = conf.http_success_page.as_deref().unwrap_or(SUCCESS_PAGE);
+    render_page(template, act_name, "")
+}
+
+/// Return the error page for `act_name` (which may be empty if the request couldn't be matched to
+/// an account) describing `error`.
+fn error_page(conf: &Config, act_name: &str, error: &str) -> String {
+    let template = conf.http_error_page.as_deref().unwrap_or(ERROR_PAGE);
+    render_page(template, act_name, error)
+}
+
+/// Replace the `{account}` and `{error}` placeholders in `template` with HTML escaped versions of
+/// `act_name` and `error` respectively. Other text, including unknown placeholders, is left as-is.
+fn render_page(template: &str, act_name: &str, error: &str) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(i) = rest.find('{') {
+        out.push_str(&rest[..i]);
+        rest = &rest[i..];
+        if let Some(r) = rest.strip_prefix("{account}") {
+            out.push_str(&html_escape(act_name));
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix("{error}") {
+            out.push_str(&html_escape(error));
+            rest = r;
+        } else {
+            out.push('{');
+            rest = &rest['{'.len_utf8()..];
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+fn html_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// Send an HTML page `body` with HTTP status `status` (e.g. "200 OK"). The page must not be cached
+/// since it is specific to a single authentication attempt.
+fn http_html(mut stream: TcpStream, status: &str, body: &str) {
+    let hdrs = [
+        "Content-Type: text/html; charset=utf-8".to_owned(),
+        format!("Content-Length: {}", body.len()),
+        "Cache-Control: no-store".to_owned(),
+        "Connection: close".to_owned(),
+    ];
+    let resp = format!("HTTP/1.1 {status:}\r\n{}\r\n\r\n{body:}", hdrs.join("\r\n"));
+    stream.write_all(resp.as_bytes()).ok();
+}
+
+/// Respond to a HEAD request.
+fn http_head(mut stream: TcpStream) {
     stream
-        .write_all(
-            format!("HTTP/1.1 200 OK\r\n\r\n<html><body><h2>{body}</h2></body></html>").as_bytes(),
-        )
+        .write_all(b"HTTP/1.1 200 OK\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n")
         .ok();
 }
 
 fn http_404(mut stream: TcpStream) {
-    stream.write_all(b"HTTP/1.1 404").ok();
+    stream
+        .write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+        .ok();
 }
 
 fn http_400(mut stream: TcpStream) {
-    stream.write_all(b"HTTP/1.1 400").ok();
+    stream
+        .write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+        .ok();
+}
+
+/// Create listeners on IPv4 loopback and (if `conf.http_ipv6` is true) IPv6 loopback, both using
+/// the same port: `port` if it is `Some`, or an arbitrary free port otherwise. Browsers differ in
+/// which address they try first for `localhost`, so we want to be reachable on both, but we fall
+/// back to listening on only one if the other is unavailable and no account's `redirect_uri`
+/// specifically requires it.
+pub fn http_server_setup(
+    conf: &Config,
+    port: Option<u16>,
+) -> Result<(u16, Vec<TcpListener>), Box<dyn Error>> {
+    if !conf.http_ipv6 {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port.unwrap_or(0)))
+            .map_err(|e| format!("Can't listen on IPv4 loopback: {e:}"))?;
+        return Ok((listener.local_addr()?.port(), vec![listener]));
+    }
+
+    for _ in 0..BIND_ATTEMPTS {
+        let v4 = TcpListener::bind((Ipv4Addr::LOCALHOST, port.unwrap_or(0)));
+        let v6_port = match (&v4, port) {
+            (Ok(l), _) => l.local_addr()?.port(),
+            (Err(_), Some(p)) => p,
+            (Err(_), None) => 0,
+        };
+        match (v4, TcpListener::bind((Ipv6Addr::LOCALHOST, v6_port))) {
+            (Ok(v4), Ok(v6)) => return Ok((v6_port, vec![v4, v6])),
+            // The port we were given on IPv4 loopback is in use on IPv6 loopback: try again.
+            (Ok(_), Err(e)) if e.kind() == io::ErrorKind::AddrInUse && port.is_none() => (),
+            (Ok(v4), Err(e)) => {
+                check_no_account_requires(conf, true, &e)?;
+                warn!("Listening only on IPv4 loopback: {e:}");
+                return Ok((v6_port, vec![v4]));
+            }
+            (Err(e), Ok(v6)) => {
+                check_no_account_requires(conf, false, &e)?;
+                warn!("Listening only on IPv6 loopback: {e:}");
+                return Ok((v6.local_addr()?.port(), vec![v6]));
+            }
+            (Err(e4), Err(e6)) => {
+                return Err(format!(
+                    "Can't listen on IPv4 loopback ({e4:}) or IPv6 loopback ({e6:})"
+                )
+                .into())
+            }
+        }
+    }
+    Err("Can't find a port which is free on both IPv4 and IPv6 loopback".into())
 }
 
-pub fn http_server_setup() -> Result<(u16, TcpListener), Box<dyn Error>> {
-    let listener = TcpListener::bind("127.0.0.1:0")?;
-    Ok((listener.local_addr()?.port(), listener))
+/// If any account's `redirect_uri` has an IPv6 (if `ipv6` is true) or IPv4 (otherwise) address as
+/// its host, return an error explaining that we can't listen on that address because of `e`.
+fn check_no_account_requires(conf: &Config, ipv6: bool, e: &io::Error) -> Result<(), String> {
+    let mut act_names = conf
+        .accounts
+        .values()
+        .filter(|act| act.redirect_ip().is_some_and(|x| x.is_ipv6() == ipv6))
+        .map(|act| act.name.as_str())
+        .collect::<Vec<_>>();
+    if act_names.is_empty() {
+        return Ok(());
+    }
+    act_names.sort();
+    let family = if ipv6 { "IPv6" } else { "IPv4" };
+    Err(format!(
+        "Can't listen on {family:} loopback ({e:}), but the redirect_uri of {} requires it",
+        act_names.join(", ")
+    ))
 }
 
 pub fn http_server(
     pstate: Arc<AuthenticatorState>,
-    listener: TcpListener,
+    listeners: Vec<TcpListener>,
 ) -> Result<(), Box<dyn Error>> {
-    thread::spawn(move || {
-        for stream in listener.incoming().flatten() {
-            let pstate = Arc::clone(&pstate);
-            thread::spawn(|| {
-                if let Err(e) = request(pstate, stream) {
-                    warn!("{e:}");
+    for listener in listeners {
+        let pstate = Arc::clone(&pstate);
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let pstate = Arc::clone(&pstate);
+                thread::spawn(|| {
+                    if let Err(e) = request(pstate, stream) {
+                        warn!("{e:}");
+                    }
+                });
+            }
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::{
+        test_utils::{mock_pstate_with_port, CONF_STR},
+        STATE_LEN,
+    };
+
+    /// Send `req` to the HTTP server on `port`, returning its response.
+    fn send(port: u16, req: &[u8]) -> String {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream.write_all(req).unwrap();
+        let mut rtn = String::new();
+        stream.read_to_string(&mut rtn).unwrap();
+        rtn
+    }
+
+    /// Accept a single request on `listener` and respond to it with a valid token.
+    fn token_server(listener: TcpListener) {
+        let (stream, _) = listener.accept().unwrap();
+        let mut rdr = BufReader::new(stream);
+        let mut len = 0;
+        loop {
+            let mut line = String::new();
+            rdr.read_line(&mut line).unwrap();
+            if line.trim().is_empty() {
+                break;
+            }
+            if let Some((k, v)) = line.split_once(':') {
+                if k.eq_ignore_ascii_case("content-length") {
+                    len = v.trim().parse::<usize>().unwrap();
                 }
-            });
+            }
         }
-    });
-    Ok(())
+        let mut body = vec![0; len];
+        rdr.read_exact(&mut body).unwrap();
+        let token = r#"{"token_type": "Bearer", "expires_in": 3600, "access_token": "a"}"#;
+        let resp = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{token:}",
+            token.len()
+        );
+        rdr.into_inner().write_all(resp.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn test_listen_on_all_loopbacks() {
+        let conf = Config::from_str(CONF_STR).unwrap();
+        let (http_port, listeners) = http_server_setup(&conf, None).unwrap();
+        let addrs = listeners
+            .iter()
+            .map(|l| l.local_addr().unwrap())
+            .collect::<Vec<_>>();
+        assert!(addrs.iter().all(|x| x.port() == http_port));
+        let (pstate, _) = mock_pstate_with_port(CONF_STR, http_port);
+        http_server(Arc::new(pstate), listeners).unwrap();
+        for addr in addrs {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(b"GET /x HTTP/1.1\r\n\r\n").unwrap();
+            let mut rtn = String::new();
+            stream.read_to_string(&mut rtn).unwrap();
+            assert!(rtn.starts_with("HTTP/1.1 404"));
+        }
+
+        let conf = Config::from_str(&format!("http_ipv6 = false; {CONF_STR:}")).unwrap();
+        let (_, listeners) = http_server_setup(&conf, None).unwrap();
+        assert_eq!(listeners.len(), 1);
+        assert!(listeners[0].local_addr().unwrap().is_ipv4());
+    }
+
+    #[test]
+    fn test_listen_on_fixed_port() {
+        let conf = Config::from_str(CONF_STR).unwrap();
+        let (port, listeners) = http_server_setup(&conf, None).unwrap();
+        drop(listeners);
+        let (http_port, listeners) = http_server_setup(&conf, Some(port)).unwrap();
+        assert_eq!(http_port, port);
+        assert!(listeners
+            .iter()
+            .all(|l| l.local_addr().unwrap().port() == port));
+        // The port is now in use, so asking for it again must fail rather than pick another.
+        assert!(http_server_setup(&conf, Some(port)).is_err());
+    }
+
+    #[test]
+    fn test_bad_clients_dont_block_callbacks() {
+        let token_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let token_port = token_listener.local_addr().unwrap().port();
+        thread::spawn(move || token_server(token_listener));
+
+        let conf_str =
+            CONF_STR.replace("http://g.com", &format!("http://127.0.0.1:{token_port:}/"));
+        let (http_port, listeners) =
+            http_server_setup(&Config::from_str(&conf_str).unwrap(), None).unwrap();
+        let (pstate, _) = mock_pstate_with_port(&conf_str, http_port);
+        let pstate = Arc::new(pstate);
+        let state = [1; STATE_LEN];
+        {
+            let mut ct_lk = pstate.ct_lock();
+            let act_id = ct_lk.validate_act_name("x").unwrap();
+            ct_lk.tokenstate_replace(
+                act_id,
+                TokenState::Pending {
+                    last_notification: None,
+                    notification_count: 0,
+                    nonce: None,
+                    state,
+                    url: Url::parse("http://a.com/").unwrap(),
+                },
+            );
+        }
+        http_server(Arc::clone(&pstate), listeners).unwrap();
+
+        // A client which connects but never sends anything.
+        let _stalled = TcpStream::connect(("127.0.0.1", http_port)).unwrap();
+        assert!(send(http_port, b"\x00\xffgarbage\r\n\r\n").starts_with("HTTP/1.1 400"));
+        // The server may stop reading, and close the connection, before we've finished writing.
+        let mut long = TcpStream::connect(("127.0.0.1", http_port)).unwrap();
+        long.write_all(b"GET /").ok();
+        long.write_all(&vec![b'a'; MAX_REQUEST_LEN as usize]).ok();
+        assert!(send(
+            http_port,
+            b"GET /favicon.ico HTTP/1.1\r\nHost: f.com\r\n\r\n"
+        )
+        .starts_with("HTTP/1.1 404"));
+
+        let state_str = urlencoding::encode_binary(&state);
+        let callback =
+            format!("/?state={state_str:}&code=c HTTP/1.1\r\nHost: f.com:{http_port:}\r\n\r\n");
+        // HEAD requests mustn't consume the state.
+        let rtn = send(http_port, format!("HEAD {callback:}").as_bytes());
+        assert!(rtn.starts_with("HTTP/1.1 200"));
+        let rtn = send(http_port, format!("GET {callback:}").as_bytes());
+        assert!(rtn.starts_with("HTTP/1.1 200"));
+        assert!(rtn.contains("Cache-Control: no-store"));
+        assert!(rtn.contains("processing authentication for x"));
+
+        for _ in 0..100 {
+            let ct_lk = pstate.ct_lock();
+            let act_id = ct_lk.validate_act_name("x").unwrap();
+            if matches!(ct_lk.tokenstate(&act_id), TokenState::Active { .. }) {
+                return;
+            }
+            drop(ct_lk);
+            thread::sleep(Duration::from_millis(100));
+        }
+        panic!("Callback did not complete");
+    }
+
+    #[test]
+    fn test_complete() {
+        let token_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let token_port = token_listener.local_addr().unwrap().port();
+        thread::spawn(move || token_server(token_listener));
+
+        let conf_str =
+            CONF_STR.replace("http://g.com", &format!("http://127.0.0.1:{token_port:}/"));
+        let (pstate, _) = mock_pstate_with_port(&conf_str, 0);
+        let pstate = Arc::new(pstate);
+        let complete = |url: &str| complete(Arc::clone(&pstate), "x", url);
+        assert!(complete("http://f.com/?state=a&code=c")
+            .unwrap_err()
+            .starts_with("x has no pending authentication"));
+
+        let state = [3; STATE_LEN];
+        {
+            let mut ct_lk = pstate.ct_lock();
+            let act_id = ct_lk.validate_act_name("x").unwrap();
+            ct_lk.tokenstate_replace(
+                act_id,
+                TokenState::Pending {
+                    last_notification: None,
+                    notification_count: 0,
+                    nonce: None,
+                    state,
+                    url: Url::parse("http://a.com/").unwrap(),
+                },
+            );
+        }
+        let state_str = urlencoding::encode_binary(&state);
+        assert!(complete("f.com/?code=c")
+            .unwrap_err()
+            .starts_with("Invalid URL"));
+        assert!(complete("http://f.com/?code=c")
+            .unwrap_err()
+            .starts_with("No 'state' in URL"));
+        assert!(complete("http://f.com/?state=a&code=c")
+            .unwrap_err()
+            .starts_with("URL's state doesn't match"));
+        assert!(complete(&format!("http://f.com/?state={state_str:}"))
+            .unwrap_err()
+            .starts_with("No 'code' in URL"));
+        assert!(
+            complete(&format!("http://f.com/?state={state_str:}&state=b"))
+                .unwrap_err()
+                .starts_with("Contradictory values")
+        );
+
+        // None of the above should have affected the pending request.
+        complete(&format!("http://f.com/?state={state_str:}&code=c")).unwrap();
+        let ct_lk = pstate.ct_lock();
+        let act_id = ct_lk.validate_act_name("x").unwrap();
+        assert!(matches!(
+            ct_lk.tokenstate(&act_id),
+            TokenState::Active { .. }
+        ));
+    }
+
+    #[test]
+    fn test_base64url_decode() {
+        assert_eq!(base64url_decode("").unwrap(), b"");
+        assert_eq!(base64url_decode("aGVsbG8").unwrap(), b"hello");
+        assert_eq!(base64url_decode("aGVsbG8=").unwrap(), b"hello");
+        assert_eq!(base64url_decode("-_8").unwrap(), &[0xfb, 0xff]);
+        assert!(base64url_decode("a+b").is_err());
+    }
+
+    #[test]
+    fn test_query_params() {
+        let uri = Url::parse("http://a.com/?a=b+c&d=%26e%3D&a=b%20c&f").unwrap();
+        let params = query_params(&uri).unwrap();
+        assert_eq!(params.len(), 3);
+        assert_eq!(params["a"], "b c");
+        assert_eq!(params["d"], "&e=");
+        assert_eq!(params["f"], "");
+        let uri = Url::parse("http://a.com/?state=a&state=b").unwrap();
+        assert!(query_params(&uri).is_err());
+    }
+
+    #[test]
+    fn test_callback_validation() {
+        let conf_str = CONF_STR.replace("http://f.com", "http://f.com/cb?x=1");
+        let conf = Config::from_str(&conf_str).unwrap();
+        let (http_port, listeners) = http_server_setup(&conf, None).unwrap();
+        let (pstate, _) = mock_pstate_with_port(&conf_str, http_port);
+        let pstate = Arc::new(pstate);
+        let state = [2; STATE_LEN];
+        {
+            let mut ct_lk = pstate.ct_lock();
+            let act_id = ct_lk.validate_act_name("x").unwrap();
+            ct_lk.tokenstate_replace(
+                act_id,
+                TokenState::Pending {
+                    last_notification: None,
+                    notification_count: 0,
+                    nonce: None,
+                    state,
+                    url: Url::parse("http://a.com/").unwrap(),
+                },
+            );
+        }
+        http_server(Arc::clone(&pstate), listeners).unwrap();
+
+        let state_str = urlencoding::encode_binary(&state);
+        let get = |path: &str| {
+            send(
+                http_port,
+                format!("GET {path:} HTTP/1.1\r\nHost: f.com:{http_port:}\r\n\r\n").as_bytes(),
+            )
+        };
+        assert!(get("/favicon.ico").starts_with("HTTP/1.1 404"));
+        assert!(get("/cb?x=1&code=c").starts_with("HTTP/1.1 400"));
+        assert!(get(&format!("/cb?x=1&state={state_str:}&state=a")).starts_with("HTTP/1.1 400"));
+        assert!(get(&format!("/other?x=1&state={state_str:}&code=c")).starts_with("HTTP/1.1 404"));
+        assert!(get(&format!("/cb?state={state_str:}&code=c")).starts_with("HTTP/1.1 404"));
+        assert!(get(&format!("/cb?x=1&state={state_str:}")).starts_with("HTTP/1.1 400"));
+
+        // None of the above should have affected the pending request.
+        let rtn = get(&format!(
+            "/cb?x=1&state={state_str:}&error=access_denied&error_description=User+said+no%21"
+        ));
+        assert!(rtn.starts_with("HTTP/1.1 400"));
+        assert!(rtn.contains("access_denied: User said no!"));
+        let ct_lk = pstate.ct_lock();
+        let act_id = ct_lk.validate_act_name("x").unwrap();
+        assert!(matches!(ct_lk.tokenstate(&act_id), TokenState::Empty));
+        assert_eq!(
+            ct_lk.last_error(&act_id).unwrap().1,
+            "Authentication failed: access_denied: User said no!"
+        );
+    }
+
+    #[test]
+    fn test_implicit_flow() {
+        let conf_str = CONF_STR.replace(
+            "redirect_uri = \"http://f.com\";",
+            "redirect_uri = \"http://f.com/cb\"; response_type = \"token\";",
+        );
+        let conf = Config::from_str(&conf_str).unwrap();
+        let (http_port, listeners) = http_server_setup(&conf, None).unwrap();
+        let (pstate, _) = mock_pstate_with_port(&conf_str, http_port);
+        let pstate = Arc::new(pstate);
+        let set_pending = |state| {
+            let mut ct_lk = pstate.ct_lock();
+            let act_id = ct_lk.validate_act_name("x").unwrap();
+            ct_lk.tokenstate_replace(
+                act_id,
+                TokenState::Pending {
+                    last_notification: None,
+                    notification_count: 0,
+                    nonce: None,
+                    state,
+                    url: Url::parse("http://a.com/").unwrap(),
+                },
+            );
+        };
+        let access_token = || {
+            let ct_lk = pstate.ct_lock();
+            let act_id = ct_lk.validate_act_name("x").unwrap();
+            match ct_lk.tokenstate(&act_id) {
+                TokenState::Active {
+                    access_token,
+                    refresh_token: None,
+                    ..
+                } => Some(access_token.expose().to_owned()),
+                _ => None,
+            }
+        };
+        let state = [4; STATE_LEN];
+        set_pending(state);
+        http_server(Arc::clone(&pstate), listeners).unwrap();
+
+        let state_str = urlencoding::encode_binary(&state);
+        let get = |path: &str| {
+            send(
+                http_port,
+                format!("GET {path:} HTTP/1.1\r\nHost: f.com:{http_port:}\r\n\r\n").as_bytes(),
+            )
+        };
+        // The browser doesn't send the fragment, so we're asked for the bare redirect URI.
+        let rtn = get("/cb");
+        assert!(rtn.starts_with("HTTP/1.1 200"));
+        assert!(rtn.contains("window.location.hash"));
+        assert!(get("/favicon.ico").starts_with("HTTP/1.1 404"));
+        assert!(get(&format!("/cb?state={state_str:}&token_type=Bearer"))
+            .contains("No &#39;access_token&#39; in request"));
+
+        let rtn = get(&format!(
+            "/cb?state={state_str:}&access_token=a&token_type=Bearer&expires_in=60"
+        ));
+        assert!(rtn.starts_with("HTTP/1.1 200"));
+        for _ in 0..100 {
+            if access_token().is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        assert_eq!(access_token().as_deref(), Some("a"));
+
+        // Users completing authentication by hand paste the URL with the token in its fragment.
+        let state = [5; STATE_LEN];
+        set_pending(state);
+        let state_str = urlencoding::encode_binary(&state);
+        complete(
+            Arc::clone(&pstate),
+            "x",
+            &format!("http://f.com/cb#state={state_str:}&access_token=b&token_type=bearer"),
+        )
+        .unwrap();
+        assert_eq!(access_token().as_deref(), Some("b"));
+    }
+
+    #[test]
+    fn test_id_token_nonce() {
+        // Header and payload `{"nonce":"abc"}`; the signature is not checked.
+        assert_eq!(
+            id_token_nonce("eyJhbGciOiJub25lIn0.eyJub25jZSI6ImFiYyJ9.")
+                .unwrap()
+                .as_deref(),
+            Some("abc")
+        );
+        assert_eq!(id_token_nonce("eyJhbGciOiJub25lIn0.e30.").unwrap(), None);
+        assert!(id_token_nonce("blah").is_err());
+    }
+
+    #[test]
+    fn test_render_page() {
+        assert_eq!(render_page("", "x", "e"), "");
+        assert_eq!(
+            render_page("{account}: {error}", "x", "failed"),
+            "x: failed"
+        );
+        assert_eq!(
+            render_page("{{account}} {other} {", "x", ""),
+            "{x} {other} {"
+        );
+        // Substituted values must not be interpreted as HTML or as placeholders.
+        assert_eq!(
+            render_page("{account} {error}", "{error}", "<b>&'\"</b>"),
+            "{error} &lt;b&gt;&amp;&#39;&quot;&lt;/b&gt;"
+        );
+    }
 }
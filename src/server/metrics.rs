@@ -0,0 +1,219 @@
+//! Metrics about each account in the Prometheus text exposition format, so that `pizauth metrics`
+//! can be fed to e.g. node_exporter's textfile collector without pizauth listening on a TCP port.
+
+use std::{
+    fmt::Write,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use super::{state::AccountMetrics, AuthenticatorState, CTGuard, TokenState};
+
+/// The metrics of one account.
+struct Row {
+    act_name: String,
+    metrics: AccountMetrics,
+    /// Seconds since the active token (if there is one) was obtained.
+    token_age: Option<u64>,
+    /// Seconds until the active token (if there is one) expires.
+    expires_in: Option<u64>,
+    /// The Unix time of the last error (if there was one).
+    last_error: Option<u64>,
+}
+
+/// Each per-account metric's name, type, description, and value (if it has one) for an account.
+type Family = (
+    &'static str,
+    &'static str,
+    &'static str,
+    fn(&Row) -> Option<u64>,
+);
+const FAMILIES: [Family; 7] = [
+    (
+        "pizauth_refreshes_attempted_total",
+        "counter",
+        "Requests made to refresh the account's token.",
+        |r| Some(r.metrics.refreshes_attempted),
+    ),
+    (
+        "pizauth_refreshes_succeeded_total",
+        "counter",
+        "Requests to refresh the account's token which succeeded.",
+        |r| Some(r.metrics.refreshes_succeeded),
+    ),
+    (
+        "pizauth_refreshes_failed_total",
+        "counter",
+        "Requests to refresh the account's token which failed.",
+        |r| Some(r.metrics.refreshes_failed),
+    ),
+    (
+        "pizauth_authentications_total",
+        "counter",
+        "Authentications completed by the user.",
+        |r| Some(r.metrics.authentications),
+    ),
+    (
+        "pizauth_token_age_seconds",
+        "gauge",
+        "Seconds since the account's active token was obtained.",
+        |r| r.token_age,
+    ),
+    (
+        "pizauth_token_expires_in_seconds",
+        "gauge",
+        "Seconds until the account's active token expires.",
+        |r| r.expires_in,
+    ),
+    (
+        "pizauth_last_error_timestamp_seconds",
+        "gauge",
+        "Unix time of the account's most recent refresh or authentication error.",
+        |r| r.last_error,
+    ),
+];
+
+/// Return the metrics of all accounts.
+pub fn metrics(pstate: &AuthenticatorState) -> String {
+    let rows = rows(
+        &pstate.ct_lock(),
+        pstate.clock.now(),
+        pstate.clock.wall_now(),
+    );
+    let mut out = String::new();
+    family(
+        &mut out,
+        "pizauth_accounts_total",
+        "gauge",
+        "Number of accounts in the config.",
+    );
+    writeln!(out, "pizauth_accounts_total {}", rows.len()).unwrap();
+    for (name, kind, help, value) in FAMILIES {
+        family(&mut out, name, kind, help);
+        for r in &rows {
+            if let Some(x) = value(r) {
+                let act_name = escape_label(&r.act_name);
+                writeln!(out, "{name:}{{account=\"{act_name:}\"}} {x:}").unwrap();
+            }
+        }
+    }
+    out
+}
+
+/// Return the metrics of each account in `ct_lk`, sorted by account name.
+fn rows(ct_lk: &CTGuard, now: Instant, wall_now: SystemTime) -> Vec<Row> {
+    let mut rows = ct_lk
+        .act_ids()
+        .map(|act_id| {
+            let (token_age, expires_in) = match ct_lk.tokenstate(&act_id) {
+                TokenState::Active {
+                    refreshed_at,
+                    expiry,
+                    ..
+                } => (
+                    Some(now.saturating_duration_since(*refreshed_at).as_secs()),
+                    Some(
+                        expiry
+                            .duration_since(wall_now)
+                            .map(|d| d.as_secs())
+                            .unwrap_or(0),
+                    ),
+                ),
+                _ => (None, None),
+            };
+            // Errors are timestamped with an [Instant], which we convert to a wall-clock time.
+            let last_error = ct_lk.last_error(&act_id).and_then(|(t, _)| {
+                wall_now
+                    .checked_sub(now.saturating_duration_since(*t))?
+                    .duration_since(UNIX_EPOCH)
+                    .ok()
+                    .map(|d| d.as_secs())
+            });
+            Row {
+                act_name: ct_lk.account(&act_id).name.clone(),
+                metrics: ct_lk.metrics(&act_id).clone(),
+                token_age,
+                expires_in,
+                last_error,
+            }
+        })
+        .collect::<Vec<_>>();
+    rows.sort_by(|a, b| a.act_name.cmp(&b.act_name));
+    rows
+}
+
+/// Append the `HELP` and `TYPE` lines of the metric `name` to `out`.
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {name:} {help:}").unwrap();
+    writeln!(out, "# TYPE {name:} {kind:}").unwrap();
+}
+
+/// Escape `s` so that it can be used as a label value.
+fn escape_label(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    use crate::{
+        config::Config,
+        secret::SecretString,
+        server::{
+            clock::Clock,
+            test_utils::{act_conf, mock_pstate},
+        },
+    };
+
+    #[test]
+    fn test_escape_label() {
+        assert_eq!(escape_label("a"), "a");
+        assert_eq!(escape_label("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+
+    #[test]
+    fn test_metrics() {
+        let conf_str = format!("{}\n{}", act_conf("y", &[]), act_conf("x", &[]));
+        let (pstate, clock) = mock_pstate(&conf_str);
+        {
+            let mut ct_lk = pstate.ct_lock();
+            let act_id = ct_lk.validate_act_name("x").unwrap();
+            let act_id = ct_lk.tokenstate_replace(
+                act_id,
+                TokenState::Active {
+                    access_token: SecretString::from("a"),
+                    refreshed_at: clock.now(),
+                    last_refresh_attempt: None,
+                    consecutive_refresh_failures: 0,
+                    expiry: clock.wall_now() + Duration::from_secs(3600),
+                    id_token: None,
+                    refresh_token: None,
+                    display_name: None,
+                },
+            );
+            ct_lk.metrics_mut(&act_id).authentications += 1;
+            ct_lk.metrics_mut(&act_id).refreshes_attempted += 2;
+        }
+        clock.advance(Duration::from_secs(60));
+        let m = metrics(&pstate);
+        assert!(m.contains("\npizauth_accounts_total 2\n"));
+        // Accounts are listed in order.
+        assert!(m.contains(
+            "pizauth_refreshes_attempted_total{account=\"x\"} 2\n\
+             pizauth_refreshes_attempted_total{account=\"y\"} 0\n"
+        ));
+        assert!(m.contains("pizauth_authentications_total{account=\"x\"} 1\n"));
+        // Only active tokens have an age and expiry.
+        assert!(m.contains("pizauth_token_age_seconds{account=\"x\"} 60\n"));
+        assert!(!m.contains("pizauth_token_age_seconds{account=\"y\"}"));
+        assert!(m.contains("pizauth_token_expires_in_seconds{account=\"x\"} 3540\n"));
+        assert!(!m.contains("pizauth_last_error_timestamp_seconds{"));
+
+        // Metrics survive a reload which doesn't affect the account's token.
+        pstate.update_conf(Config::from_str(&conf_str).unwrap());
+        assert!(metrics(&pstate).contains("pizauth_authentications_total{account=\"x\"} 1\n"));
+    }
+}
@@ -0,0 +1,165 @@
+//! Importing refresh tokens from legacy formats when the server starts (`pizauth server
+//! --migrate-v1 <path>`). Two formats are understood: dumps created by `pizauth dump`; and the
+//! (decrypted) JSON token files created by `mutt_oauth2.py`, which are matched to the account whose
+//! `login_hint` or `sasl_user` is the token file's `email`.
+
+use std::{error::Error, ffi::OsString, fs, path::Path};
+
+use log::{info, warn};
+
+use super::{
+    dump::{parse_entries, restore_tokens, RestoreEntry, RestoreOutcome, DUMP_VERSION},
+    AuthenticatorState,
+};
+use crate::secret::SecretString;
+
+/// The suffix added to a legacy file once it has been migrated.
+const MIGRATED_SUFFIX: &str = ".migrated";
+
+/// Import the refresh tokens in the legacy file at `path`, logging what was imported and skipped,
+/// and then rename it to `<path>.migrated` so that it isn't imported again. If `path` has already
+/// been migrated, nothing is done.
+pub fn migrate(pstate: &AuthenticatorState, path: &Path) -> Result<(), Box<dyn Error>> {
+    let mut migrated = OsString::from(path);
+    migrated.push(MIGRATED_SUFFIX);
+    if !path.exists() && Path::new(&migrated).exists() {
+        info!("{} has already been migrated", path.display());
+        return Ok(());
+    }
+    let input = SecretString::from(
+        fs::read_to_string(path).map_err(|e| format!("Can't read {}: {e:}", path.display()))?,
+    );
+    let outcomes = migrate_str(pstate, input.expose())
+        .map_err(|e| format!("Can't migrate {}: {e:}", path.display()))?;
+    for (act_name, skipped) in outcomes {
+        match skipped {
+            Some(reason) => warn!("{act_name:}: not migrated ({reason:})"),
+            None => info!("{act_name:}: migrated"),
+        }
+    }
+    fs::rename(path, &migrated).map_err(|e| {
+        format!(
+            "Can't rename {} to {}: {e:}",
+            path.display(),
+            Path::new(&migrated).display()
+        )
+    })?;
+    Ok(())
+}
+
+/// Import the refresh tokens in `input`, which is in one of the legacy formats.
+fn migrate_str(
+    pstate: &AuthenticatorState,
+    input: &str,
+) -> Result<Vec<RestoreOutcome>, Box<dyn Error>> {
+    let mut lines = input
+        .lines()
+        .filter(|x| !x.starts_with('#') && !x.trim().is_empty());
+    if lines.next() == Some(DUMP_VERSION) {
+        let entries = lines.flat_map(|x| x.split(' ')).collect::<Vec<_>>();
+        return restore_tokens(pstate, parse_entries(&entries)?);
+    }
+
+    // A `mutt_oauth2.py` token file.
+    let parsed =
+        json::parse(input).map_err(|_| "Not a pizauth dump or mutt_oauth2.py token file")?;
+    let (email, refresh_token) = match (parsed["email"].as_str(), parsed["refresh_token"].as_str())
+    {
+        (Some(x), Some(y)) if !y.is_empty() => (x, SecretString::from(y)),
+        _ => return Err("Token file has no email or refresh token".into()),
+    };
+    let ct_lk = pstate.ct_lock();
+    let act_names = ct_lk
+        .act_ids()
+        .map(|act_id| ct_lk.account(&act_id))
+        .filter(|act| {
+            act.login_hint.as_deref() == Some(email) || act.sasl_user.as_deref() == Some(email)
+        })
+        .map(|act| act.name.clone())
+        .collect::<Vec<_>>();
+    drop(ct_lk);
+    match &act_names[..] {
+        [act_name] => restore_tokens(
+            pstate,
+            vec![RestoreEntry {
+                act_name: act_name.to_owned(),
+                fingerprint: None,
+                refresh_token,
+            }],
+        ),
+        [] => Ok(vec![(
+            email.to_owned(),
+            Some("no account has this login_hint or sasl_user"),
+        )]),
+        _ => Ok(vec![(
+            email.to_owned(),
+            Some("more than one account has this login_hint or sasl_user"),
+        )]),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::{dump::dump, test_utils::mock_pstate, TokenState};
+
+    #[test]
+    fn test_migrate_str() {
+        let conf_str = r#"
+            account "x" {
+                auth_uri = "http://a.com";
+                client_id = "b";
+                client_secret = "c";
+                scopes = ["d"];
+                redirect_uri = "http://f.com";
+                token_uri = "http://g.com";
+                login_hint = "x@example.com";
+            }
+            account "y" {
+                auth_uri = "http://a.com";
+                client_id = "b";
+                client_secret = "c";
+                scopes = ["d"];
+                redirect_uri = "http://f.com";
+                token_uri = "http://g.com";
+            }
+        "#;
+        let refresh_token = |pstate: &AuthenticatorState, act_name| {
+            let ct_lk = pstate.ct_lock();
+            let act_id = ct_lk.validate_act_name(act_name).unwrap();
+            match ct_lk.tokenstate(&act_id) {
+                TokenState::Active {
+                    refresh_token: Some(x),
+                    ..
+                } => Some(x.expose().to_owned()),
+                _ => None,
+            }
+        };
+
+        let (pstate, _) = mock_pstate(conf_str);
+        let mutt = r#"{"registration": "google", "email": "x@example.com", "refresh_token": "r"}"#;
+        assert_eq!(
+            migrate_str(&pstate, mutt).unwrap(),
+            vec![("x".to_owned(), None)]
+        );
+        assert_eq!(refresh_token(&pstate, "x").as_deref(), Some("r"));
+        let mutt = r#"{"email": "z@example.com", "refresh_token": "r"}"#;
+        assert_eq!(
+            migrate_str(&pstate, mutt).unwrap()[0].0,
+            "z@example.com".to_owned()
+        );
+        assert!(migrate_str(&pstate, r#"{"email": "x@example.com"}"#).is_err());
+        assert!(migrate_str(&pstate, "blah").is_err());
+
+        // A dump from one server can be migrated into another.
+        let d = dump(&pstate);
+        let (pstate2, _) = mock_pstate(conf_str);
+        assert_eq!(
+            migrate_str(&pstate2, d.expose()).unwrap(),
+            vec![("x".to_owned(), None)]
+        );
+        assert_eq!(refresh_token(&pstate2, "x").as_deref(), Some("r"));
+        assert_eq!(refresh_token(&pstate2, "y"), None);
+        assert!(migrate_str(&pstate2, &format!("{DUMP_VERSION:}\nx y")).is_err());
+    }
+}
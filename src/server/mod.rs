@@ -1,31 +1,75 @@
+mod audit;
+mod clock;
+mod diagnose;
+mod dump;
 mod http_server;
+mod metrics;
+mod migrate;
 mod notifier;
+mod one_shot;
 mod refresher;
 mod request_token;
+mod restart;
 mod state;
+mod test_auth;
+#[cfg(test)]
+pub(crate) mod test_utils;
+mod token_response;
+mod userinfo;
 
 use std::{
+    cmp,
     error::Error,
-    fs,
-    io::{Read, Write},
+    fs::{self, File},
+    io::{self, Write},
     os::unix::net::{UnixListener, UnixStream},
     path::{Path, PathBuf},
-    sync::Arc,
+    process,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
     thread,
-    time::Instant,
+    time::Duration,
 };
 
-use log::warn;
-use nix::sys::signal::{raise, Signal};
+use json::JsonValue;
+use log::{error, info, warn};
+use nix::{
+    sys::signal::{kill, SigSet, Signal},
+    unistd::getpid,
+};
+use url::form_urlencoded;
 
-use crate::{config::Config, frontends::preferred_frontend, PIZAUTH_CACHE_SOCK_LEAF};
+use crate::{
+    config::{Account, AuthFlow, Config, ScopesEncode, TokenUriMethod},
+    frontends::{preferred_frontend, ErrorKind},
+    ipc::{decode_request, read_frame, write_frame, VersionMismatch, PROTOCOL_VERSION},
+    secret::SecretString,
+    DAEMON_READY, PIZAUTH_CACHE_PID_LEAF, PIZAUTH_CACHE_SOCK_LEAF,
+};
+use clock::SystemClock;
 use notifier::Notifier;
 use refresher::{RefreshKind, Refresher};
 use request_token::request_token;
 use state::{AuthenticatorState, CTGuard, CTGuardAccountId, TokenState};
 
-/// Length of the OAuth state in bytes.
-const STATE_LEN: usize = 8;
+pub use diagnose::validate_accounts;
+pub use dump::DUMP_VERSION;
+pub use one_shot::OneShot;
+pub use test_auth::test_auth;
+
+/// Length of the OAuth state in bytes. This must give at least the 128 bits of entropy that
+/// security guidelines recommend: since there's no reason for users to weaken that, it isn't
+/// configurable.
+const STATE_LEN: usize = 16;
+/// The maximum number of client connections handled at once.
+const MAX_CONNECTIONS: usize = 64;
+/// How many seconds can a client take to send a request, or to read our reply, before we close the
+/// connection?
+const CONNECTION_TIMEOUT: u64 = 10;
+/// How long does shutting down wait for queued audit log entries to be written?
+const AUDIT_FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
 
 pub fn sock_path(cache_path: &Path) -> PathBuf {
     let mut p = cache_path.to_owned();
@@ -33,140 +77,1925 @@ pub fn sock_path(cache_path: &Path) -> PathBuf {
     p
 }
 
+fn pid_path(cache_path: &Path) -> PathBuf {
+    let mut p = cache_path.to_owned();
+    p.push(PIZAUTH_CACHE_PID_LEAF);
+    p
+}
+
+/// Bind a new listening socket in `cache_path`. If the socket file already exists but nothing is
+/// listening on it (e.g. because a previous server crashed), it is removed first. If another
+/// server is listening on it, an error naming that server's PID (if known) is returned.
+pub fn bind_socket(cache_path: &Path) -> Result<UnixListener, Box<dyn Error>> {
+    let sock_path = sock_path(cache_path);
+    if sock_path.exists() {
+        match UnixStream::connect(&sock_path) {
+            Ok(_) => {
+                let pid = fs::read_to_string(pid_path(cache_path))
+                    .ok()
+                    .and_then(|x| x.trim().parse::<u32>().ok())
+                    .map(|x| format!(" (PID {x:})"))
+                    .unwrap_or_default();
+                return Err(format!(
+                    "Another pizauth server{pid:} is already running (socket {})",
+                    sock_path.display()
+                )
+                .into());
+            }
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                fs::remove_file(&sock_path).map_err(|e| {
+                    format!("Can't remove stale socket {}: {e:}", sock_path.display())
+                })?;
+            }
+            Err(e) => return Err(format!("Can't connect to {}: {e:}", sock_path.display()).into()),
+        }
+    }
+    Ok(UnixListener::bind(sock_path)?)
+}
+
+/// Return the listening socket passed to us via systemd-style socket activation (see
+/// `sd_listen_fds(3)`). This must be called before pizauth forks, since the passed socket is
+/// only meant for the process whose PID is in `$LISTEN_PID`.
+#[cfg(feature = "socket_activation")]
+pub fn activated_listener() -> Result<UnixListener, Box<dyn Error>> {
+    use std::{env, os::unix::io::FromRawFd};
+
+    use nix::{
+        fcntl::{fcntl, FcntlArg, FdFlag},
+        unistd::getpid,
+    };
+
+    /// The first file descriptor passed by socket activation.
+    const SD_LISTEN_FDS_START: i32 = 3;
+
+    let pid = env::var("LISTEN_PID")
+        .map_err(|_| "$LISTEN_PID not set")?
+        .parse::<i32>()
+        .map_err(|_| "$LISTEN_PID is not a valid PID")?;
+    if pid != getpid().as_raw() {
+        return Err("$LISTEN_PID does not match pizauth's PID".into());
+    }
+    let fds = env::var("LISTEN_FDS")
+        .map_err(|_| "$LISTEN_FDS not set")?
+        .parse::<i32>()
+        .map_err(|_| "$LISTEN_FDS is not a valid integer")?;
+    if fds != 1 {
+        return Err(format!("Expected to be passed 1 socket but $LISTEN_FDS is {fds:}").into());
+    }
+    // Child processes must not think that the socket was meant for them.
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    fcntl(SD_LISTEN_FDS_START, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+    // SAFETY: `sd_listen_fds(3)` guarantees that this file descriptor is open and ours.
+    Ok(unsafe { UnixListener::from_raw_fd(SD_LISTEN_FDS_START) })
+}
+
+/// Is `e` transient, such that retrying the request might succeed? Connection-level errors, 429
+/// (rate limited) and 5xx responses are transient, but other 4xx responses never are: for
+/// example, retrying with an authorisation code which the server has already rejected can only
+/// make things worse.
+fn is_transient(e: &ureq::Error) -> bool {
+    match e {
+        ureq::Error::Status(code, _) => *code == 429 || (500..600).contains(code),
+        ureq::Error::Transport(_) => true,
+    }
+}
+
+/// Form encode `pairs` for a request for `act`. If the account's `scopes_encode` is `none`, the
+/// value of the `scope` parameter is included verbatim.
+fn encode_params(act: &Account, pairs: &[(&str, &str)]) -> String {
+    pairs
+        .iter()
+        .map(|(k, v)| {
+            if *k == "scope" && act.scopes_encode == ScopesEncode::None {
+                format!("scope={v:}")
+            } else {
+                form_urlencoded::Serializer::new(String::new())
+                    .append_pair(k, v)
+                    .finish()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Make a request to `act`'s token endpoint with the parameters `pairs`. RFC 6749 requires the
+/// parameters to be sent form encoded in a POST, but if the account's `token_uri_method` is `GET`
+/// they are sent in the query string instead. The lock must not be held when calling this
+/// function.
+#[allow(clippy::result_large_err)]
+fn make_token_request(
+    act: &Account,
+    pairs: &[(&str, &str)],
+) -> Result<ureq::Response, ureq::Error> {
+    let agent = act.agent(&act.token_uri);
+    let params = encode_params(act, pairs);
+    match act.token_uri_method {
+        TokenUriMethod::Post => agent
+            .post(&act.token_uri)
+            .set("Content-Type", "application/x-www-form-urlencoded")
+            .send_string(&params),
+        TokenUriMethod::Get => {
+            let sep = if act.token_uri.contains('?') {
+                '&'
+            } else {
+                '?'
+            };
+            agent
+                .get(&format!("{}{sep:}{params:}", act.token_uri))
+                .call()
+        }
+    }
+}
+
+/// Create the reply to a `showtoken` (or, if `want_id_token` is true, `showidtoken`) request for
+/// a token which expires in `expires_in`. The payload is the number of seconds until the token
+/// expires, a space, and then the token.
+fn token_reply(
+    want_id_token: bool,
+    access_token: &SecretString,
+    id_token: &Option<SecretString>,
+    expires_in: Duration,
+) -> SecretString {
+    let (kind, token) = if want_id_token {
+        match id_token {
+            Some(x) => ("id_token", x),
+            None => {
+                return SecretString::from("error:No ID token: is the 'openid' scope specified?")
+            }
+        }
+    } else {
+        ("access_token", access_token)
+    };
+    let expires_in = expires_in.as_secs().to_string();
+    let mut s = String::with_capacity(kind.len() + expires_in.len() + 2 + token.expose().len());
+    s.push_str(kind);
+    s.push(':');
+    s.push_str(&expires_in);
+    s.push(' ');
+    s.push_str(token.expose());
+    SecretString::from(s)
+}
+
+/// Discard `act_id`'s token. If there was an active token and the provider allows us to, we
+/// revoke the token so that it can't be used by anyone else: revoking a refresh token normally
+/// also revokes the access tokens derived from it. The lock is not held while revoking. If
+/// revoking fails, the token is still discarded, and a description of the failure is returned.
+fn forget_token(
+    pstate: &AuthenticatorState,
+    mut ct_lk: CTGuard,
+    act_id: CTGuardAccountId,
+) -> Result<(), String> {
+    let act = ct_lk.account(&act_id);
+    let revoke = match (&act.revoke_uri, ct_lk.tokenstate(&act_id)) {
+        (
+            Some(revoke_uri),
+            TokenState::Active {
+                access_token,
+                refresh_token,
+                ..
+            },
+        ) => {
+            let (token, hint) = match refresh_token {
+                Some(x) => (x.clone(), "refresh_token"),
+                None => (access_token.clone(), "access_token"),
+            };
+            Some((
+                act.agent(revoke_uri),
+                act.transport_desc(revoke_uri),
+                revoke_uri.clone(),
+                act.client_id.clone(),
+                act.client_secret.clone(),
+                token,
+                hint,
+            ))
+        }
+        _ => None,
+    };
+    ct_lk.tokenstate_replace(act_id, TokenState::Empty);
+    drop(ct_lk);
+    pstate.refresher.notify_changes();
+
+    if let Some((agent, transport_desc, revoke_uri, client_id, client_secret, token, hint)) = revoke
+    {
+        let pairs = [
+            ("client_id", client_id.as_str()),
+            ("client_secret", client_secret.expose()),
+            ("token", token.expose()),
+            ("token_type_hint", hint),
+        ];
+        match agent.post(revoke_uri.as_str()).send_form(&pairs) {
+            Ok(_) => (),
+            Err(e @ ureq::Error::Status(..)) => return Err(format!("revoking it failed: {e:}")),
+            Err(e) => return Err(format!("revoking it failed: {e:}{transport_desc:}")),
+        }
+    }
+    Ok(())
+}
+
+/// Reply to a `showtoken` (or, if `want_id_token` is true, `showidtoken`) request for `act_id`,
+/// whose [TokenState::Active] token expires within `min_validity`, by first refreshing the token.
+/// The lock is not held while refreshing. If the provider's tokens are never valid for as long as
+/// `min_validity`, an error saying so is sent instead, since refreshing can never satisfy the
+/// request. [AuthFlow::ClientCredentials] accounts can also be in any other tokenstate, in which
+/// case the grant is run to obtain a token.
+fn show_refreshed_token(
+    pstate: &AuthenticatorState,
+    stream: &mut UnixStream,
+    want_id_token: bool,
+    ct_lk: CTGuard,
+    act_id: CTGuardAccountId,
+    min_validity: Duration,
+) -> Result<(), Box<dyn Error>> {
+    let act_name = ct_lk.account(&act_id).name.clone();
+    let too_short = |lifetime: Duration| {
+        format!(
+            "error:Tokens for '{act_name:}' are valid for {}s, less than the minimum validity of {}s",
+            lifetime.as_secs(),
+            min_validity.as_secs()
+        )
+    };
+    let client_credentials = ct_lk.account(&act_id).auth_flow == AuthFlow::ClientCredentials;
+    let (lifetime, refreshable) = match ct_lk.tokenstate(&act_id) {
+        TokenState::Active {
+            expiry,
+            refreshed_at,
+            refresh_token,
+            ..
+        } => (
+            Some(
+                expiry
+                    .duration_since(pstate.clock.wall_now())
+                    .unwrap_or(Duration::ZERO)
+                    + pstate.clock.now().saturating_duration_since(*refreshed_at),
+            ),
+            refresh_token.is_some() || client_credentials,
+        ),
+        _ if client_credentials => (None, true),
+        _ => unreachable!(),
+    };
+    if let Some(lifetime) = lifetime.filter(|x| *x < min_validity) {
+        drop(ct_lk);
+        write_frame(stream, too_short(lifetime).as_bytes())?;
+        return Ok(());
+    }
+    if !refreshable {
+        drop(ct_lk);
+        write_frame(
+            stream,
+            format!("error:Token for '{act_name:}' expires too soon and can't be refreshed")
+                .as_bytes(),
+        )?;
+        return Ok(());
+    }
+
+    let rk = pstate.refresher.refresh(pstate, ct_lk, act_id);
+    // Even a failed refresh changes when the refresher next needs to wake up.
+    pstate.refresher.notify_changes();
+    match rk? {
+        RefreshKind::Refreshed => (),
+        RefreshKind::AccountOrTokenStateChanged => {
+            write_frame(stream, b"pending:")?;
+            return Ok(());
+        }
+        RefreshKind::PermanentError(_, msg) if client_credentials => {
+            write_frame(stream, format!("error:{msg:}").as_bytes())?;
+            return Ok(());
+        }
+        RefreshKind::PermanentError(_, msg) => {
+            write_frame(stream, format!("error:refresh failed: {msg:}").as_bytes())?;
+            return Ok(());
+        }
+        RefreshKind::TransitoryError(msg) => {
+            write_frame(stream, format!("error:{msg:}").as_bytes())?;
+            return Ok(());
+        }
+    }
+
+    let ct_lk = pstate.ct_lock();
+    let act_id = match ct_lk.validate_act_name(&act_name) {
+        Some(x) => x,
+        None => {
+            drop(ct_lk);
+            write_frame(stream, b"no_account:")?;
+            return Ok(());
+        }
+    };
+    let response = match ct_lk.tokenstate(&act_id) {
+        TokenState::Active {
+            access_token,
+            expiry,
+            id_token,
+            ..
+        } => {
+            let lifetime = expiry
+                .duration_since(pstate.clock.wall_now())
+                .unwrap_or(Duration::ZERO);
+            if lifetime < min_validity {
+                SecretString::from(too_short(lifetime))
+            } else {
+                token_reply(want_id_token, access_token, id_token, lifetime)
+            }
+        }
+        _ => SecretString::from("pending:"),
+    };
+    drop(ct_lk);
+    write_frame(stream, response.expose().as_bytes())?;
+    Ok(())
+}
+
+/// Create a `kind:secret` reply, without leaving partial copies of `secret` in memory.
+fn secret_reply(kind: &str, secret: &SecretString) -> SecretString {
+    let mut s = String::with_capacity(kind.len() + 1 + secret.expose().len());
+    s.push_str(kind);
+    s.push(':');
+    s.push_str(secret.expose());
+    SecretString::from(s)
+}
+
+/// Return details about this server, for `pizauth info`, as a JSON object.
+fn info(pstate: &AuthenticatorState) -> JsonValue {
+    let path = |x: &Option<PathBuf>| -> JsonValue {
+        match x {
+            Some(x) => x.to_string_lossy().as_ref().into(),
+            None => JsonValue::Null,
+        }
+    };
+    let mut info = JsonValue::new_object();
+    info["version"] = env!("CARGO_PKG_VERSION").into();
+    info["protocol_version"] = PROTOCOL_VERSION.into();
+    info["pid"] = process::id().into();
+    info["config_path"] = path(&pstate.conf_path);
+    info["restart_args"] = match &pstate.restart_args {
+        Ok(x) => x.clone().into(),
+        Err(_) => JsonValue::Null,
+    };
+    info["socket_path"] = path(&pstate.sock_path);
+    info["http_addrs"] = pstate
+        .http_addrs
+        .iter()
+        .map(|x| x.to_string())
+        .collect::<Vec<_>>()
+        .into();
+    info["uptime_secs"] = pstate
+        .clock
+        .now()
+        .saturating_duration_since(pstate.started_at)
+        .as_secs()
+        .into();
+    info["accounts"] = pstate.account_count().into();
+    info
+}
+
+/// Return the state of each account, for `pizauth status --json` and `pizauth monitor`, as a JSON
+/// object mapping account names to objects with the keys `state` (the name of the account's
+/// [TokenState]), `expires_in_secs` (for active tokens, the seconds until the token expires;
+/// otherwise null), and `display_name` (for active tokens, the user's email address or user name,
+/// if known; otherwise null).
+fn status_json(pstate: &AuthenticatorState, tag: Option<&str>) -> JsonValue {
+    let ct_lk = pstate.ct_lock();
+    let wall_now = pstate.clock.wall_now();
+    let mut status = JsonValue::new_object();
+    for act_id in ct_lk
+        .act_ids()
+        .filter(|act_id| tag.is_none_or(|x| ct_lk.account(act_id).has_tag(x)))
+    {
+        let ts = ct_lk.tokenstate(&act_id);
+        let mut act = JsonValue::new_object();
+        act["state"] = ts.variant_name().into();
+        act["expires_in_secs"] = match ts {
+            TokenState::Active { expiry, .. } => expiry
+                .duration_since(wall_now)
+                .map(|d| d.as_secs())
+                .unwrap_or(0)
+                .into(),
+            _ => JsonValue::Null,
+        };
+        act["display_name"] = match ts {
+            TokenState::Active {
+                display_name: Some(x),
+                ..
+            } => x.as_str().into(),
+            _ => JsonValue::Null,
+        };
+        status[ct_lk.account(&act_id).name.as_str()] = act;
+    }
+    status
+}
+
+/// Return a human readable description of the state of each account (or, if `tag` is `Some`, each
+/// account with that tag).
+fn status(pstate: &AuthenticatorState, tag: Option<&str>) -> String {
+    let ct_lk = pstate.ct_lock();
+    let now = pstate.clock.now();
+    let wall_now = pstate.clock.wall_now();
+    let mut acts = ct_lk
+        .act_ids()
+        .filter(|act_id| tag.is_none_or(|x| ct_lk.account(act_id).has_tag(x)))
+        .map(|act_id| {
+            let st = match ct_lk.tokenstate(&act_id) {
+                TokenState::Empty => "no token".to_owned(),
+                TokenState::Pending { .. } => "pending authentication".to_owned(),
+                TokenState::Exchanging => "completing authentication".to_owned(),
+                TokenState::Active {
+                    expiry,
+                    display_name,
+                    ..
+                } => {
+                    let expiry = match expiry.duration_since(wall_now) {
+                        Ok(d) => format!("expires in {}s", d.as_secs()),
+                        Err(_) => "expired".to_owned(),
+                    };
+                    match display_name {
+                        Some(x) => format!("active as {x:} ({expiry:})"),
+                        None => format!("active ({expiry:})"),
+                    }
+                }
+                TokenState::Failed {
+                    reason,
+                    failed_at,
+                    previous_expiry,
+                } => {
+                    let expiry = match previous_expiry.duration_since(wall_now) {
+                        Ok(d) => format!("expires in {}s", d.as_secs()),
+                        Err(_) => "expired".to_owned(),
+                    };
+                    format!(
+                        "refresh failed {}s ago (old token {expiry:}): {reason:}",
+                        now.saturating_duration_since(*failed_at).as_secs()
+                    )
+                }
+            };
+            let mut s = format!("{}: {st:}", ct_lk.account(&act_id).name);
+            if let Some(t) = ct_lk.last_used(&act_id) {
+                s.push_str(&format!(
+                    "\n  last used: {}s ago",
+                    now.saturating_duration_since(t).as_secs()
+                ));
+            }
+            if let Some((t, msg)) = ct_lk.last_error(&act_id) {
+                s.push_str(&format!(
+                    "\n  last error ({}s ago): {msg:}",
+                    now.saturating_duration_since(*t).as_secs()
+                ));
+            }
+            s
+        })
+        .collect::<Vec<_>>();
+    drop(ct_lk);
+    acts.sort();
+    acts.join("\n")
+}
+
+/// Request a new token for `act_id`, replying `pending:` to the client on `stream` or, if the
+/// request couldn't be started, an error.
+fn request_token_reply(
+    pstate: &Arc<AuthenticatorState>,
+    stream: &mut UnixStream,
+    ct_lk: CTGuard,
+    act_id: CTGuardAccountId,
+) -> Result<(), Box<dyn Error>> {
+    match request_token(Arc::clone(pstate), ct_lk, act_id) {
+        Ok(()) => write_frame(stream, b"pending:")?,
+        Err(e) => write_frame(stream, format!("error:{e:}").as_bytes())?,
+    }
+    Ok(())
+}
+
+/// Accept client connections on `listener` until it fails. Clients can send several requests on one
+/// connection (some of which, e.g. `diagnose`, can be slow), so each connection is handled in its
+/// own thread, of which there are at most [MAX_CONNECTIONS].
+fn listen(pstate: Arc<AuthenticatorState>, listener: UnixListener) {
+    let active = Arc::new(AtomicUsize::new(0));
+    for mut stream in listener.incoming().flatten() {
+        let timeout = Some(Duration::from_secs(CONNECTION_TIMEOUT));
+        if let Err(e) = stream
+            .set_read_timeout(timeout)
+            .and_then(|_| stream.set_write_timeout(timeout))
+        {
+            warn!("{e:}");
+            continue;
+        }
+        if pstate.shutting_down.load(Ordering::SeqCst) {
+            write_frame(&mut stream, b"error:The pizauth server is shutting down").ok();
+            continue;
+        }
+        if active.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+            active.fetch_sub(1, Ordering::SeqCst);
+            warn!("Too many connections: rejecting client");
+            write_frame(
+                &mut stream,
+                b"error:Too many connections to the pizauth server",
+            )
+            .ok();
+            continue;
+        }
+        let pstate = Arc::clone(&pstate);
+        let active = Arc::clone(&active);
+        thread::spawn(move || {
+            if let Err(e) = request(pstate, stream) {
+                warn!("{e:}");
+            }
+            active.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+}
+
+/// Handle each request a client sends on `stream` in turn, until the client closes its half of
+/// the connection.
 fn request(pstate: Arc<AuthenticatorState>, mut stream: UnixStream) -> Result<(), Box<dyn Error>> {
-    let mut cmd = String::new();
-    stream.read_to_string(&mut cmd)?;
+    loop {
+        match read_frame(&mut stream) {
+            Ok(Some(cmd)) => command(Arc::clone(&pstate), &mut stream, &cmd)?,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                match VersionMismatch::from_io_error(&e) {
+                    // Clients which predate framing send, and expect, plain text, so this is the
+                    // only way they'll find out what went wrong.
+                    Some(vm) if vm.is_unframed() => {
+                        stream.write_all(format!("error:{e:}").as_bytes()).ok();
+                    }
+                    // Other clients will see our protocol version in the frame's header.
+                    _ => {
+                        write_frame(&mut stream, format!("error:{e:}").as_bytes()).ok();
+                    }
+                }
+                return Err(e.into());
+            }
+        }
+    }
+}
 
-    match &cmd.split(' ').collect::<Vec<_>>()[..] {
+/// Execute the single command `cmd`, writing the reply to `stream`.
+fn command(
+    pstate: Arc<AuthenticatorState>,
+    stream: &mut UnixStream,
+    cmd: &str,
+) -> Result<(), Box<dyn Error>> {
+    let args = match decode_request(cmd) {
+        Ok(x) => x,
+        Err(e) => {
+            write_frame(stream, format!("error:{e:}").as_bytes())?;
+            return Ok(());
+        }
+    };
+    match &args.iter().map(|x| x.as_ref()).collect::<Vec<_>>()[..] {
+        ["diagnose"] => {
+            let table = diagnose::diagnose(&pstate);
+            write_frame(stream, format!("diagnose:{table:}").as_bytes())?;
+            Ok(())
+        }
+        ["info"] => {
+            let reply = format!("info:{}", info(&pstate).dump());
+            write_frame(stream, reply.as_bytes())?;
+            Ok(())
+        }
+        ["metrics"] => {
+            let reply = format!("metrics:{}", metrics::metrics(&pstate));
+            write_frame(stream, reply.as_bytes())?;
+            Ok(())
+        }
+        ["dump", rest @ ..] => {
+            // A dump of refresh tokens gives access to every account, and even a snapshot of the
+            // accounts' state reveals details about them, so both must be explicitly allowed.
+            if !pstate.allow_dump {
+                write_frame(
+                    stream,
+                    b"error:Dumps are disabled: start the server with --allow-dump",
+                )?;
+                return Ok(());
+            }
+            match rest {
+                [] => {
+                    let reply = secret_reply("dump", &dump::dump(&pstate));
+                    write_frame(stream, reply.expose().as_bytes())?;
+                }
+                ["json"] => {
+                    let reply = format!("dump:{}", dump::dump_state(&pstate).dump());
+                    write_frame(stream, reply.as_bytes())?;
+                }
+                _ => write_frame(stream, b"error:Invalid dump request")?,
+            }
+            Ok(())
+        }
+        ["restore", entries @ ..] => {
+            match dump::restore(&pstate, entries) {
+                Ok(report) => write_frame(stream, format!("restore:{report:}").as_bytes())?,
+                Err(e) => write_frame(stream, format!("error:{e:}").as_bytes())?,
+            }
+            Ok(())
+        }
+        ["completion", act_name, url] => {
+            if pstate.ct_lock().validate_act_name(act_name).is_none() {
+                write_frame(stream, b"no_account:")?;
+                return Ok(());
+            }
+            match http_server::complete(Arc::clone(&pstate), act_name, url) {
+                Ok(()) => write_frame(stream, b"ok:")?,
+                Err(e) => write_frame(stream, format!("error:{e:}").as_bytes())?,
+            }
+            Ok(())
+        }
+        ["test", act_name] => {
+            // We copy the account and drop the lock before doing any network I/O.
+            let ct_lk = pstate.ct_lock();
+            let act = match ct_lk.validate_act_name(act_name) {
+                Some(act_id) => Arc::clone(&ct_lk.config().accounts[&ct_lk.account(&act_id).name]),
+                None => {
+                    drop(ct_lk);
+                    write_frame(stream, b"no_account:")?;
+                    return Ok(());
+                }
+            };
+            drop(ct_lk);
+            // Each check is reported as soon as it completes, since some can be slow to fail.
+            let passed = diagnose::test_account(&act, |x| {
+                write_frame(stream, format!("info:{x:}").as_bytes())
+            })?;
+            if passed {
+                write_frame(stream, b"ok:")?;
+            } else {
+                write_frame(stream, b"error:Connectivity test failed")?;
+            }
+            Ok(())
+        }
+        ["reload", _] if pstate.conf_path.is_none() => {
+            write_frame(
+                stream,
+                b"error:The server's config was read from stdin, so it can't be reloaded: \
+                  restart the server instead",
+            )?;
+            Ok(())
+        }
         ["reload", conf_path] => {
-            match Config::from_path(Path::new(conf_path)) {
+            match reload_conf(&pstate, Path::new(conf_path)) {
                 Ok(new_conf) => {
-                    pstate.update_conf(new_conf);
-                    stream.write_all(b"ok:")?
+                    let diff = pstate.update_conf(new_conf);
+                    write_frame(stream, format!("ok:{diff:}").as_bytes())?
                 }
-                Err(e) => stream.write_all(format!("error:{e:}").as_bytes())?,
+                Err(e) => write_frame(stream, format!("error:{e:}").as_bytes())?,
             }
             Ok(())
         }
-        ["refresh", act_name] => {
+        ["forget", act_name] => {
             let ct_lk = pstate.ct_lock();
             let act_id = match ct_lk.validate_act_name(act_name) {
                 Some(x) => x,
                 None => {
                     drop(ct_lk);
-                    stream.write_all(format!("error:No account '{act_name:}'").as_bytes())?;
+                    write_frame(stream, b"no_account:")?;
                     return Ok(());
                 }
             };
+            match forget_token(&pstate, ct_lk, act_id) {
+                Ok(()) => write_frame(stream, b"ok:")?,
+                Err(e) => {
+                    write_frame(stream, format!("error:Token forgotten but {e:}").as_bytes())?
+                }
+            }
+            Ok(())
+        }
+        ["rotate", act_name] => {
+            let ct_lk = pstate.ct_lock();
+            let act_id = match ct_lk.validate_act_name(act_name) {
+                Some(x) => x,
+                None => {
+                    drop(ct_lk);
+                    write_frame(stream, b"no_account:")?;
+                    return Ok(());
+                }
+            };
+            let forgotten = forget_token(&pstate, ct_lk, act_id);
+            let ct_lk = pstate.ct_lock();
+            let act_id = match ct_lk.validate_act_name(act_name) {
+                Some(x) => x,
+                None => {
+                    drop(ct_lk);
+                    write_frame(stream, b"no_account:")?;
+                    return Ok(());
+                }
+            };
+            // While the lock was released, another client may already have started a new
+            // authentication, in which case we needn't start another.
+            let started = match ct_lk.tokenstate(&act_id) {
+                TokenState::Empty => request_token(Arc::clone(&pstate), ct_lk, act_id),
+                _ => {
+                    drop(ct_lk);
+                    Ok(())
+                }
+            };
+            match (forgotten, started) {
+                (Ok(()), Ok(())) => write_frame(stream, b"pending:")?,
+                (Err(e), Ok(())) => write_frame(
+                    stream,
+                    format!("error:Authentication started but {e:}").as_bytes(),
+                )?,
+                (_, Err(e)) => write_frame(stream, format!("error:{e:}").as_bytes())?,
+            }
+            Ok(())
+        }
+        [cmd @ ("refresh" | "refresh-force"), act_name] => {
+            let force = *cmd == "refresh-force";
+            let mut ct_lk = pstate.ct_lock();
+            let mut act_id = match ct_lk.validate_act_name(act_name) {
+                Some(x) => x,
+                None => {
+                    drop(ct_lk);
+                    write_frame(stream, b"no_account:")?;
+                    return Ok(());
+                }
+            };
+            let client_credentials =
+                ct_lk.account(&act_id).auth_flow == AuthFlow::ClientCredentials;
+            if force {
+                // Forgetting previous attempts means that the refresh isn't held back by them
+                // (e.g. because the provider asked us to wait).
+                let mut ts = ct_lk.tokenstate(&act_id).clone();
+                if let TokenState::Active {
+                    ref mut last_refresh_attempt,
+                    ..
+                } = ts
+                {
+                    *last_refresh_attempt = None;
+                    act_id = ct_lk.tokenstate_replace(act_id, ts);
+                }
+            }
             match ct_lk.tokenstate(&act_id) {
-                TokenState::Empty | TokenState::Pending { .. } => {
-                    request_token(Arc::clone(&pstate), ct_lk, act_id)?;
-                    stream.write_all(b"pending:")?;
-                }
-                TokenState::Active { .. } => {
-                    match pstate.refresher.refresh(&pstate, ct_lk, act_id)? {
-                        RefreshKind::AccountOrTokenStateChanged => stream.write_all(b"error:")?,
-                        RefreshKind::PermanentError(msg) => {
-                            stream.write_all(format!("error:{msg:}").as_bytes())?
+                TokenState::Empty | TokenState::Pending { .. } | TokenState::Failed { .. }
+                    if !client_credentials =>
+                {
+                    request_token_reply(&pstate, stream, ct_lk, act_id)?;
+                }
+                TokenState::Exchanging => {
+                    drop(ct_lk);
+                    write_frame(stream, b"pending:")?;
+                }
+                // Client credentials accounts are "refreshed" by rerunning the grant, whatever
+                // their tokenstate.
+                _ => {
+                    let rk = pstate.refresher.refresh(&pstate, ct_lk, act_id);
+                    // Even a failed refresh changes when the refresher next needs to wake up.
+                    pstate.refresher.notify_changes();
+                    match rk? {
+                        RefreshKind::AccountOrTokenStateChanged => write_frame(stream, b"error:")?,
+                        RefreshKind::PermanentError(_, msg) if force && !client_credentials => {
+                            // The user wanted to know if the token is still usable: since it
+                            // isn't, we start reauthenticating straight away.
+                            let ct_lk = pstate.ct_lock();
+                            match ct_lk.validate_act_name(act_name) {
+                                Some(act_id) => {
+                                    request_token_reply(&pstate, stream, ct_lk, act_id)?
+                                }
+                                None => {
+                                    drop(ct_lk);
+                                    write_frame(stream, format!("error:{msg:}").as_bytes())?
+                                }
+                            }
                         }
-                        RefreshKind::Refreshed => stream.write_all(b"ok:")?,
+                        RefreshKind::PermanentError(_, msg) => {
+                            write_frame(stream, format!("error:{msg:}").as_bytes())?
+                        }
+                        RefreshKind::Refreshed => write_frame(stream, b"ok:")?,
                         RefreshKind::TransitoryError(msg) => {
-                            stream.write_all(format!("error:{msg:}").as_bytes())?
+                            write_frame(stream, format!("error:{msg:}").as_bytes())?
                         }
                     }
                 }
             }
             Ok(())
         }
-        ["showtoken", act_name] => {
+        [cmd @ ("showtoken" | "showidtoken"), min_validity, act_name, scopes @ ..] => {
+            let min_validity = match min_validity.parse::<u64>() {
+                Ok(0) => None,
+                Ok(x) => Some(Duration::from_secs(x)),
+                Err(_) => {
+                    write_frame(stream, b"error:Invalid minimum validity")?;
+                    return Ok(());
+                }
+            };
+            // We only have one token per account, so if the user asks for scopes that the
+            // account doesn't have, the token we would hand out would not be what they expected.
+            // A `scopes_cmd` can take an arbitrary amount of time to run, so we mustn't hold the
+            // lock while checking.
+            if !scopes.is_empty() {
+                let act = pstate.ct_lock().config().accounts.get(*act_name).cloned();
+                let act_scopes = match act {
+                    Some(x) => x.scopes(),
+                    None => {
+                        write_frame(stream, b"no_account:")?;
+                        return Ok(());
+                    }
+                };
+                let missing = scopes
+                    .iter()
+                    .filter(|x| !act_scopes.iter().any(|y| y == *x))
+                    .copied()
+                    .collect::<Vec<_>>();
+                if !missing.is_empty() {
+                    write_frame(
+                        stream,
+                        format!(
+                            "error:Account '{act_name:}' is not configured with scope(s): {}",
+                            missing.join(" ")
+                        )
+                        .as_bytes(),
+                    )?;
+                    return Ok(());
+                }
+            }
             // If unwrap()ing the lock fails, we're in such deep trouble that trying to carry on is
             // pointless.
-            let ct_lk = pstate.ct_lock();
+            let mut ct_lk = pstate.ct_lock();
             let act_id = match ct_lk.validate_act_name(act_name) {
                 Some(x) => x,
                 None => {
                     drop(ct_lk);
-                    stream.write_all(format!("error:No account '{act_name:}'").as_bytes())?;
+                    write_frame(stream, b"no_account:")?;
                     return Ok(());
                 }
             };
+            let track_usage = ct_lk.account(&act_id).refresh_if_unused_for.is_some();
+            let refresh_before_expiry = ct_lk.account(&act_id).refresh_before_expiry;
+            ct_lk.set_last_used(&act_id);
+            if track_usage {
+                // The refresher may have been ignoring this account because it was unused.
+                pstate.refresher.notify_changes();
+            }
+            if ct_lk.account(&act_id).auth_flow == AuthFlow::ClientCredentials {
+                let needed_until = pstate.clock.wall_now().checked_add(
+                    cmp::max(min_validity, refresh_before_expiry).unwrap_or(Duration::ZERO),
+                );
+                if !matches!(ct_lk.tokenstate(&act_id), TokenState::Active { expiry, .. }
+                    if needed_until.is_some_and(|t| *expiry > t))
+                {
+                    // Obtaining a token doesn't involve the user, so rather than asking the client
+                    // to wait, we obtain one now.
+                    return show_refreshed_token(
+                        &pstate,
+                        stream,
+                        *cmd == "showidtoken",
+                        ct_lk,
+                        act_id,
+                        min_validity.unwrap_or(Duration::ZERO),
+                    );
+                }
+            }
             match ct_lk.tokenstate(&act_id) {
                 TokenState::Empty => {
-                    request_token(Arc::clone(&pstate), ct_lk, act_id)?;
-                    stream.write_all(b"pending:")?;
+                    request_token_reply(&pstate, stream, ct_lk, act_id)?;
                 }
                 TokenState::Pending {
                     last_notification: _,
+                    notification_count: _,
+                    nonce: _,
                     state: _,
                     url: _,
-                } => {
+                }
+                | TokenState::Exchanging => {
+                    drop(ct_lk);
+                    write_frame(stream, b"pending:")?;
+                }
+                TokenState::Failed { reason, .. } => {
+                    // Handing out the old token, or silently starting a new authentication, would
+                    // hide from the user why they need to run `pizauth refresh`.
+                    let msg = format!("error:refresh failed: {reason:}");
                     drop(ct_lk);
-                    stream.write_all(b"pending:")?;
+                    write_frame(stream, msg.as_bytes())?;
+                }
+                TokenState::Active {
+                    expiry,
+                    refresh_token,
+                    ..
+                } if *expiry <= pstate.clock.wall_now() => {
+                    // We must never hand out an expired token. This most often happens after the
+                    // system has been suspended, so we try to obtain a new token immediately.
+                    if refresh_token.is_some() {
+                        drop(ct_lk);
+                        pstate.refresher.notify_changes();
+                        write_frame(stream, b"pending:")?;
+                    } else {
+                        let act_id = ct_lk.tokenstate_replace(act_id, TokenState::Empty);
+                        request_token_reply(&pstate, stream, ct_lk, act_id)?;
+                    }
+                }
+                TokenState::Active { expiry, .. }
+                    if min_validity
+                        .and_then(|d| pstate.clock.wall_now().checked_add(d))
+                        .is_some_and(|t| *expiry <= t) =>
+                {
+                    // The client needs the token to remain valid for longer than it will, so we
+                    // refresh it now rather than leaving the client to try again later.
+                    show_refreshed_token(
+                        &pstate,
+                        stream,
+                        *cmd == "showidtoken",
+                        ct_lk,
+                        act_id,
+                        min_validity.unwrap(),
+                    )?;
+                }
+                TokenState::Active {
+                    expiry,
+                    refresh_token: Some(_),
+                    ..
+                } if refresh_before_expiry
+                    .and_then(|d| pstate.clock.wall_now().checked_add(d))
+                    .is_some_and(|t| *expiry <= t) =>
+                {
+                    // The token is still valid, but clients may rely on it remaining valid for
+                    // at least `refresh_before_expiry`, so we hold on until it's been refreshed.
+                    drop(ct_lk);
+                    pstate.refresher.notify_changes();
+                    write_frame(stream, b"pending:")?;
                 }
                 TokenState::Active {
                     access_token,
                     expiry,
                     refreshed_at: _,
                     last_refresh_attempt: _,
+                    consecutive_refresh_failures: _,
+                    id_token,
                     refresh_token: _,
+                    display_name: _,
                 } => {
-                    let response = if expiry > &Instant::now() {
-                        format!("access_token:{access_token:}")
-                    } else {
-                        "error:Token has expired and refreshing has not yet succeeded".into()
-                    };
+                    let expires_in = expiry
+                        .duration_since(pstate.clock.wall_now())
+                        .unwrap_or(Duration::ZERO);
+                    let response =
+                        token_reply(*cmd == "showidtoken", access_token, id_token, expires_in);
                     drop(ct_lk);
-                    stream.write_all(response.as_bytes())?;
+                    write_frame(stream, response.expose().as_bytes())?;
+                }
+            }
+            Ok(())
+        }
+        ["status", rest @ ..] => {
+            let (json, tag) = match rest {
+                [] => (false, None),
+                ["json"] => (true, None),
+                ["tag", tag] => (false, Some(*tag)),
+                ["json", "tag", tag] => (true, Some(*tag)),
+                _ => {
+                    write_frame(stream, b"error:Invalid status request")?;
+                    return Ok(());
+                }
+            };
+            if let Some(tag) = tag {
+                if let Err(e) = pstate.ct_lock().config().accounts_tagged(tag) {
+                    write_frame(stream, format!("error:{e:}").as_bytes())?;
+                    return Ok(());
                 }
             }
+            let reply = if json {
+                status_json(&pstate, tag).dump()
+            } else {
+                status(&pstate, tag)
+            };
+            write_frame(stream, format!("status:{reply:}").as_bytes())?;
+            Ok(())
+        }
+        ["expand-tag", tag] => {
+            let reply = match pstate.ct_lock().config().accounts_tagged(tag) {
+                Ok(act_names) => format!(
+                    "accounts:{}",
+                    act_names
+                        .iter()
+                        .map(|x| urlencoding::encode(x))
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                ),
+                Err(e) => format!("error:{e:}"),
+            };
+            write_frame(stream, reply.as_bytes())?;
             Ok(())
         }
+        ["restart"] => {
+            let path = match (&pstate.restart_path, &pstate.restart_args) {
+                (Some(x), Ok(_)) => x,
+                (_, Err(e)) => {
+                    write_frame(stream, format!("error:{e:}").as_bytes())?;
+                    return Ok(());
+                }
+                (None, Ok(_)) => {
+                    write_frame(stream, b"error:This server can't be restarted")?;
+                    return Ok(());
+                }
+            };
+            // The lock is held until the process exits: were a token to be rotated after the
+            // handoff file was written, the new server would be given a token that no longer works.
+            let ct_lk = pstate.ct_lock();
+            if let Err(e) = restart::save(&ct_lk, path) {
+                drop(ct_lk);
+                write_frame(stream, format!("error:{e:}").as_bytes())?;
+                return Ok(());
+            }
+            // Even if the client has gone away, the handoff file has been written, so we must exit.
+            write_frame(stream, b"ok:").ok();
+            kill(getpid(), Signal::SIGTERM).ok();
+            loop {
+                thread::park();
+            }
+        }
         ["shutdown"] => {
-            raise(Signal::SIGTERM).ok();
+            // `raise` would send the signal to this thread, which has it blocked: it must be sent
+            // to the process so that the signal handling thread receives it.
+            kill(getpid(), Signal::SIGTERM).ok();
+            Ok(())
+        }
+        _ => {
+            // Commands can contain secrets, so we only report the command's name.
+            let name = &args[0];
+            write_frame(
+                stream,
+                format!("error:unknown command '{name:}'").as_bytes(),
+            )?;
             Ok(())
         }
-        _ => Err(format!("Invalid cmd '{cmd:}'").into()),
     }
 }
 
-pub fn server(conf: Config, cache_path: &Path) -> Result<(), Box<dyn Error>> {
-    let sock_path = sock_path(cache_path);
-    if sock_path.exists() {
-        // Is an existing authenticator running?
-        if UnixStream::connect(&sock_path).is_ok() {
-            return Err("pizauth authenticator already running".into());
+/// Return the sorted names of the accounts in `conf` whose `redirect_uri` specifies a literal port
+/// other than `http_port`.
+fn mismatched_redirect_ports(conf: &Config, http_port: u16) -> Vec<&str> {
+    let mut mismatched = conf
+        .accounts
+        .values()
+        .filter(|act| act.redirect_uri_port().is_some_and(|p| p != http_port))
+        .map(|act| act.name.as_str())
+        .collect::<Vec<_>>();
+    mismatched.sort();
+    mismatched
+}
+
+/// If the HTTP server's port is fixed at `http_port`, a `redirect_uri` with a different literal
+/// port is almost certainly a mistake: the provider would have to be configured with a redirect
+/// URI that pizauth can never honour.
+fn check_redirect_ports(conf: &Config, http_port: u16) -> Result<(), String> {
+    let mismatched = mismatched_redirect_ports(conf, http_port);
+    if mismatched.is_empty() {
+        Ok(())
+    } else {
+        Err(format!(
+            "The redirect_uri port of account(s) {} doesn't match the HTTP server's port {http_port:}: use '{{port}}' or the same port",
+            mismatched.join(", ")
+        ))
+    }
+}
+
+/// Load the config at `conf_path` to replace the server's current config.
+fn reload_conf(pstate: &AuthenticatorState, conf_path: &Path) -> Result<Config, String> {
+    let conf = Config::from_path(conf_path)?;
+    if pstate.http_port_fixed {
+        check_redirect_ports(&conf, pstate.http_port)?;
+    }
+    Ok(conf)
+}
+
+/// Wait for, and handle, `signals`, which must be blocked in all threads. `SIGHUP` reloads the
+/// config from the path it was originally read from; other signals stop new client connections
+/// being accepted, wait (for up to `shutdown_grace_period`) for in-flight token requests to
+/// complete, remove the PID file, and exit.
+fn signal_handler(pstate: Arc<AuthenticatorState>, signals: SigSet) {
+    loop {
+        match signals.wait() {
+            Ok(Signal::SIGHUP) => match &pstate.conf_path {
+                Some(conf_path) => match reload_conf(&pstate, conf_path) {
+                    Ok(new_conf) => {
+                        let diff = pstate.update_conf(new_conf);
+                        info!("Reloaded config from {}\n{diff:}", conf_path.display());
+                    }
+                    // The old config remains in force.
+                    Err(e) => {
+                        error!("Can't reload config: {e:}");
+                        // Unlike `pizauth reload`, nobody is waiting to be told about this.
+                        let msg = format!("Can't reload config: {e:}");
+                        if let Err(e) = pstate.notifier.notify_error(
+                            &pstate,
+                            None,
+                            ErrorKind::ConfigReload,
+                            &msg,
+                        ) {
+                            error!("{e:}");
+                        }
+                    }
+                },
+                None => error!("The server's config was read from stdin, so it can't be reloaded"),
+            },
+            Ok(_) => {
+                prepare_exit(&pstate);
+                process::exit(0);
+            }
+            Err(e) => {
+                error!("Can't wait for signals: {e:}");
+                return;
+            }
         }
-        fs::remove_file(&sock_path).ok();
     }
+}
 
-    let (http_port, http_state) = http_server::http_server_setup()?;
-    let frontend = preferred_frontend()?;
+/// Stop new client connections being accepted, wait (for up to `shutdown_grace_period`) for
+/// in-flight token requests to complete, and remove the PID file. This must not take the global
+/// lock, since the `restart` command holds it until the process exits.
+fn prepare_exit(pstate: &AuthenticatorState) {
+    pstate.shutting_down.store(true, Ordering::SeqCst);
+    // A token request which is abandoned may leave the user having to reauthenticate (e.g.
+    // because an authorisation code can only be used once), so we give those in flight a chance
+    // to complete.
+    let n = pstate.wait_for_inflight(pstate.shutdown_grace_period());
+    if n > 0 {
+        warn!("Abandoning {n:} token request(s) which didn't complete in time");
+    }
+    pstate.audit.flush(AUDIT_FLUSH_TIMEOUT);
+    if let Some(p) = &pstate.pid_path {
+        fs::remove_file(p).ok();
+    }
+}
+
+/// Run the server, accepting socket connections on `listener`. If `check_interval` is `Some`, it
+/// overrides the config's `refresh_check_interval`. If `http_port` is `Some`, the HTTP server
+/// listens on that port rather than an arbitrary free port. If `migrate_v1` is `Some`, tokens are
+/// imported from that legacy file before the server starts. If `one_shot` is `Some`, the process
+/// exits once every account has been authenticated (see [one_shot::one_shot]). If `allow_dump` is
+/// true, clients can dump each account's refresh token or a snapshot of its state. `restart_args`
+/// are the arguments (after `server`) with which `pizauth restart` starts this server's
+/// replacement, or why it can't. If `ready` is `Some`, [DAEMON_READY] is written to it once the
+/// server is ready to accept requests.
+#[allow(clippy::too_many_arguments)]
+pub fn server(
+    conf: Config,
+    conf_path: Option<PathBuf>,
+    cache_path: &Path,
+    listener: UnixListener,
+    check_interval: Option<Duration>,
+    http_port: Option<u16>,
+    migrate_v1: Option<PathBuf>,
+    one_shot: Option<OneShot>,
+    allow_dump: bool,
+    restart_args: Result<Vec<String>, String>,
+    ready: Option<File>,
+) -> Result<(), Box<dyn Error>> {
+    // Signals are handled by a dedicated thread, so they must be blocked before any other threads
+    // are created, since threads inherit their creator's signal mask.
+    let mut signals = SigSet::empty();
+    for s in [Signal::SIGHUP, Signal::SIGINT, Signal::SIGTERM] {
+        signals.add(s);
+    }
+    signals.thread_block()?;
+
+    // The PID file is used to give better error messages, so it doesn't matter if it's stale.
+    let pid_path = pid_path(cache_path);
+    let pid_path = match fs::write(&pid_path, format!("{}\n", process::id())) {
+        Ok(()) => Some(pid_path),
+        Err(e) => {
+            warn!("Can't write {}: {e:}", pid_path.display());
+            None
+        }
+    };
+
+    let http_port_fixed = http_port.is_some();
+    if let Some(p) = http_port {
+        check_redirect_ports(&conf, p)?;
+    }
+    let (http_port, http_listeners) = http_server::http_server_setup(&conf, http_port)?;
+    let mismatched = mismatched_redirect_ports(&conf, http_port);
+    if !mismatched.is_empty() {
+        warn!(
+            "The redirect_uri port of account(s) {} will be replaced with {http_port:}",
+            mismatched.join(", ")
+        );
+    }
+    let mut get_acts = conf
+        .accounts
+        .values()
+        .filter(|act| act.token_uri_method == TokenUriMethod::Get)
+        .map(|act| act.name.as_str())
+        .collect::<Vec<_>>();
+    if !get_acts.is_empty() {
+        get_acts.sort();
+        warn!(
+            "Account(s) {} use the non-standard 'token_uri_method = \"GET\"', which sends secrets in token_uri's query string",
+            get_acts.join(", ")
+        );
+    }
+    let frontend = preferred_frontend(&conf)?;
     let notifier = Arc::new(Notifier::new()?);
-    let refresher = Refresher::new();
+    let refresher = Refresher::new(check_interval);
 
-    let pstate = Arc::new(AuthenticatorState::new(
+    let mut pstate = AuthenticatorState::new(
         conf,
+        conf_path,
         http_port,
         Arc::clone(&frontend),
         Arc::clone(&notifier),
         Arc::clone(&refresher),
-    ));
+        Arc::new(SystemClock),
+    );
+    pstate.pid_path = pid_path;
+    pstate.http_port_fixed = http_port_fixed;
+    pstate.allow_dump = allow_dump;
+    pstate.restart_path = Some(restart::restart_path(cache_path));
+    pstate.restart_args = restart_args;
+    pstate.sock_path = listener
+        .local_addr()
+        .ok()
+        .and_then(|x| x.as_pathname().map(|x| x.to_owned()));
+    pstate.http_addrs = http_listeners
+        .iter()
+        .filter_map(|x| x.local_addr().ok())
+        .collect();
+    let pstate = Arc::new(pstate);
+
+    // Tokens must be imported before the refresher starts, so that it doesn't race with us.
+    if let Some(p) = &pstate.restart_path {
+        // Failing to pick up a handoff file only means that accounts need to be reauthenticated.
+        if let Err(e) = restart::load(&pstate, p) {
+            warn!("{e:}");
+        }
+    }
+    if let Some(p) = migrate_v1 {
+        migrate::migrate(&pstate, &p)?;
+    }
 
-    http_server::http_server(Arc::clone(&pstate), http_state)?;
+    http_server::http_server(Arc::clone(&pstate), http_listeners)?;
     refresher.refresher(Arc::clone(&pstate))?;
     notifier.notifier(Arc::clone(&pstate))?;
+    {
+        let pstate = Arc::clone(&pstate);
+        thread::spawn(move || signal_handler(pstate, signals));
+    }
+    info!("Started with {} accounts", pstate.account_count());
 
-    let listener = UnixListener::bind(sock_path)?;
-    thread::spawn(move || {
-        for stream in listener.incoming().flatten() {
-            let pstate = Arc::clone(&pstate);
-            if let Err(e) = request(pstate, stream) {
-                warn!("{e:}");
+    if let Some(one_shot) = one_shot {
+        let pstate = Arc::clone(&pstate);
+        thread::spawn(move || match one_shot::one_shot(pstate, one_shot) {
+            Ok(()) => process::exit(0),
+            Err(e) => {
+                error!("{e:}");
+                process::exit(1);
             }
-        }
-    });
+        });
+    }
+
+    thread::spawn(move || listen(pstate, listener));
+
+    if let Some(mut x) = ready {
+        // If this fails, the process which started us has already gone, so there's no-one to tell.
+        x.write_all(DAEMON_READY.as_bytes()).ok();
+    }
 
     frontend.main_loop()?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use clock::Clock;
+    use http_server::{http_server, http_server_setup};
+    use std::io::Read;
+    use test_utils::{act_conf, mock_pstate_with_port, MockOAuthServer};
+
+    /// Send `cmd` to the server, returning its reply.
+    fn send(pstate: &Arc<AuthenticatorState>, cmd: &str) -> String {
+        let (mut client, mut server) = UnixStream::pair().unwrap();
+        command(Arc::clone(pstate), &mut server, cmd).unwrap();
+        read_frame(&mut client).unwrap().unwrap()
+    }
+
+    /// Ask for `act_name`'s access token until one is available.
+    fn wait_for_token(pstate: &Arc<AuthenticatorState>, act_name: &str) -> String {
+        for _ in 0..100 {
+            let rtn = send(pstate, &format!("showtoken 0 {act_name:}"));
+            if let Some((_, x)) = rtn
+                .strip_prefix("access_token:")
+                .and_then(|x| x.split_once(' '))
+            {
+                return x.to_owned();
+            }
+            assert_eq!(rtn, "pending:");
+            thread::sleep(Duration::from_millis(100));
+        }
+        panic!("No token for {act_name:}");
+    }
+
+    #[test]
+    fn test_flow() {
+        let oauth = MockOAuthServer::new();
+        // "y" checks that token requests also work for non-standard providers.
+        let conf_str = format!(
+            "{}\n{}",
+            oauth.act_conf("x", &[]),
+            oauth.act_conf("y", &[("token_uri_method", r#""GET""#)])
+        );
+        let (http_port, listeners) =
+            http_server_setup(&Config::from_str(&conf_str).unwrap(), None).unwrap();
+        let (pstate, _) = mock_pstate_with_port(&conf_str, http_port);
+        let pstate = Arc::new(pstate);
+        http_server(Arc::clone(&pstate), listeners).unwrap();
+
+        let mut tokens = Vec::new();
+        for act_name in ["x", "y"] {
+            // Asking for a token starts authentication...
+            assert_eq!(
+                send(&pstate, &format!("showtoken 0 {act_name:}")),
+                "pending:"
+            );
+            let url = {
+                let ct_lk = pstate.ct_lock();
+                let act_id = ct_lk.validate_act_name(act_name).unwrap();
+                match ct_lk.tokenstate(&act_id) {
+                    TokenState::Pending { url, .. } => url.clone(),
+                    _ => panic!(),
+                }
+            };
+            // ...which the "user" approves in their "browser", which the mock server then
+            // redirects to pizauth's HTTP server.
+            let page = ureq::get(url.as_str())
+                .call()
+                .unwrap()
+                .into_string()
+                .unwrap();
+            assert!(page.contains(act_name));
+            tokens.push(wait_for_token(&pstate, act_name));
+        }
+        assert_ne!(tokens[0], tokens[1]);
+        assert_eq!(oauth.issued(), 2);
+
+        // Refreshing replaces each access token, but doesn't need the user's involvement.
+        for (act_name, old_token) in ["x", "y"].into_iter().zip(tokens) {
+            assert_eq!(send(&pstate, &format!("refresh {act_name:}")), "ok:");
+            assert_ne!(wait_for_token(&pstate, act_name), old_token);
+        }
+        assert_eq!(oauth.issued(), 4);
+    }
+
+    #[test]
+    fn test_single_pending() {
+        let oauth = MockOAuthServer::new();
+        let conf_str = oauth.act_conf("x", &[]);
+        let (http_port, listeners) =
+            http_server_setup(&Config::from_str(&conf_str).unwrap(), None).unwrap();
+        let (pstate, _) = mock_pstate_with_port(&conf_str, http_port);
+        let pstate = Arc::new(pstate);
+        http_server(Arc::clone(&pstate), listeners).unwrap();
+
+        let pending = |pstate: &AuthenticatorState| {
+            let ct_lk = pstate.ct_lock();
+            let act_id = ct_lk.validate_act_name("x").unwrap();
+            match ct_lk.tokenstate(&act_id) {
+                TokenState::Pending { url, .. } => (url.clone(), act_id.tokenstate_version()),
+                _ => panic!(),
+            }
+        };
+
+        assert_eq!(send(&pstate, "showtoken 0 x"), "pending:");
+        let (url, version) = pending(&pstate);
+        // Triggering authentication again reuses the pending URL rather than replacing it.
+        assert_eq!(send(&pstate, "showtoken 0 x"), "pending:");
+        assert_eq!(send(&pstate, "refresh x"), "pending:");
+        assert_eq!(pending(&pstate), (url.clone(), version));
+
+        ureq::get(url.as_str()).call().unwrap();
+        wait_for_token(&pstate, "x");
+        assert_eq!(oauth.issued(), 1);
+        // The only changes after the Pending state are to Exchanging and then to Active.
+        let ct_lk = pstate.ct_lock();
+        let act_id = ct_lk.validate_act_name("x").unwrap();
+        assert_eq!(act_id.tokenstate_version(), version + 2);
+    }
+
+    #[test]
+    fn test_refresh_force() {
+        let oauth = MockOAuthServer::new();
+        let conf_str = oauth.act_conf("x", &[]);
+        let (http_port, listeners) =
+            http_server_setup(&Config::from_str(&conf_str).unwrap(), None).unwrap();
+        let (pstate, clock) = mock_pstate_with_port(&conf_str, http_port);
+        let pstate = Arc::new(pstate);
+        http_server(Arc::clone(&pstate), listeners).unwrap();
+
+        assert_eq!(send(&pstate, "refresh-force x"), "pending:");
+        let url = {
+            let ct_lk = pstate.ct_lock();
+            let act_id = ct_lk.validate_act_name("x").unwrap();
+            match ct_lk.tokenstate(&act_id) {
+                TokenState::Pending { url, .. } => url.clone(),
+                _ => panic!(),
+            }
+        };
+        ureq::get(url.as_str()).call().unwrap();
+        wait_for_token(&pstate, "x");
+
+        // Change account "x"'s active token with `f`.
+        let update = |f: &dyn Fn(&mut Option<state::RefreshAttempt>, &mut Option<SecretString>)| {
+            let mut ct_lk = pstate.ct_lock();
+            let act_id = ct_lk.validate_act_name("x").unwrap();
+            let mut ts = ct_lk.tokenstate(&act_id).clone();
+            match ts {
+                TokenState::Active {
+                    ref mut last_refresh_attempt,
+                    ref mut refresh_token,
+                    ..
+                } => f(last_refresh_attempt, refresh_token),
+                _ => panic!(),
+            }
+            ct_lk.tokenstate_replace(act_id, ts);
+        };
+
+        // A forced refresh ignores the provider having asked us to wait...
+        update(&|lra, _| {
+            *lra = Some(state::RefreshAttempt {
+                at: clock.now(),
+                retry_after: Some(clock.now() + Duration::from_secs(60)),
+            })
+        });
+        assert!(send(&pstate, "refresh x").starts_with("error:rate limited by provider"));
+        assert_eq!(send(&pstate, "refresh-force x"), "ok:");
+        assert_eq!(oauth.issued(), 2);
+
+        // ...and if the refresh token turns out to have been revoked, authentication restarts.
+        update(&|_, rt| *rt = Some(SecretString::from("revoked")));
+        assert_eq!(send(&pstate, "refresh-force x"), "pending:");
+        let ct_lk = pstate.ct_lock();
+        let act_id = ct_lk.validate_act_name("x").unwrap();
+        assert!(matches!(
+            ct_lk.tokenstate(&act_id),
+            TokenState::Pending { .. }
+        ));
+    }
+
+    #[test]
+    fn test_client_credentials() {
+        let oauth = MockOAuthServer::new();
+        let act = |act_name: &str, client_secret: &str| {
+            oauth.act_conf(
+                act_name,
+                &[
+                    ("auth_flow", r#""client_credentials""#),
+                    ("auth_uri", ""),
+                    ("redirect_uri", ""),
+                    ("client_secret", client_secret),
+                ],
+            )
+        };
+        let conf_str = format!("{}\n{}", act("x", r#""c""#), act("y", r#""wrong""#));
+        let (pstate, clock) = mock_pstate_with_port(&conf_str, 0);
+        let pstate = Arc::new(pstate);
+
+        // A token is obtained without the user's involvement, so the client never has to wait...
+        assert_eq!(send(&pstate, "showtoken 0 x"), "access_token:3600 access_0");
+        // ...and is then handed out until it needs replacing.
+        assert_eq!(send(&pstate, "showtoken 0 x"), "access_token:3600 access_0");
+        assert_eq!(
+            send(&pstate, "showtoken 600 x"),
+            "access_token:3600 access_0"
+        );
+        assert_eq!(oauth.issued(), 1);
+        clock.advance(Duration::from_secs(3600));
+        assert_eq!(send(&pstate, "showtoken 0 x"), "access_token:3600 access_1");
+        assert_eq!(send(&pstate, "refresh x"), "ok:");
+        assert_eq!(send(&pstate, "showtoken 0 x"), "access_token:3600 access_2");
+        assert_eq!(oauth.issued(), 3);
+
+        // The provider's error is passed on, and the grant is rerun on each request.
+        for _ in 0..2 {
+            let rtn = send(&pstate, "showtoken 0 y");
+            assert!(
+                rtn.starts_with("error:") && rtn.contains("invalid_grant"),
+                "{rtn:}"
+            );
+        }
+        let ct_lk = pstate.ct_lock();
+        let act_id = ct_lk.validate_act_name("y").unwrap();
+        assert!(matches!(
+            ct_lk.tokenstate(&act_id),
+            TokenState::Failed { .. }
+        ));
+    }
+
+    #[test]
+    fn test_min_validity() {
+        let oauth = MockOAuthServer::new();
+        let conf_str = oauth.act_conf("x", &[]);
+        let (pstate, clock) = mock_pstate_with_port(&conf_str, 0);
+        let pstate = Arc::new(pstate);
+        {
+            let mut ct_lk = pstate.ct_lock();
+            let act_id = ct_lk.validate_act_name("x").unwrap();
+            ct_lk.tokenstate_replace(
+                act_id,
+                TokenState::Active {
+                    access_token: SecretString::from("old"),
+                    refreshed_at: pstate.clock.now(),
+                    last_refresh_attempt: None,
+                    consecutive_refresh_failures: 0,
+                    expiry: pstate.clock.wall_now() + Duration::from_secs(3600),
+                    id_token: None,
+                    refresh_token: Some(SecretString::from("r")),
+                    display_name: None,
+                },
+            );
+        }
+
+        // A token which remains valid for long enough is handed out as-is...
+        assert_eq!(send(&pstate, "showtoken 60 x"), "access_token:3600 old");
+        // ...but no refresh can give a token which outlives the provider's token lifetime.
+        assert_eq!(
+            send(&pstate, "showtoken 7200 x"),
+            "error:Tokens for 'x' are valid for 3600s, less than the minimum validity of 7200s"
+        );
+        assert_eq!(oauth.issued(), 0);
+
+        // A token which expires too soon is refreshed before the reply is sent.
+        clock.advance(Duration::from_secs(3570));
+        assert_eq!(
+            send(&pstate, "showtoken 60 x"),
+            "access_token:3600 access_0"
+        );
+        assert_eq!(oauth.issued(), 1);
+        assert_eq!(
+            send(&pstate, "showtoken 60 x"),
+            "access_token:3600 access_0"
+        );
+        assert_eq!(
+            send(&pstate, "showtoken x x"),
+            "error:Invalid minimum validity"
+        );
+    }
+
+    #[test]
+    fn test_check_redirect_ports() {
+        let conf = |redirect_uri: &str| {
+            Config::from_str(&act_conf("x", &[("redirect_uri", redirect_uri)])).unwrap()
+        };
+        assert!(check_redirect_ports(&conf(""), 8080).is_ok());
+        assert!(check_redirect_ports(&conf(r#""http://localhost:{port}/""#), 8080).is_ok());
+        assert!(check_redirect_ports(&conf(r#""http://localhost:8080/""#), 8080).is_ok());
+        let e = check_redirect_ports(&conf(r#""http://localhost:8081/""#), 8080).unwrap_err();
+        assert!(e.contains("account(s) x doesn't match the HTTP server's port 8080"));
+    }
+
+    #[test]
+    fn test_rotate() {
+        let (pstate, _) = test_utils::mock_pstate(test_utils::CONF_STR);
+        let pstate = Arc::new(pstate);
+        {
+            let mut ct_lk = pstate.ct_lock();
+            let act_id = ct_lk.validate_act_name("x").unwrap();
+            ct_lk.tokenstate_replace(
+                act_id,
+                TokenState::Active {
+                    access_token: SecretString::from("a"),
+                    refreshed_at: pstate.clock.now(),
+                    last_refresh_attempt: None,
+                    consecutive_refresh_failures: 0,
+                    expiry: pstate.clock.wall_now() + Duration::from_secs(3600),
+                    id_token: None,
+                    refresh_token: Some(SecretString::from("r")),
+                    display_name: None,
+                },
+            );
+        }
+        // Unlike `forget`, an active account is immediately reauthenticated.
+        assert_eq!(send(&pstate, "rotate x"), "pending:");
+        {
+            let ct_lk = pstate.ct_lock();
+            let act_id = ct_lk.validate_act_name("x").unwrap();
+            assert!(matches!(
+                ct_lk.tokenstate(&act_id),
+                TokenState::Pending { .. }
+            ));
+        }
+        assert_eq!(send(&pstate, "showtoken 0 x"), "pending:");
+        assert_eq!(send(&pstate, "rotate y"), "no_account:");
+    }
+
+    #[test]
+    fn test_unknown_command() {
+        let (pstate, _) = test_utils::mock_pstate(test_utils::CONF_STR);
+        let pstate = Arc::new(pstate);
+        assert_eq!(send(&pstate, "blah x y"), "error:unknown command 'blah'");
+        assert_eq!(
+            send(&pstate, "showtoken"),
+            "error:unknown command 'showtoken'"
+        );
+    }
+
+    #[test]
+    fn test_account_names() {
+        let act_names = ["work: primary", "a  b", "caf\u{e9} \u{1f355}", "100%"];
+        let conf_str = act_names.map(|x| act_conf(x, &[])).join("\n");
+        let (pstate, _) = test_utils::mock_pstate(&conf_str);
+        let pstate = Arc::new(pstate);
+        for act_name in act_names {
+            assert_eq!(
+                send(&pstate, &crate::ipc::encode_request("forget", &[act_name])),
+                "ok:"
+            );
+        }
+        assert_eq!(
+            send(&pstate, &crate::ipc::encode_request("forget", &["work"])),
+            "no_account:"
+        );
+        assert!(send(&pstate, "forget %ff").starts_with("error:Malformed argument"));
+    }
+
+    #[test]
+    fn test_concurrent_connections() {
+        let (pstate, _) = test_utils::mock_pstate(test_utils::CONF_STR);
+        let pstate = Arc::new(pstate);
+        let sock_path =
+            std::env::temp_dir().join(format!("pizauth_test_{}.sock", std::process::id()));
+        fs::remove_file(&sock_path).ok();
+        let listener = UnixListener::bind(&sock_path).unwrap();
+        thread::spawn(move || listen(pstate, listener));
+
+        // A client which connects but never sends a request mustn't hold up anyone else.
+        let _stalled = UnixStream::connect(&sock_path).unwrap();
+        let start = std::time::Instant::now();
+        let clients = (0..32)
+            .map(|_| {
+                let sock_path = sock_path.clone();
+                thread::spawn(move || {
+                    let mut stream = UnixStream::connect(sock_path).unwrap();
+                    write_frame(&mut stream, b"showtoken 0 x").unwrap();
+                    stream.shutdown(std::net::Shutdown::Write).unwrap();
+                    read_frame(&mut stream).unwrap().unwrap()
+                })
+            })
+            .collect::<Vec<_>>();
+        for c in clients {
+            assert_eq!(c.join().unwrap(), "pending:");
+        }
+        assert!(start.elapsed() < Duration::from_secs(CONNECTION_TIMEOUT));
+        fs::remove_file(&sock_path).ok();
+    }
+
+    #[test]
+    fn test_info() {
+        let (mut pstate, _) = test_utils::mock_pstate(test_utils::CONF_STR);
+        pstate.conf_path = None;
+        pstate.http_addrs = vec!["127.0.0.1:1234".parse().unwrap()];
+        let pstate = Arc::new(pstate);
+        let rtn = send(&pstate, "info");
+        let info = json::parse(rtn.strip_prefix("info:").unwrap()).unwrap();
+        assert_eq!(info["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(info["protocol_version"], PROTOCOL_VERSION);
+        assert!(info["config_path"].is_null());
+        assert!(info["restart_args"].is_null());
+        assert_eq!(info["http_addrs"][0], "127.0.0.1:1234");
+        assert_eq!(info["accounts"], 1);
+    }
+
+    #[test]
+    fn test_status_json() {
+        let (pstate, _) = test_utils::mock_pstate(test_utils::CONF_STR);
+        let pstate = Arc::new(pstate);
+        let rtn = send(&pstate, "status json");
+        let status = json::parse(rtn.strip_prefix("status:").unwrap()).unwrap();
+        assert_eq!(status["x"]["state"], "Empty");
+        assert!(status["x"]["expires_in_secs"].is_null());
+        assert!(status["x"]["display_name"].is_null());
+
+        {
+            let mut ct_lk = pstate.ct_lock();
+            let act_id = ct_lk.validate_act_name("x").unwrap();
+            ct_lk.tokenstate_replace(
+                act_id,
+                TokenState::Active {
+                    access_token: SecretString::from("a"),
+                    refreshed_at: pstate.clock.now(),
+                    last_refresh_attempt: None,
+                    consecutive_refresh_failures: 0,
+                    expiry: pstate.clock.wall_now() + Duration::from_secs(60),
+                    id_token: None,
+                    refresh_token: None,
+                    display_name: Some("a@b.com".to_owned()),
+                },
+            );
+        }
+        let rtn = send(&pstate, "status json");
+        let status = json::parse(rtn.strip_prefix("status:").unwrap()).unwrap();
+        assert_eq!(status["x"]["display_name"], "a@b.com");
+        assert_eq!(
+            send(&pstate, "status"),
+            "status:x: active as a@b.com (expires in 60s)"
+        );
+    }
+
+    #[test]
+    fn test_tags() {
+        let conf_str = [
+            act_conf("a b", &[("tags", r#"["work"]"#)]),
+            act_conf("c", &[("tags", r#"["home"]"#)]),
+            act_conf("d", &[("tags", r#"["work", "home"]"#)]),
+        ]
+        .join("\n");
+        let (pstate, _) = test_utils::mock_pstate(&conf_str);
+        let pstate = Arc::new(pstate);
+        assert_eq!(send(&pstate, "expand-tag work"), "accounts:a%20b d");
+        assert_eq!(
+            send(&pstate, "expand-tag x"),
+            "error:Unknown tag 'x': known tags are home, work"
+        );
+
+        let rtn = send(&pstate, "status json tag home");
+        let status = json::parse(rtn.strip_prefix("status:").unwrap()).unwrap();
+        assert_eq!(status.len(), 2);
+        assert!(status.has_key("c") && status.has_key("d"));
+        let rtn = send(&pstate, "status tag work");
+        assert!(rtn.contains("a b: ") && !rtn.contains("c: "));
+        assert!(send(&pstate, "status tag x").starts_with("error:Unknown tag"));
+        assert_eq!(send(&pstate, "status x"), "error:Invalid status request");
+    }
+
+    #[test]
+    fn test_dump_json() {
+        let (pstate, _) = test_utils::mock_pstate(test_utils::CONF_STR);
+        let pstate = Arc::new(pstate);
+        assert!(send(&pstate, "dump json").contains("--allow-dump"));
+        assert!(send(&pstate, "dump").contains("--allow-dump"));
+
+        let (mut pstate, _) = test_utils::mock_pstate(test_utils::CONF_STR);
+        pstate.allow_dump = true;
+        let pstate = Arc::new(pstate);
+        {
+            let mut ct_lk = pstate.ct_lock();
+            let act_id = ct_lk.validate_act_name("x").unwrap();
+            ct_lk.tokenstate_replace(
+                act_id,
+                TokenState::Active {
+                    access_token: SecretString::from("secret_access"),
+                    refreshed_at: pstate.clock.now(),
+                    last_refresh_attempt: None,
+                    consecutive_refresh_failures: 0,
+                    expiry: pstate.clock.wall_now(),
+                    id_token: None,
+                    refresh_token: Some(SecretString::from("secret_refresh")),
+                    display_name: None,
+                },
+            );
+        }
+        let rtn = send(&pstate, "dump json");
+        // Token material must never be dumped.
+        assert!(!rtn.contains("secret_"));
+        let dump = json::parse(rtn.strip_prefix("dump:").unwrap()).unwrap();
+        assert_eq!(dump["x"]["state"], "Active");
+        assert_eq!(dump["x"]["version"], "1");
+        assert_eq!(dump["x"]["has_refresh_token"], true);
+        assert_eq!(dump["x"]["has_id_token"], false);
+        assert!(dump["x"]["failed_reason"].is_null());
+    }
+
+    #[test]
+    fn test_bind_socket() {
+        let cache_path =
+            std::env::temp_dir().join(format!("pizauth_test_bind_{}", std::process::id()));
+        fs::create_dir_all(&cache_path).unwrap();
+
+        // A socket left behind by a server which crashed.
+        drop(UnixListener::bind(sock_path(&cache_path)).unwrap());
+        assert!(sock_path(&cache_path).exists());
+        let listener = bind_socket(&cache_path).unwrap();
+
+        // A running server.
+        let e = bind_socket(&cache_path).unwrap_err().to_string();
+        assert!(e.starts_with("Another pizauth server is already running"));
+        fs::write(pid_path(&cache_path), "1234\n").unwrap();
+        let e = bind_socket(&cache_path).unwrap_err().to_string();
+        assert!(e.starts_with("Another pizauth server (PID 1234) is already running"));
+
+        drop(listener);
+        fs::remove_dir_all(&cache_path).ok();
+    }
+
+    #[test]
+    fn test_version_mismatch() {
+        let (pstate, _) = test_utils::mock_pstate(test_utils::CONF_STR);
+        let pstate = Arc::new(pstate);
+        let (mut client, server) = UnixStream::pair().unwrap();
+        client
+            .write_all(&[crate::ipc::PROTOCOL_VERSION + 1, 0, 0, 0, 6])
+            .unwrap();
+        client.write_all(b"status").unwrap();
+        assert!(request(pstate, server).is_err());
+        // The reply is in a frame of our protocol version, so a newer client can't read it...
+        let e = read_frame(&mut client).unwrap_err();
+        assert!(VersionMismatch::from_io_error(&e).is_some());
+        // ...but it can tell which version we speak.
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).unwrap();
+        assert!(String::from_utf8(buf[4..].to_vec())
+            .unwrap()
+            .starts_with("error:Protocol version mismatch"));
+    }
+
+    #[test]
+    fn test_reload_stdin() {
+        let (mut pstate, _) = test_utils::mock_pstate(test_utils::CONF_STR);
+        pstate.conf_path = None;
+        let pstate = Arc::new(pstate);
+        assert!(send(&pstate, "reload pizauth.conf")
+            .starts_with("error:The server's config was read from stdin"));
+    }
+
+    #[test]
+    fn test_scopes_cmd_unlocked() {
+        let p = std::env::temp_dir().join(format!(
+            "pizauth_test_scopes_cmd_unlocked_{}",
+            std::process::id()
+        ));
+        fs::remove_file(&p).ok();
+        let conf_str = test_utils::CONF_STR.replace(
+            r#"scopes = ["d", "e"];"#,
+            &format!(
+                r#"scopes_cmd = "echo run >> '{}'; sleep 1; false";"#,
+                p.display()
+            ),
+        );
+        let (pstate, _) = test_utils::mock_pstate(&conf_str);
+        let pstate = Arc::new(pstate);
+        let t = {
+            let pstate = Arc::clone(&pstate);
+            thread::spawn(move || send(&pstate, "showtoken 0 x"))
+        };
+        let start = std::time::Instant::now();
+        while !p.exists() {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(10));
+        }
+        // The command is now running, but the lock is still available.
+        let start = std::time::Instant::now();
+        drop(pstate.ct_lock());
+        assert!(start.elapsed() < Duration::from_millis(500));
+        assert_eq!(t.join().unwrap(), "pending:");
+        // The failing command is only run once to build the authorisation URL.
+        assert_eq!(fs::read_to_string(&p).unwrap(), "run\n");
+        fs::remove_file(&p).ok();
+    }
+
+    #[test]
+    fn test_restart_then_shutdown() {
+        let dir = std::env::temp_dir().join(format!(
+            "pizauth_test_restart_shutdown_{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let (mut pstate, _) = test_utils::mock_pstate(test_utils::CONF_STR);
+        pstate.pid_path = Some(dir.join("pizauth.pid"));
+        fs::write(pstate.pid_path.as_ref().unwrap(), "1").unwrap();
+        let pstate = Arc::new(pstate);
+
+        // As the `restart` command does, save the handoff file and then hold the lock forever.
+        let (tx, rx) = std::sync::mpsc::channel();
+        {
+            let pstate = Arc::clone(&pstate);
+            let path = restart::restart_path(&dir);
+            thread::spawn(move || {
+                let ct_lk = pstate.ct_lock();
+                restart::save(&ct_lk, &path).unwrap();
+                tx.send(()).unwrap();
+                loop {
+                    thread::park();
+                }
+            });
+        }
+        rx.recv().unwrap();
+
+        // Shutting down (which `restart` triggers with SIGTERM) mustn't need the lock.
+        let (tx, rx) = std::sync::mpsc::channel();
+        {
+            let pstate = Arc::clone(&pstate);
+            thread::spawn(move || {
+                prepare_exit(&pstate);
+                tx.send(()).unwrap();
+            });
+        }
+        rx.recv_timeout(Duration::from_secs(10))
+            .expect("Shutting down blocked on the global lock");
+        assert!(pstate.shutting_down.load(Ordering::SeqCst));
+        assert!(!dir.join("pizauth.pid").exists());
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_shutdown_grace_period() {
+        let (pstate, _) = test_utils::mock_pstate(test_utils::CONF_STR);
+        assert_eq!(
+            pstate.shutdown_grace_period(),
+            Config::from_str(test_utils::CONF_STR)
+                .unwrap()
+                .shutdown_grace_period
+        );
+        pstate.update_conf(
+            Config::from_str(&format!(
+                "shutdown_grace_period = 7s;\n{}",
+                test_utils::CONF_STR
+            ))
+            .unwrap(),
+        );
+        assert_eq!(pstate.shutdown_grace_period(), Duration::from_secs(7));
+    }
+}
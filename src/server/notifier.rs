@@ -1,19 +1,38 @@
 use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
     error::Error,
+    hash::{Hash, Hasher},
+    process::{Command, Stdio},
     sync::{Arc, Condvar, Mutex},
-    thread,
-    time::Instant,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
 #[cfg(debug_assertions)]
 use log::debug;
-use log::error;
+use log::{error, info};
+use url::Url;
 
-use super::{AuthenticatorState, CTGuard, CTGuardAccountId, TokenState};
+use super::{
+    request_token::expand_vars, AuthenticatorState, CTGuard, CTGuardAccountId, TokenState,
+};
+use crate::{config::AuthFlow, frontends::ErrorKind};
+
+/// The most recent error notification passed to the frontend for an account.
+struct ErrorRecord {
+    /// A hash of the error's kind and message.
+    hash: u64,
+    sent_at: Instant,
+    /// How many identical errors have been suppressed since `sent_at`.
+    suppressed: u64,
+}
 
 pub struct Notifier {
     pred: Mutex<bool>,
     condvar: Condvar,
+    /// The most recent error notification for each account (or `None` for errors which don't
+    /// relate to an account): see [Notifier::notify_error].
+    errors: Mutex<HashMap<Option<String>, ErrorRecord>>,
 }
 
 impl Notifier {
@@ -21,9 +40,60 @@ impl Notifier {
         Ok(Notifier {
             pred: Mutex::new(false),
             condvar: Condvar::new(),
+            errors: Mutex::new(HashMap::new()),
         })
     }
 
+    /// Pass an error to the frontend (see [crate::frontends::Frontend::notify_error]), unless an
+    /// identical error was passed for `act_name` within the last `error_notify_interval`, in which
+    /// case it is suppressed. When an identical error is next passed on, the number of errors
+    /// suppressed in the meantime is appended to it. Must not be called with the config lock held.
+    pub fn notify_error(
+        &self,
+        pstate: &AuthenticatorState,
+        act_name: Option<String>,
+        kind: ErrorKind,
+        msg: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        let interval = pstate.ct_lock().config().error_notify_interval;
+        let now = pstate.clock.now();
+        let mut hasher = DefaultHasher::new();
+        kind.hash(&mut hasher);
+        msg.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        let mut errors = self.errors.lock().unwrap();
+        let msg = match errors.get_mut(&act_name) {
+            Some(r) if r.hash == hash && now.saturating_duration_since(r.sent_at) < interval => {
+                r.suppressed += 1;
+                return Ok(());
+            }
+            Some(r) if r.hash == hash && r.suppressed > 0 => {
+                format!("{msg:} ({} occurrences suppressed)", r.suppressed)
+            }
+            _ => msg.to_owned(),
+        };
+        errors.insert(
+            act_name.clone(),
+            ErrorRecord {
+                hash,
+                sent_at: now,
+                suppressed: 0,
+            },
+        );
+        drop(errors);
+        pstate.frontend.notify_error(act_name, kind, &msg)
+    }
+
+    /// Forget the errors notified for `act_name`, so that its next error is shown immediately.
+    /// This should be called when the account authenticates, refreshes, or is changed.
+    pub fn reset_errors(&self, act_name: &str) {
+        self.errors
+            .lock()
+            .unwrap()
+            .remove(&Some(act_name.to_owned()));
+    }
+
     pub fn notifier(
         self: Arc<Self>,
         pstate: Arc<AuthenticatorState>,
@@ -37,17 +107,17 @@ impl Notifier {
                     "Notifier: next wakeup {}",
                     next_wakeup
                         .map(|x| x
-                            .checked_duration_since(Instant::now())
+                            .checked_duration_since(pstate.clock.now())
                             .map(|x| x.as_secs().to_string())
                             .unwrap_or_else(|| "<none>".to_owned()))
                         .unwrap_or_else(|| "<none>".to_owned())
                 );
                 match next_wakeup {
                     Some(t) => {
-                        if Instant::now() >= t {
+                        if pstate.clock.now() >= t {
                             break;
                         }
-                        match t.checked_duration_since(Instant::now()) {
+                        match t.checked_duration_since(pstate.clock.now()) {
                             Some(d) => {
                                 notify_lk = self.condvar.wait_timeout(notify_lk, d).unwrap().0
                             }
@@ -62,24 +132,37 @@ impl Notifier {
 
             let mut to_notify = Vec::new();
             let mut ct_lk = pstate.ct_lock();
-            let now = Instant::now();
-            let notify_interval = ct_lk.config().notify_interval; // Pulled out to avoid borrow checker problems.
+            let notify_cmd = ct_lk.config().notify_cmd.clone();
+            let now = pstate.clock.now();
             for act_id in ct_lk.act_ids().collect::<Vec<_>>() {
                 let mut ts = ct_lk.tokenstate(&act_id).clone();
                 if let TokenState::Pending {
                     ref mut last_notification,
-                    state: _,
+                    ref mut notification_count,
                     ref url,
+                    ..
                 } = ts
                 {
+                    let act = ct_lk.account(&act_id);
+                    // Client credentials accounts never need the user to authorise them, so
+                    // they must never cause authorisation notifications.
+                    if act.auth_flow == AuthFlow::ClientCredentials {
+                        continue;
+                    }
+                    if let Some(max) = act.notify_max_count {
+                        if *notification_count >= max {
+                            continue;
+                        }
+                    }
                     if let Some(t) = last_notification {
-                        if let Some(t) = t.checked_add(notify_interval) {
+                        if let Some(t) = t.checked_add(notify_interval(&ct_lk, &act_id)) {
                             if t > now {
                                 continue;
                             }
                         }
                     }
                     *last_notification = Some(now);
+                    *notification_count += 1;
                     let url = url.clone();
                     to_notify.push((ct_lk.account(&act_id).name.to_owned(), url.clone()));
                     ct_lk.tokenstate_replace(act_id, ts);
@@ -91,6 +174,11 @@ impl Notifier {
                 continue;
             }
 
+            if let Some(cmd) = &notify_cmd {
+                for (act_name, url) in &to_notify {
+                    run_notify_cmd(cmd, act_name, url);
+                }
+            }
             if let Err(e) = pstate.frontend.notify_authorisations(to_notify) {
                 error!("Notifier: {e:}");
             }
@@ -117,23 +205,206 @@ impl Notifier {
 /// If `act_id` has a pending token, return the next time when that user should be notified that
 /// it is pending.
 fn notify_at(
-    _pstate: &AuthenticatorState,
+    pstate: &AuthenticatorState,
     ct_lk: &CTGuard,
     act_id: &CTGuardAccountId,
 ) -> Option<Instant> {
     match ct_lk.tokenstate(act_id) {
         TokenState::Pending {
-            last_notification, ..
-        } => {
+            last_notification,
+            notification_count,
+            ..
+        } if ct_lk.account(act_id).auth_flow == AuthFlow::AuthorizationCode => {
+            if let Some(max) = ct_lk.account(act_id).notify_max_count {
+                if *notification_count >= max {
+                    return None;
+                }
+            }
             match last_notification {
-                None => Some(Instant::now()),
+                None => Some(pstate.clock.now()),
                 Some(t) => {
                     // There is no concept of Instant::MAX, so if `refreshed_at + d` exceeds
                     // Instant's bounds, there's nothing we can fall back on.
-                    t.checked_add(ct_lk.config().notify_interval)
+                    t.checked_add(notify_interval(ct_lk, act_id))
                 }
             }
         }
         _ => None,
     }
 }
+
+/// How long should we wait between notifications for `act_id`?
+fn notify_interval(ct_lk: &CTGuard, act_id: &CTGuardAccountId) -> Duration {
+    ct_lk
+        .account(act_id)
+        .notify_pending_interval
+        .unwrap_or(ct_lk.config().notify_interval)
+}
+
+/// Run the user's `notify_cmd` in the background, with `{account}` and `{url}` replaced by
+/// (shell quoted) `act_name` and `url`. The command's exit status is logged, but otherwise
+/// ignored.
+fn run_notify_cmd(cmd: &str, act_name: &str, url: &Url) -> JoinHandle<()> {
+    let act_name = act_name.to_owned();
+    let cmd = expand_vars(
+        cmd,
+        &[("account", act_name.clone()), ("url", url.to_string())],
+    );
+    thread::spawn(move || {
+        match Command::new("sh")
+            .arg("-c")
+            .arg(&cmd)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .status()
+        {
+            Ok(status) if status.success() => info!("{act_name:}: notify_cmd succeeded"),
+            Ok(status) => error!("{act_name:}: notify_cmd failed: {status:}"),
+            Err(e) => error!("{act_name:}: can't run notify_cmd: {e:}"),
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::{
+        clock::Clock,
+        test_utils::{mock_pstate, CONF_STR},
+        STATE_LEN,
+    };
+    use std::{env, fs, process};
+
+    /// Set account "x" to be pending, last notified at `last_notification`, having been notified
+    /// `notification_count` times.
+    fn set_pending(
+        pstate: &AuthenticatorState,
+        last_notification: Option<Instant>,
+        notification_count: usize,
+    ) {
+        let mut ct_lk = pstate.ct_lock();
+        let act_id = ct_lk.validate_act_name("x").unwrap();
+        ct_lk.tokenstate_replace(
+            act_id,
+            TokenState::Pending {
+                last_notification,
+                notification_count,
+                nonce: None,
+                state: [0; STATE_LEN],
+                url: Url::parse("http://a.com/").unwrap(),
+            },
+        );
+    }
+
+    fn x_notify_at(pstate: &AuthenticatorState) -> Option<Instant> {
+        let ct_lk = pstate.ct_lock();
+        let act_id = ct_lk.validate_act_name("x").unwrap();
+        notify_at(pstate, &ct_lk, &act_id)
+    }
+
+    #[test]
+    fn test_notify_intervals() {
+        let (pstate, clock) = mock_pstate(
+            r#"
+            notify_interval = 10m;
+            account "x" {
+                auth_uri = "http://a.com";
+                client_id = "b";
+                client_secret = "c";
+                scopes = ["d", "e"];
+                redirect_uri = "http://f.com";
+                token_uri = "http://g.com";
+            }
+            "#,
+        );
+
+        assert!(x_notify_at(&pstate).is_none());
+        set_pending(&pstate, None, 0);
+        assert_eq!(x_notify_at(&pstate), Some(clock.now()));
+        set_pending(&pstate, Some(clock.now()), 1);
+        assert_eq!(
+            x_notify_at(&pstate),
+            Some(clock.now() + Duration::from_secs(600))
+        );
+        clock.advance(Duration::from_secs(600));
+        assert_eq!(x_notify_at(&pstate), Some(clock.now()));
+    }
+
+    #[test]
+    fn test_notify_account_overrides() {
+        let (pstate, clock) = mock_pstate(
+            r#"
+            notify_interval = 10m;
+            account "x" {
+                auth_uri = "http://a.com";
+                client_id = "b";
+                client_secret = "c";
+                scopes = ["d", "e"];
+                redirect_uri = "http://f.com";
+                token_uri = "http://g.com";
+                notify_max_count = 2;
+                notify_pending_interval = 1m;
+            }
+            "#,
+        );
+
+        set_pending(&pstate, Some(clock.now()), 1);
+        assert_eq!(
+            x_notify_at(&pstate),
+            Some(clock.now() + Duration::from_secs(60))
+        );
+        set_pending(&pstate, Some(clock.now()), 2);
+        assert!(x_notify_at(&pstate).is_none());
+    }
+
+    #[test]
+    fn test_coalesce_errors() {
+        let (pstate, clock) = mock_pstate(&format!("error_notify_interval = 10m; {CONF_STR:}"));
+        let suppressed =
+            || pstate.notifier.errors.lock().unwrap()[&Some("x".to_owned())].suppressed;
+        let notify = |kind, msg| {
+            pstate
+                .notifier
+                .notify_error(&pstate, Some("x".to_owned()), kind, msg)
+                .unwrap()
+        };
+
+        notify(ErrorKind::TransientNetwork, "e");
+        notify(ErrorKind::TransientNetwork, "e");
+        notify(ErrorKind::TransientNetwork, "e");
+        assert_eq!(suppressed(), 2);
+        // Once the interval has passed, the error is shown again (with the suppressed count).
+        clock.advance(Duration::from_secs(600));
+        notify(ErrorKind::TransientNetwork, "e");
+        assert_eq!(suppressed(), 0);
+        notify(ErrorKind::TransientNetwork, "e");
+        assert_eq!(suppressed(), 1);
+        // A different error, or a reset, means the next error is shown immediately.
+        notify(ErrorKind::RefreshTokenInvalid, "e");
+        assert_eq!(suppressed(), 0);
+        notify(ErrorKind::RefreshTokenInvalid, "e");
+        assert_eq!(suppressed(), 1);
+        pstate.notifier.reset_errors("x");
+        notify(ErrorKind::RefreshTokenInvalid, "e");
+        assert_eq!(suppressed(), 0);
+    }
+    #[test]
+    fn test_notify_cmd() {
+        let p = env::temp_dir().join(format!("pizauth_test_notify_cmd_{}", process::id()));
+        let url = Url::parse("http://a.com/?x=1&y='2'").unwrap();
+        run_notify_cmd(
+            &format!("echo {{account}} {{url}} > '{}'", p.display()),
+            "x y",
+            &url,
+        )
+        .join()
+        .unwrap();
+        assert_eq!(
+            fs::read_to_string(&p).unwrap(),
+            "x y http://a.com/?x=1&y=%272%27\n"
+        );
+        fs::remove_file(&p).unwrap();
+        // A failing command is only logged.
+        run_notify_cmd("exit 1", "x", &url).join().unwrap();
+    }
+}
@@ -0,0 +1,197 @@
+//! `pizauth server --one-shot`: authenticate every account, write their tokens as JSON, and then
+//! exit, for use in scripts and CI pipelines where no long-lived server is wanted.
+
+use std::{
+    error::Error,
+    fs::OpenOptions,
+    io::{self, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::PathBuf,
+    sync::Arc,
+    thread,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use json::JsonValue;
+
+use super::{request_token::request_token, AuthenticatorState, TokenState};
+
+/// How often do we check whether all accounts have been authenticated?
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The options for one-shot mode.
+pub struct OneShot {
+    /// Where to write the tokens to. If `None`, they are written to stdout.
+    pub output: Option<PathBuf>,
+    /// How long to wait for all accounts to be authenticated. If `None`, wait forever.
+    pub timeout: Option<Duration>,
+}
+
+/// Request a token for every account, printing the URLs the user must visit to stderr, and wait
+/// until all accounts have an active token, at which point the tokens are written out. Returns an
+/// error if not all accounts were authenticated before `one_shot.timeout`: each account is only
+/// asked to authenticate once.
+pub fn one_shot(pstate: Arc<AuthenticatorState>, one_shot: OneShot) -> Result<(), Box<dyn Error>> {
+    let deadline = one_shot.timeout.map(|d| pstate.clock.now() + d);
+    let ct_lk = pstate.ct_lock();
+    let mut act_names = ct_lk
+        .act_ids()
+        .map(|act_id| ct_lk.account(&act_id).name.clone())
+        .collect::<Vec<_>>();
+    let to_request = ct_lk
+        .accounts_requiring_auth()
+        .into_iter()
+        .map(|x| x.to_owned())
+        .collect::<Vec<_>>();
+    drop(ct_lk);
+    act_names.sort();
+
+    for act_name in &to_request {
+        let ct_lk = pstate.ct_lock();
+        let act_id = ct_lk
+            .validate_act_name(act_name)
+            .ok_or_else(|| format!("{act_name:}: account removed"))?;
+        // The tokenstate may have changed since we released the lock.
+        if let TokenState::Empty | TokenState::Failed { .. } = ct_lk.tokenstate(&act_id) {
+            request_token(Arc::clone(&pstate), ct_lk, act_id)
+                .map_err(|e| format!("{act_name:}: {e:}"))?;
+        }
+    }
+    let ct_lk = pstate.ct_lock();
+    for act_name in &act_names {
+        if let Some(act_id) = ct_lk.validate_act_name(act_name) {
+            if let TokenState::Pending { url, .. } = ct_lk.tokenstate(&act_id) {
+                eprintln!("{act_name:}: {url:}");
+            }
+        }
+    }
+    drop(ct_lk);
+
+    loop {
+        match tokens(&pstate) {
+            Ok(tokens) => return write_tokens(one_shot.output, tokens),
+            Err(pending) => {
+                if deadline.is_some_and(|d| pstate.clock.now() >= d) {
+                    return Err(format!(
+                        "Timed out waiting for {} to authenticate",
+                        pending.join(", ")
+                    )
+                    .into());
+                }
+            }
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// If every account has an unexpired access token, return them as a JSON object keyed by account
+/// name, otherwise return the (sorted) names of the accounts which don't.
+fn tokens(pstate: &AuthenticatorState) -> Result<JsonValue, Vec<String>> {
+    let now = pstate.clock.wall_now();
+    let ct_lk = pstate.ct_lock();
+    let mut tokens = JsonValue::new_object();
+    let mut pending = Vec::new();
+    for act_id in ct_lk.act_ids() {
+        let act_name = &ct_lk.account(&act_id).name;
+        match ct_lk.tokenstate(&act_id) {
+            // A token restored with `--migrate-v1` has an empty access token and is immediately
+            // expired: it must be refreshed before we can use it.
+            TokenState::Active {
+                access_token,
+                expiry,
+                id_token,
+                refresh_token,
+                ..
+            } if *expiry > now => {
+                let mut token = JsonValue::new_object();
+                token["access_token"] = access_token.expose().into();
+                token["expiry"] = expiry
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or(0)
+                    .into();
+                if let Some(x) = id_token {
+                    token["id_token"] = x.expose().into();
+                }
+                if let Some(x) = refresh_token {
+                    token["refresh_token"] = x.expose().into();
+                }
+                tokens[act_name.as_str()] = token;
+            }
+            _ => pending.push(act_name.clone()),
+        }
+    }
+    if pending.is_empty() {
+        Ok(tokens)
+    } else {
+        pending.sort();
+        Err(pending)
+    }
+}
+
+/// Write `tokens` to `output` (which is only readable by the user) or, if `output` is `None`, to
+/// stdout.
+fn write_tokens(output: Option<PathBuf>, tokens: JsonValue) -> Result<(), Box<dyn Error>> {
+    let s = tokens.pretty(2);
+    match output {
+        Some(p) => {
+            let mut f = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .mode(0o600)
+                .open(&p)
+                .map_err(|e| format!("Can't write {}: {e:}", p.display()))?;
+            writeln!(f, "{s:}")?;
+        }
+        None => writeln!(io::stdout(), "{s:}")?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        secret::SecretString,
+        server::test_utils::{mock_pstate, CONF_STR},
+    };
+
+    #[test]
+    fn test_tokens() {
+        let (pstate, _) = mock_pstate(CONF_STR);
+        assert_eq!(tokens(&pstate).unwrap_err(), vec!["x".to_owned()]);
+
+        let set_expiry = |expiry| {
+            let mut ct_lk = pstate.ct_lock();
+            let act_id = ct_lk.validate_act_name("x").unwrap();
+            ct_lk.tokenstate_replace(
+                act_id,
+                TokenState::Active {
+                    access_token: SecretString::from("a"),
+                    refreshed_at: pstate.clock.now(),
+                    last_refresh_attempt: None,
+                    consecutive_refresh_failures: 0,
+                    expiry,
+                    id_token: None,
+                    refresh_token: Some(SecretString::from("r")),
+                    display_name: None,
+                },
+            );
+        };
+        // An expired token (e.g. one restored by `--migrate-v1`) isn't good enough...
+        set_expiry(pstate.clock.wall_now());
+        assert!(tokens(&pstate).is_err());
+        // ...but an unexpired one is.
+        let expiry = pstate.clock.wall_now() + Duration::from_secs(60);
+        set_expiry(expiry);
+        let t = tokens(&pstate).unwrap();
+        assert_eq!(t["x"]["access_token"], "a");
+        assert_eq!(t["x"]["refresh_token"], "r");
+        assert!(t["x"]["id_token"].is_null());
+        assert_eq!(
+            t["x"]["expiry"],
+            expiry.duration_since(UNIX_EPOCH).unwrap().as_secs()
+        );
+    }
+}
@@ -3,21 +3,32 @@ use std::{
     error::Error,
     sync::{Arc, Condvar, Mutex},
     thread,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 #[cfg(debug_assertions)]
 use log::debug;
-use log::error;
+use log::{error, info};
+use regex::Regex;
 
-use super::{AuthenticatorState, CTGuard, CTGuardAccountId, TokenState};
+use super::{
+    audit::iso8601, is_transient, make_token_request, request_token::request_token,
+    state::RefreshAttempt, token_response::TokenResponse, AuthenticatorState, CTGuard,
+    CTGuardAccountId, TokenState,
+};
+use crate::{config::AuthFlow, frontends::ErrorKind, secret::SecretString};
+
+/// How far the wall-clock must get ahead of the monotonic clock before we consider that a clock
+/// jump has occurred.
+const CLOCK_JUMP_THRESHOLD: Duration = Duration::from_secs(30);
 
 /// The outcome of an attempted refresh.
 pub enum RefreshKind {
     /// Refreshing terminated because the config or tokenstate changed.
     AccountOrTokenStateChanged,
-    /// Refreshing failed in a way that is likely to repeat if retried.
-    PermanentError(String),
+    /// Refreshing failed, for a reason of kind [ErrorKind], in a way that is likely to repeat if
+    /// retried.
+    PermanentError(ErrorKind, String),
     /// The token was refreshed.
     Refreshed,
     /// Refreshing failed but in a way that is not likely to repeat if retried.
@@ -27,181 +38,353 @@ pub enum RefreshKind {
 pub struct Refresher {
     pred: Mutex<bool>,
     condvar: Condvar,
+    /// If `Some`, overrides the config's `refresh_check_interval`.
+    check_interval: Option<Duration>,
 }
 
 impl Refresher {
-    pub fn new() -> Arc<Self> {
+    pub fn new(check_interval: Option<Duration>) -> Arc<Self> {
         Arc::new(Refresher {
             pred: Mutex::new(false),
             condvar: Condvar::new(),
+            check_interval,
         })
     }
 
+    /// The maximum time the refresher will sleep before checking whether the system clock has
+    /// jumped forward (e.g. because the system was suspended).
+    fn check_interval(&self, pstate: &AuthenticatorState) -> Duration {
+        self.check_interval
+            .unwrap_or_else(|| pstate.ct_lock().config().refresh_check_interval)
+    }
+
     /// For a [TokenState::Active] token for `act_id`, refresh it, blocking until the token is
     /// refreshed or an error occurred. This function must be called with a [TokenState::Active]
-    /// tokenstate.
+    /// tokenstate, unless `act_id` is an [AuthFlow::ClientCredentials] account, for which
+    /// "refreshing" means rerunning the grant, and which can also be in the [TokenState::Empty]
+    /// or [TokenState::Failed] states.
     pub fn refresh(
         &self,
         pstate: &AuthenticatorState,
         mut ct_lk: CTGuard,
         mut act_id: CTGuardAccountId,
     ) -> Result<RefreshKind, Box<dyn Error>> {
-        let refresh_token = match ct_lk.tokenstate(&act_id) {
+        let client_credentials = ct_lk.account(&act_id).auth_flow == AuthFlow::ClientCredentials;
+        let (refresh_token, old_id_token, old_display_name, old_expiry) = match ct_lk
+            .tokenstate(&act_id)
+        {
             TokenState::Active {
                 refresh_token: Some(refresh_token),
+                id_token,
+                display_name,
+                expiry,
+                ..
+            } => (
+                Some(refresh_token.clone()),
+                id_token.clone(),
+                display_name.clone(),
+                *expiry,
+            ),
+            TokenState::Active {
+                id_token,
+                display_name,
+                expiry,
                 ..
-            } => refresh_token.to_owned(),
+            } if client_credentials => (None, id_token.clone(), display_name.clone(), *expiry),
+            TokenState::Empty if client_credentials => (None, None, None, pstate.clock.wall_now()),
+            TokenState::Failed {
+                previous_expiry, ..
+            } if client_credentials => (None, None, None, *previous_expiry),
             _ => return Err("tokenstate is not TokenState::Active".into()),
         };
 
+        let now = pstate.clock.now();
         let mut new_ts = ct_lk.tokenstate(&act_id).clone();
         if let TokenState::Active {
             ref mut last_refresh_attempt,
             ..
         } = new_ts
         {
-            *last_refresh_attempt = Some(Instant::now());
+            // The server has asked us not to try again yet, and we'd only be rejected if we did.
+            if let Some(t) = last_refresh_attempt
+                .and_then(|x| x.retry_after)
+                .filter(|t| *t > now)
+            {
+                let until = pstate.clock.wall_now() + (t - now);
+                return Ok(RefreshKind::TransitoryError(format!(
+                    "rate limited by provider until {}",
+                    iso8601(until)
+                )));
+            }
+            *last_refresh_attempt = Some(RefreshAttempt {
+                at: now,
+                retry_after: None,
+            });
             act_id = ct_lk.tokenstate_replace(act_id, new_ts);
         }
+        ct_lk.metrics_mut(&act_id).refreshes_attempted += 1;
 
-        let act = ct_lk.account(&act_id);
-        let token_uri = act.token_uri.clone();
+        let act = Arc::clone(&ct_lk.config().accounts[&ct_lk.account(&act_id).name]);
+        let transport_desc = act.transport_desc(&act.token_uri);
         let client_id = act.client_id.clone();
         let client_secret = act.client_secret.clone();
-        let pairs = [
+        let not_transient_error_if = act.not_transient_error_if.clone();
+        let transient_error_if = act.transient_error_if.clone();
+        drop(ct_lk);
+
+        // Only the client credentials grant sends scopes. A `scopes_cmd` can take an arbitrary
+        // amount of time to run, so we mustn't hold the lock while it does so.
+        let scope = match refresh_token {
+            Some(_) => String::new(),
+            None => act.scope_param(&act.scopes()),
+        };
+        let mut pairs = vec![
             ("client_id", client_id.as_str()),
-            ("client_secret", client_secret.as_str()),
-            ("refresh_token", refresh_token.as_str()),
-            ("grant_type", "refresh_token"),
+            ("client_secret", client_secret.expose()),
         ];
+        match &refresh_token {
+            Some(refresh_token) => {
+                pairs.push(("refresh_token", refresh_token.expose()));
+                pairs.push(("grant_type", "refresh_token"));
+            }
+            None => {
+                if !scope.is_empty() {
+                    pairs.push(("scope", scope.as_str()));
+                }
+                pairs.push(("grant_type", "client_credentials"));
+            }
+        }
 
-        drop(ct_lk);
-        let body = match ureq::post(token_uri.as_str()).send_form(&pairs) {
-            Ok(response) => match response.into_string() {
-                Ok(s) => s,
-                Err(e) => {
-                    return Ok(RefreshKind::TransitoryError(e.to_string()));
+        let _inflight = pstate.inflight();
+        let (content_type, body) = match make_token_request(&act, &pairs) {
+            Ok(response) => match (response.content_type().to_owned(), response.into_string()) {
+                // The body contains secrets.
+                (content_type, Ok(s)) => (content_type, SecretString::from(s)),
+                (_, Err(e)) => {
+                    return Ok(refresh_error(
+                        pstate,
+                        act_id,
+                        old_expiry,
+                        &not_transient_error_if,
+                        &transient_error_if,
+                        ErrorKind::TransientNetwork,
+                        format!("{e:}{transport_desc:}"),
+                    ));
                 }
             },
-            Err(ureq::Error::Status(code, response)) => {
-                let reason = match response.into_string() {
-                    Ok(r) => format!("{code:}: {r:}"),
-                    Err(_) => format!("{code:}"),
-                };
-                let mut ct_lk = pstate.ct_lock();
-                match ct_lk.validate_act_id(act_id) {
-                    Some(act_id) => {
-                        ct_lk.tokenstate_replace(act_id, TokenState::Empty);
-                        return Ok(RefreshKind::PermanentError(reason));
+            Err(e) => {
+                // A network error or 5xx response (e.g. the server is temporarily overloaded)
+                // says nothing about whether our refresh token is still valid.
+                let transient = is_transient(&e);
+                let mut reason_suffix = String::new();
+                if let ureq::Error::Status(429 | 503, response) = &e {
+                    if let Some(d) = response
+                        .header("Retry-After")
+                        .and_then(|x| parse_retry_after(x, pstate.clock.wall_now()))
+                    {
+                        act_id = match set_retry_after(pstate, act_id, d) {
+                            Some(x) => x,
+                            None => return Ok(RefreshKind::AccountOrTokenStateChanged),
+                        };
+                        reason_suffix = format!(" (retry after {}s)", d.as_secs());
                     }
-                    None => return Ok(RefreshKind::AccountOrTokenStateChanged),
                 }
+                let (kind, reason) = match e {
+                    ureq::Error::Status(code, response) => {
+                        let content_type = response.content_type().to_owned();
+                        match response.into_string() {
+                            Ok(r) => {
+                                let err = TokenResponse::parse(
+                                    &content_type,
+                                    &SecretString::from(r.as_str()),
+                                )
+                                .ok()
+                                .and_then(|x| x.error);
+                                (rejection_kind(err.as_deref()), format!("{code:}: {r:}"))
+                            }
+                            Err(_) => (rejection_kind(None), format!("{code:}")),
+                        }
+                    }
+                    e => (
+                        ErrorKind::TokenEndpointRejected,
+                        format!("{e:}{transport_desc:}"),
+                    ),
+                };
+                let kind = if transient {
+                    ErrorKind::TransientNetwork
+                } else {
+                    kind
+                };
+                return Ok(refresh_error(
+                    pstate,
+                    act_id,
+                    old_expiry,
+                    &not_transient_error_if,
+                    &transient_error_if,
+                    kind,
+                    format!("{reason:}{reason_suffix:}"),
+                ));
             }
-            Err(e) => return Ok(RefreshKind::TransitoryError(e.to_string())),
         };
 
-        let parsed = json::parse(&body)?;
-        if parsed["error"].as_str().is_some() {
+        let parsed = TokenResponse::parse(&content_type, &body)?;
+        if let Some(err) = parsed.error.as_deref() {
             // Refreshing failed. Unfortunately there is no standard way of knowing why it failed, so
             // we take the most pessimistic assumption which is that the refresh token is no longer
             // valid at all.
-            let mut ct_lk = pstate.ct_lock();
-            match ct_lk.validate_act_id(act_id) {
-                Some(act_id) => {
-                    let act_id = ct_lk.tokenstate_replace(act_id, TokenState::Empty);
-                    let msg = format!("Refreshing {} failed", ct_lk.account(&act_id).name);
-                    drop(ct_lk);
-                    return Ok(RefreshKind::PermanentError(msg));
-                }
-                None => return Ok(RefreshKind::AccountOrTokenStateChanged),
-            }
+            let reason = match parsed.error_description.as_deref() {
+                Some(desc) => format!("{err:}: {desc:}"),
+                None => err.to_owned(),
+            };
+            return Ok(refresh_error(
+                pstate,
+                act_id,
+                old_expiry,
+                &not_transient_error_if,
+                &transient_error_if,
+                rejection_kind(Some(err)),
+                reason,
+            ));
         }
 
-        match (
-            parsed["access_token"].as_str(),
-            parsed["expires_in"].as_u64(),
-            parsed["token_type"].as_str(),
-        ) {
-            (Some(access_token), Some(expires_in), Some(token_type)) if token_type == "Bearer" => {
-                let refreshed_at = Instant::now();
-                let expiry = refreshed_at
-                    .checked_add(Duration::from_secs(expires_in))
+        let is_bearer = parsed.is_bearer();
+        let lifetime = parsed.lifetime(act.default_token_lifetime);
+        match parsed.access_token {
+            Some(access_token) if is_bearer => {
+                let refreshed_at = pstate.clock.now();
+                let expiry = pstate
+                    .clock
+                    .wall_now()
+                    .checked_add(lifetime)
                     .ok_or("Can't represent expiry")?;
+                // Servers don't have to send a new ID token when refreshing, in which case we keep
+                // the old one.
+                let id_token = parsed.id_token.or(old_id_token);
                 let mut ct_lk = pstate.ct_lock();
                 match ct_lk.validate_act_id(act_id) {
                     Some(act_id) => {
-                        ct_lk.tokenstate_replace(
+                        let act_id = ct_lk.tokenstate_replace(
                             act_id,
                             TokenState::Active {
-                                access_token: access_token.to_owned(),
+                                access_token,
                                 expiry,
                                 refreshed_at,
                                 last_refresh_attempt: None,
-                                refresh_token: Some(refresh_token),
+                                consecutive_refresh_failures: 0,
+                                id_token,
+                                refresh_token,
+                                // Refreshing doesn't change who the user is.
+                                display_name: old_display_name,
                             },
                         );
+                        ct_lk.clear_last_error(&act_id);
+                        ct_lk.metrics_mut(&act_id).refreshes_succeeded += 1;
+                        let act_name = ct_lk.account(&act_id).name.clone();
                         drop(ct_lk);
+                        pstate.notifier.reset_errors(&act_name);
                         self.notify_changes();
                         Ok(RefreshKind::Refreshed)
                     }
                     None => Ok(RefreshKind::AccountOrTokenStateChanged),
                 }
             }
-            _ => {
-                let mut ct_lk = pstate.ct_lock();
-                match ct_lk.validate_act_id(act_id) {
-                    Some(act_id) => {
-                        ct_lk.tokenstate_replace(act_id, TokenState::Empty);
-                        Ok(RefreshKind::PermanentError(
-                            "Received JSON in unexpected format".to_string(),
-                        ))
-                    }
-                    None => Ok(RefreshKind::AccountOrTokenStateChanged),
-                }
-            }
+            _ => Ok(permanent_error(
+                pstate,
+                act_id,
+                old_expiry,
+                ErrorKind::TokenEndpointRejected,
+                "Received JSON in unexpected format".to_owned(),
+            )),
         }
     }
 
     /// If `act_id` has an active token, return the time when that token should be refreshed.
     fn refresh_at(
         &self,
-        _pstate: &AuthenticatorState,
+        pstate: &AuthenticatorState,
         ct_lk: &CTGuard,
         act_id: &CTGuardAccountId,
     ) -> Option<Instant> {
         match ct_lk.tokenstate(act_id) {
             TokenState::Active {
-                mut expiry,
+                expiry,
                 refreshed_at,
                 last_refresh_attempt,
                 ..
             } => {
                 let act = &ct_lk.account(act_id);
-                if let Some(d) = act.refresh_before_expiry {
-                    expiry = expiry
-                        .checked_sub(d)
-                        .unwrap_or_else(|| cmp::min(Instant::now(), expiry));
+                let now = pstate.clock.now();
+                let mut expiry = wall_to_instant(*expiry, pstate.clock.wall_now(), now);
+                if let (Some(d), Some(t)) = (act.refresh_before_expiry, expiry) {
+                    expiry = Some(t.checked_sub(d).unwrap_or_else(|| cmp::min(now, t)));
                 }
+                // If the token hasn't been used recently, we don't refresh it before it expires,
+                // but we do still honour `refresh_at_least` (which keeps the refresh token alive).
+                let mut refresh_at = if self.unused(pstate, ct_lk, act_id) {
+                    None
+                } else {
+                    expiry
+                };
                 if let Some(d) = act.refresh_at_least {
                     // There is no concept of Instant::MAX, so if `refreshed_at + d` exceeds
                     // Instant's bounds, there's nothing we can fall back on.
                     if let Some(t) = refreshed_at.checked_add(d) {
-                        expiry = cmp::min(expiry, t);
+                        refresh_at = Some(refresh_at.map(|x| cmp::min(x, t)).unwrap_or(t));
                     }
                 }
+                let mut refresh_at = refresh_at?;
                 if let Some(lra) = last_refresh_attempt {
-                    if let Some(t) = lra.checked_add(ct_lk.config().refresh_retry_interval) {
-                        if t > expiry {
-                            return Some(t.to_owned());
-                        }
+                    if let Some(t) = lra.at.checked_add(ct_lk.config().refresh_retry_interval) {
+                        refresh_at = cmp::max(refresh_at, t);
+                    }
+                    // Whatever our own schedule says, we don't try again before the server has said
+                    // we may.
+                    if let Some(t) = lra.retry_after {
+                        refresh_at = cmp::max(refresh_at, t);
                     }
                 }
-                Some(expiry.to_owned())
+                Some(refresh_at)
             }
             _ => None,
         }
     }
 
+    /// Has `act_id`'s token gone unrequested for longer than its `refresh_if_unused_for`?
+    fn unused(
+        &self,
+        pstate: &AuthenticatorState,
+        ct_lk: &CTGuard,
+        act_id: &CTGuardAccountId,
+    ) -> bool {
+        match ct_lk.account(act_id).refresh_if_unused_for {
+            Some(d) => match ct_lk.last_used(act_id).and_then(|t| t.checked_add(d)) {
+                Some(t) => t < pstate.clock.now(),
+                None => true,
+            },
+            None => false,
+        }
+    }
+
+    /// Return the accounts whose tokens are due to be refreshed, ordered so that those whose tokens
+    /// expire soonest come first: refreshing is slow, and when many tokens are due at once we don't
+    /// want a nearly expired token to lapse while we refresh ones which still have plenty of life.
+    fn due(&self, pstate: &AuthenticatorState) -> Vec<CTGuardAccountId> {
+        let ct_lk = pstate.ct_lock();
+        let now = pstate.clock.now();
+        let mut due = ct_lk
+            .act_ids()
+            .filter_map(|act_id| match self.refresh_at(pstate, &ct_lk, &act_id) {
+                Some(t) if t <= now => match ct_lk.tokenstate(&act_id) {
+                    TokenState::Active { expiry, .. } => Some((*expiry, act_id)),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        due.sort_by_key(|(expiry, _)| *expiry);
+        due.into_iter().map(|(_, act_id)| act_id).collect()
+    }
+
     fn next_wakeup(&self, pstate: &AuthenticatorState) -> Option<Instant> {
         let ct_lk = pstate.ct_lock();
         ct_lk
@@ -225,6 +408,9 @@ impl Refresher {
     ) -> Result<(), Box<dyn Error>> {
         thread::spawn(move || loop {
             let next_wakeup = self.next_wakeup(&pstate);
+            let check_interval = self.check_interval(&pstate);
+            let mono_start = pstate.clock.now();
+            let wall_start = pstate.clock.wall_now();
             let mut refresh_lk = self.pred.lock().unwrap();
             while !*refresh_lk {
                 #[cfg(debug_assertions)]
@@ -232,19 +418,33 @@ impl Refresher {
                     "Refresher: next wakeup {}",
                     next_wakeup
                         .map(|x| x
-                            .checked_duration_since(Instant::now())
+                            .checked_duration_since(pstate.clock.now())
                             .map(|x| x.as_secs().to_string())
                             .unwrap_or_else(|| "<none>".to_owned()))
                         .unwrap_or_else(|| "<none>".to_owned())
                 );
                 match next_wakeup {
                     Some(t) => {
-                        if Instant::now() >= t {
+                        if pstate.clock.now() >= t {
                             break;
                         }
-                        match t.checked_duration_since(Instant::now()) {
+                        match t.checked_duration_since(pstate.clock.now()) {
                             Some(d) => {
-                                refresh_lk = self.condvar.wait_timeout(refresh_lk, d).unwrap().0
+                                // Instants may not advance while the system is suspended, so
+                                // we wake up periodically to check if the clock has jumped.
+                                let d = cmp::min(d, check_interval);
+                                refresh_lk = self.condvar.wait_timeout(refresh_lk, d).unwrap().0;
+                                if clock_jumped(
+                                    pstate.clock.now().saturating_duration_since(mono_start),
+                                    pstate
+                                        .clock
+                                        .wall_now()
+                                        .duration_since(wall_start)
+                                        .unwrap_or(Duration::ZERO),
+                                ) {
+                                    info!("Clock jump detected: re-evaluating all tokens");
+                                    break;
+                                }
                             }
                             None => break,
                         }
@@ -256,28 +456,71 @@ impl Refresher {
             *refresh_lk = false;
             drop(refresh_lk);
 
-            let ct_lk = pstate.ct_lock();
-            let now = Instant::now();
-            let to_refresh = ct_lk
-                .act_ids()
-                .filter(|act_id| self.refresh_at(&pstate, &ct_lk, act_id) <= Some(now))
-                .collect::<Vec<_>>();
-            drop(ct_lk);
-
-            for act_id in to_refresh.into_iter() {
+            for act_id in self.due(&pstate).into_iter() {
                 let ct_lk = pstate.ct_lock();
                 if let Some(act_id) = ct_lk.validate_act_id(act_id) {
                     if let TokenState::Active { .. } = ct_lk.tokenstate(&act_id) {
+                        let act_name = ct_lk.account(&act_id).name.clone();
+                        let client_credentials =
+                            ct_lk.account(&act_id).auth_flow == AuthFlow::ClientCredentials;
                         match self.refresh(&pstate, ct_lk, act_id) {
                             Ok(rk) => match rk {
                                 RefreshKind::AccountOrTokenStateChanged
-                                | RefreshKind::Refreshed
-                                | RefreshKind::TransitoryError(_) => (),
-                                RefreshKind::PermanentError(msg) => {
-                                    error!("Token refresh failed: {msg:}")
+                                | RefreshKind::Refreshed => (),
+                                RefreshKind::PermanentError(kind, msg) if client_credentials => {
+                                    // There is nothing for the user to authenticate: the grant is
+                                    // rerun when the token is next requested.
+                                    error!("{act_name:}: {msg:}");
+                                    if let Err(e) = pstate.notifier.notify_error(
+                                        &pstate,
+                                        Some(act_name),
+                                        kind,
+                                        &msg,
+                                    ) {
+                                        error!("{e:}");
+                                    }
+                                }
+                                RefreshKind::PermanentError(kind, msg) => {
+                                    error!("{act_name:}: {msg:}");
+                                    // The user must reauthenticate before they can obtain a token
+                                    // for this account again, so they need to know about this.
+                                    let msg = format!("{msg:}; reauthentication required");
+                                    if let Err(e) = pstate.notifier.notify_error(
+                                        &pstate,
+                                        Some(act_name.clone()),
+                                        kind,
+                                        &msg,
+                                    ) {
+                                        error!("{e:}");
+                                    }
+                                    if let Err(e) = reauthenticate(&pstate, &act_name) {
+                                        error!("{act_name:}: {e:}");
+                                    }
+                                }
+                                RefreshKind::TransitoryError(msg) => {
+                                    // The frontend decides whether this is worth bothering the
+                                    // user with: we'll retry regardless.
+                                    if let Err(e) = pstate.notifier.notify_error(
+                                        &pstate,
+                                        Some(act_name),
+                                        ErrorKind::TransientNetwork,
+                                        &msg,
+                                    ) {
+                                        error!("{e:}");
+                                    }
                                 }
                             },
-                            Err(e) => error!("Token refresh failed: {e:}"),
+                            Err(e) => {
+                                error!("Token refresh failed: {e:}");
+                                if let Err(e) = pstate.notifier.notify_error(
+                                    &pstate,
+                                    Some(act_name),
+                                    ErrorKind::Internal,
+                                    &format!("Token refresh failed: {e:}"),
+                                ) {
+                                    error!("{e:}");
+                                }
+                            }
                         }
                     }
                 }
@@ -287,3 +530,814 @@ impl Refresher {
         Ok(())
     }
 }
+
+/// If `act_name`'s token is still [TokenState::Failed], start a new authentication, so that the
+/// user is sent the URL they need straight after being told that refreshing failed. Thereafter,
+/// the user is reminded of the URL at the account's normal notification interval.
+fn reauthenticate(pstate: &Arc<AuthenticatorState>, act_name: &str) -> Result<(), Box<dyn Error>> {
+    let ct_lk = pstate.ct_lock();
+    match ct_lk.validate_act_name(act_name) {
+        Some(act_id) if matches!(ct_lk.tokenstate(&act_id), TokenState::Failed { .. }) => {
+            request_token(Arc::clone(pstate), ct_lk, act_id)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Convert the wall-clock time `t` into an [Instant], given that `wall_now` and `mono_now`
+/// represent the same moment. Times in the past are clamped to `mono_now`. Returns `None` if `t` is
+/// too far in the future to be represented as an [Instant].
+fn wall_to_instant(t: SystemTime, wall_now: SystemTime, mono_now: Instant) -> Option<Instant> {
+    match t.duration_since(wall_now) {
+        Ok(d) => mono_now.checked_add(d),
+        Err(_) => Some(mono_now),
+    }
+}
+
+/// Given the elapsed monotonic and wall-clock times over the same period, has the wall-clock jumped
+/// forward (most likely because the system was suspended)?
+fn clock_jumped(mono_elapsed: Duration, wall_elapsed: Duration) -> bool {
+    wall_elapsed > mono_elapsed + CLOCK_JUMP_THRESHOLD
+}
+
+/// Record `msg` as the last error of `act_id` (if it is still valid) and return a
+/// [RefreshKind::TransitoryError]. If this failure means that the account's `max_refresh_failures`
+/// has been reached, it is instead treated as a [permanent_error].
+fn transitory_error(
+    pstate: &AuthenticatorState,
+    act_id: CTGuardAccountId,
+    previous_expiry: SystemTime,
+    msg: String,
+) -> RefreshKind {
+    let mut ct_lk = pstate.ct_lock();
+    if let Some(mut act_id) = ct_lk.validate_act_id(act_id) {
+        let max_refresh_failures = ct_lk.account(&act_id).max_refresh_failures;
+        let mut new_ts = ct_lk.tokenstate(&act_id).clone();
+        if let TokenState::Active {
+            ref mut consecutive_refresh_failures,
+            ..
+        } = new_ts
+        {
+            *consecutive_refresh_failures += 1;
+            let failures = *consecutive_refresh_failures;
+            if max_refresh_failures
+                .is_some_and(|x| usize::try_from(failures).unwrap_or(usize::MAX) >= x)
+            {
+                drop(ct_lk);
+                // We've given up on the refresh token, even if the server hasn't.
+                return permanent_error(
+                    pstate,
+                    act_id,
+                    previous_expiry,
+                    ErrorKind::RefreshTokenInvalid,
+                    format!("{msg:} (failed {failures:} times in a row)"),
+                );
+            }
+            act_id = ct_lk.tokenstate_replace(act_id, new_ts);
+        }
+        ct_lk.set_last_error(&act_id, format!("Refreshing failed: {msg:}"));
+        ct_lk.metrics_mut(&act_id).refreshes_failed += 1;
+    }
+    RefreshKind::TransitoryError(msg)
+}
+
+/// Parse the value `v` of a `Retry-After` header (RFC 9110 section 10.2.3), which is either a
+/// number of seconds or an HTTP-date, into how long we must wait from `wall_now`. Only the
+/// preferred IMF-fixdate form of HTTP-date (e.g. `Sun, 06 Nov 1994 08:49:37 GMT`) is supported.
+fn parse_retry_after(v: &str, wall_now: SystemTime) -> Option<Duration> {
+    let v = v.trim();
+    if let Ok(secs) = v.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let (_, date) = v.split_once(", ")?;
+    let fields = date.split(' ').collect::<Vec<_>>();
+    let [day, month, year, time, "GMT"] = fields.as_slice() else {
+        return None;
+    };
+    let day = day.parse::<u64>().ok()?;
+    let month = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ]
+    .iter()
+    .position(|x| x == month)? as u64
+        + 1;
+    let year = year.parse::<u64>().ok()?;
+    let hms = time
+        .split(':')
+        .map(|x| x.parse::<u64>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    let [h, m, s] = hms.as_slice() else {
+        return None;
+    };
+    if year < 1970 || !(1..=31).contains(&day) || *h > 23 || *m > 59 || *s > 60 {
+        return None;
+    }
+    // Convert the date to days since the epoch, using the algorithm from
+    // http://howardhinnant.github.io/date_algorithms.html#days_from_civil.
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y / 400;
+    let yoe = y - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = (era * 146097 + doe).checked_sub(719468)?;
+    let t = UNIX_EPOCH + Duration::from_secs(days * 86400 + h * 3600 + m * 60 + s);
+    // A date in the past means that we can try again immediately.
+    Some(t.duration_since(wall_now).unwrap_or(Duration::ZERO))
+}
+
+/// Record that the server has told us not to try refreshing `act_id` for `d`. Returns `None` if
+/// `act_id` is no longer valid.
+fn set_retry_after(
+    pstate: &AuthenticatorState,
+    act_id: CTGuardAccountId,
+    d: Duration,
+) -> Option<CTGuardAccountId> {
+    let mut ct_lk = pstate.ct_lock();
+    let act_id = ct_lk.validate_act_id(act_id)?;
+    let mut new_ts = ct_lk.tokenstate(&act_id).clone();
+    match new_ts {
+        TokenState::Active {
+            ref mut last_refresh_attempt,
+            ..
+        } => {
+            let now = pstate.clock.now();
+            *last_refresh_attempt = Some(RefreshAttempt {
+                at: last_refresh_attempt.map(|x| x.at).unwrap_or(now),
+                retry_after: now.checked_add(d),
+            });
+            Some(ct_lk.tokenstate_replace(act_id, new_ts))
+        }
+        _ => Some(act_id),
+    }
+}
+
+/// Classify the OAuth2 `error` code (if any) with which a server rejected a refresh. RFC 6749
+/// section 5.2 uses `invalid_grant` for a refresh token which is no longer valid, and the other
+/// codes it defines for requests which were rejected for other reasons (e.g. an unknown client
+/// ID). Non-standard (or missing) codes are assumed to be a problem with the refresh token.
+fn rejection_kind(error: Option<&str>) -> ErrorKind {
+    match error {
+        Some(
+            "invalid_request"
+            | "invalid_client"
+            | "unauthorized_client"
+            | "unsupported_grant_type"
+            | "invalid_scope",
+        ) => ErrorKind::TokenEndpointRejected,
+        _ => ErrorKind::RefreshTokenInvalid,
+    }
+}
+
+/// Handle a failed refresh of `act_id` whose cause, of kind `kind`, is described by `reason`. By
+/// default the failure is treated as transient if `kind` is [ErrorKind::TransientNetwork], but if
+/// `reason` matches one of `not_transient_error_if` (or, respectively, `transient_error_if`), that
+/// is overridden.
+fn refresh_error(
+    pstate: &AuthenticatorState,
+    act_id: CTGuardAccountId,
+    previous_expiry: SystemTime,
+    not_transient_error_if: &[Regex],
+    transient_error_if: &[Regex],
+    kind: ErrorKind,
+    reason: String,
+) -> RefreshKind {
+    if kind == ErrorKind::TransientNetwork {
+        match not_transient_error_if
+            .iter()
+            .find(|re| re.is_match(&reason))
+        {
+            // The user has told us that this error means the refresh token is no good.
+            Some(re) => permanent_error(
+                pstate,
+                act_id,
+                previous_expiry,
+                ErrorKind::RefreshTokenInvalid,
+                format!("{reason:} (matches not_transient_error_if \"{re:}\")"),
+            ),
+            None => transitory_error(pstate, act_id, previous_expiry, reason),
+        }
+    } else {
+        match transient_error_if.iter().find(|re| re.is_match(&reason)) {
+            Some(re) => transitory_error(
+                pstate,
+                act_id,
+                previous_expiry,
+                format!("{reason:} (matches transient_error_if \"{re:}\")"),
+            ),
+            None => permanent_error(pstate, act_id, previous_expiry, kind, reason),
+        }
+    }
+}
+
+/// Move `act_id` (if it is still valid) to [TokenState::Failed] because refreshing its token
+/// failed for `reason` (of kind `kind`), from which we assume it can't recover without the user
+/// reauthenticating. `previous_expiry` is the expiry time of the token that could not be
+/// refreshed.
+fn permanent_error(
+    pstate: &AuthenticatorState,
+    act_id: CTGuardAccountId,
+    previous_expiry: SystemTime,
+    kind: ErrorKind,
+    reason: String,
+) -> RefreshKind {
+    let mut ct_lk = pstate.ct_lock();
+    match ct_lk.validate_act_id(act_id) {
+        Some(act_id) => {
+            let act_id = ct_lk.tokenstate_replace(
+                act_id,
+                TokenState::Failed {
+                    reason: reason.clone(),
+                    failed_at: pstate.clock.now(),
+                    previous_expiry,
+                },
+            );
+            let msg = format!("Refreshing failed: {reason:}");
+            ct_lk.set_last_error(&act_id, msg.clone());
+            ct_lk.metrics_mut(&act_id).refreshes_failed += 1;
+            RefreshKind::PermanentError(kind, msg)
+        }
+        None => RefreshKind::AccountOrTokenStateChanged,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{
+        fs,
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        sync::mpsc,
+    };
+
+    use crate::{
+        config::Config,
+        server::{
+            clock::Clock,
+            test_utils::{mock_pstate, CONF_STR},
+        },
+    };
+
+    /// Install an active token for account "x" which expires `expires_in` seconds from now.
+    fn install(pstate: &AuthenticatorState, expires_in: u64) {
+        let mut ct_lk = pstate.ct_lock();
+        let act_id = ct_lk.validate_act_name("x").unwrap();
+        ct_lk.tokenstate_replace(
+            act_id,
+            TokenState::Active {
+                access_token: SecretString::from("a"),
+                refreshed_at: pstate.clock.now(),
+                last_refresh_attempt: None,
+                consecutive_refresh_failures: 0,
+                expiry: pstate.clock.wall_now() + Duration::from_secs(expires_in),
+                id_token: None,
+                refresh_token: Some(SecretString::from("r")),
+                display_name: None,
+            },
+        );
+    }
+
+    fn refresh_at(pstate: &AuthenticatorState) -> Option<Instant> {
+        let ct_lk = pstate.ct_lock();
+        let act_id = ct_lk.validate_act_name("x").unwrap();
+        pstate.refresher.refresh_at(pstate, &ct_lk, &act_id)
+    }
+
+    #[test]
+    fn test_wakeup_moves_earlier() {
+        let (pstate, clock) = mock_pstate(CONF_STR);
+
+        assert!(pstate.refresher.next_wakeup(&pstate).is_none());
+        install(&pstate, 3600);
+        let wakeup1 = pstate.refresher.next_wakeup(&pstate).unwrap();
+        install(&pstate, 120);
+        let wakeup2 = pstate.refresher.next_wakeup(&pstate).unwrap();
+        assert!(wakeup2 < wakeup1);
+        assert_eq!(wakeup2, clock.now() + Duration::from_secs(30));
+
+        // Reloading the config must wake the refresher up.
+        *pstate.refresher.pred.lock().unwrap() = false;
+        pstate.update_conf(Config::from_str(CONF_STR).unwrap());
+        assert!(*pstate.refresher.pred.lock().unwrap());
+    }
+
+    #[test]
+    fn test_due_order() {
+        let conf_str = r#"
+            account "x" {
+                auth_uri = "http://a.com";
+                client_id = "b";
+                client_secret = "c";
+                scopes = ["d"];
+                redirect_uri = "http://f.com";
+                token_uri = "http://g.com";
+            }
+            account "y" {
+                auth_uri = "http://a.com";
+                client_id = "b";
+                client_secret = "c";
+                scopes = ["d"];
+                redirect_uri = "http://f.com";
+                token_uri = "http://g.com";
+            }
+        "#;
+        let (pstate, _) = mock_pstate(conf_str);
+        let set_expiry = |act_name, expires_in| {
+            let mut ct_lk = pstate.ct_lock();
+            let act_id = ct_lk.validate_act_name(act_name).unwrap();
+            ct_lk.tokenstate_replace(
+                act_id,
+                TokenState::Active {
+                    access_token: SecretString::from("a"),
+                    refreshed_at: pstate.clock.now(),
+                    last_refresh_attempt: None,
+                    consecutive_refresh_failures: 0,
+                    expiry: pstate.clock.wall_now() + Duration::from_secs(expires_in),
+                    id_token: None,
+                    refresh_token: Some(SecretString::from("r")),
+                    display_name: None,
+                },
+            );
+        };
+        let due = |pstate: &AuthenticatorState| {
+            let ids = pstate.refresher.due(pstate);
+            let ct_lk = pstate.ct_lock();
+            ids.into_iter()
+                .map(|act_id| {
+                    let act_id = ct_lk.validate_act_id(act_id).unwrap();
+                    ct_lk.account(&act_id).name.clone()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert!(due(&pstate).is_empty());
+        set_expiry("x", 60);
+        set_expiry("y", 10);
+        assert_eq!(due(&pstate), vec!["y".to_owned(), "x".to_owned()]);
+        set_expiry("y", 3600);
+        assert_eq!(due(&pstate), vec!["x".to_owned()]);
+    }
+
+    #[test]
+    fn test_reauthenticate() {
+        let (pstate, _) = mock_pstate(CONF_STR);
+        let pstate = Arc::new(pstate);
+        let tokenstate = || {
+            let ct_lk = pstate.ct_lock();
+            let act_id = ct_lk.validate_act_name("x").unwrap();
+            ct_lk.tokenstate(&act_id).clone()
+        };
+
+        // Only a failed token is reauthenticated...
+        install(&pstate, 3600);
+        reauthenticate(&pstate, "x").unwrap();
+        assert!(matches!(tokenstate(), TokenState::Active { .. }));
+        {
+            let mut ct_lk = pstate.ct_lock();
+            let act_id = ct_lk.validate_act_name("x").unwrap();
+            ct_lk.tokenstate_replace(
+                act_id,
+                TokenState::Failed {
+                    reason: "invalid_grant".to_owned(),
+                    failed_at: pstate.clock.now(),
+                    previous_expiry: pstate.clock.wall_now(),
+                },
+            );
+        }
+        // ...and it is immediately pending notification.
+        reauthenticate(&pstate, "x").unwrap();
+        assert!(matches!(
+            tokenstate(),
+            TokenState::Pending {
+                last_notification: None,
+                ..
+            }
+        ));
+        assert!(reauthenticate(&pstate, "y").is_ok());
+    }
+
+    #[test]
+    fn test_expiry_boundaries() {
+        let (pstate, clock) = mock_pstate(CONF_STR);
+
+        // With the default `refresh_before_expiry` of 90s, a token expiring in 100s must be
+        // refreshed in exactly 10s.
+        install(&pstate, 100);
+        assert_eq!(
+            refresh_at(&pstate),
+            Some(clock.now() + Duration::from_secs(10))
+        );
+        clock.advance(Duration::from_secs(9));
+        assert!(refresh_at(&pstate).unwrap() > clock.now());
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(refresh_at(&pstate), Some(clock.now()));
+
+        // A token which expires sooner than `refresh_before_expiry` must be refreshed now.
+        install(&pstate, 30);
+        assert!(refresh_at(&pstate).unwrap() <= clock.now());
+
+        // Without `refresh_before_expiry`'s influence, `refresh_at_least` (default 90m) applies.
+        install(&pstate, 86400);
+        assert_eq!(
+            refresh_at(&pstate),
+            Some(clock.now() + Duration::from_secs(90 * 60))
+        );
+
+        // After a suspend, the monotonic clock hasn't moved, but the token has expired.
+        install(&pstate, 3600);
+        clock.suspend(Duration::from_secs(7200));
+        assert!(refresh_at(&pstate).unwrap() <= clock.now());
+    }
+
+    #[test]
+    fn test_refresh_backoff() {
+        let (pstate, clock) = mock_pstate(CONF_STR);
+
+        install(&pstate, 30);
+        {
+            let mut ct_lk = pstate.ct_lock();
+            let act_id = ct_lk.validate_act_name("x").unwrap();
+            let mut ts = ct_lk.tokenstate(&act_id).clone();
+            if let TokenState::Active {
+                ref mut last_refresh_attempt,
+                ..
+            } = ts
+            {
+                *last_refresh_attempt = Some(RefreshAttempt {
+                    at: clock.now(),
+                    retry_after: None,
+                });
+            }
+            ct_lk.tokenstate_replace(act_id, ts);
+        }
+        // The default `refresh_retry_interval` is 40s.
+        assert_eq!(
+            refresh_at(&pstate),
+            Some(clock.now() + Duration::from_secs(40))
+        );
+        clock.advance(Duration::from_secs(39));
+        assert!(refresh_at(&pstate).unwrap() > clock.now());
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(refresh_at(&pstate), Some(clock.now()));
+    }
+
+    #[test]
+    fn test_unused() {
+        let (pstate, clock) = mock_pstate(
+            r#"
+            account "x" {
+                auth_uri = "http://a.com";
+                client_id = "b";
+                client_secret = "c";
+                scopes = ["d", "e"];
+                redirect_uri = "http://f.com";
+                token_uri = "http://g.com";
+                refresh_if_unused_for = 1h;
+            }
+            "#,
+        );
+
+        install(&pstate, 100);
+        // The token has never been used so only `refresh_at_least` applies.
+        assert_eq!(
+            refresh_at(&pstate),
+            Some(clock.now() + Duration::from_secs(90 * 60))
+        );
+        {
+            let mut ct_lk = pstate.ct_lock();
+            let act_id = ct_lk.validate_act_name("x").unwrap();
+            ct_lk.set_last_used(&act_id);
+        }
+        assert_eq!(
+            refresh_at(&pstate),
+            Some(clock.now() + Duration::from_secs(10))
+        );
+        clock.advance(Duration::from_secs(3601));
+        assert_eq!(
+            refresh_at(&pstate),
+            Some(clock.now() + Duration::from_secs(90 * 60 - 3601))
+        );
+    }
+
+    #[test]
+    fn test_lock_not_held_during_refresh() {
+        // While a refresh is waiting on the network, and while a `pizauth show` is waiting on
+        // another account's `scopes_cmd`, other users of the lock (e.g. many concurrent `pizauth
+        // show`s, each of which writes to the audit log) must not be blocked.
+        let dir =
+            std::env::temp_dir().join(format!("pizauth_test_lock_not_held_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (started, release) = (dir.join("started"), dir.join("release"));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let x_conf = CONF_STR.replace("http://g.com", &format!("http://127.0.0.1:{port:}/"));
+        let y_conf = CONF_STR.replace(r#"account "x""#, r#"account "y""#).replace(
+            r#"scopes = ["d", "e"];"#,
+            &format!(
+                r#"scopes_cmd = "touch '{}'; while [ ! -e '{}' ]; do sleep 0.01; done; echo d";"#,
+                started.display(),
+                release.display()
+            ),
+        );
+        let z_conf = CONF_STR.replace(r#"account "x""#, r#"account "z""#);
+        let (pstate, _) = mock_pstate(&format!(
+            r#"audit_log = "{}"; {x_conf:} {y_conf:} {z_conf:}"#,
+            dir.join("audit.log").display()
+        ));
+        let pstate = Arc::new(pstate);
+        install(&pstate, 3600);
+
+        let show_pstate = Arc::clone(&pstate);
+        let show_thread = thread::spawn(move || {
+            let ct_lk = show_pstate.ct_lock();
+            let act_id = ct_lk.validate_act_name("y").unwrap();
+            request_token(Arc::clone(&show_pstate), ct_lk, act_id).unwrap();
+        });
+        let start = Instant::now();
+        while !started.exists() {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let refresh_pstate = Arc::clone(&pstate);
+        let refresh_thread = thread::spawn(move || {
+            let ct_lk = refresh_pstate.ct_lock();
+            let act_id = ct_lk.validate_act_name("x").unwrap();
+            refresh_pstate
+                .refresher
+                .refresh(&refresh_pstate, ct_lk, act_id)
+                .unwrap()
+        });
+        // Once the token server has been connected to, the refresh is in progress. We don't
+        // respond until all the readers have finished.
+        let (stream, _) = listener.accept().unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        for _ in 0..32 {
+            let pstate = Arc::clone(&pstate);
+            let tx = tx.clone();
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    let mut ct_lk = pstate.ct_lock();
+                    let act_id = ct_lk.validate_act_name("x").unwrap();
+                    assert!(matches!(
+                        ct_lk.tokenstate(&act_id),
+                        TokenState::Active { .. }
+                    ));
+                    ct_lk.set_last_used(&act_id);
+                    let act_id = ct_lk.validate_act_name("z").unwrap();
+                    ct_lk.tokenstate_replace(act_id, TokenState::Empty);
+                }
+                tx.send(()).unwrap();
+            });
+        }
+        for _ in 0..32 {
+            rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        }
+
+        drop(stream);
+        assert!(matches!(
+            refresh_thread.join().unwrap(),
+            RefreshKind::TransitoryError(_)
+        ));
+        fs::write(&release, "").unwrap();
+        show_thread.join().unwrap();
+        let ct_lk = pstate.ct_lock();
+        let act_id = ct_lk.validate_act_name("y").unwrap();
+        assert!(matches!(
+            ct_lk.tokenstate(&act_id),
+            TokenState::Pending { .. }
+        ));
+        drop(ct_lk);
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    /// Accept one connection on `listener` and respond to its request with the status line and
+    /// headers `head`, followed by an empty body.
+    fn respond_once(listener: TcpListener, head: String) {
+        thread::spawn(move || {
+            // Consume the whole request, so that closing the connection doesn't cause it to be
+            // reset before the client has read our response.
+            let mut rdr = BufReader::new(listener.accept().unwrap().0);
+            let mut len = 0;
+            loop {
+                let mut line = String::new();
+                rdr.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                if let Some((k, v)) = line.split_once(':') {
+                    if k.eq_ignore_ascii_case("content-length") {
+                        len = v.trim().parse::<usize>().unwrap();
+                    }
+                }
+            }
+            rdr.read_exact(&mut vec![0; len]).unwrap();
+            let resp = format!("{head:}Content-Length: 0\r\n\r\n");
+            rdr.into_inner().write_all(resp.as_bytes()).unwrap();
+        });
+    }
+
+    #[test]
+    fn test_server_errors() {
+        // Refresh `x`, with the extra account fields `fields`, against a token server which
+        // responds with `status`.
+        fn refresh_with_status(fields: &str, status: &str) -> (RefreshKind, TokenState) {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let (pstate, _) = mock_pstate(&CONF_STR.replace(
+                r#""http://g.com";"#,
+                &format!(r#""http://127.0.0.1:{port:}/"; {fields:}"#),
+            ));
+            install(&pstate, 3600);
+            respond_once(listener, format!("HTTP/1.1 {status:}\r\n"));
+            let ct_lk = pstate.ct_lock();
+            let act_id = ct_lk.validate_act_name("x").unwrap();
+            let rk = pstate.refresher.refresh(&pstate, ct_lk, act_id).unwrap();
+            let ct_lk = pstate.ct_lock();
+            let act_id = ct_lk.validate_act_name("x").unwrap();
+            let ts = ct_lk.tokenstate(&act_id).clone();
+            (rk, ts)
+        }
+
+        // A 5xx error is transient and mustn't lose the refresh token...
+        let (rk, ts) = refresh_with_status("", "503 Service Unavailable");
+        assert!(matches!(rk, RefreshKind::TransitoryError(_)));
+        assert!(matches!(ts, TokenState::Active { .. }));
+        // ...but a 4xx error means the refresh token is no longer any good.
+        // We remember why, rather than silently discarding the token.
+        let (rk, ts) = refresh_with_status("", "400 Bad Request");
+        assert!(matches!(
+            rk,
+            RefreshKind::PermanentError(ErrorKind::RefreshTokenInvalid, _)
+        ));
+        assert!(matches!(ts, TokenState::Failed { reason, .. } if reason.starts_with("400")));
+
+        // The user can override either default.
+        let (rk, ts) = refresh_with_status(
+            r#"not_transient_error_if = ["^503"];"#,
+            "503 Service Unavailable",
+        );
+        assert!(matches!(rk, RefreshKind::PermanentError(_, msg) if msg.contains("\"^503\"")));
+        assert!(matches!(ts, TokenState::Failed { .. }));
+        let (rk, ts) = refresh_with_status(r#"transient_error_if = ["^400"];"#, "400 Bad Request");
+        assert!(matches!(rk, RefreshKind::TransitoryError(_)));
+        assert!(matches!(ts, TokenState::Active { .. }));
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (pstate, clock) = mock_pstate(&CONF_STR.replace(
+            r#""http://g.com";"#,
+            &format!(r#""http://127.0.0.1:{port:}/";"#),
+        ));
+        let refresh = || {
+            let ct_lk = pstate.ct_lock();
+            let act_id = ct_lk.validate_act_name("x").unwrap();
+            pstate.refresher.refresh(&pstate, ct_lk, act_id).unwrap()
+        };
+        let refresh_at = || {
+            let ct_lk = pstate.ct_lock();
+            let act_id = ct_lk.validate_act_name("x").unwrap();
+            pstate.refresher.refresh_at(&pstate, &ct_lk, &act_id)
+        };
+
+        install(&pstate, 30);
+        respond_once(
+            listener,
+            "HTTP/1.1 429 Too Many Requests\r\nRetry-After: 120\r\n".to_owned(),
+        );
+        assert!(matches!(refresh(), RefreshKind::TransitoryError(msg) if msg.starts_with("429")));
+        // Even though the default `refresh_retry_interval` is 40s, we wait as long as the server
+        // asked us to.
+        assert_eq!(refresh_at(), Some(clock.now() + Duration::from_secs(120)));
+        // Attempts in the meantime don't even contact the server.
+        clock.advance(Duration::from_secs(119));
+        assert!(matches!(refresh(),
+            RefreshKind::TransitoryError(msg) if msg.starts_with("rate limited by provider until")));
+        assert_eq!(refresh_at(), Some(clock.now() + Duration::from_secs(1)));
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(refresh_at(), Some(clock.now()));
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        let now = UNIX_EPOCH + Duration::from_secs(784111777);
+        assert_eq!(
+            parse_retry_after("120", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Sun, 06 Nov 1994 08:51:37 GMT", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after(
+                "Sun, 06 Nov 1994 08:49:37 GMT",
+                now + Duration::from_secs(5)
+            ),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(
+            parse_retry_after("Thu, 01 Jan 1970 00:00:00 GMT", UNIX_EPOCH),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("-1", now), None);
+        assert_eq!(
+            parse_retry_after("Sunday, 06-Nov-94 08:49:37 GMT", now),
+            None
+        );
+        assert_eq!(
+            parse_retry_after("Sun, 06 Nov 1994 08:49:37 PST", now),
+            None
+        );
+        assert_eq!(
+            parse_retry_after("Sun, 06 Foo 1994 08:49:37 GMT", now),
+            None
+        );
+    }
+
+    #[test]
+    fn test_max_refresh_failures() {
+        let (pstate, _) = mock_pstate(&CONF_STR.replace(
+            r#""http://g.com";"#,
+            r#""http://g.com"; max_refresh_failures = 2;"#,
+        ));
+        let fail = || {
+            let ct_lk = pstate.ct_lock();
+            let act_id = ct_lk.validate_act_name("x").unwrap();
+            drop(ct_lk);
+            transitory_error(&pstate, act_id, pstate.clock.wall_now(), "e".to_owned())
+        };
+        install(&pstate, 3600);
+        assert!(matches!(fail(), RefreshKind::TransitoryError(_)));
+        // A successful refresh resets the count.
+        install(&pstate, 3600);
+        assert!(matches!(fail(), RefreshKind::TransitoryError(_)));
+        assert!(matches!(
+            fail(),
+            RefreshKind::PermanentError(ErrorKind::RefreshTokenInvalid, msg)
+                if msg.contains("failed 2 times in a row")
+        ));
+        let ct_lk = pstate.ct_lock();
+        let act_id = ct_lk.validate_act_name("x").unwrap();
+        assert!(matches!(
+            ct_lk.tokenstate(&act_id),
+            TokenState::Failed { .. }
+        ));
+    }
+
+    #[test]
+    fn test_wall_to_instant() {
+        let mono_now = Instant::now();
+        let wall_now = SystemTime::now();
+        let d = Duration::from_secs(100);
+        assert_eq!(
+            wall_to_instant(wall_now + d, wall_now, mono_now),
+            Some(mono_now + d)
+        );
+        assert_eq!(
+            wall_to_instant(wall_now, wall_now, mono_now),
+            Some(mono_now)
+        );
+        // Simulate the system having been suspended for longer than the token's lifetime: the
+        // wall-clock has moved on, but the monotonic clock has not.
+        assert_eq!(
+            wall_to_instant(wall_now + d, wall_now + d * 10, mono_now),
+            Some(mono_now)
+        );
+    }
+
+    #[test]
+    fn test_clock_jumped() {
+        let s = Duration::from_secs;
+        assert!(!clock_jumped(s(0), s(0)));
+        assert!(!clock_jumped(s(60), s(61)));
+        assert!(!clock_jumped(s(60), s(60) + CLOCK_JUMP_THRESHOLD));
+        assert!(clock_jumped(s(60), s(3600)));
+        // The wall-clock going backwards is not a forward jump.
+        assert!(!clock_jumped(s(60), s(0)));
+    }
+
+    #[test]
+    fn test_check_interval() {
+        let (pstate, _) = mock_pstate(CONF_STR);
+        assert_eq!(
+            pstate.refresher.check_interval(&pstate),
+            Duration::from_secs(60)
+        );
+        pstate.update_conf(
+            Config::from_str(&format!("refresh_check_interval = 5m; {CONF_STR:}")).unwrap(),
+        );
+        assert_eq!(
+            pstate.refresher.check_interval(&pstate),
+            Duration::from_secs(300)
+        );
+        // An explicit interval (i.e. from the command line) overrides the config.
+        let refresher = Refresher::new(Some(Duration::from_secs(7)));
+        assert_eq!(refresher.check_interval(&pstate), Duration::from_secs(7));
+    }
+}
@@ -1,50 +1,338 @@
-use std::{error::Error, sync::Arc};
+use std::{
+    error::Error,
+    process::{Command, Stdio},
+    sync::Arc,
+    thread,
+};
 
+use log::error;
 use rand::{thread_rng, RngCore};
 use url::Url;
 
-use super::{AuthenticatorState, CTGuard, CTGuardAccountId, TokenState, STATE_LEN};
+use super::{
+    encode_params, refresher::RefreshKind, AuthenticatorState, CTGuard, CTGuardAccountId,
+    TokenState, STATE_LEN,
+};
+use crate::config::{Account, AuthFlow, ResponseType};
 
-/// Request a new token for `act_id`, whose tokenstate must be `Empty`.
+/// Length of the OpenID Connect nonce in bytes.
+const NONCE_LEN: usize = 16;
+/// Parameters which a user's `auth_params` cannot override, since doing so would create malformed
+/// requests.
+const RESERVED_PARAMS: &[&str] = &[
+    "client_id",
+    "nonce",
+    "redirect_uri",
+    "response_type",
+    "scope",
+    "state",
+];
+
+/// Request a new token for `act_id`, whose tokenstate must be `Empty`, `Pending`, or `Failed`. If
+/// the account is already `Pending`, the existing authorisation URL is kept, and the user is only
+/// reminded of it if the account's notification interval has passed. If the account has an
+/// `auth_uri_override_cmd` which fails, the tokenstate is left unchanged and an error returned.
+/// For [AuthFlow::ClientCredentials] accounts, the token is obtained in the background without
+/// involving the user.
 pub fn request_token(
     pstate: Arc<AuthenticatorState>,
-    mut ct_lk: CTGuard,
+    ct_lk: CTGuard,
     act_id: CTGuardAccountId,
 ) -> Result<(), Box<dyn Error>> {
     assert!(matches!(
         ct_lk.tokenstate(&act_id),
-        TokenState::Empty | TokenState::Pending { .. }
+        TokenState::Empty | TokenState::Pending { .. } | TokenState::Failed { .. }
     ));
 
-    let act = ct_lk.account(&act_id);
+    if let TokenState::Pending { .. } = ct_lk.tokenstate(&act_id) {
+        // A new URL would have a new state, so that completing the URL the user has already been
+        // sent would fail.
+        drop(ct_lk);
+        pstate.notifier.notify_new(Arc::clone(&pstate));
+        return Ok(());
+    }
+
+    let act = Arc::clone(&ct_lk.config().accounts[&ct_lk.account(&act_id).name]);
+    if act.auth_flow == AuthFlow::ClientCredentials {
+        drop(ct_lk);
+        client_credentials_in_background(pstate, act.name.clone());
+        return Ok(());
+    }
+    let auth_uri = act
+        .auth_uri
+        .clone()
+        .ok_or("No auth_uri for authorization code flow")?;
 
     let mut state = [0u8; STATE_LEN];
     thread_rng().fill_bytes(&mut state);
     let state_str = urlencoding::encode_binary(&state).into_owned();
-
-    let scopes_join = act.scopes.join(" ");
     let redirect_uri = act.redirect_uri(pstate.http_port)?.to_string();
-    let mut params = vec![
-        ("access_type", "offline"),
-        ("scope", scopes_join.as_str()),
-        ("client_id", act.client_id.as_str()),
-        ("redirect_uri", redirect_uri.as_str()),
-        ("response_type", "code"),
-        ("state", state_str.as_str()),
-    ];
-    if let Some(x) = &act.login_hint {
-        params.push(("login_hint", x));
+    // Both `auth_uri_override_cmd` and `scopes_cmd` can take an arbitrary amount of time to run, so
+    // we mustn't hold the lock while they do so.
+    drop(ct_lk);
+
+    match act.auth_uri_override_cmd.clone() {
+        Some(cmd) => {
+            let vars = [
+                ("account", act.name.clone()),
+                ("auth_uri", auth_uri),
+                ("redirect_uri", redirect_uri),
+                ("state", state_str),
+            ];
+            let url = run_auth_uri_override_cmd(&cmd, &vars);
+            let mut ct_lk = pstate.ct_lock();
+            let act_id = match ct_lk.validate_act_id(act_id) {
+                Some(x) => x,
+                // The account or its tokenstate changed while the command was running, so our
+                // URL is no longer wanted.
+                None => return Ok(()),
+            };
+            match url {
+                Ok(url) => set_pending(&pstate, ct_lk, act_id, url, None, state),
+                Err(e) => {
+                    let msg = format!("auth_uri_override_cmd failed: {e:}");
+                    ct_lk.set_last_error(&act_id, msg.clone());
+                    Err(msg.into())
+                }
+            }
+        }
+        None => {
+            let scopes = act.scopes();
+            let (url, nonce) = build_url(&act, &scopes, &auth_uri, &redirect_uri, &state_str)?;
+            let ct_lk = pstate.ct_lock();
+            match ct_lk.validate_act_id(act_id) {
+                Some(act_id) => set_pending(&pstate, ct_lk, act_id, url, nonce, state),
+                // As above, our URL is no longer wanted.
+                None => Ok(()),
+            }
+        }
     }
-    let url = Url::parse_with_params(ct_lk.account(&act_id).auth_uri.as_str(), &params)?;
+}
+
+/// Obtain a token for the [AuthFlow::ClientCredentials] account `act_name` in a new thread,
+/// logging any failure.
+fn client_credentials_in_background(pstate: Arc<AuthenticatorState>, act_name: String) {
+    thread::spawn(move || {
+        let ct_lk = pstate.ct_lock();
+        let act_id = match ct_lk.validate_act_name(&act_name) {
+            Some(x) => x,
+            None => return,
+        };
+        // The tokenstate may have changed since we were called.
+        if !matches!(
+            ct_lk.tokenstate(&act_id),
+            TokenState::Empty | TokenState::Failed { .. }
+        ) {
+            return;
+        }
+        let rk = pstate.refresher.refresh(&pstate, ct_lk, act_id);
+        pstate.refresher.notify_changes();
+        match rk {
+            Ok(RefreshKind::AccountOrTokenStateChanged | RefreshKind::Refreshed) => (),
+            Ok(RefreshKind::PermanentError(_, msg) | RefreshKind::TransitoryError(msg)) => {
+                error!("{act_name:}: {msg:}")
+            }
+            Err(e) => error!("{act_name:}: {e:}"),
+        }
+    });
+}
+
+/// Put `act_id` into the [TokenState::Pending] state for `url`, and notify the user.
+fn set_pending(
+    pstate: &Arc<AuthenticatorState>,
+    mut ct_lk: CTGuard,
+    act_id: CTGuardAccountId,
+    url: Url,
+    nonce: Option<String>,
+    state: [u8; STATE_LEN],
+) -> Result<(), Box<dyn Error>> {
     ct_lk.tokenstate_replace(
         act_id,
         TokenState::Pending {
             last_notification: None,
+            notification_count: 0,
+            nonce,
             url,
             state,
         },
     );
     drop(ct_lk);
-    pstate.notifier.notify_new(Arc::clone(&pstate));
+    pstate.notifier.notify_new(Arc::clone(pstate));
     Ok(())
 }
+
+/// Build the authorisation URL for `act`, whose scopes are `scopes`, from `auth_uri`, returning it
+/// and the nonce (if any) embedded in it.
+pub fn build_url(
+    act: &Account,
+    scopes: &[String],
+    auth_uri: &str,
+    redirect_uri: &str,
+    state_str: &str,
+) -> Result<(Url, Option<String>), Box<dyn Error>> {
+    let scope = act.scope_param(scopes);
+    let mut params = vec![
+        ("scope", scope.as_str()),
+        ("client_id", act.client_id.as_str()),
+        ("redirect_uri", redirect_uri),
+        ("response_type", act.response_type.as_str()),
+        ("state", state_str),
+    ];
+    // Offline access means a refresh token, which the implicit flow never issues: some servers
+    // reject implicit flow requests which ask for it.
+    if act.response_type == ResponseType::Code {
+        params.insert(0, ("access_type", "offline"));
+    }
+    if let Some(x) = &act.login_hint {
+        params.push(("login_hint", x));
+    }
+    // A nonce is embedded in the ID token we eventually receive, allowing us to check that the ID
+    // token really was created in response to this request.
+    let nonce = if act.use_nonce(scopes) {
+        let mut nonce = [0u8; NONCE_LEN];
+        thread_rng().fill_bytes(&mut nonce);
+        Some(nonce.iter().map(|x| format!("{x:02x}")).collect::<String>())
+    } else {
+        None
+    };
+    if let Some(x) = &nonce {
+        params.push(("nonce", x));
+    }
+    // We sort the user's parameters so that the URLs we generate are deterministic.
+    let mut auth_params = act.auth_params.iter().collect::<Vec<_>>();
+    auth_params.sort();
+    for (k, v) in auth_params {
+        if RESERVED_PARAMS.contains(&k.as_str()) {
+            continue;
+        }
+        match params.iter_mut().find(|(pk, _)| pk == k) {
+            Some(p) => p.1 = v,
+            None => params.push((k, v)),
+        }
+    }
+    // The parameters are encoded ourselves, since the `scope` parameter may need to be included
+    // verbatim.
+    let mut url = Url::parse(auth_uri)?;
+    let query = match url.query() {
+        Some(x) if !x.is_empty() => format!("{x:}&{}", encode_params(act, &params)),
+        _ => encode_params(act, &params),
+    };
+    url.set_query(Some(&query));
+    Ok((url, nonce))
+}
+
+/// Run the shell command `cmd`, with each `{name}` in `vars` replaced by its (shell quoted) value,
+/// and return the URL it prints on stdout.
+pub fn run_auth_uri_override_cmd(
+    cmd: &str,
+    vars: &[(&str, String)],
+) -> Result<Url, Box<dyn Error>> {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(expand_vars(cmd, vars))
+        .stdin(Stdio::null())
+        .output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("{} {}", output.status, stderr.trim())
+            .trim_end()
+            .into());
+    }
+    Ok(Url::parse(String::from_utf8(output.stdout)?.trim())?)
+}
+
+/// Replace each `{name}` in `cmd`, where `name` is in `vars`, with its value quoted for the
+/// shell. Other text, including unknown `{...}`s, is left as-is.
+pub fn expand_vars(cmd: &str, vars: &[(&str, String)]) -> String {
+    let mut expanded = String::with_capacity(cmd.len());
+    let mut rest = cmd;
+    while let Some(i) = rest.find('{') {
+        expanded.push_str(&rest[..i]);
+        rest = &rest[i..];
+        match vars.iter().find(|(name, _)| {
+            rest[1..].starts_with(name) && rest[1 + name.len()..].starts_with('}')
+        }) {
+            Some((name, value)) => {
+                expanded.push('\'');
+                expanded.push_str(&value.replace('\'', "'\\''"));
+                expanded.push('\'');
+                rest = &rest[name.len() + 2..];
+            }
+            None => {
+                expanded.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{config::Config, server::test_utils::act_conf};
+
+    #[test]
+    fn test_build_url() {
+        let url = |fields: &[(&str, &str)]| {
+            let fields = [
+                fields,
+                &[
+                    ("auth_uri", r#""http://a.com/?p=q""#),
+                    ("scopes", r#"["d", "e/f"]"#),
+                    ("redirect_uri", r#""http://127.0.0.1/""#),
+                    ("use_nonce", "false"),
+                ],
+            ]
+            .concat();
+            let c = Config::from_str(&act_conf("x", &fields)).unwrap();
+            let act = &c.accounts["x"];
+            build_url(
+                act,
+                &act.scopes(),
+                act.auth_uri.as_deref().unwrap(),
+                "http://127.0.0.1/",
+                "s",
+            )
+            .unwrap()
+            .0
+            .to_string()
+        };
+        assert_eq!(
+            url(&[]),
+            "http://a.com/?p=q&access_type=offline&scope=d+e%2Ff&client_id=b&\
+             redirect_uri=http%3A%2F%2F127.0.0.1%2F&response_type=code&state=s"
+        );
+        let sep = |x| ("scopes_separator", x);
+        let no_encode = ("scopes_encode", r#""none""#);
+        assert!(url(&[sep(r#"",""#)]).contains("&scope=d%2Ce%2Ff&"));
+        assert!(url(&[sep(r#""+""#)]).contains("&scope=d%2Be%2Ff&"));
+        assert!(url(&[sep(r#"",""#), no_encode]).contains("&scope=d,e/f&"));
+        assert!(url(&[sep(r#""+""#), no_encode]).contains("&scope=d+e/f&"));
+    }
+
+    #[test]
+    fn test_expand_vars() {
+        let vars = [("account", "a b".to_owned()), ("state", "it's".to_owned())];
+        assert_eq!(
+            expand_vars("x --account {account} {state}", &vars),
+            "x --account 'a b' 'it'\\''s'"
+        );
+        assert_eq!(expand_vars("{x} {account", &vars), "{x} {account");
+        assert_eq!(expand_vars("{{account}}", &vars), "{'a b'}");
+    }
+
+    #[test]
+    fn test_run_auth_uri_override_cmd() {
+        let vars = [("state", "a'b".to_owned())];
+        assert_eq!(
+            run_auth_uri_override_cmd("echo http://a.com/?state={state}", &vars)
+                .unwrap()
+                .as_str(),
+            "http://a.com/?state=a%27b"
+        );
+        assert!(run_auth_uri_override_cmd("echo http://a.com/; exit 1", &vars).is_err());
+        assert!(run_auth_uri_override_cmd("echo not a url", &vars).is_err());
+    }
+}
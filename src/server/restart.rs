@@ -0,0 +1,181 @@
+//! Handing refresh tokens over from a server to its replacement (`pizauth restart`). Just before
+//! it exits, the old server writes a dump of its refresh tokens to a file only readable by the
+//! user; the new server restores them when it starts and then removes the file. Accounts whose
+//! config has changed in the meantime no longer match the dumped fingerprint, and so start with no
+//! token, just as they would have done had the config been reloaded.
+
+use std::{
+    error::Error,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use log::{info, warn};
+
+use super::{
+    dump::{dump_locked, parse_entries, restore_tokens, DUMP_VERSION},
+    AuthenticatorState, CTGuard,
+};
+use crate::{secret::SecretString, PIZAUTH_CACHE_RESTART_LEAF};
+
+/// How long after being written is a handoff file still valid? Older files are assumed to have
+/// been left behind by a restart which didn't complete, and are removed without being loaded.
+const RESTART_TTL: Duration = Duration::from_secs(60);
+
+pub fn restart_path(cache_path: &Path) -> PathBuf {
+    let mut p = cache_path.to_owned();
+    p.push(PIZAUTH_CACHE_RESTART_LEAF);
+    p
+}
+
+/// Write the refresh tokens in `ct_lk` to the handoff file at `path`, which is only readable by
+/// the user.
+pub fn save(ct_lk: &CTGuard, path: &Path) -> Result<(), Box<dyn Error>> {
+    // The mode is only set when a file is created, so a left over file must be removed first.
+    match fs::remove_file(path) {
+        Ok(()) => (),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => return Err(format!("Can't remove {}: {e:}", path.display()).into()),
+    }
+    let d = dump_locked(ct_lk);
+    let mut f = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .map_err(|e| format!("Can't write {}: {e:}", path.display()))?;
+    f.write_all(d.expose().as_bytes())
+        .and_then(|_| f.sync_all())
+        .map_err(|e| {
+            fs::remove_file(path).ok();
+            format!("Can't write {}: {e:}", path.display())
+        })?;
+    Ok(())
+}
+
+/// If there is a handoff file at `path` which is younger than [RESTART_TTL], restore the refresh
+/// tokens in it, logging what was restored and skipped. Any handoff file is then removed.
+pub fn load(pstate: &AuthenticatorState, path: &Path) -> Result<(), Box<dyn Error>> {
+    let md = match fs::metadata(path) {
+        Ok(x) => x,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(format!("Can't read {}: {e:}", path.display()).into()),
+    };
+    let r = load_file(pstate, path, md.modified()?);
+    fs::remove_file(path).map_err(|e| format!("Can't remove {}: {e:}", path.display()))?;
+    r
+}
+
+fn load_file(
+    pstate: &AuthenticatorState,
+    path: &Path,
+    modified: SystemTime,
+) -> Result<(), Box<dyn Error>> {
+    // A file with a modification time in the future is treated as stale.
+    if SystemTime::now()
+        .duration_since(modified)
+        .map_or(true, |age| age > RESTART_TTL)
+    {
+        warn!("Ignoring stale restart file {}", path.display());
+        return Ok(());
+    }
+    let input = SecretString::from(
+        fs::read_to_string(path).map_err(|e| format!("Can't read {}: {e:}", path.display()))?,
+    );
+    let mut lines = input
+        .expose()
+        .lines()
+        .filter(|x| !x.starts_with('#') && !x.trim().is_empty());
+    if lines.next() != Some(DUMP_VERSION) {
+        return Err(format!("{} is not a pizauth dump", path.display()).into());
+    }
+    let entries = lines.flat_map(|x| x.split(' ')).collect::<Vec<_>>();
+    for (act_name, skipped) in restore_tokens(pstate, parse_entries(&entries)?)? {
+        match skipped {
+            Some(reason) => warn!("{act_name:}: token not carried over ({reason:})"),
+            None => info!("{act_name:}: token carried over"),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::{
+        state::TokenState,
+        test_utils::{mock_pstate, CONF_STR},
+    };
+    use std::{env, fs::File, os::unix::fs::PermissionsExt, process};
+
+    fn set_active(pstate: &AuthenticatorState) {
+        let mut ct_lk = pstate.ct_lock();
+        let act_id = ct_lk.validate_act_name("x").unwrap();
+        ct_lk.tokenstate_replace(
+            act_id,
+            TokenState::Active {
+                access_token: SecretString::from("a"),
+                refreshed_at: pstate.clock.now(),
+                last_refresh_attempt: None,
+                consecutive_refresh_failures: 0,
+                expiry: pstate.clock.wall_now(),
+                id_token: None,
+                refresh_token: Some(SecretString::from("r t")),
+                display_name: None,
+            },
+        );
+    }
+
+    fn has_token(pstate: &AuthenticatorState) -> bool {
+        let ct_lk = pstate.ct_lock();
+        let act_id = ct_lk.validate_act_name("x").unwrap();
+        matches!(ct_lk.tokenstate(&act_id), TokenState::Active { .. })
+    }
+
+    #[test]
+    fn test_handoff() {
+        let dir = env::temp_dir().join(format!("pizauth_restart_test_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = restart_path(&dir);
+
+        let (old, _) = mock_pstate(CONF_STR);
+        set_active(&old);
+        save(&old.ct_lock(), &path).unwrap();
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+
+        let (new, _) = mock_pstate(CONF_STR);
+        load(&new, &path).unwrap();
+        assert!(has_token(&new));
+        assert!(!path.exists());
+        // Loading with no handoff file is not an error.
+        load(&new, &path).unwrap();
+
+        // A stale handoff file is removed without being loaded.
+        save(&old.ct_lock(), &path).unwrap();
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(SystemTime::now() - RESTART_TTL * 2)
+            .unwrap();
+        let (new, _) = mock_pstate(CONF_STR);
+        load(&new, &path).unwrap();
+        assert!(!has_token(&new));
+        assert!(!path.exists());
+
+        // An account whose config has changed doesn't get the old token.
+        save(&old.ct_lock(), &path).unwrap();
+        let (new, _) = mock_pstate(&CONF_STR.replace("\"d\", \"e\"", "\"d\""));
+        load(&new, &path).unwrap();
+        assert!(!has_token(&new));
+        assert!(!path.exists());
+
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -12,52 +12,151 @@
 //! "version" has changed, the [CTGuardAccountId] is no longer valid. This API is mildly irritating
 //! to use, but guarantees that one can't do something based on an outdated idea of what the
 //! configuration actually is.
+//!
+//! There is a single lock for all accounts. This is only acceptable because a [CTGuard] must never
+//! be held while performing a blocking operation (e.g. a network request, running a user's
+//! command, or writing to a file): instead, callers copy what they need, drop the [CTGuard],
+//! perform the operation, and then reacquire a [CTGuard] and revalidate their [CTGuardAccountId].
+//! In practice the lock is thus only ever held for very short periods of time. The one exception
+//! is `pizauth restart`, which deliberately holds the lock until the server exits.
 
 use std::{
     collections::{HashMap, HashSet},
+    net::SocketAddr,
+    path::PathBuf,
     rc::{Rc, Weak},
-    sync::{Arc, Mutex, MutexGuard},
-    time::Instant,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
 };
 
 use url::Url;
 
-use super::{notifier::Notifier, refresher::Refresher, STATE_LEN};
+use super::{audit::AuditLog, clock::Clock, notifier::Notifier, refresher::Refresher, STATE_LEN};
 use crate::{
-    config::{Account, Config},
+    config::{Account, Config, ConfigDiff},
     frontends::Frontend,
+    secret::SecretString,
 };
 
+/// How often does [AuthenticatorState::wait_for_inflight] check whether requests have completed?
+const INFLIGHT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
 /// pizauth's global state.
 pub struct AuthenticatorState {
     /// The "global lock" protecting the config and current [TokenState]s. Can only be accessed via
     /// [AuthenticatorState::ct_lock].
     locked_state: Mutex<LockedState>,
+    /// The path the config was read from, or `None` if it was read from stdin (in which case it
+    /// can't be reloaded).
+    pub conf_path: Option<PathBuf>,
+    /// The path of the file containing the server's PID, or `None` if there is no such file. The
+    /// file is removed when the server shuts down cleanly.
+    pub pid_path: Option<PathBuf>,
+    /// The path of the file `pizauth restart` hands refresh tokens over in, or `None` if this
+    /// server can't be restarted.
+    pub restart_path: Option<PathBuf>,
+    /// The arguments (after `server`) with which `pizauth restart` starts this server's
+    /// replacement, or why this server can't be restarted.
+    pub restart_args: Result<Vec<String>, String>,
+    /// The path of the socket clients connect to, if known.
+    pub sock_path: Option<PathBuf>,
+    /// The addresses the HTTP server is listening on.
+    pub http_addrs: Vec<SocketAddr>,
+    /// When the server started.
+    pub started_at: Instant,
     /// port of the HTTP server required by OAuth.
     pub http_port: u16,
+    /// Was `http_port` fixed by the user (with `pizauth server --port`) rather than chosen by the
+    /// operating system?
+    pub http_port_fixed: bool,
+    /// Can clients dump each account's refresh token (with `pizauth dump`) or a snapshot of its
+    /// state (with `pizauth dump --json`)? This is only possible if the server was started with
+    /// `--allow-dump`.
+    pub allow_dump: bool,
     pub frontend: Arc<dyn Frontend>,
     pub notifier: Arc<Notifier>,
     pub refresher: Arc<Refresher>,
+    /// The source of all times used by the server.
+    pub clock: Arc<dyn Clock>,
+    /// How many requests to token servers are in flight (see [AuthenticatorState::inflight])?
+    inflight: Arc<AtomicUsize>,
+    /// Has the server started shutting down? If so, no new client connections are accepted.
+    pub shutting_down: AtomicBool,
+    /// The config's `shutdown_grace_period` in milliseconds. This is kept outside the global lock
+    /// so that shutting down never needs the lock, which `pizauth restart` holds until the process
+    /// exits.
+    shutdown_grace_period: AtomicU64,
+    /// Writes the audit log, if the config has one, without the global lock being held.
+    pub audit: AuditLog,
 }
 
 impl AuthenticatorState {
     pub fn new(
         conf: Config,
+        conf_path: Option<PathBuf>,
         http_port: u16,
         frontend: Arc<dyn Frontend>,
         notifier: Arc<Notifier>,
         refresher: Arc<Refresher>,
+        clock: Arc<dyn Clock>,
     ) -> Self {
+        let shutdown_grace_period = AtomicU64::new(duration_millis(conf.shutdown_grace_period));
         AuthenticatorState {
             locked_state: Mutex::new(LockedState::new(conf)),
+            conf_path,
+            pid_path: None,
+            restart_path: None,
+            restart_args: Err("This server can't be restarted".to_owned()),
+            sock_path: None,
+            http_addrs: Vec::new(),
+            started_at: clock.now(),
             http_port,
+            http_port_fixed: false,
+            allow_dump: false,
             frontend,
             notifier,
             refresher,
+            clock,
+            inflight: Arc::new(AtomicUsize::new(0)),
+            shutting_down: AtomicBool::new(false),
+            shutdown_grace_period,
+            audit: AuditLog::new(),
         }
     }
 
-    /// Lock the config and tokens and return a guard.
+    /// How long should shutting down wait for in-flight token requests? Unlike the rest of the
+    /// config, this can be read without taking the global lock.
+    pub fn shutdown_grace_period(&self) -> Duration {
+        Duration::from_millis(self.shutdown_grace_period.load(Ordering::SeqCst))
+    }
+
+    /// Record that a request to a token server (e.g. exchanging a code, or refreshing a token) is
+    /// in flight until the returned guard is dropped, so that shutting down can wait for it.
+    pub fn inflight(&self) -> InFlight {
+        self.inflight.fetch_add(1, Ordering::SeqCst);
+        InFlight(Arc::clone(&self.inflight))
+    }
+
+    /// Wait until no requests to token servers are in flight, or until `grace_period` has elapsed,
+    /// returning how many requests are still in flight. This uses the system clock, not
+    /// [AuthenticatorState::clock], since it really does wait.
+    pub fn wait_for_inflight(&self, grace_period: Duration) -> usize {
+        let start = Instant::now();
+        loop {
+            let n = self.inflight.load(Ordering::SeqCst);
+            if n == 0 || start.elapsed() >= grace_period {
+                return n;
+            }
+            thread::sleep(INFLIGHT_POLL_INTERVAL);
+        }
+    }
+
+    /// Lock the config and tokens and return a guard. The guard must not be held while performing
+    /// blocking operations such as network requests, running commands, or writing to files.
     ///
     /// # Panics
     ///
@@ -65,15 +164,55 @@ impl AuthenticatorState {
     /// to be done in such a case, as it is likely that pizauth is in an inconsistent, and
     /// irretrievable, state.
     pub fn ct_lock(&self) -> CTGuard {
-        CTGuard::new(self.locked_state.lock().unwrap())
+        CTGuard::new(
+            self.locked_state.lock().unwrap(),
+            self.clock.as_ref(),
+            &self.audit,
+        )
     }
 
-    /// Update the global [Config] to `new_conf`. This cannot fail, but note that there is no
-    /// guarantee that by the time this function calls the configuration is still the same as
-    /// `new_conf` since another thread(s) may also have called this function.
-    pub fn update_conf(&self, new_conf: Config) {
+    /// Return the number of accounts in the current [Config].
+    pub fn account_count(&self) -> usize {
+        self.locked_state.lock().unwrap().config.accounts.len()
+    }
+
+    /// Update the global [Config] to `new_conf`, returning how it differs from the previous
+    /// config. This cannot fail, but note that there is no guarantee that by the time this
+    /// function calls the configuration is still the same as `new_conf` since another thread(s)
+    /// may also have called this function.
+    pub fn update_conf(&self, new_conf: Config) -> ConfigDiff {
+        self.shutdown_grace_period.store(
+            duration_millis(new_conf.shutdown_grace_period),
+            Ordering::SeqCst,
+        );
         let mut lk = self.locked_state.lock().unwrap();
-        lk.update_conf(new_conf);
+        let diff = lk.update_conf(new_conf);
+        drop(lk);
+        // Errors notified for an account whose tokens have been discarded are no longer relevant.
+        for act_name in &diff.removed {
+            self.notifier.reset_errors(act_name);
+        }
+        for (act_name, _) in &diff.modified {
+            if diff.invalidates_token(act_name) {
+                self.notifier.reset_errors(act_name);
+            }
+        }
+        self.refresher.notify_changes();
+        diff
+    }
+}
+
+/// `d` in milliseconds, saturating at [u64::MAX].
+fn duration_millis(d: Duration) -> u64 {
+    u64::try_from(d.as_millis()).unwrap_or(u64::MAX)
+}
+
+/// A request to a token server which is in flight: see [AuthenticatorState::inflight].
+pub struct InFlight(Arc<AtomicUsize>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
     }
 }
 
@@ -97,6 +236,9 @@ impl LockedState {
             tokenstates.push(TokenStateVersion {
                 version: 0,
                 tokenstate: TokenState::Empty,
+                last_error: None,
+                last_used: None,
+                metrics: AccountMetrics::default(),
             });
         }
 
@@ -125,7 +267,8 @@ impl LockedState {
         &mut self.tokenstates[self.account_map[act_name]]
     }
 
-    fn update_conf(&mut self, config: Config) {
+    fn update_conf(&mut self, config: Config) -> ConfigDiff {
+        let diff = Config::diff(&self.config, &config);
         let mut account_map = HashMap::with_capacity(config.accounts.len());
         let mut tokenstates = Vec::with_capacity(config.accounts.len());
 
@@ -134,14 +277,16 @@ impl LockedState {
             tokenstates.push(TokenStateVersion {
                 version: 0,
                 tokenstate: TokenState::Empty,
+                last_error: None,
+                last_used: None,
+                metrics: AccountMetrics::default(),
             });
         }
 
         for act_name in account_map.keys() {
-            if let Some(old_act) = self.config.accounts.get(act_name) {
-                let new_act = &config.accounts[act_name];
+            if self.config.accounts.contains_key(act_name) {
                 let mut ts = self.tokenstates[self.account_map[act_name]].clone();
-                if new_act != old_act {
+                if !config.accounts[act_name].is_compatible_with(&self.config.accounts[act_name]) {
                     // The two accounts are not the same so we can't reuse the existing tokenstate,
                     // instead keeping it as Empty. However, we need to increment the version
                     // number, because there could be a very long-running thread that started
@@ -151,6 +296,9 @@ impl LockedState {
                     // calculation it has performed.
                     ts.tokenstate = TokenState::Empty;
                     ts.version += 1;
+                    ts.last_error = None;
+                    ts.last_used = None;
+                    ts.metrics = AccountMetrics::default();
                 }
                 tokenstates[account_map[act_name]] = ts;
             }
@@ -164,6 +312,7 @@ impl LockedState {
             HashSet::<&String>::from_iter(self.config.accounts.keys()),
             HashSet::from_iter(self.account_map.keys()),
         );
+        diff
     }
 }
 
@@ -176,13 +325,21 @@ impl LockedState {
 pub struct CTGuard<'a> {
     guard: MutexGuard<'a, LockedState>,
     act_rc: Rc<()>,
+    clock: &'a dyn Clock,
+    audit: &'a AuditLog,
 }
 
 impl<'a> CTGuard<'a> {
-    fn new(guard: MutexGuard<'a, LockedState>) -> CTGuard {
+    fn new(
+        guard: MutexGuard<'a, LockedState>,
+        clock: &'a dyn Clock,
+        audit: &'a AuditLog,
+    ) -> CTGuard<'a> {
         CTGuard {
             guard,
             act_rc: Rc::new(()),
+            clock,
+            audit,
         }
     }
 
@@ -244,11 +401,105 @@ impl<'a> CTGuard<'a> {
         })
     }
 
+    /// Return a snapshot of every account's state, sorted by account name, for debugging.
+    pub fn summary(&self) -> LockedStateSummary {
+        let now = self.clock.now();
+        let ago = |t: Instant| now.saturating_duration_since(t).as_secs();
+        let mut accounts = self
+            .guard
+            .config
+            .accounts
+            .keys()
+            .map(|act_name| {
+                let tsv = self.guard.tokenstate_version(act_name);
+                let mut s = AccountSummary {
+                    name: act_name.to_owned(),
+                    version: tsv.version,
+                    tokenstate: tsv.tokenstate.variant_name(),
+                    notification_count: None,
+                    last_notification_secs_ago: None,
+                    refreshed_secs_ago: None,
+                    last_refresh_attempt_secs_ago: None,
+                    consecutive_refresh_failures: None,
+                    expiry: None,
+                    has_id_token: None,
+                    has_refresh_token: None,
+                    failed_secs_ago: None,
+                    failed_reason: None,
+                    last_error: tsv
+                        .last_error
+                        .as_ref()
+                        .map(|(t, msg)| (ago(*t), msg.clone())),
+                    last_used_secs_ago: tsv.last_used.map(ago),
+                };
+                match &tsv.tokenstate {
+                    TokenState::Empty | TokenState::Exchanging => (),
+                    TokenState::Pending {
+                        last_notification,
+                        notification_count,
+                        ..
+                    } => {
+                        s.notification_count = Some(*notification_count);
+                        s.last_notification_secs_ago = last_notification.map(ago);
+                    }
+                    TokenState::Active {
+                        refreshed_at,
+                        last_refresh_attempt,
+                        consecutive_refresh_failures,
+                        expiry,
+                        id_token,
+                        refresh_token,
+                        ..
+                    } => {
+                        s.refreshed_secs_ago = Some(ago(*refreshed_at));
+                        s.last_refresh_attempt_secs_ago = last_refresh_attempt.map(|x| ago(x.at));
+                        s.consecutive_refresh_failures = Some(*consecutive_refresh_failures);
+                        s.expiry = Some(*expiry);
+                        s.has_id_token = Some(id_token.is_some());
+                        s.has_refresh_token = Some(refresh_token.is_some());
+                    }
+                    TokenState::Failed {
+                        reason,
+                        failed_at,
+                        previous_expiry,
+                    } => {
+                        s.failed_secs_ago = Some(ago(*failed_at));
+                        s.failed_reason = Some(reason.clone());
+                        s.expiry = Some(*previous_expiry);
+                    }
+                }
+                s
+            })
+            .collect::<Vec<_>>();
+        accounts.sort_by(|a, b| a.name.cmp(&b.name));
+        LockedStateSummary { accounts }
+    }
+
+    /// Return the names, sorted, of the accounts which need the user to authenticate before they
+    /// can have a token: those whose tokenstate is [TokenState::Empty] or [TokenState::Failed].
+    pub fn accounts_requiring_auth(&self) -> Vec<&str> {
+        let mut act_names = self
+            .guard
+            .config
+            .accounts
+            .values()
+            .filter(|act| {
+                matches!(
+                    self.guard.tokenstate_version(&act.name).tokenstate,
+                    TokenState::Empty | TokenState::Failed { .. }
+                )
+            })
+            .map(|act| act.name.as_str())
+            .collect::<Vec<_>>();
+        act_names.sort();
+        act_names
+    }
+
     /// Return the [CTGuardAccountId] with state `state`.
     pub fn act_id_matching_token_state(&self, state: &[u8]) -> Option<CTGuardAccountId> {
-        self.act_ids()
-            .find(|act_id|
-                matches!(self.tokenstate(act_id), &TokenState::Pending { state: s, .. } if s == state))
+        self.act_ids().find(|act_id| {
+            matches!(self.tokenstate(act_id), TokenState::Pending { state: s, .. } if ct_eq(s, state))
+        })
     }
 
     /// Return the [Account] for account `act_id`.
@@ -279,6 +530,108 @@ impl<'a> CTGuard<'a> {
             .tokenstate
     }
 
+    /// Return the time and message of the last error recorded for `act_id`, if any.
+    ///
+    /// # Panics
+    ///
+    /// If `act_id` has outlived its parent [CTGuard].
+    pub fn last_error(&self, act_id: &CTGuardAccountId) -> Option<&(Instant, String)> {
+        if Weak::strong_count(&act_id.guard_rc) != 1 {
+            panic!("CTGuardAccountId has outlived its parent CTGuard.");
+        }
+        self.guard
+            .tokenstate_version(&act_id.account.name)
+            .last_error
+            .as_ref()
+    }
+
+    /// Record `msg` as the last error for `act_id`. This does not change the tokenstate version,
+    /// so `act_id` remains valid. `msg` must not contain any secrets.
+    ///
+    /// # Panics
+    ///
+    /// If `act_id` has outlived its parent [CTGuard].
+    pub fn set_last_error(&mut self, act_id: &CTGuardAccountId, msg: String) {
+        if Weak::strong_count(&act_id.guard_rc) != 1 {
+            panic!("CTGuardAccountId has outlived its parent CTGuard.");
+        }
+        self.guard
+            .tokenstate_version_mut(&act_id.account.name)
+            .last_error = Some((self.clock.now(), msg));
+    }
+
+    /// Clear the last error for `act_id`. This does not change the tokenstate version, so `act_id`
+    /// remains valid.
+    ///
+    /// # Panics
+    ///
+    /// If `act_id` has outlived its parent [CTGuard].
+    pub fn clear_last_error(&mut self, act_id: &CTGuardAccountId) {
+        if Weak::strong_count(&act_id.guard_rc) != 1 {
+            panic!("CTGuardAccountId has outlived its parent CTGuard.");
+        }
+        self.guard
+            .tokenstate_version_mut(&act_id.account.name)
+            .last_error = None;
+    }
+
+    /// Return the last time a token for `act_id` was requested by a user, if ever.
+    ///
+    /// # Panics
+    ///
+    /// If `act_id` has outlived its parent [CTGuard].
+    pub fn last_used(&self, act_id: &CTGuardAccountId) -> Option<Instant> {
+        if Weak::strong_count(&act_id.guard_rc) != 1 {
+            panic!("CTGuardAccountId has outlived its parent CTGuard.");
+        }
+        self.guard
+            .tokenstate_version(&act_id.account.name)
+            .last_used
+    }
+
+    /// Record that a token for `act_id` has just been requested by a user. This does not change
+    /// the tokenstate version, so `act_id` remains valid.
+    ///
+    /// # Panics
+    ///
+    /// If `act_id` has outlived its parent [CTGuard].
+    pub fn set_last_used(&mut self, act_id: &CTGuardAccountId) {
+        if Weak::strong_count(&act_id.guard_rc) != 1 {
+            panic!("CTGuardAccountId has outlived its parent CTGuard.");
+        }
+        self.guard
+            .tokenstate_version_mut(&act_id.account.name)
+            .last_used = Some(self.clock.now());
+    }
+
+    /// Return `act_id`'s metrics.
+    ///
+    /// # Panics
+    ///
+    /// If `act_id` has outlived its parent [CTGuard].
+    pub fn metrics(&self, act_id: &CTGuardAccountId) -> &AccountMetrics {
+        if Weak::strong_count(&act_id.guard_rc) != 1 {
+            panic!("CTGuardAccountId has outlived its parent CTGuard.");
+        }
+        &self.guard.tokenstate_version(&act_id.account.name).metrics
+    }
+
+    /// Return `act_id`'s metrics so that they can be updated. This does not change the tokenstate
+    /// version, so `act_id` remains valid.
+    ///
+    /// # Panics
+    ///
+    /// If `act_id` has outlived its parent [CTGuard].
+    pub fn metrics_mut(&mut self, act_id: &CTGuardAccountId) -> &mut AccountMetrics {
+        if Weak::strong_count(&act_id.guard_rc) != 1 {
+            panic!("CTGuardAccountId has outlived its parent CTGuard.");
+        }
+        &mut self
+            .guard
+            .tokenstate_version_mut(&act_id.account.name)
+            .metrics
+    }
+
     /// Update the tokenstate for `act_id` to `new_tokenstate` returning a new [CTGuardAccountId]
     /// valid for the new tokenstate, updating the tokenstate version.
     ///
@@ -296,10 +649,55 @@ impl<'a> CTGuard<'a> {
         let mut ts_ver = self.guard.tokenstate_version_mut(&act_id.account.name);
         debug_assert_eq!(ts_ver.version, act_id.tokenstate_version);
         ts_ver.version += 1;
-        ts_ver.tokenstate = new_tokenstate;
+        let old_tokenstate = std::mem::replace(&mut ts_ver.tokenstate, new_tokenstate);
         act_id.tokenstate_version = ts_ver.version;
+        if let Some(path) = &self.guard.config.audit_log {
+            self.audit.record(
+                path,
+                self.clock.wall_now(),
+                &act_id.account.name,
+                &old_tokenstate,
+                &self
+                    .guard
+                    .tokenstate_version(&act_id.account.name)
+                    .tokenstate,
+            );
+        }
         act_id
     }
+
+    /// Atomically replace the [TokenState]s of multiple accounts, returning new
+    /// [CTGuardAccountId]s (in the same order as `updates`) valid for the new tokenstates. All
+    /// `CTGuardAccountId`s are checked before any tokenstate is replaced: if any is out of date, or
+    /// an account appears more than once in `updates`, no tokenstates are changed and `None` is
+    /// returned.
+    ///
+    /// # Panics
+    ///
+    /// If any `act_id` has outlived its parent [CTGuard].
+    pub fn bulk_tokenstate_replace(
+        &mut self,
+        updates: Vec<(CTGuardAccountId, TokenState)>,
+    ) -> Option<Vec<CTGuardAccountId>> {
+        let mut seen = HashSet::with_capacity(updates.len());
+        for (act_id, _) in &updates {
+            if Weak::strong_count(&act_id.guard_rc) != 1 {
+                panic!("CTGuardAccountId has outlived its parent CTGuard.");
+            }
+            if !seen.insert(act_id.account.name.as_str())
+                || self.guard.tokenstate_version(&act_id.account.name).version
+                    != act_id.tokenstate_version
+            {
+                return None;
+            }
+        }
+        Some(
+            updates
+                .into_iter()
+                .map(|(act_id, new_tokenstate)| self.tokenstate_replace(act_id, new_tokenstate))
+                .collect(),
+        )
+    }
 }
 
 /// An opaque account identifier, only fully valid while the [CTGuard] it was created from is not
@@ -318,11 +716,80 @@ pub struct CTGuardAccountId {
     guard_rc: Weak<()>,
 }
 
+#[cfg(test)]
+impl CTGuardAccountId {
+    /// The tokenstate version this identifier was created for, so that tests can check how often
+    /// a tokenstate has changed.
+    pub fn tokenstate_version(&self) -> u128 {
+        self.tokenstate_version
+    }
+}
+
 /// Track the version of a [TokenState].
 #[derive(Clone, Debug)]
 struct TokenStateVersion {
     version: u128,
     tokenstate: TokenState,
+    /// The time and message of the most recent failed refresh or authentication. This is not
+    /// affected by changes to `tokenstate`, but is reset when the account's config changes.
+    last_error: Option<(Instant, String)>,
+    /// When was a token for this account last requested by a user?
+    last_used: Option<Instant>,
+    /// Like `last_error`, this is not affected by changes to `tokenstate`, but is reset when the
+    /// account's config changes.
+    metrics: AccountMetrics,
+}
+
+/// Counts of what has happened to an account, for `pizauth metrics`.
+#[derive(Clone, Debug, Default)]
+pub struct AccountMetrics {
+    /// How many requests to refresh the account's token have been made?
+    pub refreshes_attempted: u64,
+    pub refreshes_succeeded: u64,
+    pub refreshes_failed: u64,
+    /// How many times has the user completed authentication?
+    pub authentications: u64,
+}
+
+/// A snapshot of the state of every account, as returned by [CTGuard::summary]. This never
+/// contains secrets: tokens, nonces, OAuth states, and authorisation URLs are all omitted.
+#[derive(Debug)]
+pub struct LockedStateSummary {
+    pub accounts: Vec<AccountSummary>,
+}
+
+/// A snapshot of one account's state. Times are given in seconds before the snapshot was taken.
+/// Fields which don't apply to the account's tokenstate are `None`.
+#[derive(Debug)]
+pub struct AccountSummary {
+    pub name: String,
+    /// The tokenstate version, which changes whenever the tokenstate does.
+    pub version: u128,
+    /// The name of the tokenstate's variant.
+    pub tokenstate: &'static str,
+    pub notification_count: Option<usize>,
+    pub last_notification_secs_ago: Option<u64>,
+    pub refreshed_secs_ago: Option<u64>,
+    pub last_refresh_attempt_secs_ago: Option<u64>,
+    pub consecutive_refresh_failures: Option<u32>,
+    /// When an active token expires or, for a failed token, when the old token expired.
+    pub expiry: Option<SystemTime>,
+    pub has_id_token: Option<bool>,
+    pub has_refresh_token: Option<bool>,
+    pub failed_secs_ago: Option<u64>,
+    pub failed_reason: Option<String>,
+    pub last_error: Option<(u64, String)>,
+    pub last_used_secs_ago: Option<u64>,
+}
+
+/// A refresh attempt for a [TokenState::Active] token.
+#[derive(Clone, Copy, Debug)]
+pub struct RefreshAttempt {
+    /// The instant in time when the attempt was made.
+    pub at: Instant,
+    /// If the server told us (via `Retry-After`) not to try again for a while, the instant in time
+    /// before which no further attempts may be made.
+    pub retry_after: Option<Instant>,
 }
 
 #[derive(Clone, Debug)]
@@ -332,58 +799,75 @@ pub enum TokenState {
     /// Pending authentication
     Pending {
         last_notification: Option<Instant>,
+        /// How many times has the user been notified about this pending authentication?
+        notification_count: usize,
+        /// If this is an OpenID Connect request, the nonce we sent, which must be matched by the
+        /// ID token we receive.
+        nonce: Option<String>,
         state: [u8; STATE_LEN],
         url: Url,
     },
+    /// The user has authenticated and we are exchanging the resulting code for a token. The
+    /// `state` that identified the [TokenState::Pending] this came from can no longer be matched.
+    Exchanging,
     /// There is an active token (and, possibly, also an active refresh token).
     Active {
-        access_token: String,
+        access_token: SecretString,
         refreshed_at: Instant,
-        /// The instant in time when the last ongoing, or unsuccessful, refresh attempt was made.
-        last_refresh_attempt: Option<Instant>,
-        expiry: Instant,
-        refresh_token: Option<String>,
+        /// The last ongoing, or unsuccessful, refresh attempt.
+        last_refresh_attempt: Option<RefreshAttempt>,
+        /// How many refresh attempts in a row have failed transiently since the token was last
+        /// obtained or refreshed.
+        consecutive_refresh_failures: u32,
+        /// When the token expires. This is a wall-clock time since [Instant]s may not advance
+        /// while the system is suspended.
+        expiry: SystemTime,
+        /// The OpenID Connect ID token, if one was provided.
+        id_token: Option<SecretString>,
+        refresh_token: Option<SecretString>,
+        /// The user's email address or user name, as returned by the account's
+        /// `userinfo_endpoint`, if it has one and the request succeeded.
+        display_name: Option<String>,
+    },
+    /// Refreshing the token failed in a way that we assume can only be fixed by the user
+    /// reauthenticating (e.g. the refresh token has been revoked). Until they do so, requests for
+    /// the token fail with `reason`.
+    Failed {
+        reason: String,
+        failed_at: Instant,
+        /// When the token that could not be refreshed expired (or expires).
+        previous_expiry: SystemTime,
     },
 }
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use crate::server::refresher::Refresher;
-
-    struct DummyFrontend;
-
-    impl Frontend for DummyFrontend {
-        fn new() -> Result<Self, Box<dyn std::error::Error>>
-        where
-            Self: Sized,
-        {
-            unreachable!()
-        }
-
-        fn main_loop(self: Arc<Self>) -> Result<(), Box<dyn std::error::Error>> {
-            unreachable!()
+impl TokenState {
+    /// Return the name of this tokenstate's variant, which reveals nothing secret.
+    pub fn variant_name(&self) -> &'static str {
+        match self {
+            TokenState::Empty => "Empty",
+            TokenState::Pending { .. } => "Pending",
+            TokenState::Exchanging => "Exchanging",
+            TokenState::Active { .. } => "Active",
+            TokenState::Failed { .. } => "Failed",
         }
+    }
+}
 
-        fn notify_error(
-            &self,
-            _act_name: String,
-            _msg: &str,
-        ) -> Result<(), Box<dyn std::error::Error>> {
-            unreachable!()
-        }
+/// Compare `a` and `b` in time that depends only on their lengths, so that an attacker cannot
+/// learn how much of a secret they have guessed correctly.
+fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
 
-        fn notify_success(&self, _act_name: String) -> Result<(), Box<dyn std::error::Error>> {
-            unreachable!()
-        }
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
 
-        fn notify_authorisations(
-            &self,
-            _to_notify: Vec<(String, Url)>,
-        ) -> Result<(), Box<dyn std::error::Error>> {
-            unreachable!()
-        }
-    }
+    use crate::server::{
+        refresher::Refresher,
+        test_utils::{act_conf, mock_pstate, DummyFrontend, MockClock, CONF_STR},
+    };
 
     #[test]
     fn test_act_validation() {
@@ -430,7 +914,15 @@ mod test {
         let conf = Config::from_str(conf1_str).unwrap();
         let frontend = Arc::new(DummyFrontend);
         let notifier = Arc::new(Notifier::new().unwrap());
-        let pstate = AuthenticatorState::new(conf, 0, frontend, notifier, Refresher::new());
+        let pstate = AuthenticatorState::new(
+            conf,
+            None,
+            0,
+            frontend,
+            notifier,
+            Refresher::new(None),
+            Arc::new(MockClock::new()),
+        );
 
         {
             let ct_lk = pstate.ct_lock();
@@ -440,7 +932,8 @@ mod test {
                 ct_lk.guard.tokenstate_version("x"),
                 TokenStateVersion {
                     tokenstate: TokenState::Empty,
-                    version: 0
+                    version: 0,
+                    ..
                 }
             ));
         }
@@ -453,7 +946,8 @@ mod test {
                 ct_lk.guard.tokenstate_version("x"),
                 TokenStateVersion {
                     tokenstate: TokenState::Empty,
-                    version: 1
+                    version: 1,
+                    ..
                 }
             ));
         }
@@ -466,7 +960,8 @@ mod test {
                 ct_lk.guard.tokenstate_version("x"),
                 TokenStateVersion {
                     tokenstate: TokenState::Empty,
-                    version: 1
+                    version: 1,
+                    ..
                 }
             ));
         }
@@ -479,7 +974,8 @@ mod test {
                 ct_lk.guard.tokenstate_version("x"),
                 TokenStateVersion {
                     tokenstate: TokenState::Empty,
-                    version: 2
+                    version: 2,
+                    ..
                 }
             ));
             assert!(ct_lk.validate_act_name("x").is_some());
@@ -488,7 +984,8 @@ mod test {
                 ct_lk.guard.tokenstate_version("y"),
                 TokenStateVersion {
                     tokenstate: TokenState::Empty,
-                    version: 0
+                    version: 0,
+                    ..
                 }
             ));
         }
@@ -501,7 +998,8 @@ mod test {
                 dbg!(ct_lk.guard.tokenstate_version("x")),
                 TokenStateVersion {
                     tokenstate: TokenState::Empty,
-                    version: 3
+                    version: 3,
+                    ..
                 }
             ));
             assert!(ct_lk.validate_act_name("x").is_some());
@@ -516,7 +1014,9 @@ mod test {
                 act_id,
                 TokenState::Pending {
                     last_notification: None,
-                    state: [0, 1, 2, 3, 4, 5, 6, 7],
+                    notification_count: 0,
+                    nonce: None,
+                    state: [0; STATE_LEN],
                     url: Url::parse("http://a.com/").unwrap(),
                 },
             );
@@ -524,12 +1024,23 @@ mod test {
                 ct_lk.guard.tokenstate_version("x"),
                 TokenStateVersion {
                     tokenstate: TokenState::Pending { .. },
-                    version: 4
+                    version: 4,
+                    ..
                 }
             ));
             assert!(ct_lk.validate_act_id(act_id).is_some());
         }
 
+        {
+            let mut ct_lk = pstate.ct_lock();
+            let act_id = ct_lk.validate_act_name("x").unwrap();
+            assert!(ct_lk.last_error(&act_id).is_none());
+            ct_lk.set_last_error(&act_id, "e".to_owned());
+            assert_eq!(ct_lk.last_error(&act_id).unwrap().1, "e");
+            // Recording an error must not invalidate the account ID.
+            assert!(ct_lk.validate_act_id(act_id).is_some());
+        }
+
         let conf = Config::from_str(conf2_str).unwrap();
         pstate.update_conf(conf);
         {
@@ -538,7 +1049,9 @@ mod test {
                 ct_lk.guard.tokenstate_version("x"),
                 TokenStateVersion {
                     tokenstate: TokenState::Pending { .. },
-                    version: 4
+                    version: 4,
+                    last_error: Some(_),
+                    ..
                 }
             ));
         }
@@ -551,9 +1064,234 @@ mod test {
                 ct_lk.guard.tokenstate_version("x"),
                 TokenStateVersion {
                     tokenstate: TokenState::Empty,
-                    version: 5
+                    version: 5,
+                    last_error: None,
+                    last_used: None,
+                    metrics: _,
+                }
+            ));
+        }
+    }
+    #[test]
+    fn test_bulk_tokenstate_replace() {
+        let conf_str = r#"
+            account "x" {
+                auth_uri = "http://a.com";
+                client_id = "b";
+                client_secret = "c";
+                scopes = ["d", "e"];
+                redirect_uri = "http://f.com";
+                token_uri = "http://g.com";
+            }
+
+            account "y" {
+                auth_uri = "http://a.com";
+                client_id = "b";
+                client_secret = "c";
+                scopes = ["d", "e"];
+                redirect_uri = "http://f.com";
+                token_uri = "http://g.com";
+            }
+            "#;
+
+        let conf = Config::from_str(conf_str).unwrap();
+        let frontend = Arc::new(DummyFrontend);
+        let notifier = Arc::new(Notifier::new().unwrap());
+        let pstate = AuthenticatorState::new(
+            conf,
+            None,
+            0,
+            frontend,
+            notifier,
+            Refresher::new(None),
+            Arc::new(MockClock::new()),
+        );
+        let pending = || TokenState::Pending {
+            last_notification: None,
+            notification_count: 0,
+            nonce: None,
+            state: [0; STATE_LEN],
+            url: Url::parse("http://a.com/").unwrap(),
+        };
+
+        let mut ct_lk = pstate.ct_lock();
+        let x_id = ct_lk.validate_act_name("x").unwrap();
+        let y_id = ct_lk.validate_act_name("y").unwrap();
+        let act_ids = ct_lk
+            .bulk_tokenstate_replace(vec![(x_id, pending()), (y_id, pending())])
+            .unwrap();
+        assert_eq!(act_ids.len(), 2);
+        for act_id in act_ids {
+            assert!(matches!(
+                ct_lk.tokenstate(&act_id),
+                TokenState::Pending { .. }
+            ));
+            assert!(ct_lk.validate_act_id(act_id).is_some());
+        }
+
+        // An out of date account ID must cause the whole batch to fail.
+        let x_id = ct_lk.validate_act_name("x").unwrap();
+        let x_old_id = ct_lk.validate_act_name("x").unwrap();
+        let y_id = ct_lk.validate_act_name("y").unwrap();
+        ct_lk.tokenstate_replace(x_id, TokenState::Empty);
+        assert!(ct_lk
+            .bulk_tokenstate_replace(vec![(y_id, TokenState::Empty), (x_old_id, pending())])
+            .is_none());
+        assert_eq!(ct_lk.guard.tokenstate_version("x").version, 2);
+        assert!(matches!(
+            ct_lk.guard.tokenstate_version("y").tokenstate,
+            TokenState::Pending { .. }
+        ));
+
+        // As must the same account appearing twice.
+        let x_id1 = ct_lk.validate_act_name("x").unwrap();
+        let x_id2 = ct_lk.validate_act_name("x").unwrap();
+        assert!(ct_lk
+            .bulk_tokenstate_replace(vec![(x_id1, pending()), (x_id2, pending())])
+            .is_none());
+        assert!(matches!(
+            ct_lk.guard.tokenstate_version("x").tokenstate,
+            TokenState::Empty
+        ));
+    }
+
+    #[test]
+    fn test_state_matching() {
+        assert!(ct_eq(b"abc", b"abc"));
+        assert!(!ct_eq(b"abc", b"abd"));
+        assert!(!ct_eq(b"abc", b"ab"));
+        assert!(ct_eq(b"", b""));
+
+        let (pstate, _) = mock_pstate(CONF_STR);
+        let mut state = [0; STATE_LEN];
+        state[STATE_LEN - 1] = 1;
+        let mut ct_lk = pstate.ct_lock();
+        let act_id = ct_lk.validate_act_name("x").unwrap();
+        ct_lk.tokenstate_replace(
+            act_id,
+            TokenState::Pending {
+                last_notification: None,
+                notification_count: 0,
+                nonce: None,
+                state,
+                url: Url::parse("http://a.com/").unwrap(),
+            },
+        );
+        assert!(ct_lk.act_id_matching_token_state(&[0; STATE_LEN]).is_none());
+        assert!(ct_lk.act_id_matching_token_state(&state[1..]).is_none());
+        let act_id = ct_lk.act_id_matching_token_state(&state).unwrap();
+
+        // Once a state has been matched and used, it must not match again.
+        ct_lk.tokenstate_replace(act_id, TokenState::Exchanging);
+        assert!(ct_lk.act_id_matching_token_state(&state).is_none());
+    }
+
+    #[test]
+    fn test_wait_for_inflight() {
+        let (pstate, _) = mock_pstate(CONF_STR);
+        assert_eq!(pstate.wait_for_inflight(Duration::ZERO), 0);
+        let a = pstate.inflight();
+        let b = pstate.inflight();
+        drop(a);
+        assert_eq!(pstate.wait_for_inflight(Duration::from_millis(10)), 1);
+        let t = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            drop(b);
+        });
+        assert_eq!(pstate.wait_for_inflight(Duration::from_secs(10)), 0);
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn test_accounts_requiring_auth() {
+        let (pstate, clock) = mock_pstate(&format!(
+            "{CONF_STR:} {}",
+            CONF_STR.replace("account \"x\"", "account \"w\"")
+        ));
+        let mut ct_lk = pstate.ct_lock();
+        assert_eq!(ct_lk.accounts_requiring_auth(), vec!["w", "x"]);
+        let act_id = ct_lk.validate_act_name("w").unwrap();
+        ct_lk.tokenstate_replace(act_id, TokenState::Exchanging);
+        assert_eq!(ct_lk.accounts_requiring_auth(), vec!["x"]);
+        let act_id = ct_lk.validate_act_name("x").unwrap();
+        ct_lk.tokenstate_replace(
+            act_id,
+            TokenState::Failed {
+                reason: "r".to_owned(),
+                failed_at: clock.now(),
+                previous_expiry: clock.wall_now(),
+            },
+        );
+        assert_eq!(ct_lk.accounts_requiring_auth(), vec!["x"]);
+    }
+
+    #[test]
+    fn test_cosmetic_changes_preserve_tokens() {
+        let conf_str = |fields: &[(&str, &str)]| act_conf("x", fields);
+        let (pstate, clock) = mock_pstate(&conf_str(&[]));
+        {
+            let mut ct_lk = pstate.ct_lock();
+            let act_id = ct_lk.validate_act_name("x").unwrap();
+            let act_id = ct_lk.tokenstate_replace(
+                act_id,
+                TokenState::Active {
+                    access_token: SecretString::from("a"),
+                    refreshed_at: clock.now(),
+                    last_refresh_attempt: None,
+                    consecutive_refresh_failures: 0,
+                    expiry: clock.wall_now(),
+                    id_token: None,
+                    refresh_token: None,
+                    display_name: None,
+                },
+            );
+            ct_lk.metrics_mut(&act_id).authentications += 1;
+        }
+
+        // Changing settings which don't affect the token's validity must leave the token alone.
+        pstate.update_conf(
+            Config::from_str(&conf_str(&[
+                ("notify_max_count", "2"),
+                ("notify_pending_interval", "1m"),
+                ("refresh_before_expiry", "1m"),
+                ("refresh_at_least", "1h"),
+                ("refresh_if_unused_for", "1d"),
+                ("revoke_uri", r#""http://h.com""#),
+            ]))
+            .unwrap(),
+        );
+        {
+            let ct_lk = pstate.ct_lock();
+            assert!(matches!(
+                ct_lk.guard.tokenstate_version("x"),
+                TokenStateVersion {
+                    tokenstate: TokenState::Active { .. },
+                    version: 1,
+                    ..
+                }
+            ));
+            let act_id = ct_lk.validate_act_name("x").unwrap();
+            assert_eq!(
+                ct_lk.account(&act_id).refresh_at_least,
+                Some(Duration::from_secs(3600))
+            );
+            assert_eq!(ct_lk.metrics(&act_id).authentications, 1);
+        }
+
+        // Changing settings which do affect the token's validity must reset it.
+        pstate.update_conf(Config::from_str(&conf_str(&[("login_hint", r#""i""#)])).unwrap());
+        {
+            let ct_lk = pstate.ct_lock();
+            assert!(matches!(
+                ct_lk.guard.tokenstate_version("x"),
+                TokenStateVersion {
+                    tokenstate: TokenState::Empty,
+                    version: 2,
+                    ..
                 }
             ));
+            let act_id = ct_lk.validate_act_name("x").unwrap();
+            assert_eq!(ct_lk.metrics(&act_id).authentications, 0);
         }
     }
 }
@@ -0,0 +1,284 @@
+//! `pizauth test-auth`: authenticate an account in the foreground, without a running server, so
+//! that users can check that an account works end-to-end before relying on it. The same account
+//! config, authorisation URL, and token request are used as by the server.
+
+use std::{
+    error::Error,
+    io::{self, BufRead},
+    net::{Ipv4Addr, TcpListener},
+};
+
+use rand::{thread_rng, RngCore};
+use url::Url;
+
+use super::{
+    http_server::{error_reason, grant, id_token_nonce, redirect_params, Grant},
+    make_token_request,
+    request_token::{build_url, run_auth_uri_override_cmd},
+    token_response::TokenResponse,
+    STATE_LEN,
+};
+use crate::{
+    config::{Account, AuthFlow},
+    secret::SecretString,
+};
+
+/// An authentication waiting for the user to visit `url`.
+struct Attempt {
+    url: Url,
+    redirect_uri: String,
+    state: [u8; STATE_LEN],
+    nonce: Option<String>,
+}
+
+/// Authenticate `act`: print the authorisation URL, read the URL the user's browser was then
+/// redirected to from stdin, exchange its code for a token, and report when the token expires.
+/// The token itself is discarded.
+pub fn test_auth(act: &Account) -> Result<(), Box<dyn Error>> {
+    if act.auth_flow == AuthFlow::ClientCredentials {
+        return Err(format!(
+            "{}: client credentials accounts don't need the user to authenticate: use 'pizauth \
+            test {}' instead",
+            act.name, act.name
+        )
+        .into());
+    }
+    // As with the server, the redirect URI's port is the one in the config or, if there isn't
+    // one, a port chosen by pizauth.
+    let http_port = match act.redirect_uri_port() {
+        Some(x) => x,
+        None => unused_port()?,
+    };
+    let attempt = start(act, http_port)?;
+    println!(
+        "Open this URL in a browser and authenticate:\n\n{}\n\nNothing is listening on the \
+        redirect URI, so the browser will then fail to load a page: paste the URL in its address \
+        bar here.",
+        attempt.url
+    );
+    let mut line = String::new();
+    if io::stdin().lock().read_line(&mut line)? == 0 {
+        return Err("No URL entered".into());
+    }
+    let parsed = finish(act, &attempt, line.trim())?;
+    println!(
+        "{}: authentication succeeded: access token expires in {}s",
+        act.name,
+        parsed.lifetime(act.default_token_lifetime).as_secs()
+    );
+    if parsed.refresh_token.is_none() {
+        println!(
+            "{}: no refresh token was issued, so the user will have to authenticate again when \
+            the access token expires",
+            act.name
+        );
+    }
+    Ok(())
+}
+
+/// Start an authentication for `act`, whose redirect URI uses `http_port`.
+fn start(act: &Account, http_port: u16) -> Result<Attempt, Box<dyn Error>> {
+    let auth_uri = act
+        .auth_uri
+        .clone()
+        .ok_or("No auth_uri for authorization code flow")?;
+    let mut state = [0u8; STATE_LEN];
+    thread_rng().fill_bytes(&mut state);
+    let state_str = urlencoding::encode_binary(&state).into_owned();
+    let redirect_uri = act.redirect_uri(http_port)?.to_string();
+    let (url, nonce) = match &act.auth_uri_override_cmd {
+        Some(cmd) => {
+            let vars = [
+                ("account", act.name.clone()),
+                ("auth_uri", auth_uri),
+                ("redirect_uri", redirect_uri.clone()),
+                ("state", state_str),
+            ];
+            let url = run_auth_uri_override_cmd(cmd, &vars)
+                .map_err(|e| format!("auth_uri_override_cmd failed: {e:}"))?;
+            (url, None)
+        }
+        None => build_url(act, &act.scopes(), &auth_uri, &redirect_uri, &state_str)?,
+    };
+    Ok(Attempt {
+        url,
+        redirect_uri,
+        state,
+        nonce,
+    })
+}
+
+/// Complete `attempt` with `url`, the URL the user's browser was redirected to, returning the
+/// token response if it contains a token the server would accept.
+fn finish(act: &Account, attempt: &Attempt, url: &str) -> Result<TokenResponse, Box<dyn Error>> {
+    let params = redirect_params(url)?;
+    match params.get("state") {
+        Some(x) if urlencoding::decode_binary(x.as_bytes())[..] == attempt.state[..] => (),
+        Some(_) => {
+            return Err(
+                "URL's state doesn't match: check that the URL is from this authentication attempt"
+                    .into(),
+            )
+        }
+        None => return Err("No 'state' in URL: check that the whole URL was copied".into()),
+    }
+    if let Some(reason) = error_reason(&params) {
+        return Err(format!("Authentication failed: {reason:}").into());
+    }
+    let parsed = match grant(act, &params)
+        .map_err(|e| format!("{e:} in URL: check that the whole URL was copied"))?
+    {
+        Grant::Code(code) => exchange(act, &code, &attempt.redirect_uri)?,
+        Grant::Token(x) => x,
+    };
+
+    if let Some(err_msg) = parsed.error.as_deref() {
+        return Err(match parsed.error_description.as_deref() {
+            Some(desc) => format!("Token endpoint rejected the code: {err_msg:}: {desc:}"),
+            None => format!("Token endpoint rejected the code: {err_msg:}"),
+        }
+        .into());
+    }
+    if parsed.access_token.is_none() || !parsed.is_bearer() {
+        return Err("Token endpoint sent an invalid response".into());
+    }
+    if let Some(nonce) = &attempt.nonce {
+        match parsed.id_token.as_ref().map(|x| id_token_nonce(x.expose())) {
+            Some(Ok(Some(x))) if &x == nonce => (),
+            _ => return Err("ID token missing or has incorrect nonce".into()),
+        }
+    }
+    Ok(parsed)
+}
+
+/// Exchange the authorisation `code` for a token. Unlike the server, transient errors aren't
+/// retried: the user can simply run `pizauth test-auth` again.
+fn exchange(
+    act: &Account,
+    code: &str,
+    redirect_uri: &str,
+) -> Result<TokenResponse, Box<dyn Error>> {
+    let pairs = [
+        ("code", code),
+        ("client_id", act.client_id.as_str()),
+        ("client_secret", act.client_secret.expose()),
+        ("redirect_uri", redirect_uri),
+        ("grant_type", "authorization_code"),
+    ];
+    let response = match make_token_request(act, &pairs) {
+        Ok(x) => x,
+        Err(ureq::Error::Status(code, response)) => {
+            return Err(match response.into_string() {
+                Ok(r) => format!("Token endpoint rejected the code: {code:}: {r:}"),
+                Err(_) => format!("Token endpoint rejected the code: {code:}"),
+            }
+            .into())
+        }
+        Err(e) => {
+            return Err(format!(
+                "couldn't connect to {}: {e:}{}",
+                act.token_uri,
+                act.transport_desc(&act.token_uri)
+            )
+            .into())
+        }
+    };
+    let content_type = response.content_type().to_owned();
+    // The body contains secrets.
+    let body = SecretString::from(response.into_string()?);
+    Ok(TokenResponse::parse(&content_type, &body)?)
+}
+
+/// Return a loopback port which nothing is currently listening on.
+fn unused_port() -> io::Result<u16> {
+    Ok(TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?
+        .local_addr()?
+        .port())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{sync::Arc, time::Duration};
+
+    use crate::{
+        config::Config,
+        server::test_utils::{MockOAuthServer, CONF_STR},
+    };
+
+    #[test]
+    fn test_test_auth() {
+        let server = MockOAuthServer::new();
+        let conf = Config::from_str(
+            &CONF_STR
+                .replace("http://a.com", &server.auth_uri())
+                .replace("http://g.com", &server.token_uri()),
+        )
+        .unwrap();
+        let act = Arc::clone(&conf.accounts["x"]);
+        let attempt = start(&act, 1234).unwrap();
+        assert_eq!(attempt.redirect_uri, "http://f.com:1234/");
+
+        // The mock server redirects straight back to the redirect URI with a code.
+        let redirect = ureq::AgentBuilder::new()
+            .redirects(0)
+            .build()
+            .get(attempt.url.as_str())
+            .call()
+            .unwrap();
+        let redirected_to = redirect.header("Location").unwrap().to_owned();
+
+        assert!(finish(&act, &attempt, "f.com/?code=c")
+            .unwrap_err()
+            .to_string()
+            .starts_with("Invalid URL"));
+        assert!(finish(&act, &attempt, "http://f.com:1234/?code=c")
+            .unwrap_err()
+            .to_string()
+            .starts_with("No 'state' in URL"));
+        assert!(finish(&act, &attempt, "http://f.com:1234/?state=a&code=c")
+            .unwrap_err()
+            .to_string()
+            .starts_with("URL's state doesn't match"));
+        let state_str = urlencoding::encode_binary(&attempt.state);
+        assert_eq!(
+            finish(
+                &act,
+                &attempt,
+                &format!("http://f.com:1234/?state={state_str:}&error=access_denied")
+            )
+            .unwrap_err()
+            .to_string(),
+            "Authentication failed: access_denied"
+        );
+        assert!(finish(
+            &act,
+            &attempt,
+            &format!("http://f.com:1234/?state={state_str:}&code=wrong")
+        )
+        .unwrap_err()
+        .to_string()
+        .starts_with("Token endpoint rejected the code: 400"));
+
+        let parsed = finish(&act, &attempt, &redirected_to).unwrap();
+        assert_eq!(
+            parsed.lifetime(act.default_token_lifetime),
+            Duration::from_secs(3600)
+        );
+        assert!(parsed.refresh_token.is_some());
+        assert_eq!(server.issued(), 1);
+    }
+
+    #[test]
+    fn test_client_credentials() {
+        let conf = Config::from_str(&CONF_STR.replace(
+            r#"client_id = "b";"#,
+            r#"client_id = "b"; auth_flow = "client_credentials";"#,
+        ))
+        .unwrap();
+        assert!(test_auth(&conf.accounts["x"])
+            .unwrap_err()
+            .to_string()
+            .contains("don't need the user to authenticate"));
+    }
+}
@@ -0,0 +1,348 @@
+//! Helpers shared by the server's tests.
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime},
+};
+
+use url::{form_urlencoded, Url};
+
+use super::{clock::Clock, notifier::Notifier, refresher::Refresher, AuthenticatorState};
+use crate::{
+    config::Config,
+    frontends::{ErrorKind, Frontend},
+};
+
+/// A minimal, valid, configuration with a single account "x".
+pub const CONF_STR: &str = r#"
+    account "x" {
+        auth_uri = "http://a.com";
+        client_id = "b";
+        client_secret = "c";
+        scopes = ["d", "e"];
+        redirect_uri = "http://f.com";
+        token_uri = "http://g.com";
+    }
+    "#;
+
+/// The fields of an account created by [act_conf], unless overridden.
+const ACT_CONF_FIELDS: [(&str, &str); 6] = [
+    ("auth_uri", r#""http://a.com""#),
+    ("client_id", r#""b""#),
+    ("client_secret", r#""c""#),
+    ("scopes", r#"["d"]"#),
+    ("redirect_uri", r#""http://f.com""#),
+    ("token_uri", r#""http://g.com""#),
+];
+
+/// Return the config for a valid account `act_name`. Each `(name, value)` in `fields` replaces the
+/// default field `name` (or, if there is no such default, is added), where `value` is in config
+/// syntax (e.g. `("tags", r#"["a"]"#)`), and the first of several `(name, value)`s with the same
+/// `name` takes precedence. A field with an empty `value` is omitted. Each field is on its own
+/// line, so account `n` (counting from 0) of several joined with newlines starts on line `8n + 1`
+/// if no fields are added or removed.
+pub fn act_conf(act_name: &str, fields: &[(&str, &str)]) -> String {
+    let mut lines = vec![format!("account \"{act_name:}\" {{")];
+    for (name, value) in ACT_CONF_FIELDS {
+        let value = match fields.iter().find(|(x, _)| *x == name) {
+            Some((_, x)) => x,
+            None => value,
+        };
+        if !value.is_empty() {
+            lines.push(format!("    {name:} = {value:};"));
+        }
+    }
+    for (i, (name, value)) in fields.iter().enumerate() {
+        if !value.is_empty()
+            && !ACT_CONF_FIELDS.iter().any(|(x, _)| x == name)
+            && !fields[..i].iter().any(|(x, _)| x == name)
+        {
+            lines.push(format!("    {name:} = {value:};"));
+        }
+    }
+    lines.push("}".to_owned());
+    lines.join("\n")
+}
+
+/// Create an [AuthenticatorState] for `conf_str` whose time is controlled by the returned
+/// [MockClock].
+pub fn mock_pstate(conf_str: &str) -> (AuthenticatorState, Arc<MockClock>) {
+    mock_pstate_with_port(conf_str, 0)
+}
+
+/// As [mock_pstate], but for an HTTP server listening on `http_port`.
+pub fn mock_pstate_with_port(
+    conf_str: &str,
+    http_port: u16,
+) -> (AuthenticatorState, Arc<MockClock>) {
+    let clock = Arc::new(MockClock::new());
+    let pstate = AuthenticatorState::new(
+        Config::from_str(conf_str).unwrap(),
+        Some(PathBuf::from("pizauth.conf")),
+        http_port,
+        Arc::new(DummyFrontend),
+        Arc::new(Notifier::new().unwrap()),
+        Refresher::new(None),
+        Arc::clone(&clock) as Arc<dyn Clock>,
+    );
+    (pstate, clock)
+}
+
+/// A frontend which ignores notifications. Its other functions must never be called.
+pub struct DummyFrontend;
+
+impl Frontend for DummyFrontend {
+    fn main_loop(self: Arc<Self>) -> Result<(), Box<dyn Error>> {
+        unreachable!()
+    }
+
+    fn notify_error(
+        &self,
+        _act_name: Option<String>,
+        _kind: ErrorKind,
+        _msg: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn notify_success(&self, _act_name: String) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn notify_authorisations(&self, _to_notify: Vec<(String, Url)>) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+}
+
+/// A clock which only moves when told to.
+pub struct MockClock {
+    times: Mutex<(Instant, SystemTime)>,
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        MockClock {
+            times: Mutex::new((Instant::now(), SystemTime::now())),
+        }
+    }
+
+    /// Advance both the monotonic and wall-clock times by `d`.
+    pub fn advance(&self, d: Duration) {
+        let mut lk = self.times.lock().unwrap();
+        lk.0 += d;
+        lk.1 += d;
+    }
+
+    /// Advance only the wall-clock time by `d`, as happens when the system is suspended.
+    pub fn suspend(&self, d: Duration) {
+        self.times.lock().unwrap().1 += d;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.times.lock().unwrap().0
+    }
+
+    fn wall_now(&self) -> SystemTime {
+        self.times.lock().unwrap().1
+    }
+}
+
+/// An in-memory OAuth2 server implementing just enough of the authorisation code flow for tests.
+/// Its authorisation endpoint approves every request immediately, redirecting the user's "browser"
+/// back to the `redirect_uri` it was given; its token endpoint exchanges codes and refresh tokens
+/// for new access tokens, and also issues access tokens for the client credentials grant if the
+/// client secret is "c". The server is shut down when this struct is dropped.
+pub struct MockOAuthServer {
+    port: u16,
+    /// How many access tokens have been issued.
+    issued: Arc<AtomicUsize>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MockOAuthServer {
+    pub fn new() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let issued = Arc::new(AtomicUsize::new(0));
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let issued = Arc::clone(&issued);
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if stop.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    if let Ok(stream) = stream {
+                        // Errors only affect the request in question, which the test will notice.
+                        mock_oauth_request(stream, &issued).ok();
+                    }
+                }
+            })
+        };
+        MockOAuthServer {
+            port,
+            issued,
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    pub fn auth_uri(&self) -> String {
+        format!("http://127.0.0.1:{}/auth", self.port)
+    }
+
+    pub fn token_uri(&self) -> String {
+        format!("http://127.0.0.1:{}/token", self.port)
+    }
+
+    /// As [act_conf], but for an account which authenticates with this server.
+    pub fn act_conf(&self, act_name: &str, fields: &[(&str, &str)]) -> String {
+        let auth_uri = format!(r#""{}""#, self.auth_uri());
+        let token_uri = format!(r#""{}""#, self.token_uri());
+        let mut all = fields.to_vec();
+        all.extend([
+            ("auth_uri", auth_uri.as_str()),
+            ("redirect_uri", r#""http://127.0.0.1/""#),
+            ("token_uri", token_uri.as_str()),
+        ]);
+        act_conf(act_name, &all)
+    }
+
+    /// How many access tokens has this server issued?
+    pub fn issued(&self) -> usize {
+        self.issued.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for MockOAuthServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // Wake the server thread up so that it notices `stop`.
+        TcpStream::connect(("127.0.0.1", self.port)).ok();
+        if let Some(x) = self.thread.take() {
+            x.join().unwrap();
+        }
+    }
+}
+
+/// Respond to a single request to a [MockOAuthServer].
+fn mock_oauth_request(stream: TcpStream, issued: &AtomicUsize) -> Result<(), Box<dyn Error>> {
+    let mut rdr = BufReader::new(stream);
+    let mut req_line = String::new();
+    rdr.read_line(&mut req_line)?;
+    let mut len = 0;
+    loop {
+        let mut line = String::new();
+        rdr.read_line(&mut line)?;
+        if line.trim().is_empty() {
+            break;
+        }
+        if let Some((k, v)) = line.split_once(':') {
+            if k.eq_ignore_ascii_case("content-length") {
+                len = v.trim().parse::<usize>()?;
+            }
+        }
+    }
+    let mut body = vec![0; len];
+    rdr.read_exact(&mut body)?;
+
+    let (method, target) = match req_line.split(' ').collect::<Vec<_>>()[..] {
+        [method, target, _] => (method, target),
+        _ => return Err("Malformed request".into()),
+    };
+    let url = Url::parse(&format!("http://127.0.0.1{target:}"))?;
+    let (status, headers, body) = match (method, url.path()) {
+        ("GET", "/auth") => {
+            let params = url.query_pairs().collect::<HashMap<_, _>>();
+            match (params.get("redirect_uri"), params.get("state")) {
+                (Some(redirect_uri), Some(state)) => {
+                    let mut redirect = Url::parse(redirect_uri)?;
+                    redirect
+                        .query_pairs_mut()
+                        .append_pair("code", "mock_code")
+                        .append_pair("state", state);
+                    (
+                        "302 Found",
+                        format!("Location: {redirect:}\r\n"),
+                        String::new(),
+                    )
+                }
+                _ => ("400 Bad Request", String::new(), String::new()),
+            }
+        }
+        (method @ ("GET" | "POST"), "/token") => {
+            // Accounts with `token_uri_method = "GET"` send the parameters in the query string.
+            let form = if method == "GET" {
+                url.query_pairs().into_owned().collect::<HashMap<_, _>>()
+            } else {
+                form_urlencoded::parse(&body)
+                    .into_owned()
+                    .collect::<HashMap<_, _>>()
+            };
+            // `Some(None)` means that a token is issued without a refresh token.
+            let refresh_token = match form.get("grant_type").map(|x| x.as_str()) {
+                Some("authorization_code")
+                    if form.get("code").map(|x| x.as_str()) == Some("mock_code") =>
+                {
+                    Some(Some(format!("refresh_{}", issued.load(Ordering::SeqCst))))
+                }
+                // A refresh token of "revoked" behaves as if the user had revoked it.
+                Some("refresh_token") => form
+                    .get("refresh_token")
+                    .filter(|x| *x != "revoked")
+                    .cloned()
+                    .map(Some),
+                Some("client_credentials")
+                    if form.get("client_secret").map(|x| x.as_str()) == Some("c") =>
+                {
+                    Some(None)
+                }
+                _ => None,
+            };
+            match refresh_token {
+                Some(refresh_token) => {
+                    let n = issued.fetch_add(1, Ordering::SeqCst);
+                    let mut token = json::object! {
+                        token_type: "Bearer",
+                        expires_in: 3600,
+                        access_token: format!("access_{n:}"),
+                    };
+                    if let Some(x) = refresh_token {
+                        token["refresh_token"] = x.into();
+                    }
+                    (
+                        "200 OK",
+                        "Content-Type: application/json\r\n".to_owned(),
+                        token.dump(),
+                    )
+                }
+                None => (
+                    "400 Bad Request",
+                    "Content-Type: application/json\r\n".to_owned(),
+                    r#"{"error": "invalid_grant"}"#.to_owned(),
+                ),
+            }
+        }
+        _ => ("404 Not Found", String::new(), String::new()),
+    };
+    let resp = format!(
+        "HTTP/1.1 {status:}\r\n{headers:}Content-Length: {}\r\nConnection: close\r\n\r\n\
+         {body:}",
+        body.len()
+    );
+    rdr.into_inner().write_all(resp.as_bytes())?;
+    Ok(())
+}
@@ -0,0 +1,195 @@
+//! Parsing the responses of OAuth2 token endpoints. RFC 6749 section 5.1 requires these to be JSON
+//! objects, but some (typically older) servers send them form encoded instead, and not all servers
+//! send every field that they should.
+
+use std::time::Duration;
+
+use json::JsonValue;
+use url::form_urlencoded;
+
+use crate::secret::SecretString;
+
+/// The MIME type of form encoded responses.
+const FORM_CONTENT_TYPE: &str = "application/x-www-form-urlencoded";
+
+/// The fields pizauth uses from a token endpoint's response, whether it was successful or not.
+/// Unknown fields are ignored.
+#[derive(Debug, Default)]
+pub struct TokenResponse {
+    /// The OAuth2 error code, if the server rejected the request.
+    pub error: Option<String>,
+    pub error_description: Option<String>,
+    pub access_token: Option<SecretString>,
+    pub token_type: Option<String>,
+    /// How many seconds the access token is valid for, if the server told us.
+    pub expires_in: Option<u64>,
+    pub id_token: Option<SecretString>,
+    pub refresh_token: Option<SecretString>,
+}
+
+impl TokenResponse {
+    /// Parse `body`, which the server said has the MIME type `content_type`. Anything which isn't
+    /// explicitly form encoded is assumed to be JSON, since some servers send JSON with a generic
+    /// content type such as `text/plain`.
+    pub fn parse(content_type: &str, body: &SecretString) -> Result<Self, String> {
+        if content_type.trim().eq_ignore_ascii_case(FORM_CONTENT_TYPE) {
+            Self::from_form(body.expose())
+        } else {
+            Self::from_json(body.expose())
+        }
+    }
+
+    fn from_form(body: &str) -> Result<Self, String> {
+        Self::from_pairs(form_urlencoded::parse(body.trim().as_bytes()))
+    }
+
+    /// Build a response from already decoded key/value pairs (e.g. the parameters of an implicit
+    /// flow redirect).
+    pub fn from_pairs<K: AsRef<str>, V: AsRef<str>>(
+        pairs: impl IntoIterator<Item = (K, V)>,
+    ) -> Result<Self, String> {
+        let mut r = TokenResponse::default();
+        for (k, v) in pairs {
+            let v = v.as_ref();
+            match k.as_ref() {
+                "access_token" => r.access_token = Some(SecretString::from(v)),
+                "error" => r.error = Some(v.to_owned()),
+                "error_description" => r.error_description = Some(v.to_owned()),
+                "expires_in" => r.expires_in = Some(parse_expires_in(v)?),
+                "id_token" => r.id_token = Some(SecretString::from(v)),
+                "refresh_token" => r.refresh_token = Some(SecretString::from(v)),
+                "token_type" => r.token_type = Some(v.to_owned()),
+                _ => (),
+            }
+        }
+        Ok(r)
+    }
+
+    fn from_json(body: &str) -> Result<Self, String> {
+        let parsed = json::parse(body).map_err(|e| e.to_string())?;
+        let string = |k: &str| parsed[k].as_str().map(|x| x.to_owned());
+        let secret = |k: &str| parsed[k].as_str().map(SecretString::from);
+        // Some servers send `expires_in` as a string rather than a number.
+        let expires_in = match &parsed["expires_in"] {
+            JsonValue::Null => None,
+            JsonValue::Short(x) => Some(parse_expires_in(x.as_str())?),
+            JsonValue::String(x) => Some(parse_expires_in(x)?),
+            x => Some(x.as_u64().ok_or("Invalid expires_in")?),
+        };
+        Ok(TokenResponse {
+            error: string("error"),
+            error_description: string("error_description"),
+            access_token: secret("access_token"),
+            token_type: string("token_type"),
+            expires_in,
+            id_token: secret("id_token"),
+            refresh_token: secret("refresh_token"),
+        })
+    }
+
+    /// Is this a bearer token? Token types are case insensitive (RFC 6749 section 5.1), and some
+    /// servers send `bearer`.
+    pub fn is_bearer(&self) -> bool {
+        self.token_type
+            .as_deref()
+            .is_some_and(|x| x.eq_ignore_ascii_case("Bearer"))
+    }
+
+    /// How long is the access token valid for? If the server didn't tell us, `default` is
+    /// returned.
+    pub fn lifetime(&self, default: Duration) -> Duration {
+        self.expires_in.map(Duration::from_secs).unwrap_or(default)
+    }
+}
+
+fn parse_expires_in(s: &str) -> Result<u64, String> {
+    s.trim()
+        .parse::<u64>()
+        .map_err(|_| "Invalid expires_in".to_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(content_type: &str, body: &str) -> Result<TokenResponse, String> {
+        TokenResponse::parse(content_type, &SecretString::from(body))
+    }
+
+    #[test]
+    fn test_json() {
+        let r = parse(
+            "application/json",
+            r#"{"access_token": "a", "token_type": "Bearer", "expires_in": 3600,
+                "refresh_token": "r", "id_token": "i", "unknown": [1, 2]}"#,
+        )
+        .unwrap();
+        assert_eq!(r.access_token.as_ref().unwrap().expose(), "a");
+        assert!(r.is_bearer());
+        assert_eq!(r.expires_in, Some(3600));
+        assert_eq!(r.refresh_token.as_ref().unwrap().expose(), "r");
+        assert_eq!(r.id_token.as_ref().unwrap().expose(), "i");
+        assert!(r.error.is_none());
+
+        // Servers which don't set a JSON content type are still understood.
+        let r = parse("text/plain", r#"{"error": "e", "error_description": "d"}"#).unwrap();
+        assert_eq!(r.error.as_deref(), Some("e"));
+        assert_eq!(r.error_description.as_deref(), Some("d"));
+        assert!(r.access_token.is_none());
+
+        assert!(parse("application/json", "access_token=a").is_err());
+    }
+
+    #[test]
+    fn test_form() {
+        let r = parse(
+            "application/x-www-form-urlencoded",
+            "access_token=a%20b&token_type=bearer&scope=repo&expires_in=60\n",
+        )
+        .unwrap();
+        assert_eq!(r.access_token.as_ref().unwrap().expose(), "a b");
+        assert!(r.is_bearer());
+        assert_eq!(r.expires_in, Some(60));
+        assert!(r.refresh_token.is_none());
+
+        let r = parse(
+            "application/x-www-form-urlencoded",
+            "error=bad_verification_code&error_description=The+code+is+wrong",
+        )
+        .unwrap();
+        assert_eq!(r.error.as_deref(), Some("bad_verification_code"));
+        assert_eq!(r.error_description.as_deref(), Some("The code is wrong"));
+    }
+
+    #[test]
+    fn test_expires_in() {
+        let json = |x: &str| {
+            parse(
+                "application/json",
+                &format!(r#"{{"access_token": "a", "token_type": "Bearer"{x:}}}"#),
+            )
+        };
+        let default = Duration::from_secs(123);
+        assert_eq!(json("").unwrap().lifetime(default), default);
+        assert_eq!(
+            json(r#", "expires_in": 60"#).unwrap().lifetime(default),
+            Duration::from_secs(60)
+        );
+        assert_eq!(
+            json(r#", "expires_in": "60""#).unwrap().lifetime(default),
+            Duration::from_secs(60)
+        );
+        assert_eq!(
+            json(r#", "expires_in": "a long time and then some more""#).unwrap_err(),
+            "Invalid expires_in"
+        );
+        assert_eq!(
+            json(r#", "expires_in": -1"#).unwrap_err(),
+            "Invalid expires_in"
+        );
+        assert_eq!(
+            parse("application/x-www-form-urlencoded", "expires_in=x").unwrap_err(),
+            "Invalid expires_in"
+        );
+    }
+}
@@ -0,0 +1,106 @@
+//! Fetching the user's email address or user name from an account's OpenID Connect UserInfo
+//! endpoint, so that `pizauth status` can show who each account is authenticated as.
+
+use std::{error::Error, sync::Arc, thread};
+
+use log::warn;
+
+use super::{AuthenticatorState, TokenState};
+use crate::{config::Account, secret::SecretString};
+
+/// If `act` has a `userinfo_endpoint`, fetch, in a new thread, the display name of the user who
+/// was just issued `access_token`, and store it in the account's tokenstate. This is best effort:
+/// if the request fails, or the account's token has changed by the time it completes, the token
+/// is unaffected and no display name is shown.
+pub fn fetch_display_name(
+    pstate: Arc<AuthenticatorState>,
+    act: Arc<Account>,
+    access_token: SecretString,
+) {
+    let userinfo_endpoint = match &act.userinfo_endpoint {
+        Some(x) => x.clone(),
+        None => return,
+    };
+    thread::spawn(move || {
+        let name = match request_display_name(&act, &userinfo_endpoint, &access_token) {
+            Ok(Some(x)) => x,
+            Ok(None) => return,
+            Err(e) => {
+                warn!("{}: Can't fetch userinfo: {e:}", act.name);
+                return;
+            }
+        };
+        let mut ct_lk = pstate.ct_lock();
+        let act_id = match ct_lk.validate_act_name(&act.name) {
+            Some(x) => x,
+            None => return,
+        };
+        let mut new_ts = ct_lk.tokenstate(&act_id).clone();
+        if let TokenState::Active {
+            access_token: ref x,
+            ref mut display_name,
+            ..
+        } = new_ts
+        {
+            if x.expose() == access_token.expose() {
+                *display_name = Some(name);
+                ct_lk.tokenstate_replace(act_id, new_ts);
+            }
+        }
+    });
+}
+
+/// Request `userinfo_endpoint` with `access_token`, returning the display name it contains (if
+/// any).
+fn request_display_name(
+    act: &Account,
+    userinfo_endpoint: &str,
+    access_token: &SecretString,
+) -> Result<Option<String>, Box<dyn Error>> {
+    let body = act
+        .agent(userinfo_endpoint)
+        .get(userinfo_endpoint)
+        .set(
+            "Authorization",
+            &format!("Bearer {}", access_token.expose()),
+        )
+        .call()?
+        .into_string()?;
+    display_name(&body)
+}
+
+/// Return the display name in the UserInfo response `body`: the `email` claim if there is one,
+/// or otherwise the `preferred_username` claim.
+fn display_name(body: &str) -> Result<Option<String>, Box<dyn Error>> {
+    let parsed = json::parse(body)?;
+    Ok(["email", "preferred_username"]
+        .iter()
+        .filter_map(|k| parsed[*k].as_str())
+        // Control characters would garble `pizauth status`.
+        .find(|x| !x.is_empty() && !x.chars().any(|c| c.is_control()))
+        .map(|x| x.to_owned()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_display_name() {
+        assert_eq!(
+            display_name(r#"{"sub": "1", "email": "a@b.com", "preferred_username": "a"}"#)
+                .unwrap()
+                .as_deref(),
+            Some("a@b.com")
+        );
+        assert_eq!(
+            display_name(r#"{"sub": "1", "email": "", "preferred_username": "a"}"#)
+                .unwrap()
+                .as_deref(),
+            Some("a")
+        );
+        assert_eq!(display_name(r#"{"sub": "1", "email": 3}"#).unwrap(), None);
+        assert_eq!(display_name(r#"{"email": "a\nb"}"#).unwrap(), None);
+        assert!(display_name("<html>").is_err());
+    }
+}
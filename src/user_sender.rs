@@ -1,105 +1,1291 @@
 use std::{
-    error::Error,
-    io::{Read, Write},
+    collections::BTreeMap,
+    env,
+    io::{self, Read},
     net::Shutdown,
     os::unix::net::UnixStream,
     path::{Path, PathBuf},
+    process::{self, Command},
+    thread,
+    time::{Duration, Instant},
 };
 
-use crate::{config::Config, server::sock_path};
+use json::JsonValue;
+use log::error;
+use nix::sys::signal::{SigSet, Signal};
+
+use crate::{
+    config::Config,
+    error::PizauthError,
+    ipc::{
+        encode_request, read_frame, split_reply, write_frame, VersionMismatch, PROTOCOL_VERSION,
+    },
+    secret::SecretString,
+    server::{sock_path, DUMP_VERSION},
+};
+
+/// How long does `pizauth restart` wait for the old server to exit?
+const RESTART_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The results of a request about several accounts: each account's name and whether the request
+/// succeeded for it.
+pub type AccountResults = Vec<(String, Result<(), PizauthError>)>;
+
+/// The results of a request for several accounts' tokens: each account's name and its token (or
+/// why it couldn't be shown).
+pub type TokenResults = Vec<(String, Result<ShownToken, PizauthError>)>;
+
+/// A token handed out by the server.
+#[derive(Debug)]
+pub struct ShownToken {
+    pub token: SecretString,
+    /// How long until the token expires.
+    pub expires_in: Duration,
+}
+
+/// A connection to the pizauth server. The client sends all of its requests before reading any
+/// replies, so each connection is used for a single batch of requests: the methods below thus
+/// consume the client.
+pub struct Client {
+    stream: UnixStream,
+}
+
+impl Client {
+    /// Connect to the server listening on `sock_path`. If the server takes longer than `timeout`
+    /// to accept, or reply to, any request, [PizauthError::Timeout] is returned, so that a
+    /// deadlocked server can't hang the client (and whatever is waiting for it) forever.
+    pub fn connect(sock_path: &Path, timeout: Duration) -> Result<Self, PizauthError> {
+        let stream = UnixStream::connect(sock_path).map_err(|_| PizauthError::DaemonNotRunning)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        Ok(Client::from_stream(stream))
+    }
+
+    fn from_stream(stream: UnixStream) -> Self {
+        Client { stream }
+    }
+
+    /// Send each of `cmds` to the server, returning the server's replies in the same order.
+    fn send(mut self, cmds: &[String]) -> Result<Vec<String>, PizauthError> {
+        for cmd in cmds {
+            write_frame(&mut self.stream, cmd.as_bytes())?;
+        }
+        self.stream.shutdown(Shutdown::Write)?;
+
+        let mut replies = Vec::with_capacity(cmds.len());
+        for _ in cmds {
+            match read_frame(&mut self.stream).map_err(version_mismatch)? {
+                Some(x) => replies.push(x),
+                None => {
+                    return Err(PizauthError::ProtocolError(
+                        "Server closed the connection without replying".to_owned(),
+                    ))
+                }
+            }
+        }
+        Ok(replies)
+    }
+
+    /// Send `cmd` to the server, returning its reply.
+    fn send_one(self, cmd: String) -> Result<String, PizauthError> {
+        Ok(self.send(&[cmd])?.remove(0))
+    }
+
+    /// Send the command `cmd` for each of `accounts`, where a reply of kind `ok` means that the
+    /// command succeeded.
+    fn send_accounts(
+        self,
+        cmd: &str,
+        accounts: &[String],
+        ok: &str,
+    ) -> Result<AccountResults, PizauthError> {
+        let cmds = accounts
+            .iter()
+            .map(|x| encode_request(cmd, &[x]))
+            .collect::<Vec<_>>();
+        Ok(accounts
+            .iter()
+            .zip(self.send(&cmds)?)
+            .map(|(act_name, rtn)| {
+                let r = match split_reply(&rtn) {
+                    Some((kind, "")) if kind == ok => Ok(()),
+                    Some(("error", cause)) => {
+                        Err(PizauthError::ServerError(format!("{act_name}:{cause:}")))
+                    }
+                    Some(("no_account", "")) => {
+                        Err(PizauthError::AccountNotFound(act_name.to_owned()))
+                    }
+                    Some(("pending", "")) => Err(PizauthError::TokenPending(act_name.to_owned())),
+                    _ => Err(PizauthError::ProtocolError(format!(
+                        "{act_name:}: Malformed response '{rtn:}'"
+                    ))),
+                };
+                (act_name.to_owned(), r)
+            })
+            .collect())
+    }
+
+    pub fn completion(self, account: &str, url: &str) -> Result<(), PizauthError> {
+        let rtn = self.send_one(encode_request("completion", &[account, url]))?;
+        match split_reply(&rtn) {
+            Some(("ok", "")) => Ok(()),
+            Some(("no_account", "")) => Err(PizauthError::AccountNotFound(account.to_owned())),
+            Some(("error", cause)) => {
+                Err(PizauthError::ServerError(format!("{account:}: {cause:}")))
+            }
+            _ => Err(malformed(&rtn)),
+        }
+    }
+
+    /// Return the server's diagnostics report.
+    pub fn diagnose(self) -> Result<String, PizauthError> {
+        let rtn = self.send_one("diagnose".to_owned())?;
+        match split_reply(&rtn) {
+            Some(("diagnose", x)) => Ok(x.to_owned()),
+            Some(("error", cause)) => Err(PizauthError::ServerError(cause.to_owned())),
+            _ => Err(malformed(&rtn)),
+        }
+    }
+
+    /// Return a dump of the server's refresh tokens, in the format [Client::restore] accepts.
+    pub fn dump(self) -> Result<SecretString, PizauthError> {
+        let rtn = SecretString::from(self.send_one("dump".to_owned())?);
+        match split_reply(rtn.expose()) {
+            Some(("dump", x)) => Ok(SecretString::from(x)),
+            Some(("error", cause)) => Err(PizauthError::ServerError(cause.to_owned())),
+            _ => Err(malformed_secret()),
+        }
+    }
+
+    /// Return a snapshot of each account's state, which contains no secrets, as a JSON object.
+    pub fn dump_state(self) -> Result<JsonValue, PizauthError> {
+        let rtn = self.send_one("dump json".to_owned())?;
+        match split_reply(&rtn) {
+            Some(("dump", x)) => json::parse(x).map_err(|_| malformed(&rtn)),
+            Some(("error", cause)) => Err(PizauthError::ServerError(cause.to_owned())),
+            _ => Err(malformed(&rtn)),
+        }
+    }
+
+    /// Return the names of the accounts tagged with `tag`.
+    pub fn expand_tag(self, tag: &str) -> Result<Vec<String>, PizauthError> {
+        let rtn = self.send_one(encode_request("expand-tag", &[tag]))?;
+        match split_reply(&rtn) {
+            Some(("accounts", x)) => x
+                .split(' ')
+                .map(|x| {
+                    urlencoding::decode(x)
+                        .map(|x| x.into_owned())
+                        .map_err(|_| malformed(&rtn))
+                })
+                .collect(),
+            Some(("error", cause)) => Err(PizauthError::ServerError(cause.to_owned())),
+            _ => Err(malformed(&rtn)),
+        }
+    }
+
+    pub fn forget(self, accounts: &[String]) -> Result<AccountResults, PizauthError> {
+        self.send_accounts("forget", accounts, "ok")
+    }
+
+    /// Return the server's details as a JSON object.
+    pub fn info(self) -> Result<JsonValue, PizauthError> {
+        let rtn = self.send_one("info".to_owned())?;
+        match split_reply(&rtn) {
+            Some(("info", x)) => json::parse(x).map_err(|_| malformed(&rtn)),
+            Some(("error", cause)) => Err(PizauthError::ServerError(cause.to_owned())),
+            _ => Err(malformed(&rtn)),
+        }
+    }
+
+    /// Return the server's metrics in the Prometheus text exposition format.
+    pub fn metrics(self) -> Result<String, PizauthError> {
+        let rtn = self.send_one("metrics".to_owned())?;
+        match split_reply(&rtn) {
+            Some(("metrics", x)) => Ok(x.to_owned()),
+            Some(("error", cause)) => Err(PizauthError::ServerError(cause.to_owned())),
+            _ => Err(malformed(&rtn)),
+        }
+    }
+
+    /// Ask the server to refresh the tokens of `accounts`. If `force` is true, the server contacts
+    /// the token endpoint even if it would otherwise wait before doing so.
+    pub fn refresh(self, accounts: &[String], force: bool) -> Result<AccountResults, PizauthError> {
+        let cmd = if force { "refresh-force" } else { "refresh" };
+        self.send_accounts(cmd, accounts, "ok")
+    }
+
+    /// Ask the server to reload its config from `conf_path`, returning a description of what
+    /// changed (which may be empty).
+    pub fn reload(self, conf_path: &Path) -> Result<String, PizauthError> {
+        let conf_path = conf_path
+            .as_os_str()
+            .to_str()
+            .ok_or_else(|| PizauthError::ProtocolError("Unencodable file name".into()))?;
+        let rtn = self.send_one(encode_request("reload", &[conf_path]))?;
+        match split_reply(&rtn) {
+            Some(("ok", diff)) => Ok(diff.to_owned()),
+            Some(("error", cause)) => Err(PizauthError::ServerError(cause.to_owned())),
+            _ => Err(malformed(&rtn)),
+        }
+    }
+
+    /// Ask the server to hand its refresh tokens over to its replacement and exit. The server
+    /// may still accept connections for a short while after this returns.
+    pub fn restart(self) -> Result<(), PizauthError> {
+        let rtn = self.send_one("restart".to_owned())?;
+        match split_reply(&rtn) {
+            Some(("ok", "")) => Ok(()),
+            Some(("error", cause)) => Err(PizauthError::ServerError(cause.to_owned())),
+            _ => Err(malformed(&rtn)),
+        }
+    }
+
+    /// Restore the dump `entries` (3 fields per account), returning the server's report of what
+    /// was, and wasn't, restored (which may be empty).
+    pub fn restore(self, entries: &[&str]) -> Result<String, PizauthError> {
+        let rtn = self.send_one(encode_request("restore", entries))?;
+        match split_reply(&rtn) {
+            Some(("restore", x)) => Ok(x.to_owned()),
+            Some(("error", cause)) => Err(PizauthError::ServerError(cause.to_owned())),
+            _ => Err(malformed(&rtn)),
+        }
+    }
+
+    /// Discard (and, if possible, revoke) the tokens of `accounts`, and start authenticating them
+    /// anew.
+    pub fn rotate(self, accounts: &[String]) -> Result<AccountResults, PizauthError> {
+        self.send_accounts("rotate", accounts, "pending")
+    }
+
+    /// Return `account`'s access token (or, if `id_token` is true, its ID token) with `scopes`,
+    /// which must be valid for at least `min_validity`.
+    pub fn show_token(
+        self,
+        account: &str,
+        scopes: &[String],
+        id_token: bool,
+        min_validity: Option<Duration>,
+    ) -> Result<ShownToken, PizauthError> {
+        self.show_tokens(&[account.to_owned()], scopes, id_token, min_validity)?
+            .remove(0)
+            .1
+    }
+
+    /// Like [Client::show_token], for each of `accounts`.
+    pub fn show_tokens(
+        self,
+        accounts: &[String],
+        scopes: &[String],
+        id_token: bool,
+        min_validity: Option<Duration>,
+    ) -> Result<TokenResults, PizauthError> {
+        // A minimum validity of 0 means that any unexpired token will do.
+        let min_validity = min_validity.map(|x| x.as_secs()).unwrap_or(0).to_string();
+        let cmds = accounts
+            .iter()
+            .map(|account| {
+                let mut args = vec![min_validity.as_str(), account];
+                args.extend(scopes.iter().map(|x| x.as_str()));
+                encode_request(if id_token { "showidtoken" } else { "showtoken" }, &args)
+            })
+            .collect::<Vec<_>>();
+        Ok(accounts
+            .iter()
+            .zip(self.send(&cmds)?)
+            .map(|(account, rtn)| {
+                let rtn = SecretString::from(rtn);
+                (account.to_owned(), shown_token(account, id_token, &rtn))
+            })
+            .collect())
+    }
+
+    /// Ask the server to exit. This does not wait for a reply.
+    pub fn shutdown(mut self) -> Result<(), PizauthError> {
+        write_frame(&mut self.stream, b"shutdown")?;
+        Ok(())
+    }
+
+    /// Test that the server can reach `account`'s token endpoint, calling `info` with a description
+    /// of each check as the server reports it.
+    pub fn test(mut self, account: &str, mut info: impl FnMut(&str)) -> Result<(), PizauthError> {
+        write_frame(
+            &mut self.stream,
+            encode_request("test", &[account]).as_bytes(),
+        )?;
+        self.stream.shutdown(Shutdown::Write)?;
+        loop {
+            let rtn = match read_frame(&mut self.stream).map_err(version_mismatch)? {
+                Some(x) => x,
+                None => {
+                    return Err(PizauthError::ProtocolError(
+                        "Server closed the connection without replying".to_owned(),
+                    ))
+                }
+            };
+            match split_reply(&rtn) {
+                Some(("info", x)) => info(x),
+                Some(("ok", "")) => return Ok(()),
+                Some(("no_account", "")) => {
+                    return Err(PizauthError::AccountNotFound(account.to_owned()))
+                }
+                Some(("error", cause)) => {
+                    return Err(PizauthError::ServerError(format!("{account:}: {cause:}")))
+                }
+                _ => return Err(malformed(&rtn)),
+            }
+        }
+    }
+
+    /// Return a human readable description of the state of each account (or, if `tag` is `Some`,
+    /// each account with that tag).
+    pub fn status(self, tag: Option<&str>) -> Result<String, PizauthError> {
+        let rtn = self.send_one(status_request(false, tag))?;
+        match split_reply(&rtn) {
+            Some(("status", x)) => Ok(x.to_owned()),
+            Some(("error", cause)) => Err(PizauthError::ServerError(cause.to_owned())),
+            _ => Err(malformed(&rtn)),
+        }
+    }
+
+    /// Return the state of each account (or, if `tag` is `Some`, each account with that tag) as a
+    /// JSON object: see the server's `status json` command.
+    pub fn status_json(self, tag: Option<&str>) -> Result<JsonValue, PizauthError> {
+        let rtn = self.send_one(status_request(true, tag))?;
+        match split_reply(&rtn) {
+            Some(("status", x)) => json::parse(x).map_err(|_| malformed(&rtn)),
+            Some(("error", cause)) => Err(PizauthError::ServerError(cause.to_owned())),
+            _ => Err(malformed(&rtn)),
+        }
+    }
+}
+
+/// Encode a `status` request, optionally as JSON and/or only for the accounts tagged with `tag`.
+fn status_request(json: bool, tag: Option<&str>) -> String {
+    let mut args = Vec::new();
+    if json {
+        args.push("json");
+    }
+    if let Some(tag) = tag {
+        args.extend(["tag", tag]);
+    }
+    encode_request("status", &args)
+}
+
+fn malformed(rtn: &str) -> PizauthError {
+    PizauthError::ProtocolError(format!("Malformed response '{rtn:}'"))
+}
+
+/// Parse the server's reply `rtn` to a request for `account`'s access token (or, if `id_token` is
+/// true, its ID token).
+fn shown_token(
+    account: &str,
+    id_token: bool,
+    rtn: &SecretString,
+) -> Result<ShownToken, PizauthError> {
+    let token = |x: &str| {
+        let (expires_in, token) = x.split_once(' ')?;
+        Some(ShownToken {
+            token: SecretString::from(token),
+            expires_in: Duration::from_secs(expires_in.parse().ok()?),
+        })
+    };
+    match split_reply(rtn.expose()) {
+        Some(("access_token", x)) if !id_token => token(x).ok_or_else(malformed_secret),
+        Some(("id_token", x)) if id_token => token(x).ok_or_else(malformed_secret),
+        Some(("no_account", "")) => Err(PizauthError::AccountNotFound(account.to_owned())),
+        Some(("pending", "")) => Err(PizauthError::TokenPending(account.to_owned())),
+        Some(("error", cause)) => Err(PizauthError::ServerError(cause.to_owned())),
+        _ => Err(malformed_secret()),
+    }
+}
+
+/// Like [malformed], for responses which may contain secrets, and which therefore aren't included
+/// in the error.
+fn malformed_secret() -> PizauthError {
+    PizauthError::ProtocolError("Malformed response".to_owned())
+}
+
+/// If `e` was caused by the server speaking a different protocol version, explain what the user
+/// should do about it.
+fn version_mismatch(e: io::Error) -> PizauthError {
+    match VersionMismatch::from_io_error(&e) {
+        Some(vm) => PizauthError::ProtocolError(format!(
+            "pizauth client (protocol version {PROTOCOL_VERSION:}) is {} than the running server \
+            ({}); run 'pizauth shutdown' and restart the server",
+            if vm.is_newer() { "older" } else { "newer" },
+            if vm.is_unframed() {
+                "unversioned protocol".to_owned()
+            } else {
+                format!("protocol version {}", vm.theirs)
+            }
+        )),
+        None => e.into(),
+    }
+}
+
+fn connect(timeout: Duration, cache_path: &Path) -> Result<Client, PizauthError> {
+    Client::connect(&sock_path(cache_path), timeout)
+}
+
+/// Turn the per-account `results` into a single error containing each account's error (if any).
+fn account_errors(results: AccountResults) -> Result<(), PizauthError> {
+    let mut errs = results
+        .into_iter()
+        .filter_map(|(_, r)| r.err())
+        .collect::<Vec<_>>();
+    match errs.len() {
+        0 => Ok(()),
+        1 => Err(errs.pop().unwrap()),
+        _ => Err(PizauthError::Multiple(errs)),
+    }
+}
+
+/// Add the accounts tagged with `tag`, if it is `Some`, to `accounts`, ignoring any which are
+/// already present.
+fn with_tagged(
+    timeout: Duration,
+    cache_path: &Path,
+    mut accounts: Vec<String>,
+    tag: Option<&str>,
+) -> Result<Vec<String>, PizauthError> {
+    if let Some(tag) = tag {
+        for act_name in connect(timeout, cache_path)?.expand_tag(tag)? {
+            if !accounts.contains(&act_name) {
+                accounts.push(act_name);
+            }
+        }
+    }
+    Ok(accounts)
+}
+
+pub fn diagnose(timeout: Duration, cache_path: &Path) -> Result<(), PizauthError> {
+    println!("{}", connect(timeout, cache_path)?.diagnose()?);
+    Ok(())
+}
+
+pub fn dump(timeout: Duration, cache_path: &Path, json: bool) -> Result<(), PizauthError> {
+    if json {
+        println!("{}", connect(timeout, cache_path)?.dump_state()?.pretty(2));
+    } else {
+        print!("{}", connect(timeout, cache_path)?.dump()?.expose());
+    }
+    Ok(())
+}
+
+pub fn metrics(timeout: Duration, cache_path: &Path) -> Result<(), PizauthError> {
+    print!("{}", connect(timeout, cache_path)?.metrics()?);
+    Ok(())
+}
+
+pub fn completion(
+    timeout: Duration,
+    cache_path: &Path,
+    account: &str,
+    url: &str,
+) -> Result<(), PizauthError> {
+    connect(timeout, cache_path)?.completion(account, url)
+}
+
+pub fn forget(
+    timeout: Duration,
+    cache_path: &Path,
+    accounts: Vec<String>,
+    tag: Option<&str>,
+) -> Result<(), PizauthError> {
+    let accounts = with_tagged(timeout, cache_path, accounts, tag)?;
+    account_errors(connect(timeout, cache_path)?.forget(&accounts)?)
+}
+
+pub fn rotate(
+    timeout: Duration,
+    cache_path: &Path,
+    accounts: Vec<String>,
+    tag: Option<&str>,
+) -> Result<(), PizauthError> {
+    let accounts = with_tagged(timeout, cache_path, accounts, tag)?;
+    account_errors(connect(timeout, cache_path)?.rotate(&accounts)?)
+}
+
+/// Print details of the client and, if it can be reached, the server, either as `key: value` lines
+/// or, if `json` is true, as a JSON object. If the server can't be reached, the client's details
+/// are still printed, to help the user work out why.
+pub fn info(timeout: Duration, cache_path: &Path, json: bool) -> Result<(), PizauthError> {
+    let mut info = JsonValue::new_object();
+    info["client_version"] = env!("CARGO_PKG_VERSION").into();
+    info["client_protocol_version"] = PROTOCOL_VERSION.into();
+    let server = match connect(timeout, cache_path) {
+        Ok(x) => x.info()?,
+        Err(e) => {
+            info["socket_path"] = sock_path(cache_path).to_string_lossy().as_ref().into();
+            print_info(info, json);
+            return Err(e);
+        }
+    };
+    for (k, v) in server.entries() {
+        info[k] = v.clone();
+    }
+    print_info(info, json);
+    Ok(())
+}
+
+/// Print `info`, a JSON object, either as JSON or as `key: value` lines.
+fn print_info(info: JsonValue, json: bool) {
+    if json {
+        println!("{}", info.pretty(2));
+        return;
+    }
+    for (k, v) in info.entries() {
+        let v = match v {
+            JsonValue::Null => "-".to_owned(),
+            JsonValue::Array(x) => x
+                .iter()
+                .map(|x| x.to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+            x => x.to_string(),
+        };
+        println!("{k:}: {v:}");
+    }
+}
 
 pub fn refresh(
-    _conf: Config,
+    timeout: Duration,
     cache_path: &Path,
     accounts: Vec<String>,
-) -> Result<(), Box<dyn Error>> {
+    tag: Option<&str>,
+    force: bool,
+) -> Result<(), PizauthError> {
+    let accounts = with_tagged(timeout, cache_path, accounts, tag)?;
+    account_errors(connect(timeout, cache_path)?.refresh(&accounts, force)?)
+}
+
+pub fn reload(
+    timeout: Duration,
+    conf_path: PathBuf,
+    cache_path: &Path,
+) -> Result<(), PizauthError> {
+    let diff = connect(timeout, cache_path)?.reload(&conf_path)?;
+    if !diff.is_empty() {
+        println!("{diff:}");
+    }
+    Ok(())
+}
+
+/// Ask the running server to hand its refresh tokens over and exit, then start a new server with
+/// the same config file and options as the old server.
+pub fn restart(timeout: Duration, cache_path: &Path) -> Result<(), PizauthError> {
+    let info = connect(timeout, cache_path)?.info()?;
+    let args = restart_args(&info)?;
+    // Checking the config first means that the running server isn't stopped if the new server
+    // couldn't be started.
+    if let (Some(_), Some(conf_path)) = (&args, info["config_path"].as_str()) {
+        Config::from_path(Path::new(conf_path))
+            .map_err(|e| PizauthError::ServerError(format!("Config error: {e:}")))?;
+    }
+    // A server which can't be restarted tells us why.
+    connect(timeout, cache_path)?.restart()?;
+    let args = args.ok_or_else(|| malformed(&info.dump()))?;
+
+    // The old server still accepts connections until it has exited.
     let sock_path = sock_path(cache_path);
-    let mut errs = Vec::new();
-    for act_name in accounts {
-        let mut stream = UnixStream::connect(&sock_path)
-            .map_err(|_| "pizauth authenticator not running or not responding")?;
-        stream
-            .write_all(format!("refresh {act_name:}").as_bytes())
-            .map_err(|_| "Socket not writeable")?;
-        stream.shutdown(Shutdown::Write)?;
-
-        let mut rtn = String::new();
-        stream.read_to_string(&mut rtn)?;
-        match rtn.splitn(2, ':').collect::<Vec<_>>()[..] {
-            ["ok", ""] => (),
-            ["error", cause] => errs.push(format!("{act_name}:{cause:}")),
-            ["pending", ""] => errs.push(format!(
-                "{act_name:}: Token unavailable until authentication complete"
+    let start = Instant::now();
+    while UnixStream::connect(&sock_path).is_ok() {
+        if start.elapsed() > RESTART_TIMEOUT {
+            return Err(PizauthError::Timeout);
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    // The new server only detaches once it is ready, so when this returns, it is accepting
+    // requests.
+    let status = Command::new(env::current_exe()?)
+        .arg("server")
+        .args(args)
+        .status()?;
+    if !status.success() {
+        return Err(PizauthError::ServerError(
+            "New server failed to start: if it is started within a minute, it will still pick up the old server's tokens".to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+/// Return the `restart_args` in the server's `info`, or `None` if the server can't be restarted.
+fn restart_args(info: &JsonValue) -> Result<Option<Vec<String>>, PizauthError> {
+    match &info["restart_args"] {
+        JsonValue::Null => Ok(None),
+        JsonValue::Array(x) => x
+            .iter()
+            .map(|x| x.as_str().map(|x| x.to_owned()))
+            .collect::<Option<Vec<_>>>()
+            .map(Some)
+            .ok_or_else(|| malformed(&info.dump())),
+        _ => Err(malformed(&info.dump())),
+    }
+}
+
+pub fn restore(timeout: Duration, cache_path: &Path) -> Result<(), PizauthError> {
+    let mut input = String::new();
+    io::stdin().read_to_string(&mut input)?;
+    let mut lines = input
+        .lines()
+        .filter(|x| !x.starts_with('#') && !x.trim().is_empty());
+    if lines.next() != Some(DUMP_VERSION) {
+        return Err(PizauthError::ProtocolError(
+            "Input is not a dump from this version of pizauth".to_owned(),
+        ));
+    }
+    let mut entries = Vec::new();
+    for (i, line) in lines.enumerate() {
+        let fields = line.split(' ').collect::<Vec<_>>();
+        if fields.len() != 3 || fields.contains(&"") {
+            return Err(PizauthError::ProtocolError(format!(
+                "Malformed entry {} in dump",
+                i + 1
+            )));
+        }
+        entries.extend(fields);
+    }
+    let report = connect(timeout, cache_path)?.restore(&entries)?;
+    if !report.is_empty() {
+        println!("{report:}");
+    }
+    Ok(())
+}
+
+/// How `pizauth show` prints a token.
+pub enum TokenFormat {
+    /// The token as-is.
+    Raw,
+    /// `Bearer <token>`, as used in an HTTP `Authorization` header.
+    Bearer,
+    /// `Basic <credentials>`, where the credentials are the base64 encoded `client_id:token`, for
+    /// APIs which accept the token as a password.
+    Basic { client_id: String },
+    /// A JSON object in the style of a token endpoint's response (RFC 6749 section 5.1).
+    Json,
+    /// A base64 encoded SASL XOAUTH2 initial client response for `user`.
+    XOAuth2 { user: String },
+    /// A base64 encoded SASL OAUTHBEARER (RFC 7628) initial client response for `user`.
+    OAuthBearer {
+        user: String,
+        host: Option<String>,
+        port: Option<u16>,
+    },
+}
+
+impl TokenFormat {
+    /// Return `token`, which expires in `expires_in`, in this format.
+    fn format(&self, token: &str, expires_in: Duration) -> String {
+        match self {
+            TokenFormat::Raw => token.to_owned(),
+            TokenFormat::Bearer => format!("Bearer {token:}"),
+            TokenFormat::Basic { client_id } => {
+                format!(
+                    "Basic {}",
+                    base64_encode(format!("{client_id:}:{token:}").as_bytes())
+                )
+            }
+            TokenFormat::Json => json::object! {
+                token_type: "bearer",
+                access_token: token,
+                expires_in: expires_in.as_secs(),
+            }
+            .dump(),
+            TokenFormat::XOAuth2 { user } => {
+                base64_encode(format!("user={user:}\x01auth=Bearer {token:}\x01\x01").as_bytes())
+            }
+            TokenFormat::OAuthBearer { user, host, port } => {
+                // The GS2 header's authzid is a SASL name (RFC 5801 section 4), in which ',' and
+                // '=' must be escaped.
+                let user = user.replace('=', "=3D").replace(',', "=2C");
+                let mut s = format!("n,a={user:},\x01");
+                if let Some(x) = host {
+                    s.push_str(&format!("host={x:}\x01"));
+                }
+                if let Some(x) = port {
+                    s.push_str(&format!("port={x:}\x01"));
+                }
+                s.push_str(&format!("auth=Bearer {token:}\x01\x01"));
+                base64_encode(s.as_bytes())
+            }
+        }
+    }
+}
+
+/// Encode `b` as padded base64 (RFC 4648 section 4).
+fn base64_encode(b: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(b.len().div_ceil(3) * 4);
+    for chunk in b.chunks(3) {
+        let buf = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |acc, (i, x)| acc | (u32::from(*x) << (16 - 8 * i)));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(char::from(
+                    ALPHABET[((buf >> (18 - 6 * i)) & 0x3f) as usize],
+                ));
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Print the token of each of `accounts`, formatted with that account's [TokenFormat]. A single
+/// account's token is printed on its own, for backwards compatibility; several accounts' tokens are
+/// printed as `<account>\t<token>` lines or, if `json` is true, as a JSON object mapping each
+/// account to its token. Tokens which could be obtained are printed even if others couldn't.
+///
+/// Each account has its own format, rather than there being one format for all accounts, because
+/// some formats (e.g. [TokenFormat::Basic]) embed details of the account. `scopes`, `id_token`,
+/// and `min_validity` are as for [Client::show_token].
+pub fn show_token(
+    timeout: Duration,
+    cache_path: &Path,
+    accounts: &[(String, TokenFormat)],
+    scopes: &[String],
+    id_token: bool,
+    min_validity: Option<Duration>,
+    json: bool,
+) -> Result<(), PizauthError> {
+    let client = connect(timeout, cache_path)?;
+    if let ([(act_name, format)], false) = (accounts, json) {
+        let tk = client.show_token(act_name, scopes, id_token, min_validity)?;
+        println!("{}", format.format(tk.token.expose(), tk.expires_in));
+        return Ok(());
+    }
+    let names = accounts.iter().map(|(x, _)| x.clone()).collect::<Vec<_>>();
+    let tks = client.show_tokens(&names, scopes, id_token, min_validity)?;
+    let mut obj = JsonValue::new_object();
+    let mut results = Vec::with_capacity(tks.len());
+    for ((act_name, format), (_, tk)) in accounts.iter().zip(tks) {
+        match tk {
+            Ok(tk) => {
+                let s = format.format(tk.token.expose(), tk.expires_in);
+                if json {
+                    obj[act_name.as_str()] = s.into();
+                } else {
+                    println!("{act_name:}\t{s:}");
+                }
+                results.push((act_name.clone(), Ok(())));
+            }
+            // Without the account's name, the user couldn't tell which account the error is for.
+            Err(PizauthError::ServerError(e)) => results.push((
+                act_name.clone(),
+                Err(PizauthError::ServerError(format!("{act_name:}: {e:}"))),
             )),
-            _ => errs.push(format!("{act_name:}: Malformed response '{rtn:}'")),
+            Err(e) => results.push((act_name.clone(), Err(e))),
         }
     }
-    if errs.is_empty() {
-        Ok(())
+    if json {
+        println!("{}", obj.pretty(2));
+    }
+    account_errors(results)
+}
+
+pub fn status(
+    timeout: Duration,
+    cache_path: &Path,
+    json: bool,
+    tag: Option<&str>,
+) -> Result<(), PizauthError> {
+    let client = connect(timeout, cache_path)?;
+    if json {
+        println!("{}", client.status_json(tag)?.pretty(2));
     } else {
-        Err(errs.join("\n").into())
+        println!("{}", client.status(tag)?);
     }
+    Ok(())
 }
 
-pub fn reload(_conf: Config, conf_path: PathBuf, cache_path: &Path) -> Result<(), Box<dyn Error>> {
-    let sock_path = sock_path(cache_path);
-    let mut stream = UnixStream::connect(&sock_path)
-        .map_err(|_| "pizauth authenticator not running or not responding")?;
-    stream
-        .write_all(
-            format!(
-                "reload {}",
-                conf_path
-                    .as_os_str()
-                    .to_str()
-                    .ok_or("Unencodable file name")?
+pub fn test(timeout: Duration, cache_path: &Path, account: &str) -> Result<(), PizauthError> {
+    connect(timeout, cache_path)?.test(account, |x| println!("{x:}"))
+}
+
+/// An account's state, as seen by [monitor].
+#[derive(Clone, Debug, PartialEq)]
+struct AccountStatus {
+    /// The name of the account's token state (e.g. "Active").
+    state: String,
+    /// For active tokens, the seconds until the token expires.
+    expires_in_secs: Option<u64>,
+}
+
+/// Convert the output of [Client::status_json] into a map from account names to their statuses.
+fn account_statuses(status: &JsonValue) -> BTreeMap<String, AccountStatus> {
+    status
+        .entries()
+        .map(|(act_name, x)| {
+            (
+                act_name.to_owned(),
+                AccountStatus {
+                    state: x["state"].as_str().unwrap_or("unknown").to_owned(),
+                    expires_in_secs: x["expires_in_secs"].as_u64(),
+                },
             )
-            .as_bytes(),
-        )
-        .map_err(|_| "Socket not writeable")?;
-    stream.shutdown(Shutdown::Write)?;
+        })
+        .collect()
+}
 
-    let mut rtn = String::new();
-    stream.read_to_string(&mut rtn)?;
-    match rtn.splitn(2, ':').collect::<Vec<_>>()[..] {
-        ["ok", ""] => Ok(()),
-        ["error", cause] => Err(cause.into()),
-        _ => Err(format!("Malformed response '{rtn:}'").into()),
+/// Compare the accounts' previous statuses `old` with their current statuses `new`, returning the
+/// lines to print for the user and the accounts whose tokens expire within `warn_before` (and
+/// should thus be refreshed).
+fn monitor_changes(
+    old: &BTreeMap<String, AccountStatus>,
+    new: &BTreeMap<String, AccountStatus>,
+    warn_before: Duration,
+) -> (Vec<String>, Vec<String>) {
+    let mut lines = Vec::new();
+    let mut to_refresh = Vec::new();
+    for act_name in old.keys().filter(|x| !new.contains_key(*x)) {
+        lines.push(format!("{act_name:}: removed"));
+    }
+    for (act_name, st) in new {
+        match old.get(act_name) {
+            None => lines.push(format!("{act_name:}: {}", st.state)),
+            Some(old_st) if old_st.state != st.state => {
+                lines.push(format!("{act_name:}: {} -> {}", old_st.state, st.state))
+            }
+            Some(_) => (),
+        }
+        if let Some(secs) = st.expires_in_secs {
+            if secs <= warn_before.as_secs() {
+                lines.push(format!(
+                    "{act_name:}: token expires in {secs:}s: refreshing"
+                ));
+                to_refresh.push(act_name.to_owned());
+            }
+        }
     }
+    (lines, to_refresh)
 }
 
-pub fn show_token(_conf: Config, cache_path: &Path, account: &str) -> Result<(), Box<dyn Error>> {
-    let sock_path = sock_path(cache_path);
-    let mut stream = UnixStream::connect(&sock_path)
-        .map_err(|_| "pizauth authenticator not running or not responding")?;
-    stream
-        .write_all(format!("showtoken {account:}").as_bytes())
-        .map_err(|_| "Socket not writeable")?;
-    stream.shutdown(Shutdown::Write)?;
+/// Poll the server every `interval`, printing a line whenever an account's state changes, and
+/// asking the server to refresh any active token which expires within `warn_before`. This only
+/// returns if an error occurs: SIGINT and SIGTERM exit the process.
+pub fn monitor(
+    timeout: Duration,
+    cache_path: &Path,
+    interval: Duration,
+    warn_before: Duration,
+) -> Result<(), PizauthError> {
+    let mut signals = SigSet::empty();
+    signals.add(Signal::SIGINT);
+    signals.add(Signal::SIGTERM);
+    signals.thread_block().map_err(io::Error::from)?;
+    thread::spawn(move || {
+        if signals.wait().is_ok() {
+            process::exit(0);
+        }
+    });
 
-    let mut rtn = String::new();
-    stream.read_to_string(&mut rtn)?;
-    match rtn.splitn(2, ':').collect::<Vec<_>>()[..] {
-        ["access_token", x] => {
-            println!("{x:}");
-            Ok(())
+    let mut old = BTreeMap::new();
+    let mut running = true;
+    loop {
+        let new = match connect(timeout, cache_path).and_then(|x| x.status_json(None)) {
+            Ok(x) => {
+                if !running {
+                    println!("pizauth server running");
+                    running = true;
+                }
+                account_statuses(&x)
+            }
+            // The server may just be restarting, so we keep watching for it.
+            Err(PizauthError::DaemonNotRunning) => {
+                if running {
+                    println!("pizauth server not running");
+                    running = false;
+                }
+                BTreeMap::new()
+            }
+            Err(e) => return Err(e),
+        };
+        let (lines, to_refresh) = monitor_changes(&old, &new, warn_before);
+        for l in lines {
+            println!("{l:}");
+        }
+        if !to_refresh.is_empty() {
+            if let Err(e) = connect(timeout, cache_path)
+                .and_then(|x| x.refresh(&to_refresh, false))
+                .and_then(account_errors)
+            {
+                error!("{e:}");
+            }
         }
-        ["pending", ""] => Err("Token unavailable until authentication complete".into()),
-        ["error", cause] => Err(cause.into()),
-        _ => Err(format!("Malformed response '{rtn:}'").into()),
+        old = new;
+        thread::sleep(interval);
     }
 }
 
-pub fn shutdown(
-    _conf: Config,
-    _conf_path: PathBuf,
-    cache_path: &Path,
-) -> Result<(), Box<dyn Error>> {
-    let sock_path = sock_path(cache_path);
-    let mut stream = UnixStream::connect(&sock_path)
-        .map_err(|_| "pizauth authenticator not running or not responding")?;
-    stream
-        .write_all(b"shutdown")
-        .map_err(|_| "Socket not writeable")?;
-    Ok(())
+pub fn shutdown(timeout: Duration, cache_path: &Path) -> Result<(), PizauthError> {
+    connect(timeout, cache_path)?.shutdown()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{env, fs, io::Write, os::unix::net::UnixListener, thread::JoinHandle};
+
+    /// Run a fake server on one end of a socketpair, returning a client connected to the other
+    /// end. The server reads requests until the client stops sending, then writes `replies` (which
+    /// need not be well formed) `chunk` bytes at a time, and closes the connection. Joining the
+    /// returned thread gives the requests the server received.
+    fn fake_server(replies: Vec<u8>, chunk: usize) -> (Client, JoinHandle<Vec<String>>) {
+        let (client, mut server) = UnixStream::pair().unwrap();
+        let t = thread::spawn(move || {
+            let mut reqs = Vec::new();
+            while let Some(x) = read_frame(&mut server).unwrap() {
+                reqs.push(x);
+            }
+            for x in replies.chunks(chunk) {
+                // The client may have given up on a malformed reply and closed the connection.
+                if server.write_all(x).is_err() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(1));
+            }
+            reqs
+        });
+        (Client::from_stream(client), t)
+    }
+
+    /// Encode each of `replies` as a frame.
+    fn frames(replies: &[&str]) -> Vec<u8> {
+        let mut b = Vec::new();
+        for x in replies {
+            write_frame(&mut b, x.as_bytes()).unwrap();
+        }
+        b
+    }
+
+    #[test]
+    fn test_client() {
+        let (client, t) = fake_server(frames(&["access_token:3600 a b"]), usize::MAX);
+        let tk = client
+            .show_token("x y", &["s".to_owned()], false, None)
+            .unwrap();
+        assert_eq!(tk.token.expose(), "a b");
+        assert_eq!(tk.expires_in, Duration::from_secs(3600));
+        assert_eq!(t.join().unwrap(), vec!["showtoken 0 x%20y s"]);
+
+        let (client, t) = fake_server(frames(&["id_token:60 i"]), usize::MAX);
+        let tk = client
+            .show_token("x", &[], true, Some(Duration::from_secs(60)))
+            .unwrap();
+        assert_eq!(tk.token.expose(), "i");
+        assert_eq!(t.join().unwrap(), vec!["showidtoken 60 x"]);
+
+        // Several accounts' tokens are requested on one connection, and one account's failure
+        // doesn't affect the others.
+        let (client, t) = fake_server(
+            frames(&["access_token:60 a", "pending:", "access_token:30 c"]),
+            usize::MAX,
+        );
+        let accounts = ["a", "b", "c"].map(|x| x.to_owned());
+        let r = client.show_tokens(&accounts, &[], false, None).unwrap();
+        assert_eq!(r.len(), 3);
+        assert_eq!(r[0].1.as_ref().unwrap().token.expose(), "a");
+        assert!(matches!(&r[1].1, Err(PizauthError::TokenPending(x)) if x == "b"));
+        assert_eq!(r[2].1.as_ref().unwrap().expires_in, Duration::from_secs(30));
+        assert_eq!(
+            t.join().unwrap(),
+            vec!["showtoken 0 a", "showtoken 0 b", "showtoken 0 c"]
+        );
+
+        // An access token was asked for, but the server sent an ID token.
+        let (client, _) = fake_server(frames(&["id_token:60 i"]), usize::MAX);
+        assert!(matches!(
+            client.show_token("x", &[], false, None),
+            Err(PizauthError::ProtocolError(_))
+        ));
+        // The token's expiry is missing.
+        let (client, _) = fake_server(frames(&["access_token:secret"]), usize::MAX);
+        assert_eq!(
+            client
+                .show_token("x", &[], false, None)
+                .unwrap_err()
+                .to_string(),
+            "Malformed response"
+        );
+
+        let (client, _) = fake_server(frames(&["pending:"]), usize::MAX);
+        assert!(matches!(
+            client.show_token("x", &[], false, None),
+            Err(PizauthError::TokenPending(x)) if x == "x"
+        ));
+
+        let (client, t) = fake_server(
+            frames(&["ok:", "pending:", "no_account:", "error:e"]),
+            usize::MAX,
+        );
+        let accounts = ["a", "b", "c", "d"].map(|x| x.to_owned());
+        let r = client.refresh(&accounts, true).unwrap();
+        assert_eq!(r.len(), 4);
+        assert!(r[0].1.is_ok());
+        assert!(matches!(&r[1].1, Err(PizauthError::TokenPending(x)) if x == "b"));
+        assert!(matches!(&r[2].1, Err(PizauthError::AccountNotFound(x)) if x == "c"));
+        assert!(matches!(&r[3].1, Err(PizauthError::ServerError(x)) if x == "d:e"));
+        assert_eq!(
+            t.join().unwrap(),
+            vec![
+                "refresh-force a",
+                "refresh-force b",
+                "refresh-force c",
+                "refresh-force d"
+            ]
+        );
+        assert_eq!(account_errors(r).unwrap_err().exit_code(), 1);
+
+        // For `rotate`, a pending token is what success looks like.
+        let (client, _) = fake_server(frames(&["pending:"]), usize::MAX);
+        assert!(client.rotate(&["a".to_owned()]).unwrap()[0].1.is_ok());
+    }
+
+    #[test]
+    fn test_client_partial_reads() {
+        // Each byte of the replies arrives separately.
+        let (client, _) = fake_server(frames(&["ok:"]), 1);
+        let r = client.refresh(&["a".to_owned()], false).unwrap();
+        assert!(r[0].1.is_ok());
+
+        let (client, _) = fake_server(frames(&["status:{\"a\": {}}"]), 3);
+        assert!(client.status_json(None).unwrap().has_key("a"));
+    }
+
+    #[test]
+    fn test_client_tags() {
+        let (client, t) = fake_server(frames(&["accounts:a%20b c"]), usize::MAX);
+        assert_eq!(client.expand_tag("t u").unwrap(), vec!["a b", "c"]);
+        assert_eq!(t.join().unwrap(), vec!["expand-tag t%20u"]);
+
+        let (client, _) = fake_server(frames(&["error:Unknown tag"]), usize::MAX);
+        assert!(matches!(
+            client.expand_tag("t"),
+            Err(PizauthError::ServerError(x)) if x == "Unknown tag"
+        ));
+
+        let (client, t) = fake_server(frames(&["status:{}"]), usize::MAX);
+        client.status_json(Some("t")).unwrap();
+        assert_eq!(t.join().unwrap(), vec!["status json tag t"]);
+        let (client, t) = fake_server(frames(&["status:"]), usize::MAX);
+        client.status(Some("t")).unwrap();
+        assert_eq!(t.join().unwrap(), vec!["status tag t"]);
+    }
+
+    #[test]
+    fn test_client_test() {
+        let (client, t) = fake_server(
+            frames(&["info:dns: pass: a", "info:tcp: fail: b", "error:c"]),
+            usize::MAX,
+        );
+        let mut lines = Vec::new();
+        assert!(matches!(
+            client.test("x", |x| lines.push(x.to_owned())),
+            Err(PizauthError::ServerError(x)) if x == "x: c"
+        ));
+        assert_eq!(lines, ["dns: pass: a", "tcp: fail: b"]);
+        assert_eq!(t.join().unwrap(), vec!["test x"]);
+
+        let (client, _) = fake_server(frames(&["info:a", "ok:"]), 1);
+        assert!(client.test("x", |_| ()).is_ok());
+
+        // The server closes the connection before its final reply.
+        let (client, _) = fake_server(frames(&["info:a"]), usize::MAX);
+        assert!(matches!(
+            client.test("x", |_| ()),
+            Err(PizauthError::ProtocolError(_))
+        ));
+    }
+
+    #[test]
+    fn test_client_malformed() {
+        let (client, _) = fake_server(frames(&["no colon"]), usize::MAX);
+        assert!(matches!(
+            client.diagnose(),
+            Err(PizauthError::ProtocolError(_))
+        ));
+
+        let (client, _) = fake_server(frames(&["status:{"]), usize::MAX);
+        assert!(matches!(
+            client.status_json(None),
+            Err(PizauthError::ProtocolError(_))
+        ));
+
+        // Malformed replies only affect the accounts they are for.
+        let (client, _) = fake_server(frames(&["ok:", "ok:unexpected"]), usize::MAX);
+        let r = client.forget(&["a".to_owned(), "b".to_owned()]).unwrap();
+        assert!(r[0].1.is_ok());
+        assert!(matches!(&r[1].1, Err(PizauthError::ProtocolError(_))));
+
+        // Dumps contain secrets, so malformed replies aren't included in the error.
+        let (client, _) = fake_server(frames(&["secret"]), usize::MAX);
+        assert_eq!(client.dump().unwrap_err().to_string(), "Malformed response");
+
+        // The server closes the connection before replying to every request.
+        let (client, _) = fake_server(frames(&["ok:"]), usize::MAX);
+        assert!(matches!(
+            client.refresh(&["a".to_owned(), "b".to_owned()], false),
+            Err(PizauthError::ProtocolError(_))
+        ));
+
+        // The server closes the connection part way through a frame.
+        let mut b = frames(&["status:abc"]);
+        b.truncate(b.len() - 1);
+        let (client, _) = fake_server(b, 1);
+        assert!(matches!(client.status(None), Err(PizauthError::IoError(_))));
+
+        // The server speaks a different protocol version.
+        let mut b = frames(&["ok:"]);
+        b[0] = PROTOCOL_VERSION + 1;
+        let (client, _) = fake_server(b, usize::MAX);
+        assert!(client
+            .restart()
+            .unwrap_err()
+            .to_string()
+            .contains("is older than the running server"));
+    }
+
+    #[test]
+    fn test_restart_args() {
+        let info = json::parse(r#"{"restart_args": ["--daemonize", "-c", "/a b"]}"#).unwrap();
+        assert_eq!(
+            restart_args(&info).unwrap().unwrap(),
+            vec!["--daemonize", "-c", "/a b"]
+        );
+        let info = json::parse(r#"{"restart_args": null}"#).unwrap();
+        assert!(restart_args(&info).unwrap().is_none());
+        let info = json::parse(r#"{"restart_args": [1]}"#).unwrap();
+        assert!(matches!(
+            restart_args(&info),
+            Err(PizauthError::ProtocolError(_))
+        ));
+    }
+
+    #[test]
+    fn test_client_connect() {
+        let dir = env::temp_dir().join(format!("pizauth_client_test_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = sock_path(&dir);
+        let timeout = Duration::from_millis(100);
+        assert!(matches!(
+            Client::connect(&path, timeout),
+            Err(PizauthError::DaemonNotRunning)
+        ));
+        // A server which never handles the connection.
+        let listener = UnixListener::bind(&path).unwrap();
+        let e = Client::connect(&path, timeout)
+            .unwrap()
+            .status(None)
+            .unwrap_err();
+        assert!(matches!(e, PizauthError::Timeout));
+        assert_eq!(e.exit_code(), crate::error::EXIT_SERVER_UNRESPONSIVE);
+        // A socket left behind by a server which is no longer listening.
+        drop(listener);
+        assert!(matches!(
+            Client::connect(&path, timeout),
+            Err(PizauthError::DaemonNotRunning)
+        ));
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_monitor_changes() {
+        let status = json::parse(
+            r#"{"x": {"state": "Pending", "expires_in_secs": null},
+                "y": {"state": "Active", "expires_in_secs": 3600}}"#,
+        )
+        .unwrap();
+        let old = account_statuses(&status);
+        let warn_before = Duration::from_secs(60);
+        let (lines, to_refresh) = monitor_changes(&BTreeMap::new(), &old, warn_before);
+        assert_eq!(lines, vec!["x: Pending", "y: Active"]);
+        assert!(to_refresh.is_empty());
+        assert!(monitor_changes(&old, &old, warn_before).0.is_empty());
+
+        let status = json::parse(
+            r#"{"x": {"state": "Active", "expires_in_secs": 30},
+                "z": {"state": "Empty", "expires_in_secs": null}}"#,
+        )
+        .unwrap();
+        let new = account_statuses(&status);
+        let (lines, to_refresh) = monitor_changes(&old, &new, warn_before);
+        assert_eq!(
+            lines,
+            vec![
+                "y: removed",
+                "x: Pending -> Active",
+                "x: token expires in 30s: refreshing",
+                "z: Empty"
+            ]
+        );
+        assert_eq!(to_refresh, vec!["x"]);
+    }
+
+    #[test]
+    fn test_base64_encode() {
+        // The test vectors from RFC 4648 section 10.
+        for (x, y) in [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ] {
+            assert_eq!(base64_encode(x.as_bytes()), y);
+        }
+    }
+
+    #[test]
+    fn test_token_format() {
+        assert_eq!(TokenFormat::Raw.format("abc", Duration::ZERO), "abc");
+        // The example from Google's XOAUTH2 documentation.
+        assert_eq!(
+            TokenFormat::XOAuth2 {
+                user: "someuser@example.com".to_owned()
+            }
+            .format("ya29.vF9dft4qmTc2Nvb3RlckBhdHRhdmlzdGEuY29tCg", Duration::ZERO),
+            "dXNlcj1zb21ldXNlckBleGFtcGxlLmNvbQFhdXRoPUJlYXJlciB5YTI5LnZGOWRmdDRxbVRjMk52YjNSbGNrQmhk\
+             SFJoZG1semRHRXVZMjl0Q2cBAQ=="
+        );
+        // The example from RFC 7628 section 4.1.
+        assert_eq!(
+            TokenFormat::OAuthBearer {
+                user: "user@example.com".to_owned(),
+                host: Some("server.example.com".to_owned()),
+                port: Some(143)
+            }
+            .format("vF9dft4qmTc2Nvb3RlckBhbHRhdmlzdGEuY29tCg==", Duration::ZERO),
+            "bixhPXVzZXJAZXhhbXBsZS5jb20sAWhvc3Q9c2VydmVyLmV4YW1wbGUuY29tAXBvcnQ9MTQzAWF1dGg9QmVhcmVy\
+             IHZGOWRmdDRxbVRjMk52YjNSbGNrQmhiSFJoZG1semRHRXVZMjl0Q2c9PQEB"
+        );
+        assert_eq!(
+            TokenFormat::OAuthBearer {
+                user: "a=b,c".to_owned(),
+                host: None,
+                port: None
+            }
+            .format("t", Duration::ZERO),
+            "bixhPWE9M0RiPTJDYywBYXV0aD1CZWFyZXIgdAEB"
+        );
+        assert_eq!(TokenFormat::Bearer.format("t", Duration::ZERO), "Bearer t");
+        // The example from RFC 7617 section 2.
+        assert_eq!(
+            TokenFormat::Basic {
+                client_id: "Aladdin".to_owned()
+            }
+            .format("open sesame", Duration::ZERO),
+            "Basic QWxhZGRpbjpvcGVuIHNlc2FtZQ=="
+        );
+        assert_eq!(
+            TokenFormat::Json.format("t\"", Duration::from_secs(60)),
+            r#"{"token_type":"bearer","access_token":"t\"","expires_in":60}"#
+        );
+    }
 }